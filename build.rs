@@ -0,0 +1,38 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+// WASABI_SYMBOL_MAPにnmの形式のシンボル一覧 (scripts/gen_symbols.sh で作る) が指定されていたら、
+// 関数のものだけをアドレス順に並べて埋め込む。指定が無ければ空の表になる
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=WASABI_SYMBOL_MAP");
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("symbols.txt");
+    let mut symbols = Vec::new();
+    if let Ok(path) = env::var("WASABI_SYMBOL_MAP") {
+        println!("cargo:rerun-if-changed={path}");
+        let map = fs::read_to_string(&path).expect("Failed to read WASABI_SYMBOL_MAP");
+        for line in map.lines() {
+            // "0000000140001000 T name" の形。デマングルされた名前は空白を含むことがある
+            let mut parts = line.trim().splitn(3, ' ');
+            let (Some(addr), Some(kind), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            if !kind.eq_ignore_ascii_case("t") {
+                continue;
+            }
+            if let Ok(addr) = u64::from_str_radix(addr, 16) {
+                symbols.push((addr, name.to_string()));
+            }
+        }
+    }
+    symbols.sort();
+    symbols.dedup_by_key(|(addr, _)| *addr);
+    let mut table = String::new();
+    for (addr, name) in &symbols {
+        writeln!(table, "{addr:x} {name}").unwrap();
+    }
+    fs::write(out, table).expect("Failed to write the symbol table");
+}
@@ -0,0 +1,16 @@
+#!/bin/bash -e
+# シリアルのログ (log/com1.txt) からスクリーンショットを取り出す
+# usage: scripts/extract_screenshot.sh log/com1.txt [out_prefix]
+LOG="$1"
+PREFIX="${2:-screenshot}"
+awk -v prefix="${PREFIX}" '
+/^-----BEGIN SCREENSHOT BMP-----/ { n++; out = sprintf("%s_%d.b64", prefix, n); printf "" > out; next }
+/^-----END SCREENSHOT BMP-----/ { close(out); out = ""; next }
+out != "" { sub(/\r$/, ""); print >> out }
+' "${LOG}"
+for f in "${PREFIX}"_*.b64; do
+  [ -e "$f" ] || continue
+  base64 -d "$f" > "${f%.b64}.bmp"
+  rm "$f"
+  echo "${f%.b64}.bmp"
+done
@@ -0,0 +1,17 @@
+#!/bin/bash -e
+# カーネルのシンボル一覧を作って、それを埋め込んだカーネルをビルドし直す
+# シンボルの表は.rdataに入るだけなので、2回目のビルドでも関数のアドレスは変わらない
+PROJ_ROOT="$(dirname $(dirname ${BASH_SOURCE:-$0}))"
+cd "${PROJ_ROOT}"
+
+PROFILE="${1:-debug}"
+TARGET_DIR="target/x86_64-unknown-uefi/${PROFILE}"
+CARGO_FLAGS=""
+if [ "${PROFILE}" = "release" ]; then
+  CARGO_FLAGS="--release"
+fi
+
+cargo build ${CARGO_FLAGS}
+llvm-nm --defined-only --demangle "${TARGET_DIR}/wasabi.efi" > "${TARGET_DIR}/wasabi.sym"
+WASABI_SYMBOL_MAP="$(pwd)/${TARGET_DIR}/wasabi.sym" cargo build ${CARGO_FLAGS}
+echo "Embedded $(grep -ci ' t ' ${TARGET_DIR}/wasabi.sym) symbols into ${TARGET_DIR}/wasabi.efi"
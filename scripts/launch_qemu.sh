@@ -6,6 +6,9 @@ PATH_TO_EFI="$1"
 rm -rf mnt
 mkdir -p mnt/EFI/BOOT/
 cp ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
+if [ -f initramfs.tar ]; then
+  cp initramfs.tar mnt/initramfs.tar
+fi
 set +e
 mkdir -p log
 qemu-system-x86_64 \
@@ -14,7 +17,9 @@ qemu-system-x86_64 \
   -drive format=raw,file=fat:rw:mnt \
   -chardev stdio,id=char_com1,mux=on,logfile=log/com1.txt \
   -serial chardev:char_com1 \
-  -device isa-debug-exit,iobase=0xf4,iosize=0x01
+  -debugcon file:log/debugcon.txt \
+  -device isa-debug-exit,iobase=0xf4,iosize=0x01 \
+  ${QEMU_EXTRA_ARGS}
 RETCODE=$?
 set -e
 if [ $RETCODE -eq 0 ]; then
@@ -22,6 +27,21 @@ if [ $RETCODE -eq 0 ]; then
 elif [ $RETCODE -eq 3 ]; then
   printf "\nPASS\n"
   exit 0
+elif [ $RETCODE -eq 7 ]; then
+  printf "\nFAIL:kernel assertion failed\n"
+  exit 1
+elif [ $RETCODE -eq 9 ]; then
+  printf "\nFAIL:kernel panic\n"
+  exit 1
+elif [ $RETCODE -eq 11 ]; then
+  printf "\nFAIL:double fault\n"
+  exit 1
+elif [ $RETCODE -eq 13 ]; then
+  printf "\nFAIL:watchdog timeout\n"
+  exit 1
+elif [ $RETCODE -eq 15 ]; then
+  printf "\nFAIL:test timeout\n"
+  exit 1
 else
   printf "\nFAIL:QEMU returned $RETCODE\n"
   exit 1
@@ -0,0 +1,40 @@
+#!/bin/bash -e
+# 結合テストのシナリオを、1つずつQEMUを起動し直して動かす
+# 使い方: scripts/run_scenarios.sh [シナリオ名...] (省略すると全部)
+PROJ_ROOT="$(dirname $(dirname ${BASH_SOURCE:-$0}))"
+cd "${PROJ_ROOT}"
+
+ALL_SCENARIOS="paging timer kthread block bench"
+SCENARIOS="${@:-${ALL_SCENARIOS}}"
+# 1つのシナリオにかける時間の上限 (秒)
+TIMEOUT="${SCENARIO_TIMEOUT:-60}"
+
+cargo build
+PATH_TO_EFI="target/x86_64-unknown-uefi/debug/wasabi.efi"
+mkdir -p log
+
+# シナリオごとに必要なデバイス
+scenario_args() {
+  case "$1" in
+    block)
+      dd if=/dev/zero of=log/scenario_disk.img bs=1M count=1 status=none
+      echo "-drive file=log/scenario_disk.img,if=none,id=scenario_disk,format=raw -device nvme,drive=scenario_disk,serial=wasabi"
+      ;;
+  esac
+}
+
+PASSED=0
+FAILED=""
+for s in ${SCENARIOS}; do
+  printf "==== %s ====\n" "$s"
+  export QEMU_EXTRA_ARGS="-display none -fw_cfg name=opt/wasabi/scenario,string=$s $(scenario_args $s)"
+  if timeout "${TIMEOUT}" bash scripts/launch_qemu.sh "${PATH_TO_EFI}" > "log/scenario_$s.txt" 2>&1; then
+    PASSED=$((PASSED + 1))
+    printf "PASS %s\n" "$s"
+  else
+    FAILED="${FAILED} $s"
+    printf "FAIL %s (see log/scenario_%s.txt)\n" "$s" "$s"
+  fi
+done
+printf "%d passed, failed:%s\n" "${PASSED}" "${FAILED:- none}"
+[ -z "${FAILED}" ]
@@ -1,6 +1,7 @@
 use core::mem::size_of;
 
 use crate::hpet::HpetRegisters;
+use crate::once::Once;
 use crate::result::Result;
 
 #[repr(packed)]
@@ -132,6 +133,160 @@ impl AcpiHpetDescriptor {
 }
 const _: () = assert!(size_of::<AcpiHpetDescriptor>() == 56);
 
+// PCI Express Memory-mapped Configuration Space base address description
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct AcpiMcfgEntry {
+    base_address: u64,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+const _: () = assert!(size_of::<AcpiMcfgEntry>() == 16);
+
+impl AcpiMcfgEntry {
+    pub fn base_address(&self) -> u64 {
+        self.base_address
+    }
+    pub fn segment_group(&self) -> u16 {
+        self.segment_group
+    }
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+}
+
+#[repr(packed)]
+pub struct AcpiMcfgDescriptor {
+    header: SystemDescriptionTableHeader,
+    _reserved: u64,
+    // ここからAcpiMcfgEntryの配列が続く
+}
+impl AcpiTable for AcpiMcfgDescriptor {
+    const SIGNATURE: &'static [u8; 4] = b"MCFG";
+    type Table = Self;
+}
+impl AcpiMcfgDescriptor {
+    pub fn num_of_entries(&self) -> usize {
+        (self.header.length as usize - size_of::<Self>()) / size_of::<AcpiMcfgEntry>()
+    }
+    pub fn entry(&self, index: usize) -> Option<AcpiMcfgEntry> {
+        if index >= self.num_of_entries() {
+            None
+        } else {
+            Some(unsafe {
+                ((self as *const Self as *const u8).add(size_of::<Self>()) as *const AcpiMcfgEntry)
+                    .add(index)
+                    .read_unaligned()
+            })
+        }
+    }
+}
+const _: () = assert!(size_of::<AcpiMcfgDescriptor>() == 44);
+
+// Multiple APIC Description Table
+// https://wiki.osdev.org/MADT
+#[repr(packed)]
+pub struct AcpiMadtDescriptor {
+    header: SystemDescriptionTableHeader,
+    local_apic_address: u32,
+    flags: u32,
+    // ここから可変長のエントリが続く
+}
+impl AcpiTable for AcpiMadtDescriptor {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+    type Table = Self;
+}
+const _: () = assert!(size_of::<AcpiMadtDescriptor>() == 44);
+
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    Other {
+        entry_type: u8,
+    },
+}
+
+impl AcpiMadtDescriptor {
+    pub fn local_apic_address(&self) -> u32 {
+        self.local_apic_address
+    }
+    // bit0: 8259 PICも搭載されている
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & 1 != 0
+    }
+    pub fn iter(&self) -> MadtIterator {
+        MadtIterator {
+            table: self,
+            offset: size_of::<Self>(),
+        }
+    }
+}
+
+pub struct MadtIterator<'a> {
+    table: &'a AcpiMadtDescriptor,
+    offset: usize,
+}
+
+impl<'a> Iterator for MadtIterator<'a> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = self.table.header.length as usize;
+        if self.offset + 2 > length {
+            return None;
+        }
+        let p = unsafe { (self.table as *const AcpiMadtDescriptor as *const u8).add(self.offset) };
+        let read_u8 = |ofs: usize| unsafe { p.add(ofs).read() };
+        let read_u16 = |ofs: usize| unsafe { (p.add(ofs) as *const u16).read_unaligned() };
+        let read_u32 = |ofs: usize| unsafe { (p.add(ofs) as *const u32).read_unaligned() };
+        let entry_type = read_u8(0);
+        let entry_length = read_u8(1) as usize;
+        if entry_length < 2 || self.offset + entry_length > length {
+            return None;
+        }
+        self.offset += entry_length;
+        Some(match entry_type {
+            0 => MadtEntry::LocalApic {
+                processor_id: read_u8(2),
+                apic_id: read_u8(3),
+                flags: read_u32(4),
+            },
+            1 => MadtEntry::IoApic {
+                id: read_u8(2),
+                address: read_u32(4),
+                gsi_base: read_u32(8),
+            },
+            2 => MadtEntry::InterruptSourceOverride {
+                bus: read_u8(2),
+                source: read_u8(3),
+                gsi: read_u32(4),
+                flags: read_u16(8),
+            },
+            _ => MadtEntry::Other { entry_type },
+        })
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct AcpiRsdp {
@@ -143,12 +298,46 @@ pub struct AcpiRsdp {
     length: u32,
     xsdt_address: u64,
 }
+// XSDTから辿れるテーブルの見出し
+#[derive(Clone, Copy, Debug)]
+pub struct AcpiTableInfo {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub address: usize,
+}
+
 impl AcpiRsdp {
     fn xsdt(&self) -> &Xsdt {
         unsafe { &*(self.xsdt_address as *const Xsdt) }
     }
+    pub fn tables(&self) -> impl Iterator<Item = AcpiTableInfo> + '_ {
+        self.xsdt().iter().map(|h| AcpiTableInfo {
+            signature: h.signature,
+            length: h.length,
+            address: h as *const SystemDescriptionTableHeader as usize,
+        })
+    }
     pub fn hpet(&self) -> Option<&AcpiHpetDescriptor> {
         let xsdt = self.xsdt();
         xsdt.find_table(b"HPET").map(AcpiHpetDescriptor::new)
     }
+    pub fn mcfg(&self) -> Option<&AcpiMcfgDescriptor> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(b"MCFG").map(AcpiMcfgDescriptor::new)
+    }
+    pub fn madt(&self) -> Option<&AcpiMadtDescriptor> {
+        let xsdt = self.xsdt();
+        xsdt.find_table(b"APIC").map(AcpiMadtDescriptor::new)
+    }
+}
+
+// ファームウェアが置いたテーブルは、ブートサービスを抜けた後も同じ場所に残っている
+static GLOBAL_RSDP: Once<&'static AcpiRsdp> = Once::new();
+
+pub fn set_global_rsdp(rsdp: &AcpiRsdp) -> Result<()> {
+    GLOBAL_RSDP.set(unsafe { &*(rsdp as *const AcpiRsdp) })
+}
+
+pub fn global_rsdp() -> Option<&'static AcpiRsdp> {
+    GLOBAL_RSDP.get().copied()
 }
@@ -2,15 +2,19 @@ extern crate alloc;
 use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
 use core::borrow::BorrowMut;
-use core::cell::RefCell;
 use core::cmp::max;
 use core::fmt;
 use core::mem::size_of;
 use core::ops::DerefMut;
 use core::ptr::null_mut;
+#[cfg(test)]
+use core::sync::atomic::AtomicUsize;
+#[cfg(test)]
+use core::sync::atomic::Ordering;
 
 use alloc::boxed::Box;
 
+use crate::mutex::SpinLockIrqSave;
 use crate::result::Result;
 use crate::uefi::EfiMemoryDescriptor;
 use crate::uefi::EfiMemoryType;
@@ -146,20 +150,66 @@ impl fmt::Debug for Header {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    // ヘッダの分も含めたバイト数
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+    pub used_chunks: usize,
+    pub free_chunks: usize,
+    pub largest_free_chunk: usize,
+}
+
 // アロケータ本体
+// 割り込みハンドラの中でも確保するので、ヘッダのリストは割り込みを止めてから触る
 pub struct FirstFitAllocator {
-    first_header: RefCell<Option<Box<Header>>>,
+    first_header: SpinLockIrqSave<Option<Box<Header>>>,
 }
 
 #[global_allocator]
 pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
+    first_header: SpinLockIrqSave::new(None),
 };
 
+// テストの時だけ、わざと確保に失敗させてメモリ不足の時の処理を通す
+// あとN回目の確保を失敗させる。0なら何もしない
+#[cfg(test)]
+static ALLOC_FAULT_COUNTDOWN: AtomicUsize = AtomicUsize::new(0);
+// 失敗させた後、またこの回数ごとに失敗させる。0なら1回だけ
+#[cfg(test)]
+static ALLOC_FAULT_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+// これから数えてnth回目 (1から) の確保を失敗させる。everyが0でなければ、その後もevery回ごとに失敗させる
+#[cfg(test)]
+pub fn inject_alloc_failure(nth: usize, every: usize) {
+    ALLOC_FAULT_INTERVAL.store(every, Ordering::SeqCst);
+    ALLOC_FAULT_COUNTDOWN.store(nth, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+pub fn clear_alloc_failure() {
+    inject_alloc_failure(0, 0);
+}
+
+#[cfg(test)]
+fn should_fail_allocation() -> bool {
+    let prev =
+        ALLOC_FAULT_COUNTDOWN.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| match n {
+            0 => None,
+            1 => Some(ALLOC_FAULT_INTERVAL.load(Ordering::SeqCst)),
+            n => Some(n - 1),
+        });
+    prev == Ok(1)
+}
+
 impl FirstFitAllocator {
     // allocが呼び出されたときに呼び出される
     pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
-        let mut header = self.first_header.borrow_mut();
+        #[cfg(test)]
+        if should_fail_allocation() {
+            return null_mut();
+        }
+        let mut header = self.first_header.lock();
         let mut header = header.deref_mut();
         // headerを順にたどって行く
         loop {
@@ -179,6 +229,25 @@ impl FirstFitAllocator {
         }
     }
 
+    // ヘッダのリストを一通りたどって数える。確保はしないので、どこから呼んでもよい
+    pub fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats::default();
+        let first_header = self.first_header.lock();
+        let mut header = first_header.as_deref();
+        while let Some(h) = header {
+            if h.is_allocated() {
+                stats.used_bytes += h.size;
+                stats.used_chunks += 1;
+            } else {
+                stats.free_bytes += h.size;
+                stats.free_chunks += 1;
+                stats.largest_free_chunk = max(stats.largest_free_chunk, h.size);
+            }
+            header = h.next_header.as_deref();
+        }
+        stats
+    }
+
     // 空き領域をtreeに追加する
     fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
         let mut start_addr = desc.physical_start() as usize;
@@ -196,12 +265,10 @@ impl FirstFitAllocator {
         header.next_header = None;
         header.is_allocated = false;
         header.size = size;
-        let mut first_header = self.first_header.borrow_mut();
+        let mut first_header = self.first_header.lock();
         // replaceで置き換えて、元の値を得られる
         let prev_last = first_header.replace(header);
-        drop(first_header);
-        let mut header = self.first_header.borrow_mut();
-        header.as_mut().unwrap().next_header = prev_last;
+        first_header.as_mut().unwrap().next_header = prev_last;
     }
 
     // uefiから渡されてきたmemory mapを元に初期化する
@@ -337,12 +404,76 @@ mod test {
             }
         }
     }
+    #[test_case]
+    fn alloc_from_timer_interrupt() {
+        use crate::apic::init_apic_timer;
+        use crate::apic::stop_apic_timer;
+        use crate::hpet::global_timestamp;
+        use alloc::vec::Vec;
+        use core::sync::atomic::AtomicUsize;
+        use core::sync::atomic::Ordering;
+        use core::time::Duration;
+
+        static ALLOCATED_IN_INTERRUPT: AtomicUsize = AtomicUsize::new(0);
+        fn allocate_in_interrupt(_vector: u8) {
+            let n = ALLOCATED_IN_INTERRUPT.load(Ordering::SeqCst);
+            let v = vec![n as u8; n % 200 + 1];
+            assert!(v.iter().all(|b| *b == n as u8));
+            ALLOCATED_IN_INTERRUPT.fetch_add(1, Ordering::SeqCst);
+        }
+        init_apic_timer(1000, allocate_in_interrupt).expect("Failed to start the APIC timer");
+        let start = global_timestamp();
+        // 確保や解放の途中に割り込みが来るように、ずっと繰り返す
+        while ALLOCATED_IN_INTERRUPT.load(Ordering::SeqCst) < 200 {
+            let v: Vec<Vec<u8>> = (0..32).map(|i| vec![i as u8; i * 8 + 1]).collect();
+            for (i, e) in v.iter().enumerate() {
+                assert!(e.iter().all(|b| *b == i as u8));
+            }
+            assert!(
+                global_timestamp() - start < Duration::from_secs(10),
+                "Timer interrupts did not come"
+            );
+        }
+        stop_apic_timer();
+    }
+
+    #[test_case]
+    fn injected_alloc_failures() {
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let mut results = [false; 8];
+        inject_alloc_failure(3, 2);
+        for r in results.iter_mut() {
+            let p = ALLOCATOR.alloc_with_options(layout);
+            *r = !p.is_null();
+            if !p.is_null() {
+                unsafe { ALLOCATOR.dealloc(p, layout) }
+            }
+        }
+        clear_alloc_failure();
+        assert_eq!(results, [true, true, false, true, false, true, false, true]);
+        let p = ALLOCATOR.alloc_with_options(layout);
+        assert!(!p.is_null());
+        unsafe { ALLOCATOR.dealloc(p, layout) }
+    }
+
     #[test_case]
     fn alloc_box() {
         const HANDLER_STACK_SIZE: usize = 64 * 1024;
         let b = Box::new([0u8; HANDLER_STACK_SIZE]);
         assert!(b.len() == HANDLER_STACK_SIZE)
     }
+
+    #[test_case]
+    fn stats_count_allocations() {
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let before = ALLOCATOR.stats();
+        let p = ALLOCATOR.alloc_with_options(layout);
+        assert!(!p.is_null());
+        let after = ALLOCATOR.stats();
+        assert!(after.used_bytes >= before.used_bytes + 4096);
+        assert!(after.used_chunks > before.used_chunks);
+        unsafe { ALLOCATOR.dealloc(p, layout) }
+    }
 }
 
 unsafe impl Sync for FirstFitAllocator {}
@@ -352,6 +483,8 @@ unsafe impl GlobalAlloc for FirstFitAllocator {
         self.alloc_with_options(layout)
     }
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        // 確保の途中のヘッダを書き換えないように、同じロックを取る
+        let _lock = self.first_header.lock();
         let mut region = Header::from_allocated_regional(ptr);
         // 未確保にする
         region.is_allocated = false;
@@ -0,0 +1,368 @@
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::time::Duration;
+
+use crate::acpi::AcpiRsdp;
+use crate::acpi::MadtEntry;
+use crate::hpet::busy_wait;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::mutex::SpinLockIrqSave;
+use crate::once::Once;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::allocate_interrupt_vector;
+use crate::x86::free_interrupt_vector;
+use crate::x86::map_mmio;
+use crate::x86::read_msr;
+use crate::x86::write_io_port_u8;
+use crate::x86::InterruptHandler;
+use crate::x86::FIRST_EXTERNAL_VECTOR;
+use crate::x86::NUM_OF_EXTERNAL_VECTORS;
+
+// https://wiki.osdev.org/APIC#Local_APIC_registers
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const LOCAL_APIC_REG_ID: usize = 0x20;
+const LOCAL_APIC_REG_EOI: usize = 0xB0;
+const LOCAL_APIC_REG_SPURIOUS_INTERRUPT_VECTOR: usize = 0xF0;
+const LOCAL_APIC_REG_LVT_TIMER: usize = 0x320;
+const LOCAL_APIC_REG_TIMER_INITIAL_COUNT: usize = 0x380;
+const LOCAL_APIC_REG_TIMER_CURRENT_COUNT: usize = 0x390;
+const LOCAL_APIC_REG_TIMER_DIVIDE_CONFIG: usize = 0x3E0;
+const LOCAL_APIC_REGION_SIZE: u64 = 0x1000;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// 16分周
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+pub struct LocalApic {
+    base: *mut u8,
+}
+unsafe impl Send for LocalApic {}
+// レジスタは1回のvolatileな読み書きで触るだけなので、割り込みの中と外から同時に使ってよい
+unsafe impl Sync for LocalApic {}
+
+impl LocalApic {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile(self.base.add(offset) as *const u32) }
+    }
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile(self.base.add(offset) as *mut u32, value) }
+    }
+    pub fn id(&self) -> u8 {
+        (self.read(LOCAL_APIC_REG_ID) >> 24) as u8
+    }
+    pub fn eoi(&self) {
+        self.write(LOCAL_APIC_REG_EOI, 0)
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+pub fn init_local_apic() {
+    let base = read_msr(IA32_APIC_BASE_MSR) & !0xFFF;
+    let base = map_mmio(base, LOCAL_APIC_REGION_SIZE).expect("Failed to map Local APIC");
+    let apic = LocalApic { base };
+    // APIC Software Enable, Spurious Vectorは0xFF
+    apic.write(LOCAL_APIC_REG_SPURIOUS_INTERRUPT_VECTOR, 0x1FF);
+    info!("Local APIC @ {base:#p}, id = {}", apic.id());
+    if LOCAL_APIC.set(apic).is_err() {
+        warn!("Local APIC is already initialized");
+    }
+}
+
+pub fn local_apic_id() -> u8 {
+    LOCAL_APIC.get().map(|apic| apic.id()).unwrap_or(0)
+}
+
+// HPETで周波数を測ってから、Local APICタイマを周期モードで動かす
+pub fn init_apic_timer(hz: u32, handler: InterruptHandler) -> Result<()> {
+    const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+    let apic = LOCAL_APIC.get().ok_or("Local APIC is not initialized")?;
+    apic.write(LOCAL_APIC_REG_LVT_TIMER, LVT_MASKED);
+    apic.write(LOCAL_APIC_REG_TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+    apic.write(LOCAL_APIC_REG_TIMER_INITIAL_COUNT, u32::MAX);
+    let start = global_timestamp();
+    busy_wait(CALIBRATION_PERIOD);
+    let elapsed = global_timestamp() - start;
+    let counted = u32::MAX - apic.read(LOCAL_APIC_REG_TIMER_CURRENT_COUNT);
+    apic.write(LOCAL_APIC_REG_TIMER_INITIAL_COUNT, 0);
+    if elapsed.is_zero() || counted == 0 {
+        return Err("Failed to calibrate the Local APIC timer");
+    }
+    let counts_per_sec = counted as u64 * 1_000_000 / elapsed.as_micros() as u64;
+    let vector = allocate_interrupt_vector(handler)?;
+    apic.write(LOCAL_APIC_REG_LVT_TIMER, vector as u32 | LVT_TIMER_PERIODIC);
+    apic.write(
+        LOCAL_APIC_REG_TIMER_INITIAL_COUNT,
+        (counts_per_sec / hz as u64).max(1) as u32,
+    );
+    info!("Local APIC timer: {counts_per_sec} counts/s, {hz} Hz, vector {vector:#04X}");
+    Ok(())
+}
+
+// init_apic_timerで動かしたタイマを止めて、割り込みベクタを返す
+pub fn stop_apic_timer() {
+    let Some(apic) = LOCAL_APIC.get() else {
+        return;
+    };
+    let lvt = apic.read(LOCAL_APIC_REG_LVT_TIMER);
+    apic.write(LOCAL_APIC_REG_LVT_TIMER, LVT_MASKED);
+    apic.write(LOCAL_APIC_REG_TIMER_INITIAL_COUNT, 0);
+    if lvt & LVT_MASKED == 0 {
+        free_interrupt_vector(lvt as u8);
+    }
+}
+
+pub fn send_eoi() {
+    if let Some(apic) = LOCAL_APIC.get() {
+        apic.eoi()
+    }
+}
+
+// https://wiki.osdev.org/IOAPIC
+const IO_APIC_REG_SELECT: usize = 0x00;
+const IO_APIC_REG_WINDOW: usize = 0x10;
+const IO_APIC_INDEX_VERSION: u32 = 0x01;
+const IO_APIC_INDEX_REDIRECTION_TABLE: u32 = 0x10;
+const IO_APIC_REGION_SIZE: u64 = 0x20;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+pub struct IoApic {
+    base: *mut u8,
+    gsi_base: u32,
+    num_of_entries: u32,
+}
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    fn read(&self, index: u32) -> u32 {
+        unsafe {
+            write_volatile(self.base.add(IO_APIC_REG_SELECT) as *mut u32, index);
+            read_volatile(self.base.add(IO_APIC_REG_WINDOW) as *const u32)
+        }
+    }
+    fn write(&self, index: u32, value: u32) {
+        unsafe {
+            write_volatile(self.base.add(IO_APIC_REG_SELECT) as *mut u32, index);
+            write_volatile(self.base.add(IO_APIC_REG_WINDOW) as *mut u32, value);
+        }
+    }
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_of_entries).contains(&gsi)
+    }
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        let index = IO_APIC_INDEX_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // 書き込み途中で割り込みが飛ばないように、先にマスクしてから上位を書く
+        self.write(index, REDIRECTION_MASKED as u32);
+        self.write(index + 1, (entry >> 32) as u32);
+        self.write(index, entry as u32);
+    }
+}
+
+struct InterruptSourceOverride {
+    source: u8,
+    gsi: u32,
+    polarity: Polarity,
+    trigger: TriggerMode,
+}
+
+struct IoApicState {
+    io_apic: IoApic,
+    overrides: [Option<InterruptSourceOverride>; 16],
+}
+
+static IO_APIC: Mutex<Option<IoApicState>> = Mutex::new(None);
+
+// 8259 PICからの割り込みを全て止める
+fn disable_legacy_pic() {
+    write_io_port_u8(0x21, 0xFF);
+    write_io_port_u8(0xA1, 0xFF);
+}
+
+pub fn init_io_apic(acpi: &AcpiRsdp) {
+    let madt = acpi.madt().expect("MADT not found");
+    if madt.has_legacy_pics() {
+        disable_legacy_pic();
+    }
+    let mut io_apic = None;
+    let mut overrides: [Option<InterruptSourceOverride>; 16] = Default::default();
+    for e in madt.iter() {
+        match e {
+            // 最初に見つかったIOAPICだけを使う
+            MadtEntry::IoApic {
+                address, gsi_base, ..
+            } if io_apic.is_none() => {
+                let base =
+                    map_mmio(address as u64, IO_APIC_REGION_SIZE).expect("Failed to map IOAPIC");
+                let mut a = IoApic {
+                    base,
+                    gsi_base,
+                    num_of_entries: 0,
+                };
+                a.num_of_entries = ((a.read(IO_APIC_INDEX_VERSION) >> 16) & 0xFF) + 1;
+                info!(
+                    "IOAPIC @ {base:#p}, GSI {}..{}",
+                    gsi_base,
+                    gsi_base + a.num_of_entries
+                );
+                for gsi in gsi_base..gsi_base + a.num_of_entries {
+                    a.write_redirection(gsi, REDIRECTION_MASKED);
+                }
+                io_apic = Some(a);
+            }
+            MadtEntry::InterruptSourceOverride {
+                source, gsi, flags, ..
+            } if (source as usize) < overrides.len() => {
+                // https://wiki.osdev.org/MADT#Entry_Type_2_:_IO/APIC_Interrupt_Source_Override
+                let polarity = if flags & 0b11 == 0b11 {
+                    Polarity::ActiveLow
+                } else {
+                    Polarity::ActiveHigh
+                };
+                let trigger = if (flags >> 2) & 0b11 == 0b11 {
+                    TriggerMode::Level
+                } else {
+                    TriggerMode::Edge
+                };
+                overrides[source as usize] = Some(InterruptSourceOverride {
+                    source,
+                    gsi,
+                    polarity,
+                    trigger,
+                });
+            }
+            _ => {}
+        }
+    }
+    let io_apic = io_apic.expect("IOAPIC not found in MADT");
+    *IO_APIC.lock() = Some(IoApicState { io_apic, overrides });
+}
+
+// GSIの割り込みをvectorとしてこのCPUに届ける
+pub fn set_gsi_vector(
+    gsi: u32,
+    vector: u8,
+    trigger: TriggerMode,
+    polarity: Polarity,
+) -> Result<()> {
+    let state = IO_APIC.lock();
+    let io_apic = &state.as_ref().ok_or("IOAPIC is not initialized")?.io_apic;
+    if !io_apic.handles(gsi) {
+        return Err("GSI is not handled by the IOAPIC");
+    }
+    let mut entry = vector as u64 | ((local_apic_id() as u64) << 56);
+    if trigger == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    io_apic.write_redirection(gsi, entry);
+    Ok(())
+}
+
+pub fn mask_gsi(gsi: u32) -> Result<()> {
+    let state = IO_APIC.lock();
+    let io_apic = &state.as_ref().ok_or("IOAPIC is not initialized")?.io_apic;
+    if !io_apic.handles(gsi) {
+        return Err("GSI is not handled by the IOAPIC");
+    }
+    io_apic.write_redirection(gsi, REDIRECTION_MASKED);
+    Ok(())
+}
+
+// 1つのGSIを共有できるハンドラの数 (PCIのINTxは複数のデバイスで共有される)
+const MAX_HANDLERS_PER_GSI: usize = 4;
+
+#[derive(Clone, Copy)]
+struct GsiRoute {
+    gsi: u32,
+    trigger: TriggerMode,
+    polarity: Polarity,
+    handlers: [Option<InterruptHandler>; MAX_HANDLERS_PER_GSI],
+}
+
+// route_gsiで繋いだGSI。ベクタから引けるように、外部割り込みのベクタ番号で並べる
+static GSI_ROUTES: SpinLockIrqSave<[Option<GsiRoute>; NUM_OF_EXTERNAL_VECTORS]> =
+    SpinLockIrqSave::new([None; NUM_OF_EXTERNAL_VECTORS]);
+
+// 共有しているハンドラを全部呼ぶ。どのデバイスからの割り込みかは、各ハンドラが自分で確かめる
+fn gsi_interrupt_handler(vector: u8) {
+    let route = GSI_ROUTES.lock()[vector as usize - FIRST_EXTERNAL_VECTOR];
+    for handler in route.iter().flat_map(|r| r.handlers).flatten() {
+        handler(vector);
+    }
+}
+
+// GSIからの割り込みをハンドラに繋ぐ。同じGSIに繋がれていれば、そのベクタを共有する
+pub fn route_gsi(
+    gsi: u32,
+    trigger: TriggerMode,
+    polarity: Polarity,
+    handler: InterruptHandler,
+) -> Result<u8> {
+    let mut routes = GSI_ROUTES.lock();
+    if let Some((i, route)) = routes
+        .iter_mut()
+        .enumerate()
+        .find_map(|(i, r)| r.as_mut().filter(|r| r.gsi == gsi).map(|r| (i, r)))
+    {
+        if route.trigger != trigger || route.polarity != polarity {
+            return Err("GSI is already routed with a different trigger mode or polarity");
+        }
+        let slot = route
+            .handlers
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or("Too many handlers share the GSI")?;
+        *slot = Some(handler);
+        return Ok((FIRST_EXTERNAL_VECTOR + i) as u8);
+    }
+    let vector = allocate_interrupt_vector(gsi_interrupt_handler)?;
+    let mut handlers = [None; MAX_HANDLERS_PER_GSI];
+    handlers[0] = Some(handler);
+    routes[vector as usize - FIRST_EXTERNAL_VECTOR] = Some(GsiRoute {
+        gsi,
+        trigger,
+        polarity,
+        handlers,
+    });
+    if let Err(e) = set_gsi_vector(gsi, vector, trigger, polarity) {
+        routes[vector as usize - FIRST_EXTERNAL_VECTOR] = None;
+        free_interrupt_vector(vector);
+        return Err(e);
+    }
+    Ok(vector)
+}
+
+// ISA IRQはMADTのInterrupt Source Overrideがあればそれに従う
+pub fn route_isa_irq(irq: u8, handler: InterruptHandler) -> Result<u8> {
+    let (gsi, trigger, polarity) = IO_APIC
+        .lock()
+        .as_ref()
+        .ok_or("IOAPIC is not initialized")?
+        .overrides
+        .iter()
+        .flatten()
+        .find(|o| o.source == irq)
+        .map(|o| (o.gsi, o.trigger, o.polarity))
+        .unwrap_or((irq as u32, TriggerMode::Edge, Polarity::ActiveHigh));
+    route_gsi(gsi, trigger, polarity, handler)
+}
@@ -0,0 +1,100 @@
+use core::arch::asm;
+
+use crate::println;
+
+// build.rsが作る "アドレス 名前" の行をアドレス順に並べた表
+// ビルドの時のアドレスなので、実行時のアドレスとの差はSYMBOL_ANCHORで求める
+static SYMBOL_TABLE: &str = include_str!(concat!(env!("OUT_DIR"), "/symbols.txt"));
+const SYMBOL_ANCHOR: &str = "wasabi_symbol_anchor";
+// これより深いフレームはたどらない (壊れたスタックで回り続けないように)
+const MAX_FRAMES: usize = 32;
+
+// 表の中から実行時のアドレスを知っている関数を探して、ずれを求めるためのもの
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn wasabi_symbol_anchor() {}
+
+fn symbols() -> impl Iterator<Item = (u64, &'static str)> {
+    SYMBOL_TABLE.lines().filter_map(|line| {
+        let (addr, name) = line.split_once(' ')?;
+        Some((u64::from_str_radix(addr, 16).ok()?, name))
+    })
+}
+
+// 実行時のアドレスから表のアドレスを引く時に足す値
+fn load_bias() -> Option<u64> {
+    let (addr, _) = symbols().find(|(_, name)| *name == SYMBOL_ANCHOR)?;
+    Some((wasabi_symbol_anchor as *const () as u64).wrapping_sub(addr))
+}
+
+// デマングルした名前の末尾の "::h0123456789abcdef" を取り除く
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((base, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            base
+        }
+        _ => name,
+    }
+}
+
+// addrを含む関数の名前と、その先頭からのオフセットを返す
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let addr = addr.wrapping_sub(load_bias()?);
+    let (start, name) = symbols().take_while(|(start, _)| *start <= addr).last()?;
+    Some((strip_hash(name), addr - start))
+}
+
+fn read_rbp() -> u64 {
+    let rbp;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    rbp
+}
+
+// フレームポインタ (-Cforce-frame-pointers) をたどって、呼び出し元を順に表示する
+pub fn print_backtrace() {
+    print_backtrace_from(read_rbp())
+}
+
+// 止まっている他のタスクなど、rbpがわかっているフレームからたどる
+pub fn print_backtrace_from(rbp: u64) {
+    println!("Backtrace:");
+    let mut rbp = rbp;
+    for i in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        // [rbp]に呼び出し元のrbp、[rbp + 8]に戻り先のアドレスが積まれている
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        match symbolize(ret) {
+            Some((name, offset)) => {
+                println!("  #{i:<2} {ret:#018X} {name}+{offset:#x}");
+            }
+            None => {
+                println!("  #{i:<2} {ret:#018X} ?");
+            }
+        }
+        // スタックは下に伸びるので、呼び出し元のフレームは必ず上にある
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn strip_hash_of_demangled_names() {
+        assert_eq!(
+            strip_hash("wasabi::task::yield_now::h0123456789abcdef"),
+            "wasabi::task::yield_now"
+        );
+        assert_eq!(strip_hash("wasabi_symbol_anchor"), "wasabi_symbol_anchor");
+        assert_eq!(strip_hash("foo::hbar"), "foo::hbar");
+    }
+}
@@ -0,0 +1,164 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hint::black_box;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::graphics::blit;
+use crate::graphics::BitmapBuffer;
+use crate::graphics::Rect;
+use crate::hpet::busy_wait;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::kthread;
+use crate::result::Result;
+use crate::task::yield_now;
+use crate::x86::read_tsc;
+
+// TSCの周波数をHPETで測る時間
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+// 1秒あたりのTSCのカウント数。初めて呼ばれた時にHPETと比べて求める
+pub fn tsc_hz() -> Result<u64> {
+    let hz = TSC_HZ.load(Ordering::SeqCst);
+    if hz != 0 {
+        return Ok(hz);
+    }
+    let t0 = global_timestamp();
+    if t0 == Duration::ZERO {
+        return Err("HPET is not initialized");
+    }
+    let c0 = read_tsc();
+    busy_wait(CALIBRATION_TIME);
+    let c1 = read_tsc();
+    let elapsed = (global_timestamp() - t0).as_nanos() as u64;
+    let hz = (c1 - c0) * 1_000_000_000 / elapsed.max(1);
+    TSC_HZ.store(hz, Ordering::SeqCst);
+    info!("TSC: {} MHz", hz / 1_000_000);
+    Ok(hz)
+}
+
+fn cycles_to_duration(cycles: u64, hz: u64) -> Duration {
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / hz as u128) as u64)
+}
+
+pub struct BenchResult {
+    pub name: &'static str,
+    pub iterations: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bench {:<24} {:>8} iters  min {:>10?}  avg {:>10?}  max {:>10?}",
+            self.name, self.iterations, self.min, self.avg, self.max
+        )
+    }
+}
+
+// fをiterations回呼んで、1回ごとにかかった時間を測る
+pub fn bench(name: &'static str, iterations: usize, mut f: impl FnMut()) -> Result<BenchResult> {
+    if iterations == 0 {
+        return Err("iterations must be positive");
+    }
+    let hz = tsc_hz()?;
+    let mut min = u64::MAX;
+    let mut max = 0;
+    let mut total = 0;
+    for _ in 0..iterations {
+        let start = read_tsc();
+        f();
+        let cycles = read_tsc() - start;
+        min = min.min(cycles);
+        max = max.max(cycles);
+        total += cycles;
+    }
+    Ok(BenchResult {
+        name,
+        iterations,
+        min: cycles_to_duration(min, hz),
+        avg: cycles_to_duration(total / iterations as u64, hz),
+        max: cycles_to_duration(max, hz),
+    })
+}
+
+// bench!("名前", 回数, { 測りたい処理 }) で測って結果を表示する
+#[macro_export]
+macro_rules! bench {
+    ($name:expr, $iterations:expr, $body:block) => {
+        match $crate::bench::bench($name, $iterations, || $body) {
+            Ok(result) => {
+                $crate::info!("{result}");
+            }
+            Err(e) => {
+                $crate::error!("bench {}: {e}", $name);
+            }
+        }
+    };
+}
+
+fn bench_alloc() {
+    bench!("alloc/free 64B", 10000, {
+        black_box(Box::new([0u8; 64]));
+    });
+    bench!("alloc/free 4KiB", 1000, {
+        black_box(Vec::<u8>::with_capacity(4096));
+    });
+}
+
+// 相手のタスクと交互にyield_nowする。1回で2回切り替わる
+fn bench_context_switch() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    DONE.store(false, Ordering::SeqCst);
+    let partner = kthread::spawn("bench-partner", || {
+        while !DONE.load(Ordering::SeqCst) {
+            yield_now();
+        }
+    });
+    bench!("yield round trip", 1000, {
+        yield_now();
+    });
+    DONE.store(true, Ordering::SeqCst);
+    partner.join();
+}
+
+fn bench_blit() {
+    let src = BitmapBuffer::new(256, 256);
+    let mut dst = BitmapBuffer::new(256, 256);
+    bench!("blit 256x256", 100, {
+        blit(&mut dst, 0, 0, &src, Rect::new(0, 0, 256, 256));
+        black_box(&mut dst);
+    });
+}
+
+// 決まったベンチマークを全部動かす。コミットごとに比べられるように、名前と回数は変えない
+pub fn run_benchmarks() {
+    bench_alloc();
+    bench_context_switch();
+    bench_blit();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn cycles_are_converted_with_the_tsc_frequency() {
+        assert_eq!(
+            cycles_to_duration(3_000, 3_000_000_000),
+            Duration::from_micros(1)
+        );
+        assert_eq!(cycles_to_duration(0, 1_000), Duration::ZERO);
+    }
+}
@@ -0,0 +1,326 @@
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use crate::info;
+use crate::mutex::Mutex;
+use crate::result::Result;
+
+// セクタ単位で読み書きできるデバイス
+// 複数の利用者から共有されるので、実装側で排他制御する
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+    fn num_of_blocks(&self) -> u64;
+    // bufの長さはblock_sizeの倍数であること
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()>;
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()>;
+    fn size_in_bytes(&self) -> u64 {
+        self.num_of_blocks() * self.block_size() as u64
+    }
+}
+
+// 引数のチェックをまとめたもの
+pub fn check_block_range(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64> {
+    let block_size = dev.block_size();
+    if len % block_size != 0 {
+        return Err("Buffer size is not a multiple of the block size");
+    }
+    let count = (len / block_size) as u64;
+    if lba.checked_add(count).ok_or("LBA overflow")? > dev.num_of_blocks() {
+        return Err("Access beyond the end of the device");
+    }
+    Ok(count)
+}
+
+// メモリ上のディスク。テストやramdiskに使う
+pub struct RamDisk {
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(block_size: usize, data: Vec<u8>) -> Self {
+        assert!(data.len() % block_size == 0);
+        Self {
+            block_size,
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn num_of_blocks(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+// ディスクの一部を切り出したもの
+pub struct Partition {
+    dev: Arc<dyn BlockDevice>,
+    start_lba: u64,
+    num_of_blocks: u64,
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+    fn num_of_blocks(&self) -> u64 {
+        self.num_of_blocks
+    }
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        self.dev.read_blocks(self.start_lba + lba, buf)
+    }
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        self.dev.write_blocks(self.start_lba + lba, buf)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    Mbr { partition_type: u8 },
+    Gpt { type_guid: [u8; 16], name: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionEntry {
+    pub start_lba: u64,
+    pub num_of_blocks: u64,
+    pub kind: PartitionKind,
+}
+
+const MBR_PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+// https://uefi.org/specs/UEFI/2.11/05_GUID_Partition_Table_Format.html
+fn parse_gpt(dev: &dyn BlockDevice) -> Result<Vec<PartitionEntry>> {
+    let block_size = dev.block_size();
+    let mut header = vec![0u8; block_size];
+    dev.read_blocks(1, &mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        return Err("No GPT header");
+    }
+    let u32_at = |b: &[u8], o: usize| u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+    let u64_at = |b: &[u8], o: usize| u64::from_le_bytes(b[o..o + 8].try_into().unwrap());
+    let entries_lba = u64_at(&header, 72);
+    let num_of_entries = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_size < 128 || num_of_entries > 1024 {
+        return Err("Unsupported GPT partition entry array");
+    }
+    // エントリの大きさは128 * 2^nと決まっている。ブロックをまたぐものは扱わない
+    if entry_size % 128 != 0 || entry_size > block_size {
+        return Err("Unsupported GPT partition entry size");
+    }
+    let len = num_of_entries
+        .checked_mul(entry_size)
+        .ok_or("GPT partition entry array is too large")?
+        .div_ceil(block_size)
+        * block_size;
+    let num_of_entry_blocks = (len / block_size) as u64;
+    if entries_lba
+        .checked_add(num_of_entry_blocks)
+        .map_or(true, |end| end > dev.num_of_blocks())
+    {
+        return Err("GPT partition entry array out of range");
+    }
+    let mut entries = vec![0u8; len];
+    dev.read_blocks(entries_lba, &mut entries)?;
+    let mut partitions = Vec::new();
+    for e in entries.chunks_exact(entry_size).take(num_of_entries) {
+        let type_guid: [u8; 16] = e[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first = u64_at(e, 32);
+        let last = u64_at(e, 40);
+        if last < first || last >= dev.num_of_blocks() {
+            return Err("GPT partition out of range");
+        }
+        let name: Vec<u16> = e[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        partitions.push(PartitionEntry {
+            start_lba: first,
+            num_of_blocks: last - first + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                name: String::from_utf16_lossy(&name),
+            },
+        });
+    }
+    Ok(partitions)
+}
+
+// 拡張パーティションには対応しない
+fn parse_mbr(dev: &dyn BlockDevice, mbr: &[u8]) -> Result<Vec<PartitionEntry>> {
+    let mut partitions = Vec::new();
+    for e in mbr[446..510].chunks_exact(16) {
+        let partition_type = e[4];
+        let start_lba = u32::from_le_bytes(e[8..12].try_into().unwrap()) as u64;
+        let num_of_blocks = u32::from_le_bytes(e[12..16].try_into().unwrap()) as u64;
+        if partition_type == 0 || num_of_blocks == 0 {
+            continue;
+        }
+        if partition_type == MBR_PARTITION_TYPE_GPT_PROTECTIVE {
+            return parse_gpt(dev);
+        }
+        if start_lba + num_of_blocks > dev.num_of_blocks() {
+            return Err("MBR partition out of range");
+        }
+        partitions.push(PartitionEntry {
+            start_lba,
+            num_of_blocks,
+            kind: PartitionKind::Mbr { partition_type },
+        });
+    }
+    Ok(partitions)
+}
+
+// パーティションテーブルが無ければ空を返す
+pub fn parse_partition_table(dev: &dyn BlockDevice) -> Result<Vec<PartitionEntry>> {
+    let mut mbr = vec![0u8; dev.block_size()];
+    dev.read_blocks(0, &mut mbr)?;
+    if mbr.len() < 512 || mbr[510..512] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+    // FATのブートセクタもMBRと同じシグネチャを持つ
+    if mbr[0] == 0xEB || mbr[0] == 0xE9 {
+        return Ok(Vec::new());
+    }
+    parse_mbr(dev, &mbr)
+}
+
+static BLOCK_DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+static NEXT_DISK_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn add_block_device(name: String, dev: Arc<dyn BlockDevice>) {
+    info!(
+        "block: {name}: {} blocks x {} bytes",
+        dev.num_of_blocks(),
+        dev.block_size()
+    );
+    BLOCK_DEVICES.lock().push((name, dev));
+}
+
+// disk0, disk1, ...という名前で登録し、パーティションもdisk0p1, disk0p2, ...として登録する
+pub fn register_block_device(dev: Arc<dyn BlockDevice>) -> String {
+    let name = format!("disk{}", NEXT_DISK_ID.fetch_add(1, Ordering::SeqCst));
+    add_block_device(name.clone(), dev.clone());
+    match parse_partition_table(dev.as_ref()) {
+        Ok(partitions) => {
+            for (i, p) in partitions.into_iter().enumerate() {
+                info!("block: {name}p{}: {:?}", i + 1, p.kind);
+                let part = Partition {
+                    dev: dev.clone(),
+                    start_lba: p.start_lba,
+                    num_of_blocks: p.num_of_blocks,
+                };
+                add_block_device(format!("{name}p{}", i + 1), Arc::new(part));
+            }
+        }
+        Err(e) => {
+            info!("block: {name}: failed to read the partition table: {e}");
+        }
+    }
+    name
+}
+
+pub fn block_device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, d)| d.clone())
+}
+
+pub fn block_device_names() -> Vec<String> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .map(|(n, _)| n.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn mbr_partition() {
+        let mut img = vec![0u8; 512 * 16];
+        let e = &mut img[446..462];
+        e[4] = 0x0C;
+        e[8..12].copy_from_slice(&4u32.to_le_bytes());
+        e[12..16].copy_from_slice(&8u32.to_le_bytes());
+        img[510] = 0x55;
+        img[511] = 0xAA;
+        img[512 * 4] = 0xAB;
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(512, img));
+        let partitions = parse_partition_table(disk.as_ref()).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(
+            partitions[0].kind,
+            PartitionKind::Mbr {
+                partition_type: 0x0C
+            }
+        );
+        let part = Partition {
+            dev: disk,
+            start_lba: partitions[0].start_lba,
+            num_of_blocks: partitions[0].num_of_blocks,
+        };
+        let mut buf = [0u8; 512];
+        part.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 0xAB);
+        assert!(part.read_blocks(8, &mut buf).is_err());
+    }
+
+    #[test_case]
+    fn reject_bad_gpt_entry_array() {
+        let gpt = |entries_lba: u64, num_of_entries: u32, entry_size: u32| {
+            let mut img = vec![0u8; 512 * 8];
+            let e = &mut img[446..462];
+            e[4] = MBR_PARTITION_TYPE_GPT_PROTECTIVE;
+            e[8..12].copy_from_slice(&1u32.to_le_bytes());
+            e[12..16].copy_from_slice(&7u32.to_le_bytes());
+            img[510] = 0x55;
+            img[511] = 0xAA;
+            let header = &mut img[512..1024];
+            header[0..8].copy_from_slice(b"EFI PART");
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&num_of_entries.to_le_bytes());
+            header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+            parse_partition_table(&RamDisk::new(512, img))
+        };
+        assert_eq!(gpt(2, 4, 128).map(|p| p.len()), Ok(0));
+        assert!(gpt(2, 4, 200).is_err());
+        assert!(gpt(2, 4, 1024).is_err());
+        assert!(gpt(7, 8, 128).is_err());
+        assert!(gpt(u64::MAX, 4, 128).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::sync::Weak;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::check_block_range;
+use crate::block::BlockDevice;
+use crate::mutex::Mutex;
+use crate::result::Result;
+
+struct CacheEntry {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: BTreeMap<u64, CacheEntry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub cached_blocks: usize,
+    pub dirty_blocks: usize,
+}
+
+// ブロック単位のLRUキャッシュ。書き込みはsync()か追い出しの時まで遅らせる
+pub struct BlockCache {
+    dev: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BlockCache {
+    pub fn new(dev: Arc<dyn BlockDevice>, capacity: usize) -> Arc<Self> {
+        assert!(capacity > 0);
+        let cache = Arc::new(Self {
+            dev,
+            capacity,
+            state: Mutex::new(CacheState {
+                entries: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        });
+        BLOCK_CACHES.lock().push(Arc::downgrade(&cache));
+        cache
+    }
+    // 一番長く使われていないものを追い出す
+    // 書き戻せなかった時は、書いた内容を失わないようにキャッシュに残したままにする
+    fn evict(&self, state: &mut CacheState) -> Result<()> {
+        let Some((&lba, e)) = state.entries.iter().min_by_key(|(_, e)| e.last_used) else {
+            return Ok(());
+        };
+        if e.dirty {
+            self.dev.write_blocks(lba, &e.data)?;
+        }
+        state.entries.remove(&lba);
+        Ok(())
+    }
+    fn entry<'a>(&self, state: &'a mut CacheState, lba: u64) -> Result<&'a mut CacheEntry> {
+        state.clock += 1;
+        let now = state.clock;
+        if state.entries.contains_key(&lba) {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+            if state.entries.len() >= self.capacity {
+                self.evict(state)?;
+            }
+            let mut data = vec![0u8; self.dev.block_size()];
+            self.dev.read_blocks(lba, &mut data)?;
+            state.entries.insert(
+                lba,
+                CacheEntry {
+                    data,
+                    dirty: false,
+                    last_used: now,
+                },
+            );
+        }
+        let e = state.entries.get_mut(&lba).unwrap();
+        e.last_used = now;
+        Ok(e)
+    }
+    pub fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        for (lba, e) in state.entries.iter_mut().filter(|(_, e)| e.dirty) {
+            self.dev.write_blocks(*lba, &e.data)?;
+            e.dirty = false;
+        }
+        Ok(())
+    }
+    pub fn stats(&self) -> BlockCacheStats {
+        let state = self.state.lock();
+        BlockCacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            cached_blocks: state.entries.len(),
+            dirty_blocks: state.entries.values().filter(|e| e.dirty).count(),
+        }
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+    fn num_of_blocks(&self) -> u64 {
+        self.dev.num_of_blocks()
+    }
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        let mut state = self.state.lock();
+        for (i, chunk) in buf.chunks_exact_mut(self.block_size()).enumerate() {
+            chunk.copy_from_slice(&self.entry(&mut state, lba + i as u64)?.data);
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        let mut state = self.state.lock();
+        for (i, chunk) in buf.chunks_exact(self.block_size()).enumerate() {
+            let e = self.entry(&mut state, lba + i as u64)?;
+            e.data.copy_from_slice(chunk);
+            e.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+static BLOCK_CACHES: Mutex<Vec<Weak<BlockCache>>> = Mutex::new(Vec::new());
+
+// すべてのキャッシュの内容をデバイスに書き戻す
+pub fn sync() -> Result<()> {
+    let caches: Vec<Arc<BlockCache>> = {
+        let mut caches = BLOCK_CACHES.lock();
+        caches.retain(|c| c.strong_count() > 0);
+        caches.iter().filter_map(|c| c.upgrade()).collect()
+    };
+    for c in caches {
+        c.sync()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    #[test_case]
+    fn write_back_and_lru() {
+        let disk = Arc::new(RamDisk::new(512, vec![0u8; 512 * 4]));
+        let cache = BlockCache::new(disk.clone(), 2);
+        cache.write_blocks(0, &[1u8; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        disk.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 0);
+        cache.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+        assert_eq!(cache.stats().dirty_blocks, 1);
+        // 2ブロックまでなので、ブロック0が追い出されて書き戻される
+        cache.read_blocks(1, &mut buf).unwrap();
+        cache.read_blocks(2, &mut buf).unwrap();
+        disk.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+        cache.write_blocks(3, &[3u8; 512]).unwrap();
+        cache.sync().unwrap();
+        disk.read_blocks(3, &mut buf).unwrap();
+        assert_eq!(buf[0], 3);
+        assert_eq!(cache.stats().dirty_blocks, 0);
+    }
+
+    // 書き込みに失敗するデバイス
+    struct ReadOnlyDisk(RamDisk);
+
+    impl BlockDevice for ReadOnlyDisk {
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+        fn num_of_blocks(&self) -> u64 {
+            self.0.num_of_blocks()
+        }
+        fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+            self.0.read_blocks(lba, buf)
+        }
+        fn write_blocks(&self, _lba: u64, _buf: &[u8]) -> Result<()> {
+            Err("Read-only")
+        }
+    }
+
+    #[test_case]
+    fn keep_dirty_block_when_write_back_fails() {
+        let disk = Arc::new(ReadOnlyDisk(RamDisk::new(512, vec![0u8; 512 * 4])));
+        let cache = BlockCache::new(disk, 1);
+        cache.write_blocks(0, &[1u8; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        assert!(cache.read_blocks(1, &mut buf).is_err());
+        // 追い出せなかったブロックは書いた内容のまま読める
+        cache.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+        assert_eq!(cache.stats().dirty_blocks, 1);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::info;
+use crate::mutex::Mutex;
+use crate::uefi::locate_loaded_image_protocol;
+use crate::uefi::EfiHandle;
+use crate::uefi::EfiSystemTable;
+
+const CMDLINE_MAX_LEN: usize = 512;
+
+fn is_image_name(s: &str) -> bool {
+    s.len() >= 4 && s[s.len() - 4..].eq_ignore_ascii_case(".efi")
+}
+
+// "key=value flag ..." の形式のカーネルコマンドライン
+// ブートサービス終了前に読むのでアロケータは使わない
+#[derive(Copy, Clone)]
+pub struct Cmdline {
+    buf: [u8; CMDLINE_MAX_LEN],
+    len: usize,
+}
+
+impl Cmdline {
+    pub const fn empty() -> Self {
+        Self {
+            buf: [0; CMDLINE_MAX_LEN],
+            len: 0,
+        }
+    }
+    // 長すぎる部分やASCII以外の文字は捨てる
+    pub fn new(chars: impl Iterator<Item = char>) -> Self {
+        let mut cmdline = Self::empty();
+        for c in chars.take_while(|c| *c != '\0') {
+            if cmdline.len >= CMDLINE_MAX_LEN {
+                break;
+            }
+            let c = if c.is_ascii_whitespace() { ' ' } else { c };
+            if c.is_ascii() && !c.is_ascii_control() {
+                cmdline.buf[cmdline.len] = c as u8;
+                cmdline.len += 1;
+            }
+        }
+        cmdline
+    }
+    pub fn as_str(&self) -> &str {
+        // ASCIIしか入れていない
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+    // UEFIシェルから起動するとイメージ名が先頭に付くので飛ばす
+    pub fn args(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.as_str()
+            .split(' ')
+            .filter(|s| !s.is_empty())
+            .enumerate()
+            .filter(|(i, s)| !(*i == 0 && is_image_name(s)))
+            .map(|(_, s)| match s.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (s, None),
+            })
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.args()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .and_then(|(_, v)| v)
+    }
+    pub fn has_flag(&self, key: &str) -> bool {
+        self.args().any(|(k, _)| k == key)
+    }
+}
+
+static CMDLINE: Mutex<Cmdline> = Mutex::new(Cmdline::empty());
+
+// ブートサービスの終了前に呼ぶ
+pub fn init_cmdline(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+    let Ok(loaded_image) = locate_loaded_image_protocol(image_handle, efi_system_table) else {
+        return;
+    };
+    let chars = char::decode_utf16(loaded_image.load_options().iter().copied())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER));
+    let cmdline = Cmdline::new(chars);
+    info!("cmdline: {:?}", cmdline.as_str());
+    *CMDLINE.lock() = cmdline;
+}
+
+pub fn cmdline() -> Cmdline {
+    *CMDLINE.lock()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_cmdline() {
+        let cmdline = Cmdline::new(
+            "BOOTX64.EFI loglevel=warn  test\tres=1024x768 loglevel=info\0junk".chars(),
+        );
+        assert_eq!(cmdline.get("loglevel"), Some("info"));
+        assert_eq!(cmdline.get("res"), Some("1024x768"));
+        assert!(cmdline.has_flag("test"));
+        assert!(!cmdline.has_flag("BOOTX64.EFI"));
+        assert!(!cmdline.has_flag("junk"));
+        assert_eq!(cmdline.get("test"), None);
+    }
+}
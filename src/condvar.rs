@@ -0,0 +1,163 @@
+use crate::hpet::global_timestamp;
+use crate::mutex::MutexGuard;
+use crate::task::block_current_task;
+use crate::task::current_task_id;
+use crate::task::wake_task_from_interrupt;
+use crate::task::TaskId;
+use crate::task::WaitQueue;
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+// Mutexと組にして使う条件変数。waitはロックを離して止まり、起こされたらロックを取り直して返る
+pub struct CondVar {
+    queue: WaitQueue,
+}
+
+impl CondVar {
+    pub const fn new() -> Self {
+        Self {
+            queue: WaitQueue::new(),
+        }
+    }
+    // notifyされなくても返ることがあるので、普通はwait_whileを使う
+    #[track_caller]
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = MutexGuard::mutex(&guard);
+        self.queue.wait_with_guard(guard);
+        mutex.lock()
+    }
+    // conditionがtrueを返す間、待ち続ける
+    #[track_caller]
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+    // wait_whileと同じだが、timeoutを過ぎたら諦める。諦めた時は2つ目がtrue
+    #[track_caller]
+    pub fn wait_timeout_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let deadline = global_timestamp() + timeout;
+        while condition(&mut guard) {
+            if global_timestamp() >= deadline {
+                return (guard, true);
+            }
+            let mutex = MutexGuard::mutex(&guard);
+            self.queue.wait_with_guard_until(guard, deadline);
+            guard = mutex.lock();
+        }
+        (guard, false)
+    }
+    // 割り込みハンドラからは呼ばないこと (SCHEDULERのロックを取る)
+    pub fn notify_one(&self) {
+        self.queue.notify_one();
+    }
+    pub fn notify_all(&self) {
+        self.queue.notify_all();
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const NO_WAITER: TaskId = TaskId::MAX;
+
+// 割り込みハンドラからタスクに知らせるためのフラグ。待てるのは一度に1タスクだけ
+// setはロックを取らないので、割り込みハンドラから呼んでよい
+pub struct Event {
+    signaled: AtomicBool,
+    waiter: AtomicU64,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            signaled: AtomicBool::new(false),
+            waiter: AtomicU64::new(NO_WAITER),
+        }
+    }
+    pub fn set(&self) {
+        self.signaled.store(true, Ordering::SeqCst);
+        let waiter = self.waiter.load(Ordering::SeqCst);
+        if waiter != NO_WAITER {
+            wake_task_from_interrupt(waiter);
+        }
+    }
+    pub fn is_set(&self) -> bool {
+        self.signaled.load(Ordering::SeqCst)
+    }
+    pub fn reset(&self) {
+        self.signaled.store(false, Ordering::SeqCst);
+    }
+    // setされるまで止まる。戻る時にフラグは下ろす
+    pub fn wait(&self) {
+        let id = current_task_id();
+        if let Err(other) =
+            self.waiter
+                .compare_exchange(NO_WAITER, id, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            panic!("Task {id} waits on an Event already waited by task {other}");
+        }
+        // 登録してからフラグを見るので、その間にsetされても取りこぼさない
+        while !self.signaled.swap(false, Ordering::SeqCst) {
+            block_current_task();
+        }
+        self.waiter.store(NO_WAITER, Ordering::SeqCst);
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mutex::Mutex;
+
+    #[test_case]
+    fn wait_while_returns_when_condition_is_false() {
+        let cv = CondVar::new();
+        let m = Mutex::new(3);
+        let guard = cv.wait_while(m.lock(), |v| *v == 0);
+        assert_eq!(*guard, 3);
+    }
+
+    #[test_case]
+    fn wait_timeout_while_gives_up_after_timeout() {
+        let cv = CondVar::new();
+        let m = Mutex::new(0);
+        let start = global_timestamp();
+        let (guard, timed_out) =
+            cv.wait_timeout_while(m.lock(), Duration::from_millis(20), |v| *v == 0);
+        assert!(timed_out);
+        assert_eq!(*guard, 0);
+        assert!(global_timestamp() - start >= Duration::from_millis(20));
+    }
+
+    #[test_case]
+    fn event_set_before_wait_is_not_lost() {
+        let event = Event::new();
+        event.set();
+        assert!(event.is_set());
+        event.wait();
+        assert!(!event.is_set());
+    }
+}
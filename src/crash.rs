@@ -0,0 +1,114 @@
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use crate::backtrace::print_backtrace;
+use crate::error;
+use crate::klog;
+use crate::mutex::poison_held_locks;
+use crate::print::enter_panic_mode;
+use crate::print::serial_print;
+use crate::println;
+use crate::qemu::exit_qemu;
+use crate::qemu::panic_exit_code;
+use crate::result::Result;
+use crate::task::current_task_id;
+use crate::x86::cli;
+use crate::x86::hlt;
+
+// パニックの後にどうするか。カーネルコマンドラインの panic= で選ぶ
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    // QEMUを終了する (テストやCI向け)
+    Exit,
+    // 画面を見られるように止まり続ける
+    Halt,
+}
+
+impl PanicAction {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "exit" => Ok(Self::Exit),
+            "halt" => Ok(Self::Halt),
+            _ => Err("Unknown panic action"),
+        }
+    }
+}
+
+// パニック中はロックを取れないので、起動時に決めたものをここに置いておく
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Exit as u8);
+static IN_PANIC: AtomicBool = AtomicBool::new(false);
+
+// 表示するリングバッファの末尾の大きさ。パニック中はアロケータを使わないのでスタックに取る
+const KLOG_TAIL_SIZE: usize = 2048;
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::SeqCst);
+}
+
+pub fn panic_action() -> PanicAction {
+    if PANIC_ACTION.load(Ordering::SeqCst) == PanicAction::Halt as u8 {
+        PanicAction::Halt
+    } else {
+        PanicAction::Exit
+    }
+}
+
+// 直前までのログを行単位で出す。先頭の途中で切れた行は捨てる
+fn dump_klog_tail() {
+    let mut buf = [0u8; KLOG_TAIL_SIZE];
+    let n = klog::read_tail(&mut buf);
+    let tail = &buf[..n];
+    let tail = match tail.iter().position(|b| *b == b'\n') {
+        Some(i) if n == buf.len() => &tail[i + 1..],
+        _ => tail,
+    };
+    println!("---- last kernel log ----");
+    for line in tail.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        match core::str::from_utf8(line) {
+            Ok(line) => {
+                println!("{line}");
+            }
+            Err(_) => {
+                println!("<{} bytes of non-UTF-8 text>", line.len());
+            }
+        }
+    }
+    println!("---- end of kernel log ----");
+}
+
+fn stop(action: PanicAction) -> ! {
+    match action {
+        PanicAction::Exit => exit_qemu(panic_exit_code()),
+        PanicAction::Halt => loop {
+            cli();
+            hlt();
+        },
+    }
+}
+
+// #[panic_handler]から呼ぶ
+pub fn on_panic(info: &PanicInfo) -> ! {
+    if IN_PANIC.swap(true, Ordering::SeqCst) {
+        // パニックの処理中にまたパニックした。ロックも整形も信用できないので、最低限だけ出す
+        serial_print(format_args!("PANIC while panicking: {info}\n"));
+        stop(panic_action())
+    }
+    cli();
+    enter_panic_mode();
+    poison_held_locks();
+    error!("PANIC in task {}: {info}", current_task_id());
+    print_backtrace();
+    dump_klog_tail();
+    let action = panic_action();
+    error!(
+        "{}",
+        match action {
+            PanicAction::Exit => "Exiting QEMU",
+            PanicAction::Halt => "System halted",
+        }
+    );
+    stop(action)
+}
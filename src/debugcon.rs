@@ -0,0 +1,49 @@
+use core::fmt;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+
+// QEMU -device isa-debugcon (Bochsのport e9 hack)
+// 読み出すと0xE9が返ってくるので、それで有無を判定する
+const DEBUGCON_PORT: u16 = 0xE9;
+
+const STATE_UNKNOWN: u8 = 0;
+const STATE_PRESENT: u8 = 1;
+const STATE_ABSENT: u8 = 2;
+static DEBUGCON_STATE: AtomicU8 = AtomicU8::new(STATE_UNKNOWN);
+
+pub fn is_debugcon_present() -> bool {
+    match DEBUGCON_STATE.load(Ordering::Relaxed) {
+        STATE_PRESENT => true,
+        STATE_ABSENT => false,
+        _ => {
+            let present = read_io_port_u8(DEBUGCON_PORT) == DEBUGCON_PORT as u8;
+            DEBUGCON_STATE.store(
+                if present { STATE_PRESENT } else { STATE_ABSENT },
+                Ordering::Relaxed,
+            );
+            present
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct DebugCon;
+
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            write_io_port_u8(DEBUGCON_PORT, b);
+        }
+        Ok(())
+    }
+}
+
+// 初期化もロックも要らないので、どの時点からでも使える
+pub fn debugcon_print(args: fmt::Arguments) {
+    if is_debugcon_present() {
+        let _ = fmt::write(&mut DebugCon, args);
+    }
+}
@@ -0,0 +1,55 @@
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::ptr::write_bytes;
+use core::slice;
+
+use crate::allocator::ALLOCATOR;
+use crate::result::Result;
+
+// デバイスが直接読み書きするためのメモリ領域
+// カーネルはストレートマッピングなので、仮想アドレスがそのまま物理アドレスになる
+pub struct DmaBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+unsafe impl Send for DmaBuffer {}
+
+impl DmaBuffer {
+    // ゼロ埋めされた領域を確保する
+    pub fn new(size: usize, align: usize) -> Result<Self> {
+        let layout = Layout::from_size_align(size, align).or(Err("Invalid DMA layout"))?;
+        if layout.size() == 0 {
+            return Err("DMA buffer must not be empty");
+        }
+        let ptr = ALLOCATOR.alloc_with_options(layout);
+        if ptr.is_null() {
+            return Err("Failed to allocate DMA buffer");
+        }
+        unsafe { write_bytes(ptr, 0, size) };
+        Ok(Self { ptr, layout })
+    }
+    pub fn phys_addr(&self) -> u64 {
+        self.ptr as u64
+    }
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len()) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { ALLOCATOR.dealloc(self.ptr, self.layout) }
+    }
+}
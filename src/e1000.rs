@@ -0,0 +1,472 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::task::Poll;
+
+use crate::dma::DmaBuffer;
+use crate::executor::AtomicWaker;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::net::register_nic;
+use crate::net::MacAddress;
+use crate::net::NicDriver;
+use crate::pci::MmioRegion;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+
+// PCI/PCI-X Family of Gigabit Ethernet Controllers Software Developer's Manual
+// https://www.intel.com/content/dam/doc/manual/pci-pci-x-family-gbe-controllers-software-dev-manual.pdf
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00C0;
+const REG_IMS: usize = 0x00D0;
+const REG_IMC: usize = 0x00D8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL0: usize = 0x5400;
+const REG_RAH0: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const RAH_AV: u32 = 1 << 31;
+
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+// BSIZE=00 (2048バイト), ブロードキャストを受け付け、CRCは取り除く
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+// 13.4.34 Transmit IPG Register (IEEE 802.3の推奨値)
+const TIPG_DEFAULT: u32 = 10 | (10 << 10) | (10 << 20);
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const DESC_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+
+// ディスクリプタリングの長さは128バイトの倍数である必要がある
+const NUM_OF_RX_DESC: usize = 32;
+const NUM_OF_TX_DESC: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+const MAX_FRAME_SIZE: usize = 1514;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct RxDescriptor {
+    addr: u64,
+    len: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+const _: () = assert!(size_of::<RxDescriptor>() == 16);
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct TxDescriptor {
+    addr: u64,
+    len: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+const _: () = assert!(size_of::<TxDescriptor>() == 16);
+
+// 82541以降とe1000eではEERDのアドレスとDONEビットの位置が異なる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EepromLayout {
+    Legacy,
+    Extended,
+    None,
+}
+
+pub struct E1000 {
+    pci: PciDevice,
+    regs: MmioRegion,
+    mac: MacAddress,
+    rx_ring: DmaBuffer,
+    tx_ring: DmaBuffer,
+    rx_buffers: Vec<DmaBuffer>,
+    tx_buffers: Vec<DmaBuffer>,
+    rx_next: usize,
+    tx_next: usize,
+    link_up: bool,
+}
+unsafe impl Send for E1000 {}
+
+static E1000_DEVICE: Mutex<Option<E1000>> = Mutex::new(None);
+// 割り込みハンドラからICRを読んでクリアするために、レジスタのアドレスを別に持っておく
+static E1000_REGS: AtomicUsize = AtomicUsize::new(0);
+static RX_PENDING: AtomicBool = AtomicBool::new(false);
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+static LINK_CHANGED: AtomicBool = AtomicBool::new(false);
+
+fn interrupt_handler(_vector: u8) {
+    let base = E1000_REGS.load(Ordering::SeqCst);
+    if base == 0 {
+        return;
+    }
+    let icr = unsafe { read_volatile((base + REG_ICR) as *const u32) };
+    if icr & (ICR_RXT0 | ICR_RXDMT0 | ICR_RXO) != 0 {
+        RX_PENDING.store(true, Ordering::SeqCst);
+        RX_WAKER.wake();
+    }
+    if icr & ICR_LSC != 0 {
+        LINK_CHANGED.store(true, Ordering::SeqCst);
+    }
+}
+
+impl E1000 {
+    fn new(pci: PciDevice) -> Result<Self> {
+        pci.enable_memory_space();
+        pci.enable_bus_master();
+        let regs = pci.bar(0)?.map()?;
+        let mut rx_buffers = Vec::new();
+        for _ in 0..NUM_OF_RX_DESC {
+            rx_buffers.push(DmaBuffer::new(BUFFER_SIZE, 16)?);
+        }
+        let mut tx_buffers = Vec::new();
+        for _ in 0..NUM_OF_TX_DESC {
+            tx_buffers.push(DmaBuffer::new(BUFFER_SIZE, 16)?);
+        }
+        let mut nic = Self {
+            pci,
+            regs,
+            mac: [0; 6],
+            rx_ring: DmaBuffer::new(NUM_OF_RX_DESC * size_of::<RxDescriptor>(), 128)?,
+            tx_ring: DmaBuffer::new(NUM_OF_TX_DESC * size_of::<TxDescriptor>(), 128)?,
+            rx_buffers,
+            tx_buffers,
+            rx_next: 0,
+            tx_next: 0,
+            link_up: false,
+        };
+        nic.reset()?;
+        nic.mac = nic.read_mac_address()?;
+        nic.init_rx();
+        nic.init_tx();
+        nic.link_up = nic.is_link_up();
+        Ok(nic)
+    }
+    fn reset(&mut self) -> Result<()> {
+        self.regs.write_u32(REG_IMC, 0xFFFF_FFFF);
+        let ctrl = self.regs.read_u32(REG_CTRL);
+        self.regs.write_u32(REG_CTRL, ctrl | CTRL_RST);
+        let mut retry = 0;
+        while self.regs.read_u32(REG_CTRL) & CTRL_RST != 0 {
+            retry += 1;
+            if retry > 1_000_000 {
+                return Err("e1000: reset timed out");
+            }
+            busy_loop_hint();
+        }
+        self.regs.write_u32(REG_IMC, 0xFFFF_FFFF);
+        let _ = self.regs.read_u32(REG_ICR);
+        // オートネゴシエーションの結果を使ってリンクを上げる
+        let ctrl = self.regs.read_u32(REG_CTRL);
+        self.regs.write_u32(REG_CTRL, ctrl | CTRL_SLU | CTRL_ASDE);
+        Ok(())
+    }
+    fn eeprom_read_with(&self, layout: EepromLayout, word: u8) -> Option<u16> {
+        let (request, done) = match layout {
+            EepromLayout::Legacy => (((word as u32) << 8) | 1, 1 << 4),
+            EepromLayout::Extended => (((word as u32) << 2) | 1, 1 << 1),
+            EepromLayout::None => return None,
+        };
+        self.regs.write_u32(REG_EERD, request);
+        for _ in 0..100_000 {
+            let v = self.regs.read_u32(REG_EERD);
+            if v & done != 0 {
+                return Some((v >> 16) as u16);
+            }
+            busy_loop_hint();
+        }
+        None
+    }
+    fn detect_eeprom_layout(&self) -> EepromLayout {
+        [EepromLayout::Legacy, EepromLayout::Extended]
+            .into_iter()
+            .find(|layout| self.eeprom_read_with(*layout, 0).is_some())
+            .unwrap_or(EepromLayout::None)
+    }
+    // EEPROMの先頭3ワードがMACアドレス
+    // EEPROMが読めない場合は、ファームウェアが設定したRAL0/RAH0を使う
+    fn read_mac_address(&self) -> Result<MacAddress> {
+        let layout = self.detect_eeprom_layout();
+        let mut mac = [0u8; 6];
+        let from_eeprom = (0..3).all(|i| {
+            let Some(w) = self.eeprom_read_with(layout, i) else {
+                return false;
+            };
+            mac[i as usize * 2..i as usize * 2 + 2].copy_from_slice(&w.to_le_bytes());
+            true
+        });
+        if from_eeprom {
+            let ral = u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]);
+            let rah = u16::from_le_bytes([mac[4], mac[5]]) as u32;
+            self.regs.write_u32(REG_RAL0, ral);
+            self.regs.write_u32(REG_RAH0, rah | RAH_AV);
+            return Ok(mac);
+        }
+        let rah = self.regs.read_u32(REG_RAH0);
+        if rah & RAH_AV == 0 {
+            return Err("e1000: failed to read MAC address");
+        }
+        mac[0..4].copy_from_slice(&self.regs.read_u32(REG_RAL0).to_le_bytes());
+        mac[4..6].copy_from_slice(&(rah as u16).to_le_bytes());
+        Ok(mac)
+    }
+    fn rx_desc(&self, index: usize) -> *mut RxDescriptor {
+        unsafe { (self.rx_ring.as_ptr() as *mut RxDescriptor).add(index) }
+    }
+    fn tx_desc(&self, index: usize) -> *mut TxDescriptor {
+        unsafe { (self.tx_ring.as_ptr() as *mut TxDescriptor).add(index) }
+    }
+    fn init_rx(&mut self) {
+        for i in 0..NUM_OF_RX_DESC {
+            let desc = RxDescriptor {
+                addr: self.rx_buffers[i].phys_addr(),
+                ..Default::default()
+            };
+            unsafe { write_volatile(self.rx_desc(i), desc) };
+        }
+        for i in 0..128 {
+            self.regs.write_u32(REG_MTA + i * 4, 0);
+        }
+        let ring = self.rx_ring.phys_addr();
+        self.regs.write_u32(REG_RDBAL, ring as u32);
+        self.regs.write_u32(REG_RDBAH, (ring >> 32) as u32);
+        self.regs.write_u32(REG_RDLEN, self.rx_ring.len() as u32);
+        self.regs.write_u32(REG_RDH, 0);
+        // 全部のディスクリプタをハードウェアに渡す (RDT == RDHだと空とみなされるので1つ残す)
+        self.regs.write_u32(REG_RDT, (NUM_OF_RX_DESC - 1) as u32);
+        self.regs
+            .write_u32(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+    }
+    fn init_tx(&mut self) {
+        for i in 0..NUM_OF_TX_DESC {
+            let desc = TxDescriptor {
+                addr: self.tx_buffers[i].phys_addr(),
+                // 未使用のディスクリプタは送信済みとして扱う
+                status: DESC_STATUS_DD,
+                ..Default::default()
+            };
+            unsafe { write_volatile(self.tx_desc(i), desc) };
+        }
+        let ring = self.tx_ring.phys_addr();
+        self.regs.write_u32(REG_TDBAL, ring as u32);
+        self.regs.write_u32(REG_TDBAH, (ring >> 32) as u32);
+        self.regs.write_u32(REG_TDLEN, self.tx_ring.len() as u32);
+        self.regs.write_u32(REG_TDH, 0);
+        self.regs.write_u32(REG_TDT, 0);
+        self.regs.write_u32(REG_TIPG, TIPG_DEFAULT);
+        self.regs
+            .write_u32(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    }
+    fn enable_interrupt(&mut self) -> Result<()> {
+        if let Some(msi) = self.pci.msi() {
+            msi.enable(interrupt_handler)?;
+        } else {
+            self.pci.enable_intx(interrupt_handler)?;
+        }
+        E1000_REGS.store(self.regs.base() as usize, Ordering::SeqCst);
+        self.regs
+            .write_u32(REG_IMS, ICR_LSC | ICR_RXT0 | ICR_RXDMT0 | ICR_RXO);
+        Ok(())
+    }
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+    pub fn is_link_up(&self) -> bool {
+        self.regs.read_u32(REG_STATUS) & STATUS_LU != 0
+    }
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err("Frame too large");
+        }
+        let index = self.tx_next;
+        let desc = unsafe { read_volatile(self.tx_desc(index)) };
+        if desc.status & DESC_STATUS_DD == 0 {
+            return Err("e1000: tx ring is full");
+        }
+        self.tx_buffers[index].as_mut_slice()[..frame.len()].copy_from_slice(frame);
+        let desc = TxDescriptor {
+            addr: self.tx_buffers[index].phys_addr(),
+            len: frame.len() as u16,
+            cmd: TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS,
+            ..Default::default()
+        };
+        unsafe { write_volatile(self.tx_desc(index), desc) };
+        self.tx_next = (index + 1) % NUM_OF_TX_DESC;
+        self.regs.write_u32(REG_TDT, self.tx_next as u32);
+        Ok(())
+    }
+    fn check_link(&mut self) {
+        if !LINK_CHANGED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let link_up = self.is_link_up();
+        if link_up != self.link_up {
+            info!("e1000: link {}", if link_up { "up" } else { "down" });
+            self.link_up = link_up;
+        }
+    }
+    // 受信済みのフレームをrxに渡し、ディスクリプタをハードウェアに戻す
+    pub fn poll(&mut self, rx: &mut dyn FnMut(&[u8])) -> usize {
+        RX_PENDING.store(false, Ordering::SeqCst);
+        self.check_link();
+        let mut count = 0;
+        loop {
+            let index = self.rx_next;
+            let desc = unsafe { read_volatile(self.rx_desc(index)) };
+            if desc.status & DESC_STATUS_DD == 0 {
+                break;
+            }
+            // 1フレームは1つのバッファに収まるはずなので、分割されたものは捨てる
+            if desc.status & RX_STATUS_EOP == 0 || desc.errors != 0 {
+                warn!(
+                    "e1000: dropped rx frame (status {:#X}, errors {:#X})",
+                    desc.status, desc.errors
+                );
+            } else {
+                let len = (desc.len as usize).min(BUFFER_SIZE);
+                rx(&self.rx_buffers[index].as_slice()[..len]);
+                count += 1;
+            }
+            let desc = RxDescriptor {
+                addr: self.rx_buffers[index].phys_addr(),
+                ..Default::default()
+            };
+            unsafe { write_volatile(self.rx_desc(index), desc) };
+            self.regs.write_u32(REG_RDT, index as u32);
+            self.rx_next = (index + 1) % NUM_OF_RX_DESC;
+        }
+        count
+    }
+}
+
+pub fn with_e1000<R>(f: impl FnOnce(&mut E1000) -> R) -> Option<R> {
+    E1000_DEVICE.lock().as_mut().map(f)
+}
+
+pub fn has_pending_rx() -> bool {
+    RX_PENDING.load(Ordering::SeqCst)
+}
+
+// 受信割り込みが来るまで待つ
+pub async fn wait_for_rx() {
+    poll_fn(|cx| {
+        if has_pending_rx() {
+            return Poll::Ready(());
+        }
+        RX_WAKER.register(cx.waker());
+        if has_pending_rx() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// ネットワークスタックから使うためのもの。デバイスはE1000_DEVICEに入っているものを使う
+pub struct E1000Nic;
+
+impl NicDriver for E1000Nic {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+    fn mac_address(&self) -> MacAddress {
+        with_e1000(|n| n.mac_address()).unwrap_or_default()
+    }
+    fn is_link_up(&self) -> bool {
+        with_e1000(|n| n.is_link_up()).unwrap_or(false)
+    }
+    fn transmit(&self, frame: &[u8]) -> Result<()> {
+        with_e1000(|n| n.transmit(frame)).ok_or("e1000 is not initialized")?
+    }
+    fn receive(&self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        with_e1000(|n| n.poll(&mut |frame| frames.push(frame.to_vec())));
+        frames
+    }
+}
+
+pub struct E1000Driver;
+
+impl PciDriver for E1000Driver {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        // チップセットに内蔵されたもの (82579やI217以降) はPHYの初期化などが違うので対応しない
+        const MATCHES: [PciDeviceMatch; 3] = [
+            // 82540EM (QEMU -device e1000)
+            PciDeviceMatch::id(0x8086, 0x100E),
+            // 82545EM
+            PciDeviceMatch::id(0x8086, 0x100F),
+            // 82574L (QEMU -device e1000e)
+            PciDeviceMatch::id(0x8086, 0x10D3),
+        ];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        let mut global = E1000_DEVICE.lock();
+        if global.is_some() {
+            return Err("Only one e1000 device is supported");
+        }
+        let mut nic = E1000::new(*device)?;
+        if let Err(e) = nic.enable_interrupt() {
+            warn!("e1000: interrupts unavailable, falling back to polling: {e}");
+        }
+        info!(
+            "e1000: MAC address {:02X?}, link {}",
+            nic.mac_address(),
+            if nic.is_link_up() { "up" } else { "down" }
+        );
+        *global = Some(nic);
+        drop(global);
+        register_nic(Arc::new(E1000Nic));
+        Ok(())
+    }
+}
+
+pub static E1000_DRIVER: E1000Driver = E1000Driver;
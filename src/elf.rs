@@ -0,0 +1,249 @@
+extern crate alloc;
+
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::handle_alloc_error;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::mem::size_of;
+
+use crate::allocator::LAYOUT_PAGE_4K;
+use crate::result::Result;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Elf64Header {
+    ident: [u8; 16],
+    e_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+const _: () = assert!(size_of::<Elf64Header>() == 64);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+const _: () = assert!(size_of::<ProgramHeader>() == 56);
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+}
+
+// 読み込み先のページ。ストレートマッピングなので、このアドレスがそのまま物理アドレスになる
+#[repr(C, align(4096))]
+pub struct Page {
+    pub bytes: [u8; PAGE_SIZE],
+}
+
+impl Page {
+    pub fn new_zeroed() -> Box<Self> {
+        let p = unsafe { alloc_zeroed(LAYOUT_PAGE_4K) };
+        if p.is_null() {
+            handle_alloc_error(LAYOUT_PAGE_4K);
+        }
+        unsafe { Box::from_raw(p as *mut Page) }
+    }
+    pub fn phys_addr(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+// 読み込んだ結果。pagesを捨てるとマップしたページも解放されるので、アドレス空間と同じだけ生かしておく
+pub struct LoadedImage {
+    pub entry: u64,
+    // 一番後ろのセグメントの終わり (ページ境界に切り上げたもの)。ヒープの始まりに使う
+    pub end: u64,
+    pub pages: BTreeMap<u64, Box<Page>>,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Elf64Header,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < size_of::<Elf64Header>() {
+            return Err("ELF: too short");
+        }
+        let header = unsafe { (data.as_ptr() as *const Elf64Header).read_unaligned() };
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err("ELF: bad magic");
+        }
+        if header.ident[4] != ELFCLASS64 || header.ident[5] != ELFDATA2LSB {
+            return Err("ELF: not a little-endian ELF64");
+        }
+        if header.machine != EM_X86_64 {
+            return Err("ELF: not for x86_64");
+        }
+        // 再配置はしないので、静的リンクされた実行ファイルだけを受け付ける
+        if header.e_type != ET_EXEC {
+            return Err("ELF: not a static executable");
+        }
+        if header.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err("ELF: unexpected program header size");
+        }
+        let ph_end = (header.phnum as u64)
+            .checked_mul(size_of::<ProgramHeader>() as u64)
+            .and_then(|size| size.checked_add(header.phoff))
+            .ok_or("ELF: program headers overflow")?;
+        if ph_end > data.len() as u64 {
+            return Err("ELF: program headers out of range");
+        }
+        Ok(Self { data, header })
+    }
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.header.phnum as usize).map(|i| {
+            let offset = self.header.phoff as usize + i * size_of::<ProgramHeader>();
+            unsafe { (self.data.as_ptr().add(offset) as *const ProgramHeader).read_unaligned() }
+        })
+    }
+    // PT_LOADのセグメントを新しく確保したページにコピーしてtableにマップする
+    // BSSは確保したページがゼロ埋めされているのでそのままでよい
+    pub fn load(&self, table: &mut PML4, user: bool) -> Result<LoadedImage> {
+        let mut pages: BTreeMap<u64, Box<Page>> = BTreeMap::new();
+        // 1つのページに複数のセグメントが載ることがあるので、書き込み可能かどうかはページごとにまとめる
+        let mut writable: BTreeMap<u64, bool> = BTreeMap::new();
+        let mut end = 0;
+        for ph in self.program_headers().filter(|ph| ph.is_load()) {
+            if ph.memsz == 0 {
+                continue;
+            }
+            if ph.filesz > ph.memsz {
+                return Err("ELF: filesz is larger than memsz");
+            }
+            let file_end = ph
+                .offset
+                .checked_add(ph.filesz)
+                .ok_or("ELF: segment overflow")?;
+            if file_end > self.data.len() as u64 {
+                return Err("ELF: segment out of range");
+            }
+            let seg_end = ph
+                .vaddr
+                .checked_add(ph.memsz)
+                .ok_or("ELF: segment overflow")?;
+            let first_page = ph.vaddr & !(PAGE_SIZE as u64 - 1);
+            let last_page = seg_end.saturating_sub(1) & !(PAGE_SIZE as u64 - 1);
+            for page in (first_page..=last_page).step_by(PAGE_SIZE) {
+                pages.entry(page).or_insert_with(Page::new_zeroed);
+                *writable.entry(page).or_insert(false) |= ph.flags & PF_W != 0;
+            }
+            let src = &self.data[ph.offset as usize..file_end as usize];
+            for (i, b) in src.iter().enumerate() {
+                let addr = ph.vaddr + i as u64;
+                let page = pages
+                    .get_mut(&(addr & !(PAGE_SIZE as u64 - 1)))
+                    .expect("Page was not allocated");
+                page.bytes[(addr as usize) & (PAGE_SIZE - 1)] = *b;
+            }
+            end = end.max(last_page + PAGE_SIZE as u64);
+        }
+        if pages.is_empty() {
+            return Err("ELF: no loadable segments");
+        }
+        for (virt, page) in pages.iter() {
+            let attr = match (user, writable[virt]) {
+                (true, true) => PageAttr::ReadWriteUser,
+                (true, false) => PageAttr::ReadOnlyUser,
+                (false, true) => PageAttr::ReadWriteKernel,
+                (false, false) => PageAttr::ReadOnlyKernel,
+            };
+            table.create_mapping(*virt, *virt + PAGE_SIZE as u64, page.phys_addr(), attr)?;
+        }
+        Ok(LoadedImage {
+            entry: self.entry(),
+            end,
+            pages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::TranslationResult;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    // ヘッダとPT_LOADが1つだけの最小のELFを作る
+    fn tiny_elf(vaddr: u64, code: &[u8], memsz: u64) -> Vec<u8> {
+        let data_offset = 64 + 56;
+        let mut elf = vec![0u8; data_offset];
+        elf[0..4].copy_from_slice(&ELF_MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        elf[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        elf[24..32].copy_from_slice(&vaddr.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+        let ph = &mut elf[64..];
+        ph[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        ph[4..8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+        ph[8..16].copy_from_slice(&(data_offset as u64).to_le_bytes());
+        ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        ph[32..40].copy_from_slice(&(code.len() as u64).to_le_bytes());
+        ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+        elf.extend_from_slice(code);
+        elf
+    }
+
+    #[test_case]
+    fn load_segment_and_zero_bss() {
+        let vaddr = 0x80_0000_1000;
+        let image = tiny_elf(vaddr, &[0x90, 0xcc], 0x1800);
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.entry(), vaddr);
+        let mut table = PML4::new();
+        let loaded = elf.load(&mut table, true).unwrap();
+        assert_eq!(loaded.end, vaddr + 0x2000);
+        assert_eq!(loaded.pages.len(), 2);
+        let page = &loaded.pages[&vaddr];
+        assert_eq!(page.bytes[0..3], [0x90, 0xcc, 0x00]);
+        assert_eq!(
+            table.translate(vaddr + 1),
+            Ok(TranslationResult::PageMapped4K {
+                phys: page.phys_addr() + 1
+            })
+        );
+        assert!(Elf::parse(&image[..32]).is_err());
+    }
+}
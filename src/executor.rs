@@ -1,22 +1,27 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
-use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
 
 use crate::hpet::global_timestamp;
 use crate::info;
+use crate::mutex::Mutex;
 use crate::result::Result;
-use crate::x86::busy_loop_hint;
+use crate::task::has_runnable_tasks;
+use crate::task::yield_now;
+use crate::x86::hlt;
+use core::cell::UnsafeCell;
 use core::fmt::Debug;
 use core::future::Future;
 use core::panic::Location;
 use core::pin::Pin;
-use core::ptr::null;
 use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
 use core::task::Context;
 use core::task::Poll;
-use core::task::RawWaker;
-use core::task::RawWakerVTable;
 use core::task::Waker;
 use core::time::Duration;
 
@@ -46,69 +51,214 @@ impl<T> Task<T> {
     }
 }
 
-fn no_op_waker() -> Waker {
-    unsafe { Waker::from_raw(no_op_raw_waker()) }
+// タスクごとのWaker。割り込みハンドラからも呼ばれるので、アトミック変数だけを触る
+struct TaskWaker {
+    woken: AtomicBool,
 }
 
-fn no_op_raw_waker() -> RawWaker {
-    fn no_op(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        no_op_raw_waker()
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+    }
+}
+
+impl TaskWaker {
+    fn new() -> Arc<Self> {
+        // 最初の一回は必ずpollする
+        Arc::new(Self {
+            woken: AtomicBool::new(true),
+        })
+    }
+    fn take_woken(&self) -> bool {
+        self.woken.swap(false, Ordering::SeqCst)
+    }
+}
+
+// 割り込みハンドラからFutureを起こすためのWakerの置き場所
+// registerはタスクから、wakeは割り込みハンドラから呼ばれる
+pub struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    const IDLE: u8 = 0;
+    const REGISTERING: u8 = 1;
+    const WAKING: u8 = 2;
+
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(Self::IDLE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+    pub fn register(&self, waker: &Waker) {
+        if self
+            .state
+            .compare_exchange(
+                Self::IDLE,
+                Self::REGISTERING,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_err()
+        {
+            // wakeの途中なので、すぐに起こし直してもらう
+            waker.wake_by_ref();
+            return;
+        }
+        unsafe {
+            let slot = &mut *self.waker.get();
+            if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        }
+        if self
+            .state
+            .compare_exchange(
+                Self::REGISTERING,
+                Self::IDLE,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_err()
+        {
+            // 登録中に割り込みでwakeされた
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.store(Self::IDLE, Ordering::SeqCst);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+    pub fn wake(&self) {
+        if self.state.fetch_or(Self::WAKING, Ordering::SeqCst) == Self::IDLE {
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!Self::WAKING, Ordering::SeqCst);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 期限とWaker。期限はglobal_timestamp()の値
+static TIMERS: Mutex<Vec<(Duration, Waker)>> = Mutex::new(Vec::new());
+
+fn register_timer(deadline: Duration, waker: &Waker) {
+    let mut timers = TIMERS.lock();
+    if !timers
+        .iter()
+        .any(|(d, w)| *d == deadline && w.will_wake(waker))
+    {
+        timers.push((deadline, waker.clone()));
+    }
+}
+
+fn wake_expired_timers() {
+    let now = global_timestamp();
+    let expired: Vec<Waker> = {
+        let mut timers = TIMERS.lock();
+        let mut expired = Vec::new();
+        timers.retain(|(deadline, waker)| {
+            if *deadline <= now {
+                expired.push(waker.clone());
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    };
+    for waker in expired {
+        waker.wake();
+    }
+}
+
+// 起こされるまで待つ。他のタスクがあれば譲り、無ければ次の割り込み (タイマのtickなど) まで止まる
+fn wait_for_wakeup() {
+    if has_runnable_tasks() {
+        yield_now();
+    } else {
+        hlt();
     }
-    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
-    RawWaker::new(null::<()>(), vtable)
 }
 
 // Futureを受け取って結果を返す
 pub fn block_on<T>(future: impl Future<Output = Result<T>> + 'static) -> Result<T> {
     let mut task = Task::new(future);
     info!("Starting task {:?}", task);
+    let task_waker = TaskWaker::new();
+    let waker = Waker::from(task_waker.clone());
+    let mut context = Context::from_waker(&waker);
     loop {
-        let waker = no_op_waker();
-        let mut context = Context::from_waker(&waker);
-        match task.poll(&mut context) {
-            Poll::Ready(result) => return result,
-            Poll::Pending => busy_loop_hint(),
+        wake_expired_timers();
+        if !task_waker.take_woken() {
+            wait_for_wakeup();
+            continue;
+        }
+        if let Poll::Ready(result) = task.poll(&mut context) {
+            return result;
         }
     }
 }
 
 pub struct Executor {
-    task: Option<VecDeque<Task<()>>>,
+    tasks: Vec<(Task<()>, Arc<TaskWaker>)>,
 }
 
 impl Executor {
     pub const fn new() -> Self {
-        Self { task: None }
-    }
-
-    fn task_queue(&mut self) -> &mut VecDeque<Task<()>> {
-        if self.task.is_none() {
-            self.task = Some(VecDeque::new());
-        }
-        self.task.as_mut().unwrap()
+        Self { tasks: Vec::new() }
     }
 
     pub fn enqueue(&mut self, task: Task<()>) {
-        self.task_queue().push_back(task);
+        self.tasks.push((task, TaskWaker::new()));
     }
 
+    // 起こされたタスクだけをpollする
     pub fn run(mut executor: Self) {
         info!("Executor starts running...");
         loop {
-            let task = executor.task_queue().pop_front();
-            if let Some(mut task) = task {
-                let waker = no_op_waker();
+            wake_expired_timers();
+            let mut polled = false;
+            let mut i = 0;
+            while i < executor.tasks.len() {
+                let (task, task_waker) = &mut executor.tasks[i];
+                if !task_waker.take_woken() {
+                    i += 1;
+                    continue;
+                }
+                polled = true;
+                let waker = Waker::from(task_waker.clone());
                 let mut context = Context::from_waker(&waker);
                 match task.poll(&mut context) {
                     Poll::Pending => {
-                        executor.task_queue().push_back(task);
+                        i += 1;
                     }
                     Poll::Ready(result) => {
                         info!("Task {:?} finished with {:?}", task, result);
+                        executor.tasks.remove(i);
                     }
                 }
             }
+            if polled {
+                // 同じCPUで動いている他のタスクにも順番を回す
+                yield_now();
+            } else {
+                wait_for_wakeup();
+            }
         }
     }
 }
@@ -126,13 +276,11 @@ pub struct Yield {
 
 impl Future for Yield {
     type Output = ();
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
-        if self
-            .polled
-            .fetch_or(true, core::sync::atomic::Ordering::SeqCst)
-        {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.polled.fetch_or(true, Ordering::SeqCst) {
             Poll::Ready(())
         } else {
+            cx.waker().wake_by_ref();
             Poll::Pending
         }
     }
@@ -156,11 +304,33 @@ impl TimeoutFuture {
 
 impl Future for TimeoutFuture {
     type Output = ();
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         if global_timestamp() >= self.timeout {
             Poll::Ready(())
         } else {
+            register_timer(self.timeout, cx.waker());
             Poll::Pending
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn atomic_waker_wakes_registered_task() {
+        let task_waker = TaskWaker::new();
+        assert!(task_waker.take_woken());
+        let waker = Waker::from(task_waker.clone());
+        let atomic_waker = AtomicWaker::new();
+        atomic_waker.wake();
+        assert!(!task_waker.take_woken());
+        atomic_waker.register(&waker);
+        atomic_waker.wake();
+        assert!(task_waker.take_woken());
+        // 一度wakeしたら登録は消える
+        atomic_waker.wake();
+        assert!(!task_waker.take_woken());
+    }
+}
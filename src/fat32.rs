@@ -0,0 +1,460 @@
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::block::block_device;
+use crate::block::block_device_names;
+use crate::block::BlockDevice;
+use crate::block_cache::BlockCache;
+use crate::fs::mount;
+use crate::fs::DirEntry;
+use crate::fs::FileSystem;
+use crate::fs::FileType;
+use crate::fs::Inode;
+use crate::fs::Metadata;
+use crate::info;
+use crate::once::Once;
+use crate::result::Result;
+use crate::wall_clock::DateTime;
+
+// https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DIR_ENTRY_SIZE: usize = 32;
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+struct Fat32Volume {
+    dev: Arc<dyn BlockDevice>,
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    fat_start: u64,
+    data_start: u64,
+    num_of_clusters: u32,
+    root_cluster: u32,
+}
+
+impl Fat32Volume {
+    fn new(dev: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut bs = vec![0u8; dev.block_size().max(512)];
+        dev.read_blocks(0, &mut bs)?;
+        let u16_at = |o: usize| u16::from_le_bytes([bs[o], bs[o + 1]]);
+        let u32_at = |o: usize| u32::from_le_bytes([bs[o], bs[o + 1], bs[o + 2], bs[o + 3]]);
+        if bs[510..512] != [0x55, 0xAA] {
+            return Err("No boot sector signature");
+        }
+        let bytes_per_sector = u16_at(11) as usize;
+        let sectors_per_cluster = bs[13] as usize;
+        let reserved_sectors = u16_at(14) as u64;
+        let num_fats = bs[16] as u64;
+        let root_entry_count = u16_at(17);
+        let fat_size16 = u16_at(22);
+        let total_sectors = u32_at(32) as u64;
+        let fat_size = u32_at(36) as u64;
+        let root_cluster = u32_at(44);
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || bytes_per_sector % dev.block_size() != 0
+        {
+            return Err("Unsupported sector size");
+        }
+        if !sectors_per_cluster.is_power_of_two() || num_fats == 0 {
+            return Err("Invalid BPB");
+        }
+        if root_entry_count != 0 || fat_size16 != 0 || fat_size == 0 {
+            return Err("Not a FAT32 volume");
+        }
+        let data_start = reserved_sectors + num_fats * fat_size;
+        let num_of_clusters =
+            (total_sectors.saturating_sub(data_start) / sectors_per_cluster as u64) as u32;
+        let volume = Self {
+            dev,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            data_start,
+            num_of_clusters,
+            root_cluster,
+        };
+        if !volume.is_valid_cluster(root_cluster) {
+            return Err("Invalid root cluster");
+        }
+        Ok(volume)
+    }
+    // データ領域のクラスタは2から始まる。num_of_clusters + 2はu32に収まらないことがある
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && (cluster as u64) < self.num_of_clusters as u64 + 2
+    }
+    fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let lba = sector * (self.bytes_per_sector / self.dev.block_size()) as u64;
+        self.dev.read_blocks(lba, buf)
+    }
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<()> {
+        if !self.is_valid_cluster(cluster) {
+            return Err("Cluster number out of range");
+        }
+        let sector = self.data_start + (cluster - 2) as u64 * self.sectors_per_cluster as u64;
+        self.read_sectors(sector, buf)
+    }
+    // 次のクラスタ。チェーンの終わりならNone
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>> {
+        let offset = cluster as u64 * 4;
+        let sector = self.fat_start + offset / self.bytes_per_sector as u64;
+        let i = (offset % self.bytes_per_sector as u64) as usize;
+        let mut buf = vec![0u8; self.bytes_per_sector];
+        self.read_sectors(sector, &mut buf)?;
+        let next = u32::from_le_bytes(buf[i..i + 4].try_into().unwrap()) & 0x0FFF_FFFF;
+        if next >= END_OF_CHAIN {
+            Ok(None)
+        } else if next < 2 {
+            Err("Broken cluster chain")
+        } else {
+            Ok(Some(next))
+        }
+    }
+    fn cluster_chain(&self, first_cluster: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = Some(first_cluster).filter(|c| *c != 0);
+        while let Some(c) = cluster {
+            if chain.len() > self.num_of_clusters as usize {
+                return Err("Cluster chain has a loop");
+            }
+            chain.push(c);
+            cluster = self.next_cluster(c)?;
+        }
+        Ok(chain)
+    }
+}
+
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+// "README  TXT" -> "README.TXT" (NTの小文字フラグも見る)
+fn short_name_to_string(e: &[u8]) -> String {
+    let lower_base = e[12] & 0x08 != 0;
+    let lower_ext = e[12] & 0x10 != 0;
+    let conv = |bytes: &[u8], lower: bool| {
+        let mut s = String::new();
+        for b in bytes.iter().take_while(|b| **b != b' ') {
+            let c = if lower { b.to_ascii_lowercase() } else { *b };
+            s.push(if c.is_ascii() { c as char } else { '?' });
+        }
+        s
+    };
+    let mut name = conv(&e[0..8], lower_base);
+    // 0xE5で始まる名前は0x05で表される
+    if e[0] == 0x05 {
+        name.replace_range(0..1, "\u{e5}");
+    }
+    let ext = conv(&e[8..11], lower_ext);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+// FATの日付と時刻 (ローカル時刻だが、UTCとして扱う)
+fn fat_timestamp(date: u16, time: u16) -> Option<Duration> {
+    DateTime {
+        year: 1980 + (date >> 9),
+        month: ((date >> 5) & 0xF) as u8,
+        day: (date & 0x1F) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+        nanosecond: 0,
+    }
+    .to_unix_time()
+}
+
+struct Fat32Node {
+    volume: Arc<Fat32Volume>,
+    first_cluster: u32,
+    file_type: FileType,
+    size: u64,
+    mtime: Option<Duration>,
+    // 読み込み専用なので、一度たどったチェーンは変わらない
+    chain: Once<Vec<u32>>,
+}
+
+struct Fat32DirEntry {
+    name: String,
+    node: Fat32Node,
+}
+
+impl Fat32Node {
+    fn new(
+        volume: Arc<Fat32Volume>,
+        first_cluster: u32,
+        file_type: FileType,
+        size: u64,
+        mtime: Option<Duration>,
+    ) -> Self {
+        Self {
+            volume,
+            first_cluster,
+            file_type,
+            size,
+            mtime,
+            chain: Once::new(),
+        }
+    }
+    // read_atのたびにFATをたどり直さないように、最初にたどった結果を使い回す
+    fn cluster_chain(&self) -> Result<&[u32]> {
+        if let Some(chain) = self.chain.get() {
+            return Ok(chain);
+        }
+        let chain = self.volume.cluster_chain(self.first_cluster)?;
+        Ok(self.chain.call_once(|| chain))
+    }
+    fn entries(&self) -> Result<Vec<Fat32DirEntry>> {
+        if self.file_type != FileType::Directory {
+            return Err("Not a directory");
+        }
+        let mut entries = Vec::new();
+        let mut lfn = [0u16; 260];
+        let mut lfn_checksum_expected = None;
+        let mut buf = vec![0u8; self.volume.cluster_size()];
+        for &cluster in self.cluster_chain()? {
+            self.volume.read_cluster(cluster, &mut buf)?;
+            for e in buf.chunks_exact(DIR_ENTRY_SIZE) {
+                match e[0] {
+                    0x00 => return Ok(entries),
+                    0xE5 => {
+                        lfn_checksum_expected = None;
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attr = e[11];
+                if attr & 0x3F == ATTR_LONG_NAME {
+                    let ord = (e[0] & 0x1F) as usize;
+                    if ord == 0 || ord > 20 {
+                        lfn_checksum_expected = None;
+                        continue;
+                    }
+                    if e[0] & 0x40 != 0 {
+                        lfn = [0u16; 260];
+                    }
+                    let base = (ord - 1) * 13;
+                    let chars = e[1..11]
+                        .chunks_exact(2)
+                        .chain(e[14..26].chunks_exact(2))
+                        .chain(e[28..32].chunks_exact(2));
+                    for (i, c) in chars.enumerate() {
+                        lfn[base + i] = u16::from_le_bytes([c[0], c[1]]);
+                    }
+                    lfn_checksum_expected = Some(e[13]);
+                    continue;
+                }
+                if attr & ATTR_VOLUME_ID != 0 {
+                    lfn_checksum_expected = None;
+                    continue;
+                }
+                let name = if lfn_checksum_expected == Some(lfn_checksum(&e[0..11])) {
+                    let len = lfn
+                        .iter()
+                        .position(|c| *c == 0 || *c == 0xFFFF)
+                        .unwrap_or(lfn.len());
+                    char::decode_utf16(lfn[..len].iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect()
+                } else {
+                    short_name_to_string(e)
+                };
+                lfn_checksum_expected = None;
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let u16_at = |o: usize| u16::from_le_bytes([e[o], e[o + 1]]);
+                let first_cluster = (u16_at(20) as u32) << 16 | u16_at(26) as u32;
+                let is_dir = attr & ATTR_DIRECTORY != 0;
+                let (file_type, size) = if is_dir {
+                    (FileType::Directory, 0)
+                } else {
+                    let size = u32::from_le_bytes(e[28..32].try_into().unwrap()) as u64;
+                    (FileType::File, size)
+                };
+                entries.push(Fat32DirEntry {
+                    name,
+                    node: Fat32Node::new(
+                        self.volume.clone(),
+                        first_cluster,
+                        file_type,
+                        size,
+                        fat_timestamp(u16_at(24), u16_at(22)),
+                    ),
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Fat32Node {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: self.file_type,
+            size: self.size,
+            mtime: self.mtime,
+        }
+    }
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if self.file_type != FileType::File {
+            return Err("Not a file");
+        }
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let cluster_size = self.volume.cluster_size();
+        let chain = self.cluster_chain()?;
+        let mut cluster_buf = vec![0u8; cluster_size];
+        let mut done = 0;
+        while done < len {
+            let pos = offset as usize + done;
+            let cluster = *chain
+                .get(pos / cluster_size)
+                .ok_or("Cluster chain is shorter than the file")?;
+            self.volume.read_cluster(cluster, &mut cluster_buf)?;
+            let start = pos % cluster_size;
+            let n = (cluster_size - start).min(len - done);
+            buf[done..done + n].copy_from_slice(&cluster_buf[start..start + n]);
+            done += n;
+        }
+        Ok(len)
+    }
+    // FATの名前は大文字小文字を区別しない
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let e = self
+            .entries()?
+            .into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name))
+            .ok_or("File not found")?;
+        Ok(Arc::new(e.node))
+    }
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|e| DirEntry {
+                name: e.name,
+                file_type: e.node.file_type,
+            })
+            .collect())
+    }
+}
+
+// 読み込み専用のFAT32
+pub struct Fat32 {
+    volume: Arc<Fat32Volume>,
+}
+
+impl Fat32 {
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Result<Self> {
+        Ok(Self {
+            volume: Arc::new(Fat32Volume::new(dev)?),
+        })
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Fat32Node::new(
+            self.volume.clone(),
+            self.volume.root_cluster,
+            FileType::Directory,
+            0,
+            None,
+        ))
+    }
+}
+
+const FAT32_CACHE_BLOCKS: usize = 256;
+
+// FAT32として読めるブロックデバイスを /mnt/<デバイス名> にマウントする
+pub fn mount_fat32_devices() {
+    for name in block_device_names() {
+        let Some(dev) = block_device(&name) else {
+            continue;
+        };
+        if let Ok(fs) = Fat32::new(BlockCache::new(dev, FAT32_CACHE_BLOCKS)) {
+            if let Err(e) = mount(&format!("/mnt/{name}"), Arc::new(fs)) {
+                info!("fat32: failed to mount {name}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    #[test_case]
+    fn read_file_with_long_name() {
+        // 予約1, FAT1個(1セクタ), 1クラスタ=1セクタ, ルートはクラスタ2
+        let mut img = vec![0u8; 512 * 8];
+        img[11..13].copy_from_slice(&512u16.to_le_bytes());
+        img[13] = 1;
+        img[14..16].copy_from_slice(&1u16.to_le_bytes());
+        img[16] = 1;
+        img[32..36].copy_from_slice(&8u32.to_le_bytes());
+        img[36..40].copy_from_slice(&1u32.to_le_bytes());
+        img[44..48].copy_from_slice(&2u32.to_le_bytes());
+        img[510] = 0x55;
+        img[511] = 0xAA;
+        // FAT: クラスタ2と3はどちらも1クラスタで終わり
+        let fat = 512;
+        img[fat + 8..fat + 12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        img[fat + 12..fat + 16].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        // ルートディレクトリ: "Hi There.txt"
+        let root = 512 * 2;
+        let short = b"HITHER~1TXT";
+        let lfn: Vec<u16> = "Hi There.txt".encode_utf16().chain([0]).collect();
+        let e = &mut img[root..root + 32];
+        e[0] = 0x41;
+        e[11] = ATTR_LONG_NAME;
+        e[13] = lfn_checksum(short);
+        let slots = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (i, o) in slots.enumerate() {
+            let c = lfn.get(i).copied().unwrap_or(0xFFFF);
+            e[o..o + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        let e = &mut img[root + 32..root + 64];
+        e[0..11].copy_from_slice(short);
+        e[26..28].copy_from_slice(&3u16.to_le_bytes());
+        e[28..32].copy_from_slice(&5u32.to_le_bytes());
+        img[512 * 3..512 * 3 + 5].copy_from_slice(b"hello");
+
+        let fs = Fat32::new(Arc::new(RamDisk::new(512, img))).unwrap();
+        let root = fs.root();
+        let entries = root.read_dir().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Hi There.txt");
+        let file = root.lookup("hi there.TXT").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(file.read_at(1, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ello");
+        // 2回目はたどっておいたチェーンを使う
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+}
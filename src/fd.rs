@@ -0,0 +1,257 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::str;
+
+use crate::fs;
+use crate::fs::File;
+use crate::fs::FileType;
+use crate::fs::Inode;
+use crate::fs::Metadata;
+use crate::fs::SeekFrom;
+use crate::keyboard::read_char;
+use crate::mutex::Mutex;
+use crate::pipe;
+use crate::print::global_print;
+use crate::result::Result;
+use crate::serial::SerialPort;
+use crate::task::current_fd_table;
+use crate::virtio_console::virtio_console_read_byte;
+
+pub type Fd = usize;
+
+pub const STDIN: Fd = 0;
+pub const STDOUT: Fd = 1;
+pub const STDERR: Fd = 2;
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct OpenFlags {
+    pub create: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+// 標準入出力。入力はキーボードとシリアル (とvirtio-console) から、出力はglobal_printへ
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::CharDevice,
+            size: 0,
+            mtime: None,
+        }
+    }
+    // 来ている分だけ返す (待たない)
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let serial = SerialPort::new_for_com1();
+        let mut n = 0;
+        while n < buf.len() {
+            if let Some(b) = serial.try_read_byte().or_else(virtio_console_read_byte) {
+                buf[n] = b;
+                n += 1;
+                continue;
+            }
+            let Some(c) = read_char() else {
+                break;
+            };
+            let mut utf8 = [0u8; 4];
+            let encoded = c.encode_utf8(&mut utf8).as_bytes();
+            if n + encoded.len() > buf.len() {
+                break;
+            }
+            buf[n..n + encoded.len()].copy_from_slice(encoded);
+            n += encoded.len();
+        }
+        Ok(n)
+    }
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize> {
+        match str::from_utf8(buf) {
+            Ok(s) => global_print(format_args!("{s}")),
+            Err(_) => global_print(format_args!("{}", String::from_utf8_lossy(buf))),
+        }
+        Ok(buf.len())
+    }
+}
+
+// dupしたディスクリプタ同士は位置を共有する
+type OpenFile = Arc<Mutex<File>>;
+
+pub struct FdTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+    // 0, 1, 2をコンソールにつなぐ
+    pub fn new_with_console() -> Self {
+        let console: Arc<dyn Inode> = Arc::new(Console);
+        let mut table = Self::new();
+        for _ in [STDIN, STDOUT, STDERR] {
+            table.insert(Arc::new(Mutex::new(File::new(console.clone()))));
+        }
+        table
+    }
+    // 空いている一番小さい番号を使う
+    fn insert(&mut self, file: OpenFile) -> Fd {
+        if let Some(fd) = self.files.iter().position(|f| f.is_none()) {
+            self.files[fd] = Some(file);
+            fd
+        } else {
+            self.files.push(Some(file));
+            self.files.len() - 1
+        }
+    }
+    fn get(&self, fd: Fd) -> Result<OpenFile> {
+        self.files
+            .get(fd)
+            .and_then(|f| f.clone())
+            .ok_or("Bad file descriptor")
+    }
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Fd> {
+        let mut file = if flags.create {
+            fs::create(path)?
+        } else {
+            fs::open(path)?
+        };
+        if flags.truncate {
+            file.inode().truncate(0)?;
+        }
+        if flags.append {
+            file.seek(SeekFrom::End(0))?;
+        }
+        Ok(self.insert(Arc::new(Mutex::new(file))))
+    }
+    pub fn read(&self, fd: Fd, buf: &mut [u8]) -> Result<usize> {
+        self.get(fd)?.lock().read(buf)
+    }
+    pub fn write(&self, fd: Fd, buf: &[u8]) -> Result<usize> {
+        self.get(fd)?.lock().write(buf)
+    }
+    pub fn seek(&self, fd: Fd, pos: SeekFrom) -> Result<u64> {
+        self.get(fd)?.lock().seek(pos)
+    }
+    pub fn metadata(&self, fd: Fd) -> Result<Metadata> {
+        Ok(self.get(fd)?.lock().metadata())
+    }
+    pub fn close(&mut self, fd: Fd) -> Result<()> {
+        self.files
+            .get_mut(fd)
+            .and_then(|f| f.take())
+            .map(|_| ())
+            .ok_or("Bad file descriptor")
+    }
+    pub fn dup(&mut self, fd: Fd) -> Result<Fd> {
+        let file = self.get(fd)?;
+        Ok(self.insert(file))
+    }
+    // リダイレクトに使う。newfdが開いていれば閉じてから置き換える
+    pub fn dup2(&mut self, oldfd: Fd, newfd: Fd) -> Result<Fd> {
+        let file = self.get(oldfd)?;
+        if self.files.len() <= newfd {
+            self.files.resize(newfd + 1, None);
+        }
+        self.files[newfd] = Some(file);
+        Ok(newfd)
+    }
+    // 読む側と書く側のfdを返す
+    pub fn pipe(&mut self) -> (Fd, Fd) {
+        let (reader, writer) = pipe::pipe();
+        let reader = self.insert(Arc::new(Mutex::new(File::new(Arc::new(reader)))));
+        let writer = self.insert(Arc::new(Mutex::new(File::new(Arc::new(writer)))));
+        (reader, writer)
+    }
+    // 子プロセスに渡す表。開いているファイルは位置も含めて共有する
+    pub fn inherit(&self) -> Self {
+        Self {
+            files: self.files.clone(),
+        }
+    }
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// タスクの初期化前に使う表
+static BOOT_FD_TABLE: Mutex<Option<FdTable>> = Mutex::new(None);
+
+pub fn with_current_fd_table<R>(f: impl FnOnce(&mut FdTable) -> R) -> R {
+    if let Some(table) = current_fd_table() {
+        return f(&mut table.lock());
+    }
+    let mut table = BOOT_FD_TABLE.lock();
+    f(table.get_or_insert_with(FdTable::new_with_console))
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Fd> {
+    with_current_fd_table(|t| t.open(path, flags))
+}
+// パイプのように待つことがあるものは、位置を持たないのでファイルのロックを持たずに読み書きする
+fn stream_inode(file: &OpenFile) -> Option<Arc<dyn Inode>> {
+    let file = file.lock();
+    matches!(file.metadata().file_type, FileType::Fifo).then(|| file.inode().clone())
+}
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize> {
+    let file = with_current_fd_table(|t| t.get(fd))?;
+    match stream_inode(&file) {
+        Some(inode) => inode.read_at(0, buf),
+        None => file.lock().read(buf),
+    }
+}
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize> {
+    let file = with_current_fd_table(|t| t.get(fd))?;
+    match stream_inode(&file) {
+        Some(inode) => inode.write_at(0, buf),
+        None => file.lock().write(buf),
+    }
+}
+pub fn seek(fd: Fd, pos: SeekFrom) -> Result<u64> {
+    with_current_fd_table(|t| t.seek(fd, pos))
+}
+pub fn close(fd: Fd) -> Result<()> {
+    with_current_fd_table(|t| t.close(fd))
+}
+pub fn dup(fd: Fd) -> Result<Fd> {
+    with_current_fd_table(|t| t.dup(fd))
+}
+pub fn dup2(oldfd: Fd, newfd: Fd) -> Result<Fd> {
+    with_current_fd_table(|t| t.dup2(oldfd, newfd))
+}
+pub fn pipe() -> (Fd, Fd) {
+    with_current_fd_table(|t| t.pipe())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileSystem;
+    use crate::ramfs::RamFs;
+
+    #[test_case]
+    fn dup_shares_position() {
+        let root = RamFs::new().root();
+        let inode = root.create("f", FileType::File).unwrap();
+        inode.write_at(0, b"abcdef").unwrap();
+        let mut table = FdTable::new();
+        let fd = table.insert(Arc::new(Mutex::new(File::new(inode))));
+        assert_eq!(fd, 0);
+        let fd2 = table.dup(fd).unwrap();
+        let mut buf = [0u8; 2];
+        table.read(fd, &mut buf).unwrap();
+        table.read(fd2, &mut buf).unwrap();
+        assert_eq!(&buf, b"cd");
+        table.close(fd).unwrap();
+        assert!(table.read(fd, &mut buf).is_err());
+        assert_eq!(table.dup(fd2).unwrap(), 0);
+    }
+}
@@ -0,0 +1,371 @@
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::info;
+use crate::mutex::RwLock;
+use crate::result::Result;
+use crate::task::current_cwd;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    Fifo,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    // UNIX時刻。分からなければNone
+    pub mtime: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+// ファイル、ディレクトリ、シンボリックリンクの共通のインタフェース
+// 種類に合わない操作や、読み込み専用のファイルシステムでの書き込みはデフォルト実装でエラーにする
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    // ファイルの操作
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize> {
+        Err("Not a file")
+    }
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err("Read-only file system")
+    }
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err("Read-only file system")
+    }
+
+    // ディレクトリの操作
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err("Not a directory")
+    }
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Err("Not a directory")
+    }
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>> {
+        Err("Read-only file system")
+    }
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err("Read-only file system")
+    }
+
+    // シンボリックリンクの操作
+    fn read_link(&self) -> Result<String> {
+        Err("Not a symbolic link")
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &str;
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+// 位置を持った開いているファイル
+pub struct File {
+    inode: Arc<dyn Inode>,
+    pos: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+impl File {
+    pub fn new(inode: Arc<dyn Inode>) -> Self {
+        Self { inode, pos: 0 }
+    }
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inode.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inode.write_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(p) => (0, p as i64),
+            SeekFrom::Current(d) => (self.pos, d),
+            SeekFrom::End(d) => (self.inode.metadata().size, d),
+        };
+        self.pos = base
+            .checked_add_signed(delta)
+            .ok_or("Seek to a negative position")?;
+        Ok(self.pos)
+    }
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = self.read(&mut buf)?;
+            if n == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+// "/a/./b/" -> ["a", "b"]
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+// pathがmount_pointの下にあれば、残りのパスを返す
+fn strip_mount_point<'a>(mount_point: &str, path: &'a str) -> Option<&'a str> {
+    let rest = path.strip_prefix(mount_point.trim_end_matches('/'))?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+// cwdを基準にpathを絶対パスにする (cwdは絶対パス)
+// マウントポイントをまたぐ".."もパスの上で処理するので、マウントの境界を意識しなくてよい
+pub fn resolve_path(cwd: &str, path: &str) -> Result<String> {
+    if path.is_empty() {
+        return Err("Empty path");
+    }
+    if path.starts_with('/') {
+        normalize(path)
+    } else {
+        normalize(&format!("{cwd}/{path}"))
+    }
+}
+
+// カレントディレクトリはタスクごとに持つ。タスクの初期化前は "/"
+pub fn current_dir() -> String {
+    current_cwd()
+        .map(|cwd| cwd.lock().clone())
+        .unwrap_or_else(|| String::from("/"))
+}
+
+pub fn set_current_dir(path: &str) -> Result<()> {
+    let path = absolute_path(path)?;
+    if lookup(&path)?.metadata().file_type != FileType::Directory {
+        return Err("Not a directory");
+    }
+    let cwd = current_cwd().ok_or("Task is not initialized")?;
+    *cwd.lock() = path;
+    Ok(())
+}
+
+// カレントディレクトリからの相対パスも受け付ける
+pub fn absolute_path(path: &str) -> Result<String> {
+    resolve_path(&current_dir(), path)
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNT_TABLE: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+// "."と".."を取り除いた絶対パスにする。ルートより上には行かない
+fn normalize(path: &str) -> Result<String> {
+    if !path.starts_with('/') {
+        return Err("Path must be absolute");
+    }
+    let mut stack: Vec<&str> = Vec::new();
+    for c in components(path) {
+        if c == ".." {
+            stack.pop();
+        } else {
+            stack.push(c);
+        }
+    }
+    let mut normalized = String::new();
+    for c in stack {
+        normalized.push('/');
+        normalized.push_str(c);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNT_TABLE.write();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err("Already mounted");
+    }
+    info!("fs: mounted {} at {path}", fs.name());
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<()> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNT_TABLE.write();
+    let i = mounts
+        .iter()
+        .position(|m| m.path == path)
+        .ok_or("Not mounted")?;
+    mounts.remove(i);
+    Ok(())
+}
+
+// (マウントポイント, ファイルシステム名)
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNT_TABLE
+        .read()
+        .iter()
+        .map(|m| (m.path.clone(), m.fs.name().to_string()))
+        .collect()
+}
+
+// 一番長く一致するマウントポイントのファイルシステムと、その中でのパスを返す
+fn find_mount(path: &str) -> Result<(Arc<dyn FileSystem>, String)> {
+    let mounts = MOUNT_TABLE.read();
+    let (m, rest) = mounts
+        .iter()
+        .filter_map(|m| Some((m, strip_mount_point(&m.path, path)?)))
+        .max_by_key(|(m, _)| m.path.len())
+        .ok_or("No file system mounted")?;
+    Ok((m.fs.clone(), rest.to_string()))
+}
+
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+// 絶対パスからinodeを探す。シンボリックリンクは辿る
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>> {
+    lookup_with_depth(path, 0)
+}
+
+fn lookup_with_depth(path: &str, depth: usize) -> Result<Arc<dyn Inode>> {
+    let path = normalize(path)?;
+    let (fs, rest) = find_mount(&path)?;
+    let mount_point = &path[..path.len() - rest.len()];
+    let mut inode = fs.root();
+    let mut walked = String::from(mount_point);
+    let mut remaining = components(&rest);
+    while let Some(c) = remaining.next() {
+        let next = inode.lookup(c)?;
+        if next.metadata().file_type == FileType::Symlink {
+            if depth >= MAX_SYMLINK_DEPTH {
+                return Err("Too many levels of symbolic links");
+            }
+            let target = next.read_link()?;
+            let mut new_path = if target.starts_with('/') {
+                target
+            } else {
+                format!("{walked}/{target}")
+            };
+            for c in remaining {
+                new_path.push('/');
+                new_path.push_str(c);
+            }
+            return lookup_with_depth(&new_path, depth + 1);
+        }
+        walked.push('/');
+        walked.push_str(c);
+        inode = next;
+    }
+    Ok(inode)
+}
+
+fn split_parent(path: &str) -> Result<(String, String)> {
+    let path = absolute_path(path)?;
+    let (parent, name) = path.rsplit_once('/').ok_or("Invalid path")?;
+    if name.is_empty() {
+        return Err("Invalid path");
+    }
+    let parent = if parent.is_empty() { "/" } else { parent };
+    Ok((parent.to_string(), name.to_string()))
+}
+
+pub fn open(path: &str) -> Result<File> {
+    lookup(&absolute_path(path)?).map(File::new)
+}
+
+// 無ければ作る
+pub fn create(path: &str) -> Result<File> {
+    let path = &absolute_path(path)?;
+    if let Ok(inode) = lookup(path) {
+        return Ok(File::new(inode));
+    }
+    let (parent, name) = split_parent(path)?;
+    lookup(&parent)?
+        .create(&name, FileType::File)
+        .map(File::new)
+}
+
+pub fn mkdir(path: &str) -> Result<()> {
+    let (parent, name) = split_parent(path)?;
+    lookup(&parent)?.create(&name, FileType::Directory)?;
+    Ok(())
+}
+
+pub fn remove(path: &str) -> Result<()> {
+    let (parent, name) = split_parent(path)?;
+    lookup(&parent)?.remove(&name)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata> {
+    Ok(lookup(&absolute_path(path)?)?.metadata())
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>> {
+    lookup(&absolute_path(path)?)?.read_dir()
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>> {
+    open(path)?.read_to_end()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn mount_point_matching() {
+        assert_eq!(strip_mount_point("/", "/bin/sh"), Some("/bin/sh"));
+        assert_eq!(strip_mount_point("/tmp", "/tmp"), Some(""));
+        assert_eq!(strip_mount_point("/tmp", "/tmp/a"), Some("/a"));
+        assert_eq!(strip_mount_point("/tmp", "/tmpfoo"), None);
+        assert_eq!(normalize("/a/./b//c/").unwrap(), "/a/b/c");
+        assert_eq!(normalize("/").unwrap(), "/");
+        assert_eq!(normalize("/a/../../b/..").unwrap(), "/");
+        assert_eq!(normalize("/usr/bin/../lib").unwrap(), "/usr/lib");
+    }
+
+    #[test_case]
+    fn resolve_relative_path() {
+        assert_eq!(resolve_path("/home", "a/./b").unwrap(), "/home/a/b");
+        assert_eq!(resolve_path("/home/user", "../..").unwrap(), "/");
+        assert_eq!(resolve_path("/tmp", "../mnt/disk0").unwrap(), "/mnt/disk0");
+        assert_eq!(resolve_path("/tmp", "/etc/../bin").unwrap(), "/bin");
+        assert!(resolve_path("/", "").is_err());
+    }
+}
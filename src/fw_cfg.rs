@@ -0,0 +1,168 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use core::sync::atomic::fence;
+use core::sync::atomic::Ordering;
+
+use crate::info;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u32;
+
+// https://www.qemu.org/docs/master/specs/fw_cfg.html
+const FW_CFG_PORT_SELECTOR: u16 = 0x510;
+const FW_CFG_PORT_DATA: u16 = 0x511;
+const FW_CFG_PORT_DMA_HIGH: u16 = 0x514;
+const FW_CFG_PORT_DMA_LOW: u16 = 0x518;
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_ID: u16 = 0x0001;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const FW_CFG_ID_DMA: u32 = 1 << 1;
+
+const FW_CFG_DMA_CTL_ERROR: u32 = 1 << 0;
+const FW_CFG_DMA_CTL_READ: u32 = 1 << 1;
+const FW_CFG_DMA_CTL_SELECT: u32 = 1 << 3;
+
+const FILE_NAME_SIZE: usize = 56;
+const FILE_ENTRY_SIZE: usize = 64;
+
+// DMAのディスクリプタは全部ビッグエンディアン
+#[repr(C)]
+struct FwCfgDmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+const _: () = assert!(size_of::<FwCfgDmaAccess>() == 16);
+
+#[derive(Clone, Debug)]
+pub struct FwCfgFile {
+    pub name: String,
+    pub size: u32,
+    pub select: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FwCfg {
+    has_dma: bool,
+}
+
+static FW_CFG: Mutex<Option<FwCfg>> = Mutex::new(None);
+
+impl FwCfg {
+    // "QEMU"というシグネチャが読めればfw_cfgがある
+    pub fn detect() -> Option<Self> {
+        let mut sig = [0u8; 4];
+        let mut fw_cfg = Self { has_dma: false };
+        fw_cfg.read_pio(FW_CFG_SIGNATURE, &mut sig);
+        if &sig != b"QEMU" {
+            return None;
+        }
+        let mut id = [0u8; 4];
+        fw_cfg.read_pio(FW_CFG_ID, &mut id);
+        fw_cfg.has_dma = u32::from_le_bytes(id) & FW_CFG_ID_DMA != 0;
+        Some(fw_cfg)
+    }
+    pub fn has_dma(&self) -> bool {
+        self.has_dma
+    }
+    fn read_pio(&self, select: u16, buf: &mut [u8]) {
+        write_io_port_u16(FW_CFG_PORT_SELECTOR, select);
+        for b in buf.iter_mut() {
+            *b = read_io_port_u8(FW_CFG_PORT_DATA);
+        }
+    }
+    // 恒等マッピングなので、スタック上のディスクリプタのアドレスをそのまま渡せる
+    fn read_dma(&self, select: u16, buf: &mut [u8]) -> Result<()> {
+        let access = FwCfgDmaAccess {
+            control: (((select as u32) << 16) | FW_CFG_DMA_CTL_SELECT | FW_CFG_DMA_CTL_READ)
+                .to_be(),
+            length: (buf.len() as u32).to_be(),
+            address: (buf.as_mut_ptr() as u64).to_be(),
+        };
+        let addr = &access as *const FwCfgDmaAccess as u64;
+        fence(Ordering::SeqCst);
+        write_io_port_u32(FW_CFG_PORT_DMA_HIGH, ((addr >> 32) as u32).to_be());
+        // 下位を書き込んだ時点で転送が始まる
+        write_io_port_u32(FW_CFG_PORT_DMA_LOW, (addr as u32).to_be());
+        loop {
+            let control = u32::from_be(unsafe { read_volatile(&access.control) });
+            if control & FW_CFG_DMA_CTL_ERROR != 0 {
+                return Err("fw_cfg: DMA transfer failed");
+            }
+            if control == 0 {
+                break;
+            }
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn read(&self, select: u16, buf: &mut [u8]) -> Result<()> {
+        if self.has_dma {
+            self.read_dma(select, buf)
+        } else {
+            self.read_pio(select, buf);
+            Ok(())
+        }
+    }
+    pub fn files(&self) -> Result<Vec<FwCfgFile>> {
+        let mut count = [0u8; 4];
+        self.read_pio(FW_CFG_FILE_DIR, &mut count);
+        let count = u32::from_be_bytes(count) as usize;
+        let mut dir = vec![0u8; 4 + count * FILE_ENTRY_SIZE];
+        self.read(FW_CFG_FILE_DIR, &mut dir)?;
+        Ok(dir[4..]
+            .chunks_exact(FILE_ENTRY_SIZE)
+            .map(|e| {
+                let name = &e[8..8 + FILE_NAME_SIZE];
+                let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+                FwCfgFile {
+                    name: String::from_utf8_lossy(&name[..len]).into(),
+                    size: u32::from_be_bytes(e[0..4].try_into().unwrap()),
+                    select: u16::from_be_bytes(e[4..6].try_into().unwrap()),
+                }
+            })
+            .collect())
+    }
+    pub fn find_file(&self, name: &str) -> Result<Option<FwCfgFile>> {
+        Ok(self.files()?.into_iter().find(|f| f.name == name))
+    }
+    pub fn read_file(&self, name: &str) -> Result<Vec<u8>> {
+        let file = self.find_file(name)?.ok_or("fw_cfg: file not found")?;
+        let mut data = vec![0u8; file.size as usize];
+        self.read(file.select, &mut data)?;
+        Ok(data)
+    }
+}
+
+pub fn init_fw_cfg() {
+    let Some(fw_cfg) = FwCfg::detect() else {
+        info!("fw_cfg: not found");
+        return;
+    };
+    info!("fw_cfg: found (DMA: {})", fw_cfg.has_dma());
+    match fw_cfg.files() {
+        Ok(files) => {
+            for f in files.iter().filter(|f| f.name.starts_with("opt/")) {
+                info!("fw_cfg: {} ({} bytes)", f.name, f.size);
+            }
+        }
+        Err(e) => {
+            info!("fw_cfg: failed to read file directory: {e}");
+        }
+    }
+    *FW_CFG.lock() = Some(fw_cfg);
+}
+
+// -fw_cfg name=opt/...,file=... で渡されたファイルを読む
+pub fn read_fw_cfg_file(name: &str) -> Result<Vec<u8>> {
+    let fw_cfg = (*FW_CFG.lock()).ok_or("fw_cfg is not available")?;
+    fw_cfg.read_file(name)
+}
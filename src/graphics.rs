@@ -1,4 +1,11 @@
+extern crate alloc;
+
+use crate::print::with_global_vram;
 use crate::result::Result;
+use crate::serial::SerialPort;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::max;
 use core::cmp::min;
 use core::fmt;
 
@@ -7,14 +14,34 @@ pub trait Bitmap {
     fn pixels_per_line(&self) -> i64;
     fn width(&self) -> i64;
     fn height(&self) -> i64;
+    fn buf(&self) -> *const u8;
     fn buf_mut(&mut self) -> *mut u8;
 
+    fn bounds(&self) -> Rect {
+        Rect::new(
+            0,
+            0,
+            min(self.width(), self.pixels_per_line()),
+            self.height(),
+        )
+    }
+
+    /// # Safety
+    /// (x, y) がbounds()の中にあること。範囲の確認をせずにバッファの中を指すポインタを作る
     unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
         self.buf_mut()
             .add(((y * self.pixels_per_line() + x) * self.bytes_per_pixel()) as usize)
             as *mut u32
     }
 
+    /// # Safety
+    /// unchecked_pixel_at_mutと同じく、(x, y) がbounds()の中にあること
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> *const u32 {
+        self.buf()
+            .add(((y * self.pixels_per_line() + x) * self.bytes_per_pixel()) as usize)
+            as *const u32
+    }
+
     fn is_in_x_range(&self, px: i64) -> bool {
         0 <= px && px < min(self.width(), self.pixels_per_line())
     }
@@ -32,6 +59,124 @@ pub trait Bitmap {
     }
 }
 
+// メモリ上に確保した描画先 (バックバッファやウィンドウの中身など)
+pub struct BitmapBuffer {
+    width: i64,
+    height: i64,
+    buf: Vec<u32>,
+}
+
+impl BitmapBuffer {
+    pub fn new(width: i64, height: i64) -> Self {
+        Self {
+            width,
+            height,
+            buf: vec![0; (width.max(0) * height.max(0)) as usize],
+        }
+    }
+    pub fn pixels(&self) -> &[u32] {
+        &self.buf
+    }
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.buf
+    }
+}
+
+impl Bitmap for BitmapBuffer {
+    fn bytes_per_pixel(&self) -> i64 {
+        4
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.width
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    fn buf(&self) -> *const u8 {
+        self.buf.as_ptr() as *const u8
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr() as *mut u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i64,
+    pub y: i64,
+    pub w: i64,
+    pub h: i64,
+}
+
+impl Rect {
+    pub const fn new(x: i64, y: i64, w: i64, h: i64) -> Self {
+        Self { x, y, w, h }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.w <= 0 || self.h <= 0
+    }
+    pub fn right(&self) -> i64 {
+        self.x + self.w
+    }
+    pub fn bottom(&self) -> i64 {
+        self.y + self.h
+    }
+    pub fn contains(&self, x: i64, y: i64) -> bool {
+        self.x <= x && x < self.right() && self.y <= y && y < self.bottom()
+    }
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = max(self.x, other.x);
+        let y = max(self.y, other.y);
+        let r = Rect::new(
+            x,
+            y,
+            min(self.right(), other.right()) - x,
+            min(self.bottom(), other.bottom()) - y,
+        );
+        (!r.is_empty()).then_some(r)
+    }
+    pub fn translated(&self, dx: i64, dy: i64) -> Rect {
+        Rect::new(self.x + dx, self.y + dy, self.w, self.h)
+    }
+}
+
+// srcのsrc_rectの部分をdstの(dst_x, dst_y)にコピーする
+// どちらかの端からはみ出す部分は切り捨てる
+pub fn blit<D: Bitmap, S: Bitmap>(dst: &mut D, dst_x: i64, dst_y: i64, src: &S, src_rect: Rect) {
+    let Some(src_rect) = src_rect.intersection(&src.bounds()) else {
+        return;
+    };
+    // 切り取った分だけ書き込み先もずらす
+    let dx = dst_x - src_rect.x;
+    let dy = dst_y - src_rect.y;
+    let Some(dst_rect) = src_rect.translated(dx, dy).intersection(&dst.bounds()) else {
+        return;
+    };
+    let same_format = dst.bytes_per_pixel() == src.bytes_per_pixel();
+    for y in dst_rect.y..dst_rect.bottom() {
+        let sy = y - dy;
+        unsafe {
+            let d = dst.unchecked_pixel_at_mut(dst_rect.x, y);
+            let s = src.unchecked_pixel_at(dst_rect.x - dx, sy);
+            if same_format {
+                core::ptr::copy(
+                    s as *const u8,
+                    d as *mut u8,
+                    (dst_rect.w * dst.bytes_per_pixel()) as usize,
+                );
+            } else {
+                for x in 0..dst_rect.w {
+                    let p = src.unchecked_pixel_at(dst_rect.x - dx + x, sy);
+                    *dst.unchecked_pixel_at_mut(dst_rect.x + x, y) = *p;
+                }
+            }
+        }
+    }
+}
+
 unsafe fn unchecked_draw_point<T: Bitmap>(buf: &mut T, color: u32, x: i64, y: i64) {
     *buf.unchecked_pixel_at_mut(x, y) = color;
 }
@@ -175,10 +320,246 @@ pub fn draw_test_pattern<T: Bitmap>(buf: &mut T) {
     draw_str_fg(buf, left, h * colors.len() as i64 + 16, 0x00ff00, "ABCDEF");
 }
 
+// 無圧縮の24/32ビットBMP (BI_RGB, もしくは32ビットのBI_BITFIELDS) のみ対応
+// https://learn.microsoft.com/en-us/windows/win32/gdi/bitmap-storage
+const BMP_FILE_HEADER_SIZE: usize = 14;
+const BMP_INFO_HEADER_MIN_SIZE: usize = 40;
+const BMP_COMPRESSION_RGB: u32 = 0;
+const BMP_COMPRESSION_BITFIELDS: u32 = 3;
+// BI_BITFIELDSのR, G, Bのマスクは情報ヘッダの直後 (V4/V5ヘッダでも同じ位置) にある
+const BMP_MASKS_OFFSET: usize = BMP_FILE_HEADER_SIZE + BMP_INFO_HEADER_MIN_SIZE;
+
+// 画素の中の1色分のビット
+#[derive(Clone, Copy)]
+struct BmpChannel {
+    mask: u32,
+    shift: u32,
+}
+
+impl BmpChannel {
+    // マスクは0でなく、1が続いていないといけない
+    fn new(mask: u32) -> Result<Self> {
+        let shift = mask.trailing_zeros();
+        let bits = mask.checked_shr(shift).unwrap_or(0);
+        if bits == 0 || bits & bits.wrapping_add(1) != 0 {
+            return Err("Invalid BMP color mask");
+        }
+        Ok(Self { mask, shift })
+    }
+    // 0..=255に伸縮する
+    fn value(&self, pixel: u32) -> u32 {
+        let max = (self.mask >> self.shift) as u64;
+        (((pixel & self.mask) >> self.shift) as u64 * 255 / max) as u32
+    }
+}
+
+pub struct BmpImage<'a> {
+    data: &'a [u8],
+    width: i64,
+    height: i64,
+    bottom_up: bool,
+    bytes_per_pixel: usize,
+    row_stride: usize,
+    channels: [BmpChannel; 3],
+}
+
+impl<'a> BmpImage<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let u16_at = |o: usize| -> Result<u16> {
+            Ok(u16::from_le_bytes(
+                data.get(o..o + 2)
+                    .ok_or("BMP truncated")?
+                    .try_into()
+                    .unwrap(),
+            ))
+        };
+        let u32_at = |o: usize| -> Result<u32> {
+            Ok(u32::from_le_bytes(
+                data.get(o..o + 4)
+                    .ok_or("BMP truncated")?
+                    .try_into()
+                    .unwrap(),
+            ))
+        };
+        if data.get(0..2) != Some(b"BM") {
+            return Err("Not a BMP image");
+        }
+        let pixel_offset = u32_at(10)? as usize;
+        if (u32_at(BMP_FILE_HEADER_SIZE)? as usize) < BMP_INFO_HEADER_MIN_SIZE {
+            return Err("Unsupported BMP header");
+        }
+        let width = u32_at(18)? as i32 as i64;
+        let height = u32_at(22)? as i32 as i64;
+        let bpp = u16_at(28)?;
+        let compression = u32_at(30)?;
+        let bytes_per_pixel = match (bpp, compression) {
+            (24, BMP_COMPRESSION_RGB) => 3,
+            (32, BMP_COMPRESSION_RGB) | (32, BMP_COMPRESSION_BITFIELDS) => 4,
+            _ => return Err("Unsupported BMP format"),
+        };
+        if width <= 0 || height == 0 {
+            return Err("Invalid BMP size");
+        }
+        let masks = if compression == BMP_COMPRESSION_BITFIELDS {
+            [
+                u32_at(BMP_MASKS_OFFSET)?,
+                u32_at(BMP_MASKS_OFFSET + 4)?,
+                u32_at(BMP_MASKS_OFFSET + 8)?,
+            ]
+        } else {
+            [0xff0000, 0x00ff00, 0x0000ff]
+        };
+        let channels = [
+            BmpChannel::new(masks[0])?,
+            BmpChannel::new(masks[1])?,
+            BmpChannel::new(masks[2])?,
+        ];
+        // 各行は4バイト境界に揃えられている
+        let row_stride = (width as usize * bytes_per_pixel + 3) & !3;
+        let end = row_stride
+            .checked_mul(height.unsigned_abs() as usize)
+            .and_then(|size| size.checked_add(pixel_offset))
+            .ok_or("Invalid BMP size")?;
+        let data = data
+            .get(pixel_offset..end)
+            .ok_or("BMP pixel data truncated")?;
+        Ok(Self {
+            data,
+            width,
+            height: height.abs(),
+            // 高さが正なら下の行から並んでいる
+            bottom_up: height > 0,
+            bytes_per_pixel,
+            row_stride,
+            channels,
+        })
+    }
+    pub fn width(&self) -> i64 {
+        self.width
+    }
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+    // 0xRRGGBBの形で返す
+    pub fn pixel(&self, x: i64, y: i64) -> Option<u32> {
+        if !(0..self.width).contains(&x) || !(0..self.height).contains(&y) {
+            return None;
+        }
+        let row = if self.bottom_up {
+            self.height - 1 - y
+        } else {
+            y
+        };
+        let o = row as usize * self.row_stride + x as usize * self.bytes_per_pixel;
+        let mut bytes = [0u8; 4];
+        bytes[..self.bytes_per_pixel].copy_from_slice(&self.data[o..o + self.bytes_per_pixel]);
+        let p = u32::from_le_bytes(bytes);
+        let [r, g, b] = self.channels.map(|c| c.value(p));
+        Some((r << 16) | (g << 8) | b)
+    }
+}
+
+// 画面からはみ出す部分は描画しない
+pub fn draw_bmp<T: Bitmap>(buf: &mut T, bytes: &[u8], x: i64, y: i64) -> Result<()> {
+    let image = BmpImage::parse(bytes)?;
+    for iy in 0..image.height() {
+        for ix in 0..image.width() {
+            if let (Some(p), Some(c)) = (buf.pixel_at_mut(x + ix, y + iy), image.pixel(ix, iy)) {
+                *p = c;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 上の行から並ぶ32ビットBMPにする
+pub fn encode_bmp<T: Bitmap>(bitmap: &T) -> Vec<u8> {
+    let bounds = bitmap.bounds();
+    let (w, h) = (bounds.w.max(0), bounds.h.max(0));
+    let header_size = BMP_FILE_HEADER_SIZE + BMP_INFO_HEADER_MIN_SIZE;
+    let image_size = (w * h * 4) as usize;
+    let mut out = Vec::with_capacity(header_size + image_size);
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&((header_size + image_size) as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(header_size as u32).to_le_bytes());
+    out.extend_from_slice(&(BMP_INFO_HEADER_MIN_SIZE as u32).to_le_bytes());
+    out.extend_from_slice(&(w as i32).to_le_bytes());
+    out.extend_from_slice(&(-(h as i32)).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&32u16.to_le_bytes());
+    out.extend_from_slice(&BMP_COMPRESSION_RGB.to_le_bytes());
+    out.extend_from_slice(&(image_size as u32).to_le_bytes());
+    // 解像度とパレットは使わない
+    out.extend_from_slice(&[0; 16]);
+    for y in 0..h {
+        for x in 0..w {
+            let p = unsafe { *bitmap.unchecked_pixel_at(x, y) } & 0xffffff;
+            out.extend_from_slice(&p.to_le_bytes());
+        }
+    }
+    out
+}
+
+// P6形式 (RGBの24ビット)
+pub fn encode_ppm<T: Bitmap>(bitmap: &T) -> Vec<u8> {
+    let bounds = bitmap.bounds();
+    let (w, h) = (bounds.w.max(0), bounds.h.max(0));
+    let mut out = Vec::with_capacity((w * h * 3) as usize + 32);
+    out.extend_from_slice(alloc::format!("P6\n{w} {h}\n255\n").as_bytes());
+    for y in 0..h {
+        for x in 0..w {
+            let p = unsafe { *bitmap.unchecked_pixel_at(x, y) };
+            out.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+        }
+    }
+    out
+}
+
+// 画面をBMPにしてシリアルにbase64で流す
+// ログと混ざっても取り出せるように、前後に目印の行を入れる
+// scripts/extract_screenshot.sh でファイルに戻せる
+pub fn screenshot() -> Result<()> {
+    // エンコードには時間がかかるので、ロック (割り込みも止まる) の中では場所と大きさだけを写す
+    // 読んでいる間に描かれると、その部分は途中の状態で写る
+    let vram = with_global_vram(|vram| *vram).ok_or("VRAM is not initialized")?;
+    let bmp = encode_bmp(&vram);
+    let serial = SerialPort::default();
+    serial.send_str("\n-----BEGIN SCREENSHOT BMP-----\n");
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    // 57バイトごとに76文字の行になる
+    for line in bmp.chunks(57) {
+        for chunk in line.chunks(3) {
+            let b = [
+                chunk[0],
+                chunk.get(1).copied().unwrap_or(0),
+                chunk.get(2).copied().unwrap_or(0),
+            ];
+            let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    serial.send_byte(TABLE[((n >> (18 - i * 6)) & 0x3f) as usize]);
+                } else {
+                    serial.send_byte(b'=');
+                }
+            }
+        }
+        serial.send_byte(b'\n');
+    }
+    serial.send_str("-----END SCREENSHOT BMP-----\n");
+    Ok(())
+}
+
+const CHAR_WIDTH: i64 = 8;
+const CHAR_HEIGHT: i64 = 16;
+const TEXT_FG_COLOR: u32 = 0xffffff;
+const TEXT_BG_COLOR: u32 = 0x000000;
+
 pub struct BitmapTextWriter<T> {
     buf: T,
     cursor_x: i64,
     cursor_y: i64,
+    fg_color: u32,
 }
 
 impl<T: Bitmap> BitmapTextWriter<T> {
@@ -187,21 +568,208 @@ impl<T: Bitmap> BitmapTextWriter<T> {
             buf,
             cursor_x: 0,
             cursor_y: 0,
+            fg_color: TEXT_FG_COLOR,
+        }
+    }
+    pub fn set_fg_color(&mut self, color: u32) {
+        self.fg_color = color;
+    }
+    pub fn reset_fg_color(&mut self) {
+        self.fg_color = TEXT_FG_COLOR;
+    }
+    pub fn bitmap(&self) -> &T {
+        &self.buf
+    }
+    pub fn bitmap_mut(&mut self) -> &mut T {
+        &mut self.buf
+    }
+    pub fn cols(&self) -> i64 {
+        min(self.buf.width(), self.buf.pixels_per_line()) / CHAR_WIDTH
+    }
+    pub fn rows(&self) -> i64 {
+        self.buf.height() / CHAR_HEIGHT
+    }
+    pub fn clear(&mut self) {
+        let w = min(self.buf.width(), self.buf.pixels_per_line());
+        let h = self.buf.height();
+        let _ = fill_rect(&mut self.buf, TEXT_BG_COLOR, 0, 0, w, h);
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+    // 画面全体をlines行ぶん上にずらし、空いた下の行を消す
+    fn scroll_up(&mut self, lines: i64) {
+        let w = min(self.buf.width(), self.buf.pixels_per_line());
+        let h = self.rows() * CHAR_HEIGHT;
+        let dy = min(lines * CHAR_HEIGHT, h);
+        let row_bytes = (w * self.buf.bytes_per_pixel()) as usize;
+        for y in 0..h - dy {
+            unsafe {
+                let dst = self.buf.unchecked_pixel_at_mut(0, y) as *mut u8;
+                let src = self.buf.unchecked_pixel_at_mut(0, y + dy) as *const u8;
+                core::ptr::copy(src, dst, row_bytes);
+            }
+        }
+        if dy > 0 {
+            let _ = fill_rect(&mut self.buf, TEXT_BG_COLOR, 0, h - dy, w, dy);
         }
     }
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        if self.cursor_y + CHAR_HEIGHT * 2 > self.rows() * CHAR_HEIGHT {
+            self.scroll_up(1);
+        } else {
+            self.cursor_y += CHAR_HEIGHT;
+        }
+    }
+    fn put_char(&mut self, c: char) {
+        // 右端まで来たら折り返す
+        if self.cursor_x + CHAR_WIDTH > self.cols() * CHAR_WIDTH {
+            self.new_line();
+        }
+        let _ = fill_rect(
+            &mut self.buf,
+            TEXT_BG_COLOR,
+            self.cursor_x,
+            self.cursor_y,
+            CHAR_WIDTH,
+            CHAR_HEIGHT,
+        );
+        draw_font_fg(
+            &mut self.buf,
+            self.cursor_x,
+            self.cursor_y,
+            TEXT_FG_COLOR,
+            c,
+        );
+        self.cursor_x += CHAR_WIDTH;
+    }
 }
 
 impl<T: Bitmap> fmt::Write for BitmapTextWriter<T> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.rows() == 0 || self.cols() == 0 {
+            return Ok(());
+        }
         for c in s.chars() {
-            if c == '\n' {
-                self.cursor_y += 16;
-                self.cursor_x = 0;
-                continue;
+            match c {
+                '\n' => self.new_line(),
+                '\r' => self.cursor_x = 0,
+                // 消さずに1文字戻るだけ (端末のBSと同じ)
+                '\x08' => self.cursor_x = (self.cursor_x - CHAR_WIDTH).max(0),
+                _ => self.put_char(c),
             }
-            draw_font_fg(&mut self.buf, self.cursor_x, self.cursor_y, 0xffffff, c);
-            self.cursor_x += 8;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use alloc::vec;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn text_writer_wraps_and_scrolls() {
+        let (width, height) = (8 * 4, 16 * 3);
+        let mut w = BitmapTextWriter::new(BitmapBuffer::new(width, height));
+        // 4文字で折り返し, 3行を超えたらスクロールする
+        write!(w, "AAAAB\nC\nD").unwrap();
+        assert_eq!(w.cursor_y, 16 * 2);
+        assert_eq!(w.cursor_x, 8);
+        // 最初の行("AAAA")はスクロールで消え、"B", "C", "D"が1行ずつ上に詰まっている
+        let mut expected = BitmapBuffer::new(width, height);
+        for (row, c) in ['B', 'C', 'D'].into_iter().enumerate() {
+            draw_font_fg(&mut expected, 0, row as i64 * 16, TEXT_FG_COLOR, c);
+        }
+        assert_eq!(w.buf.buf, expected.buf);
+        w.clear();
+        assert!(w.buf.buf.iter().all(|p| *p == 0));
+        assert_eq!((w.cursor_x, w.cursor_y), (0, 0));
+    }
+
+    #[test_case]
+    fn blit_clips_at_edges() {
+        let mut src = BitmapBuffer::new(4, 4);
+        for (i, p) in src.buf.iter_mut().enumerate() {
+            *p = i as u32 + 1;
+        }
+        let mut dst = BitmapBuffer::new(3, 3);
+        // 左上にはみ出す位置に置くと、srcの(1, 1)からがdstの(0, 0)に来る
+        blit(&mut dst, -1, -1, &src, Rect::new(0, 0, 4, 4));
+        assert_eq!(dst.buf, [6, 7, 8, 10, 11, 12, 14, 15, 16]);
+        let mut dst = BitmapBuffer::new(3, 3);
+        blit(&mut dst, 2, 1, &src, Rect::new(1, 1, 10, 10));
+        assert_eq!(dst.buf, [0, 0, 0, 0, 0, 6, 0, 0, 10]);
+        let mut dst = BitmapBuffer::new(3, 3);
+        blit(&mut dst, 0, 0, &src, Rect::new(5, 5, 2, 2));
+        assert!(dst.buf.iter().all(|p| *p == 0));
+    }
+
+    #[test_case]
+    fn draw_24bit_bmp() {
+        // 2x2, 24bit, 下の行から並ぶ (各行は6バイト + パディング2バイト)
+        let mut bmp = vec![0u8; 54];
+        bmp[0..2].copy_from_slice(b"BM");
+        bmp[10] = 54;
+        bmp[14] = 40;
+        bmp[18] = 2;
+        bmp[22] = 2;
+        bmp[26] = 1;
+        bmp[28] = 24;
+        // 下の行: 青, 緑 / 上の行: 赤, 白
+        bmp.extend_from_slice(&[0xff, 0, 0, 0, 0xff, 0, 0, 0]);
+        bmp.extend_from_slice(&[0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0]);
+        let mut dst = BitmapBuffer::new(3, 3);
+        draw_bmp(&mut dst, &bmp, 1, 1).unwrap();
+        assert_eq!(
+            dst.buf,
+            [0, 0, 0, 0, 0xff0000, 0xffffff, 0, 0x0000ff, 0x00ff00]
+        );
+        assert!(draw_bmp(&mut dst, &bmp[..60], 0, 0).is_err());
+    }
+
+    #[test_case]
+    fn encode_bmp_round_trip() {
+        let mut src = BitmapBuffer::new(3, 2);
+        for (i, p) in src.pixels_mut().iter_mut().enumerate() {
+            *p = 0x010203 * (i as u32 + 1);
+        }
+        let bmp = encode_bmp(&src);
+        let mut dst = BitmapBuffer::new(3, 2);
+        draw_bmp(&mut dst, &bmp, 0, 0).unwrap();
+        assert_eq!(dst.pixels(), src.pixels());
+        assert!(encode_ppm(&src).starts_with(b"P6\n3 2\n255\n"));
+    }
+
+    #[test_case]
+    fn bmp_bitfields_masks() {
+        // 1x1, 32bit, RGB565を32ビットに入れたもの
+        let bmp = |masks: [u32; 3], pixel: u32| {
+            let mut bmp = vec![0u8; 66];
+            bmp[0..2].copy_from_slice(b"BM");
+            bmp[10] = 66;
+            bmp[14] = 40;
+            bmp[18] = 1;
+            bmp[22] = 1;
+            bmp[26] = 1;
+            bmp[28] = 32;
+            bmp[30] = BMP_COMPRESSION_BITFIELDS as u8;
+            for (i, m) in masks.iter().enumerate() {
+                bmp[54 + i * 4..58 + i * 4].copy_from_slice(&m.to_le_bytes());
+            }
+            bmp.extend_from_slice(&pixel.to_le_bytes());
+            bmp
+        };
+        let image = bmp([0xf800, 0x07e0, 0x001f], 0xf81f);
+        let image = BmpImage::parse(&image).unwrap();
+        assert_eq!(image.pixel(0, 0), Some(0xff00ff));
+        assert!(BmpImage::parse(&bmp([0, 0x07e0, 0x001f], 0)).is_err());
+        assert!(BmpImage::parse(&bmp([0xf0f0, 0x0f00, 0x000f], 0)).is_err());
+        // 画素データの終わりが溢れるオフセット
+        let mut huge = bmp([0xff0000, 0xff00, 0xff], 0);
+        huge[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(BmpImage::parse(&huge).is_err());
+    }
+}
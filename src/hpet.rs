@@ -3,18 +3,30 @@ use core::ptr::read_volatile;
 use core::ptr::write_volatile;
 use core::time::Duration;
 
-use crate::mutex::Mutex;
+use crate::apic::route_gsi;
+use crate::apic::Polarity;
+use crate::apic::TriggerMode;
+use crate::once::Once;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::InterruptHandler;
 
 const TIMER_CONFIG_LEVEL_TRIGGER: u64 = 1 << 1;
 const TIMER_CONFIG_ENABLE: u64 = 1 << 2;
 const TIMER_CONFIG_PERIODIC: u64 = 1 << 3;
+const TIMER_CONFIG_INT_ROUTE_SHIFT: u64 = 9;
+const TIMER_CONFIG_INT_ROUTE_MASK: u64 = 0b11111 << TIMER_CONFIG_INT_ROUTE_SHIFT;
+// 時間が来たら1回だけ割り込みを上げるのに使うタイマ
+const ALARM_TIMER: usize = 0;
 
 #[repr(C)]
 struct TimerRegister {
     // 2.3.8
     // Timer N Configuration and Capabilities Register
     configuration_and_capabilities: u64,
-    _reserved: [u64; 3],
+    // 2.3.9 Timer N Comparator Value Register
+    comparator_value: u64,
+    _reserved: [u64; 2],
 }
 const _: () = assert!(size_of::<TimerRegister>() == 0x20);
 impl TimerRegister {
@@ -41,35 +53,50 @@ pub struct HpetRegisters {
 const _: () = assert!(size_of::<HpetRegisters>() == 0x500);
 
 pub struct Hpet {
-    registers: &'static mut HpetRegisters,
+    registers: *mut HpetRegisters,
     #[allow(unused)]
     num_of_timers: usize,
     frequency: u64,
 }
-static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
-pub fn set_global_hpet(hpet: Hpet) {
-    assert!(HPET.lock().is_none());
-    *HPET.lock() = Some(hpet);
+unsafe impl Send for Hpet {}
+// レジスタは1回のvolatileな読み書きで触るだけなので、割り込みの中と外から同時に使ってよい
+unsafe impl Sync for Hpet {}
+static HPET: Once<Hpet> = Once::new();
+pub fn set_global_hpet(hpet: Hpet) -> Result<()> {
+    HPET.set(hpet).map_err(|_| "HPET is already initialized")
 }
 pub fn global_timestamp() -> Duration {
-    if let Some(hpet) = &*HPET.lock() {
+    if let Some(hpet) = HPET.get() {
         let ns = hpet.main_counter() * 1_000_000_000 / hpet.freq();
         Duration::from_nanos(ns)
     } else {
         Duration::ZERO
     }
 }
+// タイマ割り込みを使わずに待つ (HPETの初期化前は待たずに戻る)
+pub fn busy_wait(duration: Duration) {
+    let start = global_timestamp();
+    if start == Duration::ZERO {
+        return;
+    }
+    while global_timestamp() - start < duration {
+        busy_loop_hint();
+    }
+}
 impl Hpet {
     unsafe fn globally_disable(&mut self) {
-        let config = read_volatile(&self.registers.configuration) & !0b11;
-        write_volatile(&mut self.registers.configuration, config);
+        let config = read_volatile(&(*self.registers).configuration) & !0b11;
+        write_volatile(&mut (*self.registers).configuration, config);
     }
     unsafe fn globally_enable(&mut self) {
-        let config = read_volatile(&self.registers.configuration) | 0b01;
-        write_volatile(&mut self.registers.configuration, config);
+        let config = read_volatile(&(*self.registers).configuration) | 0b01;
+        write_volatile(&mut (*self.registers).configuration, config);
     }
     pub fn main_counter(&self) -> u64 {
-        unsafe { read_volatile(&self.registers.main_counter_value) }
+        unsafe { read_volatile(&(*self.registers).main_counter_value) }
+    }
+    fn timer(&self, index: usize) -> *mut TimerRegister {
+        unsafe { &mut (*self.registers).timers[index] }
     }
     pub fn freq(&self) -> u64 {
         self.frequency
@@ -79,24 +106,69 @@ impl Hpet {
         let num_of_timers = ((registers.capabilites_and_id >> 8) & 0b11111) as usize + 1;
         let frequency = 1_000_000_000_000_000 / counter_clk_period;
         let mut hpet = Self {
-            registers,
+            registers: registers as *mut HpetRegisters,
             num_of_timers,
             frequency,
         };
         unsafe {
             hpet.globally_disable();
             for i in 0..hpet.num_of_timers {
-                let timer = &mut hpet.registers.timers[i];
+                let timer = &mut *hpet.timer(i);
                 let mut config = read_volatile(&timer.configuration_and_capabilities);
                 config &= !(TIMER_CONFIG_ENABLE
                     | TIMER_CONFIG_LEVEL_TRIGGER
                     | TIMER_CONFIG_PERIODIC
-                    | (0b1111 << 9));
+                    | TIMER_CONFIG_INT_ROUTE_MASK);
                 timer.write_config(config);
             }
-            write_volatile(&mut hpet.registers.main_counter_value, 0);
+            write_volatile(&mut (*hpet.registers).main_counter_value, 0);
             hpet.globally_enable();
         }
         hpet
     }
 }
+
+// アラーム用のタイマをIOAPICにつないで、割り込みをhandlerに届ける。まだ動かさない
+pub fn init_hpet_alarm(handler: InterruptHandler) -> Result<u8> {
+    let hpet = HPET.get().ok_or("HPET is not initialized")?;
+    let timer = unsafe { &mut *hpet.timer(ALARM_TIMER) };
+    let config = unsafe { read_volatile(&timer.configuration_and_capabilities) };
+    // 上位32ビットは、このタイマをつなげるIOAPICの入力。ISAのIRQと重ならない大きい番号から試す
+    let routes = (config >> 32) as u32;
+    let (gsi, vector) = (0..32)
+        .rev()
+        .filter(|gsi| routes & (1 << gsi) != 0)
+        .find_map(|gsi| {
+            route_gsi(gsi, TriggerMode::Edge, Polarity::ActiveHigh, handler)
+                .ok()
+                .map(|vector| (gsi, vector))
+        })
+        .ok_or("No IOAPIC input is available for the HPET timer")?;
+    let config = config
+        & !(TIMER_CONFIG_ENABLE
+            | TIMER_CONFIG_LEVEL_TRIGGER
+            | TIMER_CONFIG_PERIODIC
+            | TIMER_CONFIG_INT_ROUTE_MASK);
+    unsafe { timer.write_config(config | (gsi as u64) << TIMER_CONFIG_INT_ROUTE_SHIFT) };
+    Ok(vector)
+}
+
+// afterが経ったら1回だけ割り込みを上げる。Noneなら止める
+pub fn set_hpet_alarm(after: Option<Duration>) {
+    let Some(hpet) = HPET.get() else {
+        return;
+    };
+    let timer = unsafe { &mut *hpet.timer(ALARM_TIMER) };
+    unsafe {
+        let config = read_volatile(&timer.configuration_and_capabilities) & !TIMER_CONFIG_ENABLE;
+        timer.write_config(config);
+        if let Some(after) = after {
+            let ticks = after.as_nanos() * hpet.freq() as u128 / 1_000_000_000;
+            let deadline = hpet
+                .main_counter()
+                .saturating_add(ticks.min(u64::MAX as u128) as u64);
+            write_volatile(&mut timer.comparator_value, deadline);
+            timer.write_config(config | TIMER_CONFIG_ENABLE);
+        }
+    }
+}
@@ -2,22 +2,41 @@ extern crate alloc;
 use alloc::boxed::Box;
 
 use crate::acpi::AcpiRsdp;
+use crate::apic::init_io_apic;
+use crate::apic::init_local_apic;
+use crate::e1000::E1000_DRIVER;
+use crate::error;
 use crate::graphics::draw_test_pattern;
 use crate::graphics::fill_rect;
 use crate::graphics::Bitmap;
 use crate::hpet::set_global_hpet;
 use crate::hpet::Hpet;
 use crate::info;
+use crate::nvme::NVME_DRIVER;
+use crate::pci::dump_devices;
+use crate::pci::init_config_access;
+use crate::pci::probe_devices;
+use crate::pci::register_driver;
+use crate::pci::scan_devices;
+use crate::rtl8139::RTL8139_DRIVER;
 use crate::uefi::EfiMemoryType;
 use crate::uefi::VramBufferInfo;
+use crate::virtio_console::VIRTIO_CONSOLE_DRIVER;
+use crate::virtio_gpu::VIRTIO_GPU_DRIVER;
+use crate::virtio_net::VIRTIO_NET_DRIVER;
+use crate::virtio_rng::VIRTIO_RNG_DRIVER;
+use crate::wall_clock::seed_wall_clock;
+use crate::wall_clock::DateTime;
 use crate::x86::write_cr3;
 use crate::x86::PageAttr;
 use core::cmp::max;
+use core::time::Duration;
 
 use crate::allocator::ALLOCATOR;
 use crate::uefi::exit_from_efi_boot_services;
 use crate::uefi::EfiHandle;
 use crate::uefi::EfiSystemTable;
+use crate::uefi::EfiTime;
 use crate::uefi::MemoryMapHolder;
 use crate::x86::PAGE_SIZE;
 use crate::x86::PML4;
@@ -32,6 +51,41 @@ pub fn init_basic_runtime(
     memory_map
 }
 
+// ブートサービスの終了前に呼ぶ
+pub fn init_wall_clock(efi_system_table: &EfiSystemTable) {
+    let t = match efi_system_table.runtime_services().get_time() {
+        Ok(t) => t,
+        Err(e) => {
+            info!("Wall clock is not available: {e}");
+            return;
+        }
+    };
+    let dt = DateTime {
+        year: t.year,
+        month: t.month,
+        day: t.day,
+        hour: t.hour,
+        minute: t.minute,
+        second: t.second,
+        nanosecond: t.nanosecond,
+    };
+    let Some(mut unix_time) = dt.to_unix_time() else {
+        info!("Wall clock is not available: invalid time {dt}");
+        return;
+    };
+    // Localtime = UTC + TimeZone
+    if t.time_zone != EfiTime::UNSPECIFIED_TIMEZONE {
+        let offset = Duration::from_secs(t.time_zone.unsigned_abs() as u64 * 60);
+        unix_time = if t.time_zone > 0 {
+            unix_time.saturating_sub(offset)
+        } else {
+            unix_time + offset
+        };
+    }
+    seed_wall_clock(unix_time);
+    info!("Wall clock: {} UTC", DateTime::from_unix_time(unix_time));
+}
+
 pub fn init_paging(memory_map: &MemoryMapHolder) {
     let mut table = PML4::new();
     let mut end_of_mem = 0x1_0000_0000u64;
@@ -66,7 +120,9 @@ pub fn init_hpet(acpi: &AcpiRsdp) {
         .expect("Failed to get HPET base address");
     info!("HPET is at {hpet:#p}");
     let hpet = Hpet::new(hpet);
-    set_global_hpet(hpet);
+    if let Err(e) = set_global_hpet(hpet) {
+        error!("{e}");
+    }
 }
 
 pub fn init_allocator(memory_map: &MemoryMapHolder) {
@@ -89,3 +145,22 @@ pub fn init_display(vram: &mut VramBufferInfo) {
     fill_rect(vram, 0x000000, 0, 0, vw, vh).expect("fill_rect failed");
     draw_test_pattern(vram);
 }
+
+pub fn init_apic(acpi: &AcpiRsdp) {
+    init_local_apic();
+    init_io_apic(acpi);
+}
+
+pub fn init_pci(acpi: &AcpiRsdp) {
+    init_config_access(acpi);
+    register_driver(&VIRTIO_NET_DRIVER);
+    register_driver(&NVME_DRIVER);
+    register_driver(&E1000_DRIVER);
+    register_driver(&RTL8139_DRIVER);
+    register_driver(&VIRTIO_GPU_DRIVER);
+    register_driver(&VIRTIO_RNG_DRIVER);
+    register_driver(&VIRTIO_CONSOLE_DRIVER);
+    scan_devices();
+    dump_devices();
+    probe_devices();
+}
@@ -0,0 +1,117 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fs::mount;
+use crate::fw_cfg::read_fw_cfg_file;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::uefi::fs::EfiFileSystem;
+use crate::uefi::EfiHandle;
+use crate::uefi::EfiSystemTable;
+use crate::ustar::UstarFs;
+
+const INITRAMFS_ESP_PATH: &str = "/initramfs.tar";
+const INITRAMFS_FW_CFG_NAME: &str = "opt/wasabi/initramfs";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InitramfsFormat {
+    Ustar,
+    // "070701" (newc)
+    Cpio,
+    Unknown,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Initramfs {
+    data: &'static [u8],
+}
+
+impl Initramfs {
+    pub fn new(data: &'static [u8]) -> Self {
+        Self { data }
+    }
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+    // 物理アドレスの範囲 [start, end)
+    pub fn phys_range(&self) -> (u64, u64) {
+        let start = self.data.as_ptr() as u64;
+        (start, start + self.data.len() as u64)
+    }
+    pub fn format(&self) -> InitramfsFormat {
+        if self.data.get(257..262) == Some(b"ustar") {
+            InitramfsFormat::Ustar
+        } else if self.data.starts_with(b"070701") {
+            InitramfsFormat::Cpio
+        } else {
+            InitramfsFormat::Unknown
+        }
+    }
+}
+
+static INITRAMFS: Mutex<Option<Initramfs>> = Mutex::new(None);
+
+fn set_initramfs(initramfs: Initramfs) {
+    let (start, end) = initramfs.phys_range();
+    info!(
+        "initramfs: {:?} image at [{start:#X}, {end:#X}) ({} bytes)",
+        initramfs.format(),
+        end - start
+    );
+    *INITRAMFS.lock() = Some(initramfs);
+}
+
+// ブートサービス終了前に呼ぶ。LOADER_DATAに読み込むのでアロケータには再利用されない
+pub fn load_initramfs_from_esp(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+    let data = EfiFileSystem::open_boot_volume(image_handle, efi_system_table)
+        .and_then(|fs| fs.read_file(INITRAMFS_ESP_PATH));
+    match data {
+        Ok(data) => set_initramfs(Initramfs::new(data)),
+        Err(e) => {
+            info!("initramfs: not loaded from ESP: {e}");
+        }
+    }
+}
+
+// ESPに無ければ -fw_cfg name=opt/wasabi/initramfs,file=... で渡されたものを使う
+pub fn load_initramfs_from_fw_cfg() {
+    if INITRAMFS.lock().is_some() {
+        return;
+    }
+    match read_fw_cfg_file(INITRAMFS_FW_CFG_NAME) {
+        Ok(data) => set_initramfs(Initramfs::new(Vec::leak(data))),
+        Err(e) => {
+            info!("initramfs: not loaded from fw_cfg: {e}");
+        }
+    }
+}
+
+pub fn initramfs() -> Option<Initramfs> {
+    *INITRAMFS.lock()
+}
+
+// ustarのinitramfsがあればルートにマウントする
+pub fn mount_initramfs() {
+    let Some(initramfs) = initramfs() else {
+        return;
+    };
+    if initramfs.format() != InitramfsFormat::Ustar {
+        info!(
+            "initramfs: {:?} format is not supported",
+            initramfs.format()
+        );
+        return;
+    }
+    match UstarFs::new(initramfs.data()) {
+        Ok(fs) => {
+            if let Err(e) = mount("/", Arc::new(fs)) {
+                info!("initramfs: failed to mount: {e}");
+            }
+        }
+        Err(e) => {
+            info!("initramfs: failed to parse: {e}");
+        }
+    }
+}
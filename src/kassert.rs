@@ -0,0 +1,103 @@
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use crate::print::log;
+use crate::print::LogLevel;
+use crate::x86::print_current_registers;
+
+// kassert!が失敗した時にもレジスタを出すか。bug!は常に出す
+static DUMP_REGISTERS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dump_registers_on_failure(enabled: bool) {
+    DUMP_REGISTERS.store(enabled, Ordering::SeqCst);
+}
+
+// テストの時は専用の終了コードでQEMUを終わらせ、何が失敗したのかを区別できるようにする
+// それ以外の時は普通にパニックする
+#[cold]
+pub fn fail(kind: &str, file: &str, line: u32, args: fmt::Arguments, dump_registers: bool) -> ! {
+    log(
+        LogLevel::Error,
+        module_path!(),
+        file,
+        line,
+        format_args!("{kind}: {args}"),
+    );
+    if dump_registers || DUMP_REGISTERS.load(Ordering::SeqCst) {
+        print_current_registers();
+    }
+    #[cfg(test)]
+    {
+        crate::backtrace::print_backtrace();
+        crate::qemu::exit_qemu(crate::qemu::QemuExitCode::AssertionFailed)
+    }
+    #[cfg(not(test))]
+    {
+        // バックトレースはパニックハンドラが出す
+        panic!("{kind} at {file}:{line}")
+    }
+}
+
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kassert::fail(
+                "Assertion failed",
+                file!(),
+                line!(),
+                format_args!($($arg)+),
+                false,
+            )
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::kassert_eq!($left, $right, "{} == {}", stringify!($left), stringify!($right))
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::kassert::fail(
+                        "Assertion failed",
+                        file!(),
+                        line!(),
+                        format_args!("{}\n  left: {:?}\n right: {:?}", format_args!($($arg)+), left, right),
+                        false,
+                    )
+                }
+            }
+        }
+    };
+}
+
+// 起きてはいけない状態に来た。常にレジスタも出す
+#[macro_export]
+macro_rules! bug {
+    () => {
+        $crate::bug!("unreachable state")
+    };
+    ($($arg:tt)+) => {
+        $crate::kassert::fail("BUG", file!(), line!(), format_args!($($arg)+), true)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    #[test_case]
+    fn passing_assertions_do_nothing() {
+        let v = 40 + 2;
+        crate::kassert!(v == 42);
+        crate::kassert!(v > 0, "v = {v}");
+        crate::kassert_eq!(v, 42);
+        crate::kassert_eq!(v, 42, "v should be the answer");
+    }
+}
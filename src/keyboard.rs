@@ -0,0 +1,118 @@
+use crate::executor::AtomicWaker;
+use crate::mpsc::MpscQueue;
+use crate::task::WaitQueue;
+use core::future::poll_fn;
+use core::task::Poll;
+
+// キーボードのドライバ (USB HIDなど) はここにイベントを積み、シェルなどが取り出す
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyCode {
+    Char(char),
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    Delete,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    CapsLock,
+    F(u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const CTRL: u8 = 1 << 0;
+    pub const SHIFT: u8 = 1 << 1;
+    pub const ALT: u8 = 1 << 2;
+    pub const META: u8 = 1 << 3;
+
+    pub const fn new(bits: u8) -> Self {
+        Self(bits)
+    }
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+    pub fn ctrl(&self) -> bool {
+        self.0 & Self::CTRL != 0
+    }
+    pub fn shift(&self) -> bool {
+        self.0 & Self::SHIFT != 0
+    }
+    pub fn alt(&self) -> bool {
+        self.0 & Self::ALT != 0
+    }
+    pub fn meta(&self) -> bool {
+        self.0 & Self::META != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+}
+
+const KEY_EVENT_QUEUE_SIZE: usize = 64;
+
+static KEY_EVENTS: MpscQueue<KeyEvent, KEY_EVENT_QUEUE_SIZE> = MpscQueue::new();
+static KEY_WAKER: AtomicWaker = AtomicWaker::new();
+static KEY_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+// WaitQueueを起こすので、割り込みハンドラからは呼ばないこと
+pub fn push_key_event(e: KeyEvent) {
+    // 溢れたら捨てる
+    let _ = KEY_EVENTS.push(e);
+    KEY_WAKER.wake();
+    KEY_WAIT_QUEUE.notify_all();
+}
+
+pub fn pop_key_event() -> Option<KeyEvent> {
+    KEY_EVENTS.pop()
+}
+
+// キーイベントが来るまで今のタスクを止める
+pub fn wait_for_key_event() -> KeyEvent {
+    KEY_WAIT_QUEUE.wait_until(pop_key_event)
+}
+
+// キーイベントが来るまで待つ
+pub async fn next_key_event() -> KeyEvent {
+    poll_fn(|cx| {
+        if let Some(e) = pop_key_event() {
+            return Poll::Ready(e);
+        }
+        KEY_WAKER.register(cx.waker());
+        // 登録する前に積まれたイベントを取りこぼさないように、もう一度見る
+        match pop_key_event() {
+            Some(e) => Poll::Ready(e),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+// 押されたキーのうち文字になるものだけを取り出す
+pub fn read_char() -> Option<char> {
+    while let Some(e) = pop_key_event() {
+        if !e.pressed {
+            continue;
+        }
+        match e.code {
+            KeyCode::Char(c) => return Some(c),
+            KeyCode::Enter => return Some('\n'),
+            KeyCode::Tab => return Some('\t'),
+            KeyCode::Backspace => return Some('\x08'),
+            _ => {}
+        }
+    }
+    None
+}
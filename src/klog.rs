@@ -0,0 +1,133 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::mutex::SpinLockIrqSave;
+use crate::print::LogRecord;
+use crate::print::LogSink;
+
+// 溢れたら古い方から上書きする
+const KLOG_SIZE: usize = 64 * 1024;
+
+struct KlogRing {
+    buf: [u8; KLOG_SIZE],
+    // 次に書く位置と、入っているバイト数
+    write: usize,
+    len: usize,
+}
+
+impl KlogRing {
+    fn push(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.buf[self.write] = *b;
+            self.write = (self.write + 1) % KLOG_SIZE;
+        }
+        self.len = (self.len + bytes.len()).min(KLOG_SIZE);
+    }
+    // 古い方から順に、2つに分かれた中身を返す
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        let start = (self.write + KLOG_SIZE - self.len) % KLOG_SIZE;
+        if start + self.len <= KLOG_SIZE {
+            (&self.buf[start..start + self.len], &[])
+        } else {
+            (&self.buf[start..], &self.buf[..self.write])
+        }
+    }
+}
+
+impl fmt::Write for KlogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+// アロケータもコンソールも無いうちから書けるように、静的な領域に持つ
+static KLOG: SpinLockIrqSave<KlogRing> = SpinLockIrqSave::new(KlogRing {
+    buf: [0; KLOG_SIZE],
+    write: 0,
+    len: 0,
+});
+
+pub struct KlogSink;
+
+impl LogSink for KlogSink {
+    fn name(&self) -> &'static str {
+        "klog"
+    }
+    // 割り込みを止めて取るので、取れないのはこの中でログを出した時だけ。その分は捨てる
+    fn write(&self, args: fmt::Arguments) {
+        if let Some(mut klog) = KLOG.try_lock() {
+            let _ = fmt::write(&mut *klog, args);
+        }
+    }
+    // 後で読むためのものなので、色は付けない
+    fn write_log(&self, record: &LogRecord) {
+        self.write(format_args!(
+            "{} [{}] {}:{:<3}: {}\n",
+            record.timestamp,
+            record.level.tag(),
+            record.file,
+            record.line,
+            record.args
+        ))
+    }
+    fn is_panic_safe(&self) -> bool {
+        true
+    }
+}
+
+pub static KLOG_SINK: KlogSink = KlogSink;
+
+// これまでのログを全部返す (dmesg)。溢れて消えた分は含まない
+pub fn read() -> String {
+    let klog = KLOG.lock();
+    let (a, b) = klog.as_slices();
+    let mut bytes = Vec::with_capacity(a.len() + b.len());
+    bytes.extend_from_slice(a);
+    bytes.extend_from_slice(b);
+    drop(klog);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// アロケータを使わずに、新しい方からbufに入るだけコピーする。クラッシュダンプ用
+pub fn read_tail(buf: &mut [u8]) -> usize {
+    let Some(klog) = KLOG.try_lock() else {
+        return 0;
+    };
+    let (a, b) = klog.as_slices();
+    let n = buf.len().min(a.len() + b.len());
+    let skip = a.len() + b.len() - n;
+    for (dst, src) in buf.iter_mut().zip(a.iter().chain(b.iter()).skip(skip)) {
+        *dst = *src;
+    }
+    n
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::alloc::alloc_zeroed;
+    use alloc::alloc::Layout;
+    use alloc::boxed::Box;
+
+    #[test_case]
+    fn ring_keeps_newest_bytes() {
+        // テストはUEFIのスタックで動くので、64KiBのリングはヒープに置く。全部0なら空のリング
+        let layout = Layout::new::<KlogRing>();
+        let ring = unsafe { alloc_zeroed(layout) } as *mut KlogRing;
+        assert!(!ring.is_null());
+        let mut ring = unsafe { Box::from_raw(ring) };
+        ring.push(b"hello");
+        assert_eq!(ring.as_slices(), (&b"hello"[..], &b""[..]));
+        for _ in 0..(KLOG_SIZE - 2) / 2 {
+            ring.push(b"xx");
+        }
+        let (a, b) = ring.as_slices();
+        assert_eq!(a.len() + b.len(), KLOG_SIZE);
+        assert_eq!(&a[..3], b"lox");
+        assert_eq!(b, b"xxx");
+    }
+}
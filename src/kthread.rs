@@ -0,0 +1,74 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::mutex::Mutex;
+use crate::task::spawn_named;
+use crate::task::task_list;
+use crate::task::Priority;
+use crate::task::TaskId;
+use crate::task::TaskInfo;
+use crate::task::WaitQueue;
+
+// スレッドの戻り値を、joinする側に渡すための場所
+struct JoinState<T> {
+    result: Mutex<Option<T>>,
+    exited: WaitQueue,
+}
+
+pub struct JoinHandle<T> {
+    id: TaskId,
+    name: String,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn is_finished(&self) -> bool {
+        self.state.result.lock().is_some()
+    }
+    // スレッドが終わるまで待って、戻り値を受け取る
+    pub fn join(self) -> T {
+        self.state
+            .exited
+            .wait_until(|| self.state.result.lock().take())
+    }
+}
+
+pub fn spawn<T: 'static>(name: &str, f: impl FnOnce() -> T + 'static) -> JoinHandle<T> {
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+pub fn spawn_with_priority<T: 'static>(
+    name: &str,
+    priority: Priority,
+    f: impl FnOnce() -> T + 'static,
+) -> JoinHandle<T> {
+    let state = Arc::new(JoinState {
+        result: Mutex::new(None),
+        exited: WaitQueue::new(),
+    });
+    let thread_state = state.clone();
+    let id = spawn_named(name, priority, move || {
+        let result = f();
+        *thread_state.result.lock() = Some(result);
+        thread_state.exited.notify_all();
+    });
+    JoinHandle {
+        id,
+        name: String::from(name),
+        state,
+    }
+}
+
+// psコマンドなどで使う、動いているスレッドの一覧
+pub fn threads() -> Vec<TaskInfo> {
+    task_list()
+}
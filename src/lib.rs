@@ -9,16 +9,69 @@
 #![no_main]
 pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod bench;
+pub mod block;
+pub mod block_cache;
+pub mod boot;
+pub mod condvar;
+pub mod crash;
+pub mod debugcon;
+pub mod dma;
+pub mod e1000;
+pub mod elf;
 pub mod executor;
+pub mod fat32;
+pub mod fd;
+pub mod fs;
+pub mod fw_cfg;
 pub mod graphics;
 pub mod hpet;
 pub mod init;
+pub mod initramfs;
+pub mod kassert;
+pub mod keyboard;
+pub mod klog;
+pub mod kthread;
+pub mod monitor;
+pub mod mpsc;
 pub mod mutex;
+pub mod net;
+pub mod nvme;
+pub mod once;
+pub mod pci;
+pub mod pipe;
 pub mod print;
+pub mod process;
+pub mod ps2_mouse;
 pub mod qemu;
+pub mod ramfs;
+pub mod rand;
 pub mod result;
+pub mod rtl8139;
+pub mod scenario;
+pub mod selftest;
 pub mod serial;
+pub mod shell;
+pub mod smbios;
+pub mod speaker;
+pub mod syscall;
+pub mod task;
 pub mod uefi;
+pub mod usb;
+pub mod usb_hid;
+pub mod usb_hub;
+pub mod usb_storage;
+pub mod ustar;
+pub mod virtio;
+pub mod virtio_console;
+pub mod virtio_gpu;
+pub mod virtio_net;
+pub mod virtio_rng;
+pub mod wall_clock;
+pub mod watchdog;
+pub mod window;
 pub mod x86;
 
 #[cfg(test)]
@@ -27,6 +80,15 @@ pub mod test_runner;
 #[cfg(test)]
 #[no_mangle]
 fn efi_main(image_handle: uefi::EfiHandle, efi_system_table: &uefi::EfiSystemTable) {
-    init::init_basic_runtime(image_handle, efi_system_table);
+    let acpi = efi_system_table.acpi_table().expect("ACPI table not found");
+    boot::init_cmdline(image_handle, efi_system_table);
+    let memory_map = init::init_basic_runtime(image_handle, efi_system_table);
+    // 割り込みを使うテストのために、タイマが動かせるところまで初期化する
+    let (_gdt, _idt) = x86::init_exceptions();
+    init::init_paging(&memory_map);
+    init::init_hpet(acpi);
+    init::init_apic(acpi);
+    fw_cfg::init_fw_cfg();
+    test_runner::load_test_config();
     run_unit_tsets();
 }
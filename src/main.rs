@@ -2,38 +2,99 @@
 #![no_main]
 use core::panic::PanicInfo;
 use core::time::Duration;
+use wasabi::acpi::set_global_rsdp;
+use wasabi::boot::cmdline;
+use wasabi::boot::init_cmdline;
+use wasabi::crash::on_panic;
+use wasabi::crash::set_panic_action;
+use wasabi::crash::PanicAction;
 use wasabi::error;
 use wasabi::executor::Executor;
 use wasabi::executor::Task;
 use wasabi::executor::TimeoutFuture;
+use wasabi::fat32::mount_fat32_devices;
+use wasabi::fw_cfg::init_fw_cfg;
 use wasabi::hpet::global_timestamp;
 use wasabi::info;
 use wasabi::init::init_allocator;
+use wasabi::init::init_apic;
 use wasabi::init::init_basic_runtime;
 use wasabi::init::init_display;
 use wasabi::init::init_hpet;
 use wasabi::init::init_paging;
+use wasabi::init::init_pci;
+use wasabi::init::init_wall_clock;
+use wasabi::initramfs::load_initramfs_from_esp;
+use wasabi::initramfs::load_initramfs_from_fw_cfg;
+use wasabi::initramfs::mount_initramfs;
+use wasabi::kassert::set_dump_registers_on_failure;
+use wasabi::kthread;
+use wasabi::monitor::set_monitor_enabled;
+use wasabi::net::interface;
+use wasabi::net::start_network;
+use wasabi::net::udp::start_echo_server;
+use wasabi::net::Ipv4Config;
+use wasabi::net::QEMU_USER_NET_CONFIG;
+use wasabi::print::configure_log_levels;
 use wasabi::print::hexdump;
 use wasabi::print::set_global_vram;
 use wasabi::println;
-use wasabi::qemu::exit_qemu;
+use wasabi::process::wait;
+use wasabi::process::Process;
+use wasabi::ramfs::init_ramfs;
+use wasabi::scenario::run_scenario;
+use wasabi::scenario::selected_scenario;
+use wasabi::selftest;
+use wasabi::serial::SerialPort;
+use wasabi::shell::start_shell;
+use wasabi::smbios::init_smbios;
+use wasabi::smbios::system_info;
+use wasabi::syscall::init_syscall;
+use wasabi::task::init_task;
+use wasabi::task::run_idle;
+use wasabi::task::start_preemption;
 use wasabi::uefi::init_vram;
+use wasabi::uefi::vars::BootReport;
 
 use wasabi::uefi::locate_loaded_image_protocol;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiSystemTable;
+use wasabi::virtio_console::set_virtio_console_log_output;
 use wasabi::warn;
-use wasabi::x86::hlt;
+use wasabi::watchdog::enable_watchdog;
+use wasabi::window::start_desktop;
 use wasabi::x86::init_exceptions;
 
+// ip=10.0.2.15/24 gateway=10.0.2.2 のように指定する。無ければQEMUのユーザモードネットワークに合わせる
+fn configure_network() {
+    let Some(eth0) = interface("eth0") else {
+        return;
+    };
+    let config = match cmdline().get("ip") {
+        Some(ip) => match Ipv4Config::parse(ip, cmdline().get("gateway")) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Invalid ip={ip}: {e}");
+                return;
+            }
+        },
+        None => QEMU_USER_NET_CONFIG,
+    };
+    eth0.set_ipv4_config(config);
+}
+
+// これだけの間タスクが切り替わらなければ、止まっているとみなす
+const DEFAULT_WATCHDOG_SECS: u64 = 10;
+
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    exit_qemu(wasabi::qemu::QemuExitCode::Fail)
+fn panic(info: &PanicInfo) -> ! {
+    on_panic(info)
 }
 
 // https://uefi.org/specs/UEFI/2.11/04_EFI_System_Table.html#efi-image-entry-point
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+    SerialPort::new_for_com1().init();
     println!("Booting WasabiOS...");
     println!("image_handle: {:#018X}", image_handle);
     println!("efi_system_table: {:#p}", efi_system_table);
@@ -48,16 +109,114 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     let mut vram = init_vram(efi_system_table).expect("init_vram failed");
 
     init_display(&mut vram);
-    set_global_vram(vram);
+    if let Err(e) = set_global_vram(vram) {
+        error!("{e}");
+    }
     let acpi = efi_system_table.acpi_table().expect("ACPI table not found");
+    if let Err(e) = set_global_rsdp(acpi) {
+        error!("{e}");
+    }
+    init_cmdline(image_handle, efi_system_table);
+    set_monitor_enabled(cmdline().has_flag("monitor"));
+    if cmdline().has_flag("kassert_regs") {
+        set_dump_registers_on_failure(true);
+    }
+    if let Some(action) = cmdline().get("panic") {
+        match PanicAction::parse(action) {
+            Ok(action) => set_panic_action(action),
+            Err(e) => error!("Invalid panic={action}: {e}"),
+        }
+    }
+    load_initramfs_from_esp(image_handle, efi_system_table);
+    init_wall_clock(efi_system_table);
+    info!("{}", BootReport::collect(efi_system_table));
+    init_smbios(efi_system_table);
 
     let memory_map = init_basic_runtime(image_handle, efi_system_table);
+    // モジュールごとの設定にはアロケータを使うので、ここで設定する
+    if let Some(spec) = cmdline().get("loglevel") {
+        if let Err(e) = configure_log_levels(spec) {
+            error!("Invalid loglevel={spec}: {e}");
+        }
+    }
     info!("Hello, Non-UEFI world!");
     init_allocator(&memory_map);
+    if let Some(system_info) = system_info() {
+        info!("{system_info}");
+    }
 
     let (_gdt, _idt) = init_exceptions();
+    init_syscall();
     init_paging(&memory_map);
+    init_task();
     init_hpet(acpi);
+    init_apic(acpi);
+    start_preemption();
+    // watchdog=0 で無効にできる
+    let watchdog_secs = match cmdline().get("watchdog") {
+        Some(secs) => secs.parse().unwrap_or_else(|_| {
+            error!("Invalid watchdog={secs}");
+            DEFAULT_WATCHDOG_SECS
+        }),
+        None => DEFAULT_WATCHDOG_SECS,
+    };
+    if watchdog_secs != 0 {
+        enable_watchdog(Duration::from_secs(watchdog_secs));
+    }
+    init_pci(acpi);
+    // virtio_console を指定すると、ログをvirtio-consoleにも出す (入力はいつでも受け付ける)
+    if cmdline().has_flag("virtio_console") {
+        set_virtio_console_log_output(true);
+    }
+    configure_network();
+    start_network();
+    // udp_echo=7 のように指定すると、そのポートでエコーサーバを動かす
+    if let Some(port) = cmdline().get("udp_echo") {
+        match port.parse() {
+            Ok(port) => {
+                if let Err(e) = start_echo_server(port) {
+                    error!("Failed to start the UDP echo server: {e}");
+                }
+            }
+            Err(_) => error!("Invalid udp_echo={port}"),
+        }
+    }
+    init_fw_cfg();
+    load_initramfs_from_fw_cfg();
+    mount_initramfs();
+    mount_fat32_devices();
+    init_ramfs();
+    if cmdline().has_flag("selftest") {
+        selftest::run_all();
+    }
+    // desktop を指定すると、画面をウィンドウマネージャで描き、ログはその中のウィンドウに出す
+    if cmdline().has_flag("desktop") {
+        if let Err(e) = start_desktop() {
+            error!("Failed to start the desktop: {e}");
+        }
+    }
+    if cmdline().has_flag("shell") {
+        start_shell();
+    }
+    if let Some(name) = selected_scenario() {
+        run_scenario(&name);
+    }
+    // init=/path/to/binary が指定されていたら、最初のユーザプロセスとして動かす
+    if let Some(init) = cmdline().get("init") {
+        match Process::spawn(init, &[]) {
+            Ok(pid) => {
+                kthread::spawn("init-waiter", move || match wait(pid) {
+                    Ok(code) => {
+                        info!("init exited with code {code}");
+                    }
+                    Err(e) => {
+                        error!("Failed to wait for init: {e}");
+                    }
+                });
+            }
+            Err(e) => error!("Failed to start {init}: {e}"),
+        }
+    }
     let t0 = global_timestamp();
 
     let task1 = Task::new(async move {
@@ -79,9 +238,7 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     let mut executor = Executor::new();
     executor.enqueue(task1);
     executor.enqueue(task2);
-    Executor::run(executor);
+    kthread::spawn("executor", move || Executor::run(executor));
 
-    loop {
-        hlt()
-    }
+    run_idle()
 }
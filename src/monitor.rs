@@ -0,0 +1,178 @@
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use crate::serial::SerialPort;
+use crate::x86::read_cr2;
+use crate::x86::read_cr3;
+use crate::x86::write_io_port_u8;
+use crate::x86::TranslationResult;
+use crate::x86::PAGE_SIZE;
+
+// 致命的な例外の後、パニックする前にシリアルコンソールで調べられるようにする
+// 入力を待って止まるので、カーネルコマンドラインの monitor で有効にした時だけ使う
+static MONITOR_ENABLED: AtomicBool = AtomicBool::new(false);
+
+const LINE_MAX_LEN: usize = 80;
+// x で一度に表示できる最大のバイト数
+const DUMP_MAX_LEN: u64 = 4096;
+
+pub fn set_monitor_enabled(enabled: bool) {
+    MONITOR_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn monitor_enabled() -> bool {
+    MONITOR_ENABLED.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonitorAction {
+    // 例外を起こした命令からやり直す
+    Continue,
+    // いつも通りパニックする
+    Panic,
+}
+
+pub fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn translate(virt: u64) -> Option<u64> {
+    let table = unsafe { &*read_cr3() };
+    match table.translate(virt).ok()? {
+        TranslationResult::PageMapped4K { phys }
+        | TranslationResult::PageMapped2M { phys }
+        | TranslationResult::PageMapped1G { phys } => Some(phys),
+    }
+}
+
+// ロックを取らずにCOM1だけを使う。例外の中なので割り込みは止まっていて、受信はポーリングで読む
+fn read_line<'a>(sw: &mut SerialPort, buf: &'a mut [u8; LINE_MAX_LEN]) -> &'a str {
+    let mut len = 0;
+    loop {
+        let c = loop {
+            if let Some(c) = sw.poll_byte() {
+                break c;
+            }
+            core::hint::spin_loop();
+        };
+        match c {
+            b'\r' | b'\n' => {
+                sw.send_str("\n");
+                break;
+            }
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                sw.send_str("\x08 \x08");
+            }
+            0x20..=0x7e if len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                sw.send_byte(c);
+            }
+            _ => {}
+        }
+    }
+    // 表示できるASCIIしか入れていない
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+fn dump_memory(sw: &mut SerialPort, addr: u64, len: u64) {
+    let len = len.min(DUMP_MAX_LEN);
+    for line in (addr..addr.saturating_add(len)).step_by(16) {
+        write!(sw, "{line:#018X}:").unwrap();
+        for a in line..(line + 16).min(addr + len) {
+            // ページが無いところを読むと例外の中でまた例外になるので、先に確かめる
+            if (a == line || a % PAGE_SIZE as u64 == 0) && translate(a).is_none() {
+                writeln!(sw, " <not mapped>").unwrap();
+                return;
+            }
+            write!(sw, " {:02X}", unsafe { *(a as *const u8) }).unwrap();
+        }
+        writeln!(sw).unwrap();
+    }
+}
+
+fn reboot() -> ! {
+    // キーボードコントローラからCPUをリセットする。効かなければPCIのリセットレジスタを使う
+    write_io_port_u8(0x64, 0xfe);
+    write_io_port_u8(0xcf9, 0x06);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+const HELP: &str = "\
+  regs             show registers at the exception
+  x <addr> [len]   dump memory
+  pt <addr>        translate a virtual address with the current page table
+  continue         return from the exception and retry
+  panic            leave the monitor and panic
+  reboot           reset the machine
+";
+
+// 例外ハンドラから呼ぶ。regsは例外が起きた時のレジスタ
+pub fn enter_monitor(exception: usize, regs: &dyn fmt::Debug) -> MonitorAction {
+    let mut sw = SerialPort::new_for_com1();
+    let mut buf = [0u8; LINE_MAX_LEN];
+    writeln!(
+        sw,
+        "\nException {exception:#04X}: entering the debug monitor (help for commands)"
+    )
+    .unwrap();
+    loop {
+        sw.send_str("monitor> ");
+        let line = read_line(&mut sw, &mut buf);
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next(), args.next()) {
+            (None, _, _) => {}
+            (Some("help"), _, _) => sw.send_str(HELP),
+            (Some("regs"), _, _) => {
+                writeln!(sw, "{regs:?}").unwrap();
+                writeln!(
+                    sw,
+                    "CR2={:#018X} CR3={:#018X}",
+                    read_cr2(),
+                    read_cr3() as u64
+                )
+                .unwrap();
+            }
+            (Some("x"), Some(addr), len) => {
+                match (parse_u64(addr), len.map_or(Some(64), parse_u64)) {
+                    (Some(addr), Some(len)) => dump_memory(&mut sw, addr, len),
+                    _ => writeln!(sw, "Invalid number").unwrap(),
+                }
+            }
+            (Some("pt"), Some(addr), _) => match parse_u64(addr) {
+                Some(addr) => {
+                    let table = unsafe { &*read_cr3() };
+                    match table.translate(addr) {
+                        Ok(result) => writeln!(sw, "{addr:#018X} -> {result:?}").unwrap(),
+                        Err(e) => writeln!(sw, "{addr:#018X} -> {e}").unwrap(),
+                    }
+                }
+                None => writeln!(sw, "Invalid number").unwrap(),
+            },
+            (Some("continue"), _, _) => return MonitorAction::Continue,
+            (Some("panic"), _, _) => return MonitorAction::Panic,
+            (Some("reboot"), _, _) => reboot(),
+            (Some(cmd), _, _) => writeln!(sw, "Unknown command: {cmd}").unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_numbers() {
+        assert_eq!(parse_u64("0x1000"), Some(0x1000));
+        assert_eq!(parse_u64("4096"), Some(4096));
+        assert_eq!(parse_u64("0xZZ"), None);
+    }
+}
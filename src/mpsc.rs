@@ -0,0 +1,141 @@
+use core::cell::UnsafeCell;
+use core::cmp;
+use core::mem::MaybeUninit;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+// 割り込みハンドラが積んでタスクが取り出す、固定長のキュー
+// どちらの側もロックを取らず、pushはメモリを確保しないので、割り込みハンドラから呼んでよい
+pub struct MpscQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    // 次に読む位置と次に書く位置。Nで割った余りが添字で、商が何周目か
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct Slot<T> {
+    // lap周目の位置について、2 * lapなら空いていて、2 * lap + 1なら値が入っている
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for MpscQueue<T, N> {}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0);
+        Self {
+            // seqが全部0 (0周目で空) の状態から始める
+            slots: unsafe { core::mem::zeroed() },
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+    // いっぱいなら値をそのまま返す
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let lap = pos / N;
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.cmp(&(2 * lap)) {
+                cmp::Ordering::Equal => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.seq.store(2 * lap + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(actual) => pos = actual,
+                },
+                // 前の周の値がまだ読まれていない
+                cmp::Ordering::Less => return Err(value),
+                // 他の人が先に書いた
+                cmp::Ordering::Greater => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let lap = pos / N;
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.cmp(&(2 * lap + 1)) {
+                cmp::Ordering::Equal => match self.head.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.seq.store(2 * (lap + 1), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(actual) => pos = actual,
+                },
+                // まだ書かれていない (書いている途中も含む)
+                cmp::Ordering::Less => return None,
+                cmp::Ordering::Greater => pos = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+    // 他から同時に積まれたり取り出されたりしていると、すぐに古くなる
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::SeqCst);
+        let tail = self.tail.load(Ordering::SeqCst);
+        tail.saturating_sub(head)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for MpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn push_and_pop_in_order_across_laps() {
+        let q: MpscQueue<u32, 4> = MpscQueue::new();
+        for i in 0..10 {
+            assert_eq!(q.push(i), Ok(()));
+            assert_eq!(q.push(i + 100), Ok(()));
+            assert_eq!(q.pop(), Some(i));
+            assert_eq!(q.pop(), Some(i + 100));
+        }
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test_case]
+    fn push_fails_when_full() {
+        let q: MpscQueue<u32, 2> = MpscQueue::new();
+        assert_eq!(q.push(1), Ok(()));
+        assert_eq!(q.push(2), Ok(()));
+        assert_eq!(q.push(3), Err(3));
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.push(3), Ok(()));
+    }
+}
@@ -1,12 +1,22 @@
+use crate::apic::local_apic_id;
 use crate::result::Result;
+use crate::task::current_task_id;
+use crate::x86::cli;
+use crate::x86::interrupts_enabled;
+use crate::x86::sti;
 
 use core::cell::SyncUnsafeCell;
 use core::fmt::Debug;
+use core::hint::spin_loop;
 use core::ops::Deref;
 use core::ops::DerefMut;
 use core::panic::Location;
 use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
 use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 
 pub struct MutexGuard<'a, T> {
@@ -24,6 +34,10 @@ impl<'a, T> MutexGuard<'a, T> {
             location: *Location::caller(),
         }
     }
+    // 同じMutexを取り直したい時 (CondVar) に使う
+    pub fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.lock
+    }
 }
 
 unsafe impl<'a, T> Sync for MutexGuard<'a, T> {}
@@ -43,10 +57,82 @@ impl<'a, T> DerefMut for MutexGuard<'a, T> {
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.lock.locked.store(false, Ordering::SeqCst);
+        if self.lock.order != 0 {
+            HELD_ORDERS.fetch_and(!(1 << self.lock.order), Ordering::SeqCst);
+        }
+        self.lock.owner_task.store(NO_OWNER, Ordering::SeqCst);
+        untrack_held_lock(&self.lock.poisoned);
+        self.lock.now_serving.fetch_add(1, Ordering::SeqCst);
+        NUM_OF_HELD_LOCKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// 取られているロックの数。0でない間はタスクを横取りで切り替えない
+static NUM_OF_HELD_LOCKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn num_of_held_locks() -> usize {
+    NUM_OF_HELD_LOCKS.load(Ordering::SeqCst)
+}
+
+// 順番付きのMutexのうち、今取られているもののorderのビット
+// CPUは1つなので、全体で1つ持っておけば足りる
+static HELD_ORDERS: AtomicU64 = AtomicU64::new(0);
+
+const NO_OWNER: u64 = u64::MAX;
+
+// 取られているMutexのpoisonedフラグ。パニックした時に、これを全部立てる
+// 数えきれない分は記録しない (そのロックは汚れたことにならない)
+const MAX_TRACKED_LOCKS: usize = 64;
+static HELD_LOCK_FLAGS: [AtomicPtr<AtomicBool>; MAX_TRACKED_LOCKS] = unsafe { core::mem::zeroed() };
+
+fn track_held_lock(flag: &AtomicBool) {
+    let flag = flag as *const AtomicBool as *mut AtomicBool;
+    for slot in &HELD_LOCK_FLAGS {
+        if slot
+            .compare_exchange(
+                core::ptr::null_mut(),
+                flag,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        {
+            return;
+        }
     }
 }
 
+fn untrack_held_lock(flag: &AtomicBool) {
+    let flag = flag as *const AtomicBool as *mut AtomicBool;
+    for slot in &HELD_LOCK_FLAGS {
+        if slot
+            .compare_exchange(
+                flag,
+                core::ptr::null_mut(),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+// パニックハンドラから呼ぶ。今取られているMutexの中身は、更新の途中だったかもしれない
+pub fn poison_held_locks() {
+    for slot in &HELD_LOCK_FLAGS {
+        let flag = slot.load(Ordering::SeqCst);
+        if let Some(flag) = unsafe { flag.as_ref() } {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// Mutex::new_with_orderに渡す順番。小さい方を先に取る
+pub const LOCK_ORDER_PROCESSES: u8 = 1;
+pub const LOCK_ORDER_SCHEDULER: u8 = 2;
+
 impl<'a, T> Debug for MutexGuard<'a, T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
@@ -60,10 +146,20 @@ impl<'a, T> Debug for MutexGuard<'a, T> {
     }
 }
 
+// チケットロック。取ろうとした順に取れるので、待っている間に他の人に何度も追い越されることが無い
 pub struct Mutex<T> {
     data: SyncUnsafeCell<T>,
-    locked: AtomicBool,
-    taker_line_num: AtomicU32,
+    // 次に配る番号と、今ロックを持っている番号。等しければ空いている
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    // 今持っているタスクとCPU、取った場所。誰も持っていなければowner_taskはNO_OWNER
+    owner_task: AtomicU64,
+    owner_cpu: AtomicU8,
+    taker: AtomicPtr<Location<'static>>,
+    // 持っている間にパニックした。中身が壊れているかもしれない
+    poisoned: AtomicBool,
+    // 0なら順番を調べない。そうでなければ、これ以上のorderのロックを持ったまま取るとパニックする
+    order: u8,
     created_at_file: &'static str,
     created_at_line: u32,
 }
@@ -81,58 +177,503 @@ impl<T> Debug for Mutex<T> {
 impl<T> Mutex<T> {
     #[track_caller]
     pub const fn new(data: T) -> Self {
-        let location = Location::caller();
+        Self::new_at(data, 0, Location::caller())
+    }
+
+    // 外側で取るロックほど小さいorder (1..=63) を付ける
+    #[track_caller]
+    pub const fn new_with_order(data: T, order: u8) -> Self {
+        assert!(order != 0 && order < 64);
+        Self::new_at(data, order, Location::caller())
+    }
+
+    const fn new_at(data: T, order: u8, location: &'static Location<'static>) -> Self {
         Mutex {
             data: SyncUnsafeCell::new(data),
-            locked: AtomicBool::new(false),
-            taker_line_num: AtomicU32::new(0),
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            owner_task: AtomicU64::new(NO_OWNER),
+            owner_cpu: AtomicU8::new(0),
+            taker: AtomicPtr::new(core::ptr::null_mut()),
+            poisoned: AtomicBool::new(false),
+            order,
             created_at_file: location.file(),
             created_at_line: location.line(),
         }
     }
 
     #[track_caller]
-    fn try_lock(&self) -> Result<MutexGuard<T>> {
+    fn locked_by_caller(&self) -> MutexGuard<T> {
+        self.owner_task.store(current_task_id(), Ordering::SeqCst);
+        self.owner_cpu.store(local_apic_id(), Ordering::SeqCst);
+        self.taker.store(
+            Location::caller() as *const Location<'static> as *mut Location<'static>,
+            Ordering::SeqCst,
+        );
+        if self.order != 0 {
+            HELD_ORDERS.fetch_or(1 << self.order, Ordering::SeqCst);
+        }
+        track_held_lock(&self.poisoned);
+        NUM_OF_HELD_LOCKS.fetch_add(1, Ordering::SeqCst);
+        unsafe { MutexGuard::new(self, &self.data) }
+    }
+
+    // 「誰がどこで持っているか」と「自分は誰か」をまとめて表示してパニックする
+    #[track_caller]
+    fn panic_with_owner(&self, reason: &str) -> ! {
+        let taker = self.taker.load(Ordering::SeqCst);
+        // takerは&'static Locationから作ったものしか入らない
+        let taker = unsafe { taker.as_ref() };
+        panic!(
+            "{} Mutex at {}:{}: held by task {} on CPU {} locked at {}:{}, you are task {} on CPU {} at {}",
+            reason,
+            self.created_at_file,
+            self.created_at_line,
+            self.owner_task.load(Ordering::SeqCst),
+            self.owner_cpu.load(Ordering::SeqCst),
+            taker.map_or("?", |l| l.file()),
+            taker.map_or(0, |l| l.line()),
+            current_task_id(),
+            local_apic_id(),
+            Location::caller(),
+        )
+    }
+
+    // 前の人が離すたびに数え直すので、順番待ちが長いだけではパニックしない
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self.order != 0 {
+            let held = HELD_ORDERS.load(Ordering::SeqCst);
+            if held >> self.order != 0 {
+                panic!(
+                    "Lock order violation: locking Mutex at {}:{} (order {}) while holding a lock of order {}, caller: {:?}",
+                    self.created_at_file,
+                    self.created_at_line,
+                    self.order,
+                    63 - held.leading_zeros(),
+                    Location::caller(),
+                );
+            }
+        }
+        // 同じCPUの同じタスクが持っているなら、待っても離されることは無い
+        if self.owner_task.load(Ordering::SeqCst) == current_task_id()
+            && self.owner_cpu.load(Ordering::SeqCst) == local_apic_id()
+        {
+            self.panic_with_owner("Self-deadlock on");
+        }
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        let mut serving = self.now_serving.load(Ordering::SeqCst);
+        let mut spins = 0;
+        while serving != ticket {
+            spin_loop();
+            let now = self.now_serving.load(Ordering::SeqCst);
+            if now != serving {
+                serving = now;
+                spins = 0;
+                continue;
+            }
+            spins += 1;
+            if spins >= 100000 {
+                self.panic_with_owner("Failed to lock")
+            }
+        }
+        self.locked_by_caller()
+    }
+
+    // 空いていなければ待たずにNoneを返す。ロガーのように、止まってはいけない所で使う
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let serving = self.now_serving.load(Ordering::SeqCst);
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .ok()?;
+        Some(self.locked_by_caller())
+    }
+
+    // lockと同じだが、汚れている時はエラーにする
+    #[track_caller]
+    pub fn lock_checked(&self) -> Result<MutexGuard<T>> {
+        let guard = self.lock();
+        if self.is_poisoned() {
+            return Err("Mutex is poisoned");
+        }
+        Ok(guard)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::SeqCst)
+    }
+
+    // 中身を確かめ直したら呼ぶ
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::SeqCst);
+    }
+
+    // 誰も使っていないので、汚れていても取り出せる
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    #[track_caller]
+    pub fn under_locked<R>(&self, f: &dyn Fn(&mut T) -> Result<R>) -> Result<R> {
+        let mut guard = self.lock();
+        f(&mut *guard)
+    }
+}
+
+unsafe impl<T> Sync for Mutex<T> {}
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+// 割り込みハンドラと普通のコードの両方から取るロック
+// Mutexだと、持っている間に来た割り込みのハンドラが同じロックを取ろうとして止まってしまう
+// こちらは持っている間は割り込みを止めて、離す時に元の状態 (RFLAGS.IF) に戻す
+pub struct SpinLockIrqSave<T> {
+    data: SyncUnsafeCell<T>,
+    locked: AtomicBool,
+    created_at_file: &'static str,
+    created_at_line: u32,
+}
+
+pub struct SpinLockIrqSaveGuard<'a, T> {
+    lock: &'a SpinLockIrqSave<T>,
+    data: &'a mut T,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> SpinLockIrqSave<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        let location = Location::caller();
+        Self {
+            data: SyncUnsafeCell::new(data),
+            locked: AtomicBool::new(false),
+            created_at_file: location.file(),
+            created_at_line: location.line(),
+        }
+    }
+    pub fn try_lock(&self) -> Option<SpinLockIrqSaveGuard<T>> {
+        let interrupts_were_enabled = interrupts_enabled();
+        cli();
         if self
             .locked
             .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
             .is_ok()
         {
-            self.taker_line_num
-                .store(Location::caller().line(), Ordering::SeqCst);
-            Ok(unsafe { MutexGuard::new(self, &self.data) })
+            NUM_OF_HELD_LOCKS.fetch_add(1, Ordering::SeqCst);
+            Some(SpinLockIrqSaveGuard {
+                lock: self,
+                data: unsafe { &mut *self.data.get() },
+                interrupts_were_enabled,
+            })
         } else {
-            Err("Locke failed")
+            if interrupts_were_enabled {
+                sti();
+            }
+            None
         }
     }
-
+    // 割り込みを止めているので、取れないのは同じロックを二重に取ろうとした時だけ
     #[track_caller]
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> SpinLockIrqSaveGuard<T> {
         for _ in 0..100000 {
-            if let Ok(locked) = self.try_lock() {
-                return locked;
+            if let Some(guard) = self.try_lock() {
+                return guard;
             }
+            spin_loop();
         }
         panic!(
-            "Failed to lock Mutex at {}:{}, caller: {:?}, taker_line_num: {}",
+            "Failed to lock SpinLockIrqSave at {}:{}, caller: {:?}",
             self.created_at_file,
             self.created_at_line,
             Location::caller(),
-            self.taker_line_num.load(Ordering::SeqCst),
         )
     }
+}
+
+unsafe impl<T> Sync for SpinLockIrqSave<T> {}
+
+impl<T> Debug for SpinLockIrqSave<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "SpinLockIrqSave @ {}:{}",
+            self.created_at_file, self.created_at_line
+        )
+    }
+}
+
+impl<'a, T> Deref for SpinLockIrqSaveGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockIrqSaveGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T> Drop for SpinLockIrqSaveGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::SeqCst);
+        NUM_OF_HELD_LOCKS.fetch_sub(1, Ordering::SeqCst);
+        if self.interrupts_were_enabled {
+            sti();
+        }
+    }
+}
 
+// 読むだけなら何人でも同時に持てて、書く時は1人だけが持てるロック
+// 読むことがほとんどの表 (PCIのデバイスやマウントの一覧など) に使う
+pub struct RwLock<T> {
+    data: SyncUnsafeCell<T>,
+    // 読んでいる数。書いている間はRW_LOCK_WRITER
+    state: AtomicUsize,
+    writer_line_num: AtomicU32,
+    created_at_file: &'static str,
+    created_at_line: u32,
+}
+
+const RW_LOCK_WRITER: usize = usize::MAX;
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    data: &'a T,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    data: &'a mut T,
+    location: Location<'a>,
+}
+
+impl<T> RwLock<T> {
     #[track_caller]
-    pub fn under_locked<R>(&self, f: &dyn Fn(&mut T) -> Result<R>) -> Result<R> {
-        let mut guard = self.lock();
-        f(&mut *guard)
+    pub const fn new(data: T) -> Self {
+        let location = Location::caller();
+        Self {
+            data: SyncUnsafeCell::new(data),
+            state: AtomicUsize::new(0),
+            writer_line_num: AtomicU32::new(0),
+            created_at_file: location.file(),
+            created_at_line: location.line(),
+        }
+    }
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let readers = self.state.load(Ordering::SeqCst);
+        if readers == RW_LOCK_WRITER || readers == RW_LOCK_WRITER - 1 {
+            return None;
+        }
+        self.state
+            .compare_exchange(readers, readers + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+        NUM_OF_HELD_LOCKS.fetch_add(1, Ordering::SeqCst);
+        Some(RwLockReadGuard {
+            lock: self,
+            data: unsafe { &*self.data.get() },
+        })
+    }
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.state
+            .compare_exchange(0, RW_LOCK_WRITER, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+        self.writer_line_num
+            .store(Location::caller().line(), Ordering::SeqCst);
+        NUM_OF_HELD_LOCKS.fetch_add(1, Ordering::SeqCst);
+        Some(RwLockWriteGuard {
+            lock: self,
+            data: unsafe { &mut *self.data.get() },
+            location: *Location::caller(),
+        })
+    }
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        for _ in 0..100000 {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            spin_loop();
+        }
+        self.lock_failed("read")
+    }
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        for _ in 0..100000 {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            spin_loop();
+        }
+        self.lock_failed("write")
+    }
+    #[track_caller]
+    fn lock_failed(&self, kind: &str) -> ! {
+        let state = self.state.load(Ordering::SeqCst);
+        if state == RW_LOCK_WRITER {
+            panic!(
+                "Failed to {kind}-lock RwLock at {}:{}, caller: {:?}, writer_line_num: {}",
+                self.created_at_file,
+                self.created_at_line,
+                Location::caller(),
+                self.writer_line_num.load(Ordering::SeqCst),
+            )
+        } else {
+            panic!(
+                "Failed to {kind}-lock RwLock at {}:{}, caller: {:?}, held by {state} readers",
+                self.created_at_file,
+                self.created_at_line,
+                Location::caller(),
+            )
+        }
     }
 }
 
-unsafe impl<T> Sync for Mutex<T> {}
-impl<T: Default> Default for Mutex<T> {
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "RwLock @ {}:{}",
+            self.created_at_file, self.created_at_line
+        )
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
     #[track_caller]
     fn default() -> Self {
         Self::new(T::default())
     }
 }
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::SeqCst);
+        NUM_OF_HELD_LOCKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::SeqCst);
+        NUM_OF_HELD_LOCKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<'a, T> Debug for RwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "RwLockWriteGuard @ {}:{}, taken at {}:{}",
+            self.lock.created_at_file,
+            self.lock.created_at_line,
+            self.location.file(),
+            self.location.line()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn irq_save_lock_restores_interrupt_flag() {
+        let lock = SpinLockIrqSave::new(1);
+        let were_enabled = interrupts_enabled();
+        {
+            let mut guard = lock.lock();
+            assert!(!interrupts_enabled());
+            assert!(lock.try_lock().is_none());
+            *guard += 1;
+        }
+        assert_eq!(interrupts_enabled(), were_enabled);
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test_case]
+    fn rw_lock_allows_many_readers_or_one_writer() {
+        let lock = RwLock::new(0);
+        {
+            let r1 = lock.read();
+            let r2 = lock.read();
+            assert_eq!(*r1 + *r2, 0);
+            assert!(lock.try_write().is_none());
+        }
+        {
+            let mut w = lock.write();
+            *w = 5;
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+        }
+        assert_eq!(*lock.read(), 5);
+    }
+
+    #[test_case]
+    fn mutex_records_owner_and_order() {
+        let outer = Mutex::new_with_order(1, 40);
+        let inner = Mutex::new_with_order(2, 41);
+        {
+            let a = outer.lock();
+            let b = inner.lock();
+            assert_eq!(outer.owner_task.load(Ordering::SeqCst), current_task_id());
+            assert_eq!(HELD_ORDERS.load(Ordering::SeqCst) >> 40, 0b11);
+            assert_eq!(*a + *b, 3);
+        }
+        assert_eq!(outer.owner_task.load(Ordering::SeqCst), NO_OWNER);
+        assert_eq!(HELD_ORDERS.load(Ordering::SeqCst) >> 40, 0);
+    }
+
+    #[test_case]
+    fn try_lock_and_poison() {
+        let lock = Mutex::new(1);
+        {
+            let _guard = lock.lock();
+            assert!(lock.try_lock().is_none());
+            poison_held_locks();
+        }
+        assert!(lock.is_poisoned());
+        assert!(lock.lock_checked().is_err());
+        lock.clear_poison();
+        assert_eq!(lock.lock_checked().map(|g| *g), Ok(1));
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+        assert_eq!(lock.into_inner(), 1);
+    }
+}
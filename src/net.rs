@@ -0,0 +1,217 @@
+extern crate alloc;
+
+pub mod arp;
+pub mod ethernet;
+pub mod http;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::info;
+use crate::kthread;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::task::sleep;
+
+pub type MacAddress = [u8; 6];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Self = Self([0, 0, 0, 0]);
+    pub const BROADCAST: Self = Self([255, 255, 255, 255]);
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut addr = [0u8; 4];
+        let mut parts = s.split('.');
+        for e in addr.iter_mut() {
+            *e = parts
+                .next()
+                .and_then(|p| p.parse().ok())
+                .ok_or("Invalid IPv4 address")?;
+        }
+        if parts.next().is_some() {
+            return Err("Invalid IPv4 address");
+        }
+        Ok(Self(addr))
+    }
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+    pub fn from_u32(v: u32) -> Self {
+        Self(v.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    // "10.0.2.15/24" の形
+    pub fn parse(cidr: &str, gateway: Option<&str>) -> Result<Self> {
+        let (addr, prefix_len) = cidr.split_once('/').ok_or("Prefix length is missing")?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| "Invalid prefix length")?;
+        if prefix_len > 32 {
+            return Err("Invalid prefix length");
+        }
+        Ok(Self {
+            addr: Ipv4Addr::parse(addr)?,
+            prefix_len,
+            gateway: gateway.map(Ipv4Addr::parse).transpose()?,
+        })
+    }
+    pub fn netmask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+    pub fn broadcast_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | !self.netmask())
+    }
+    // 同じネットワークにいて、直接届くアドレスならtrue
+    pub fn is_local(&self, ip: Ipv4Addr) -> bool {
+        (ip.to_u32() ^ self.addr.to_u32()) & self.netmask() == 0
+    }
+}
+
+// QEMUのユーザモードネットワーク (-netdev user) の既定値
+pub const QEMU_USER_NET_CONFIG: Ipv4Config = Ipv4Config {
+    addr: Ipv4Addr([10, 0, 2, 15]),
+    prefix_len: 24,
+    gateway: Some(Ipv4Addr([10, 0, 2, 2])),
+};
+
+// ネットワークカードのドライバがネットワークスタックに見せるもの
+// 複数の利用者から共有されるので、実装側で排他制御する
+pub trait NicDriver: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn mac_address(&self) -> MacAddress;
+    fn is_link_up(&self) -> bool;
+    // frameはEthernetヘッダから始まり、FCSは含まない
+    fn transmit(&self, frame: &[u8]) -> Result<()>;
+    // 受信済みのフレームを取り出す。上の層が返信を送れるように、ドライバのロックを放してから渡す
+    fn receive(&self) -> Vec<Vec<u8>>;
+}
+
+// NICと、それに割り当てたアドレスなどの設定
+pub struct Interface {
+    pub name: String,
+    pub nic: Arc<dyn NicDriver>,
+    ipv4: Mutex<Option<Ipv4Config>>,
+}
+
+impl Interface {
+    pub fn mac_address(&self) -> MacAddress {
+        self.nic.mac_address()
+    }
+    pub fn ipv4_config(&self) -> Option<Ipv4Config> {
+        *self.ipv4.lock()
+    }
+    pub fn set_ipv4_config(&self, config: Ipv4Config) {
+        info!(
+            "net: {}: {}/{} gateway {:?}",
+            self.name, config.addr, config.prefix_len, config.gateway
+        );
+        *self.ipv4.lock() = Some(config);
+    }
+    pub fn ipv4_addr(&self) -> Option<Ipv4Addr> {
+        self.ipv4_config().map(|c| c.addr)
+    }
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+static NEXT_NIC_ID: AtomicUsize = AtomicUsize::new(0);
+// 割り込みを使わないデバイスもあるので、受信が無い間はこの間隔で見に行く
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// eth0, eth1, ...という名前で登録する
+pub fn register_nic(nic: Arc<dyn NicDriver>) -> String {
+    let name = format!("eth{}", NEXT_NIC_ID.fetch_add(1, Ordering::SeqCst));
+    info!(
+        "net: {name}: {} MAC {:02X?}, link {}",
+        nic.name(),
+        nic.mac_address(),
+        if nic.is_link_up() { "up" } else { "down" }
+    );
+    INTERFACES.lock().push(Arc::new(Interface {
+        name: name.clone(),
+        nic,
+        ipv4: Mutex::new(None),
+    }));
+    name
+}
+
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.lock().iter().find(|i| i.name == name).cloned()
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+// 全部のNICから受信したフレームを上の層に渡す。処理したフレームの数を返す
+pub fn poll_nics() -> usize {
+    let mut count = 0;
+    for iface in interfaces() {
+        for frame in iface.nic.receive() {
+            ethernet::handle_frame(&iface, &frame);
+            count += 1;
+        }
+    }
+    count
+}
+
+// 受信したフレームを処理し続けるスレッドを動かす
+pub fn start_network() {
+    if INTERFACES.lock().is_empty() {
+        info!("net: no network interface");
+        return;
+    }
+    arp::init_arp();
+    ipv4::init_ipv4();
+    icmp::init_icmp();
+    udp::init_udp();
+    kthread::spawn("net-rx", || loop {
+        if poll_nics() == 0 {
+            sleep(RX_POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_ipv4_config() {
+        let config = Ipv4Config::parse("10.0.2.15/24", Some("10.0.2.2")).unwrap();
+        assert_eq!(config, QEMU_USER_NET_CONFIG);
+        assert!(config.is_local(Ipv4Addr([10, 0, 2, 200])));
+        assert!(!config.is_local(Ipv4Addr([10, 0, 3, 1])));
+        assert_eq!(config.broadcast_addr(), Ipv4Addr([10, 0, 2, 255]));
+        assert_eq!(Ipv4Config::parse("0.0.0.0/0", None).unwrap().netmask(), 0);
+        assert!(Ipv4Addr::parse("10.0.2").is_err());
+        assert!(Ipv4Addr::parse("10.0.2.256").is_err());
+    }
+}
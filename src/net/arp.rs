@@ -0,0 +1,216 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::condvar::CondVar;
+use crate::hpet::global_timestamp;
+use crate::mutex::Mutex;
+use crate::net::ethernet::register_ethertype_handler;
+use crate::net::ethernet::send_frame;
+use crate::net::ethernet::EthernetFrame;
+use crate::net::ethernet::BROADCAST_MAC;
+use crate::net::ethernet::ETHERTYPE_ARP;
+use crate::net::ethernet::ETHERTYPE_IPV4;
+use crate::net::Interface;
+use crate::net::Ipv4Addr;
+use crate::net::MacAddress;
+use crate::result::Result;
+use crate::warn;
+
+// RFC 826
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+// 覚えたアドレスを使い続ける時間
+const CACHE_TTL: Duration = Duration::from_secs(300);
+// 返事が来なければこの間隔でリクエストを送り直す
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < PACKET_SIZE {
+            return Err("ARP packet too short");
+        }
+        let be16 = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        if be16(0) != HTYPE_ETHERNET || be16(2) != ETHERTYPE_IPV4 || data[4] != 6 || data[5] != 4 {
+            return Err("Unsupported ARP packet");
+        }
+        let mac = |i: usize| {
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(&data[i..i + 6]);
+            mac
+        };
+        let ip = |i: usize| Ipv4Addr([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self {
+            op: be16(6),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PACKET_SIZE);
+        data.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        data.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        data.extend_from_slice(&[6, 4]);
+        data.extend_from_slice(&self.op.to_be_bytes());
+        data.extend_from_slice(&self.sender_mac);
+        data.extend_from_slice(&self.sender_ip.0);
+        data.extend_from_slice(&self.target_mac);
+        data.extend_from_slice(&self.target_ip.0);
+        data
+    }
+}
+
+// IPv4アドレス -> (MACアドレス, 期限)
+static CACHE: Mutex<BTreeMap<Ipv4Addr, (MacAddress, Duration)>> = Mutex::new(BTreeMap::new());
+// resolveで返事を待つタスクを、キャッシュが増えた時に起こす
+static CACHE_UPDATED: CondVar = CondVar::new();
+
+fn cache_insert(ip: Ipv4Addr, mac: MacAddress, now: Duration) {
+    CACHE.lock().insert(ip, (mac, now + CACHE_TTL));
+    CACHE_UPDATED.notify_all();
+}
+
+fn cache_lookup(ip: Ipv4Addr, now: Duration) -> Option<MacAddress> {
+    let mut cache = CACHE.lock();
+    match cache.get(&ip) {
+        Some((mac, expires)) if *expires > now => Some(*mac),
+        Some(_) => {
+            cache.remove(&ip);
+            None
+        }
+        None => None,
+    }
+}
+
+pub fn cache_entries() -> Vec<(Ipv4Addr, MacAddress)> {
+    let now = global_timestamp();
+    CACHE
+        .lock()
+        .iter()
+        .filter(|(_, (_, expires))| *expires > now)
+        .map(|(ip, (mac, _))| (*ip, *mac))
+        .collect()
+}
+
+fn send_arp(iface: &Interface, dst: MacAddress, packet: &ArpPacket) -> Result<()> {
+    send_frame(iface.nic.as_ref(), dst, ETHERTYPE_ARP, &packet.to_bytes())
+}
+
+fn send_request(iface: &Interface, ip: Ipv4Addr) -> Result<()> {
+    let packet = ArpPacket {
+        op: OP_REQUEST,
+        sender_mac: iface.mac_address(),
+        sender_ip: iface.ipv4_addr().unwrap_or(Ipv4Addr::UNSPECIFIED),
+        target_mac: [0; 6],
+        target_ip: ip,
+    };
+    send_arp(iface, BROADCAST_MAC, &packet)
+}
+
+fn handle_arp(iface: &Arc<Interface>, frame: &EthernetFrame) {
+    let Ok(packet) = ArpPacket::parse(frame.payload) else {
+        return;
+    };
+    let Some(my_ip) = iface.ipv4_addr() else {
+        return;
+    };
+    if packet.target_ip != my_ip {
+        return;
+    }
+    // 自分宛てのものだけから覚える (RFC 826 の Packet Reception)
+    if packet.sender_ip != Ipv4Addr::UNSPECIFIED {
+        cache_insert(packet.sender_ip, packet.sender_mac, global_timestamp());
+    }
+    if packet.op == OP_REQUEST {
+        let reply = ArpPacket {
+            op: OP_REPLY,
+            sender_mac: iface.mac_address(),
+            sender_ip: my_ip,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        if let Err(e) = send_arp(iface, packet.sender_mac, &reply) {
+            warn!("arp: failed to reply to {}: {e}", packet.sender_ip);
+        }
+    }
+}
+
+// ipのMACアドレスを返す。知らなければリクエストを送り、返事が来るかtimeoutまで待つ
+// 返事は受信スレッドが処理するので、そのスレッドの中ではtimeoutを0にして待たないこと
+pub fn resolve(iface: &Interface, ip: Ipv4Addr, timeout: Duration) -> Result<MacAddress> {
+    if ip == Ipv4Addr::BROADCAST {
+        return Ok(BROADCAST_MAC);
+    }
+    let deadline = global_timestamp() + timeout;
+    let mut next_request = Duration::ZERO;
+    loop {
+        let now = global_timestamp();
+        if let Some(mac) = cache_lookup(ip, now) {
+            return Ok(mac);
+        }
+        if now >= next_request {
+            send_request(iface, ip)?;
+            next_request = now + RETRY_INTERVAL;
+        }
+        if now >= deadline {
+            return Err("ARP resolution timed out");
+        }
+        // 返事が来るか、送り直す時刻になるまで止まる
+        let wait = deadline.min(next_request) - now;
+        drop(CACHE_UPDATED.wait_timeout_while(CACHE.lock(), wait, |c| !c.contains_key(&ip)));
+    }
+}
+
+pub fn init_arp() {
+    if let Err(e) = register_ethertype_handler(ETHERTYPE_ARP, handle_arp) {
+        warn!("arp: {e}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn arp_packet_round_trip() {
+        let packet = ArpPacket {
+            op: OP_REQUEST,
+            sender_mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            sender_ip: Ipv4Addr([10, 0, 2, 15]),
+            target_mac: [0; 6],
+            target_ip: Ipv4Addr([10, 0, 2, 2]),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), PACKET_SIZE);
+        assert_eq!(ArpPacket::parse(&bytes), Ok(packet));
+        assert!(ArpPacket::parse(&bytes[..27]).is_err());
+    }
+
+    #[test_case]
+    fn cache_entries_expire() {
+        let ip = Ipv4Addr([192, 0, 2, 1]);
+        let mac = [2, 0, 0, 0, 0, 1];
+        cache_insert(ip, mac, Duration::ZERO);
+        assert_eq!(cache_lookup(ip, CACHE_TTL / 2), Some(mac));
+        assert_eq!(cache_lookup(ip, CACHE_TTL), None);
+        assert_eq!(cache_lookup(ip, Duration::ZERO), None);
+    }
+}
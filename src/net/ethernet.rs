@@ -0,0 +1,118 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::mutex::Mutex;
+use crate::net::Interface;
+use crate::net::MacAddress;
+use crate::net::NicDriver;
+use crate::result::Result;
+use crate::trace;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+pub const BROADCAST_MAC: MacAddress = [0xff; 6];
+pub const HEADER_SIZE: usize = 14;
+// FCSを除いた最小の長さ。短いフレームは0で埋めて送る
+const MIN_FRAME_SIZE: usize = 60;
+
+pub struct EthernetFrame<'a> {
+    pub dst: MacAddress,
+    pub src: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Result<Self> {
+        if frame.len() < HEADER_SIZE {
+            return Err("Ethernet frame too short");
+        }
+        let mut dst = [0u8; 6];
+        let mut src = [0u8; 6];
+        dst.copy_from_slice(&frame[0..6]);
+        src.copy_from_slice(&frame[6..12]);
+        Ok(Self {
+            dst,
+            src,
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+            payload: &frame[HEADER_SIZE..],
+        })
+    }
+}
+
+pub fn build_frame(dst: MacAddress, src: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((HEADER_SIZE + payload.len()).max(MIN_FRAME_SIZE));
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(MIN_FRAME_SIZE), 0);
+    frame
+}
+
+pub fn send_frame(
+    nic: &dyn NicDriver,
+    dst: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Result<()> {
+    nic.transmit(&build_frame(dst, nic.mac_address(), ethertype, payload))
+}
+
+pub type EtherTypeHandler = fn(iface: &Arc<Interface>, frame: &EthernetFrame);
+
+static HANDLERS: Mutex<Vec<(u16, EtherTypeHandler)>> = Mutex::new(Vec::new());
+
+pub fn register_ethertype_handler(ethertype: u16, handler: EtherTypeHandler) -> Result<()> {
+    let mut handlers = HANDLERS.lock();
+    if handlers.iter().any(|(t, _)| *t == ethertype) {
+        return Err("EtherType is already handled");
+    }
+    handlers.push((ethertype, handler));
+    Ok(())
+}
+
+// 自分宛てかブロードキャスト・マルチキャストのフレームだけを、EtherTypeごとのハンドラに渡す
+pub fn handle_frame(iface: &Arc<Interface>, frame: &[u8]) {
+    let Ok(frame) = EthernetFrame::parse(frame) else {
+        return;
+    };
+    let is_group = frame.dst[0] & 1 != 0;
+    if !is_group && frame.dst != iface.mac_address() {
+        return;
+    }
+    // ハンドラの中で登録が増えてもいいように、ロックは放してから呼ぶ
+    let handler = HANDLERS
+        .lock()
+        .iter()
+        .find(|(t, _)| *t == frame.ethertype)
+        .map(|(_, h)| *h);
+    match handler {
+        Some(handler) => handler(iface, &frame),
+        None => {
+            trace!("net: dropped a frame of EtherType {:#06X}", frame.ethertype);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn build_and_parse_frame() {
+        let src = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let frame = build_frame(BROADCAST_MAC, src, ETHERTYPE_ARP, &[1, 2, 3]);
+        assert_eq!(frame.len(), MIN_FRAME_SIZE);
+        let parsed = EthernetFrame::parse(&frame).unwrap();
+        assert_eq!(parsed.dst, BROADCAST_MAC);
+        assert_eq!(parsed.src, src);
+        assert_eq!(parsed.ethertype, ETHERTYPE_ARP);
+        assert_eq!(&parsed.payload[..3], &[1, 2, 3]);
+        assert!(EthernetFrame::parse(&frame[..13]).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::fs;
+use crate::fs::FileType;
+use crate::result::Result;
+
+// HTTP/1.0 のメッセージを組み立てたり読んだりする部分だけ。送受信はTCPの上で行う想定
+// まだTCPとDNSが無いので、httpgetやサーバとしてはつながっていない
+
+pub const DEFAULT_PORT: u16 = 80;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    // "http://host[:port][/path]" の形
+    pub fn parse(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or("Only http:// is supported")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "Invalid port")?),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err("Host is missing");
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+pub fn build_request(url: &Url) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    )
+    .into_bytes()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+// ヘッダの終わり (空行) までを受け取っていればSomeを返す。ヘッダの中身は使わない
+pub fn parse_request(data: &[u8]) -> Option<Result<Request>> {
+    let end = data.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let Ok(head) = core::str::from_utf8(&data[..end]) else {
+        return Some(Err("Request is not UTF-8"));
+    };
+    let line = head.lines().next().unwrap_or("");
+    let mut parts = line.split(' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Some(Err("Malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") || !path.starts_with('/') {
+        return Some(Err("Malformed request line"));
+    }
+    Some(Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+    }))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    // 接続が閉じられるまでに受け取った全体を渡す
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let end = data
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or("Response header is incomplete")?;
+        let head = core::str::from_utf8(&data[..end]).map_err(|_| "Response is not UTF-8")?;
+        let status = head
+            .lines()
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|s| s.parse().ok())
+            .ok_or("Malformed status line")?;
+        Ok(Self {
+            status,
+            body: data[end + 4..].to_vec(),
+        })
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = format!(
+            "HTTP/1.0 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.body.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(&self.body);
+        data
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Unknown",
+    }
+}
+
+fn error_response(status: u16) -> Response {
+    Response {
+        status,
+        body: format!("{status} {}\n", reason_phrase(status)).into_bytes(),
+    }
+}
+
+// rootの下のファイルを返す。".."でrootの外には出られない
+pub fn serve_file(root: &str, request: &Request) -> Response {
+    if request.method != "GET" {
+        return error_response(405);
+    }
+    let path = request.path.split('?').next().unwrap_or("/");
+    let Ok(path) = fs::resolve_path("/", path) else {
+        return error_response(400);
+    };
+    let mut path = format!("{}{path}", root.trim_end_matches('/'));
+    if matches!(fs::metadata(&path), Ok(m) if m.file_type == FileType::Directory) {
+        path = format!("{}/index.html", path.trim_end_matches('/'));
+    }
+    match fs::read_file(&path) {
+        Ok(body) => Response { status: 200, body },
+        Err(_) => error_response(404),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_http_url() {
+        let url = Url::parse("http://10.0.2.2:8080/index.html").unwrap();
+        assert_eq!(url.host, "10.0.2.2");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/index.html");
+        assert_eq!(Url::parse("http://example.com").unwrap().path, "/");
+        assert!(Url::parse("https://example.com/").is_err());
+    }
+
+    #[test_case]
+    fn request_and_response_round_trip() {
+        let url = Url::parse("http://example.com/a/../b").unwrap();
+        let request = build_request(&url);
+        assert_eq!(parse_request(&request[..10]), None);
+        let request = parse_request(&request).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/a/../b");
+        let response = Response {
+            status: 404,
+            body: b"missing".to_vec(),
+        };
+        assert_eq!(Response::parse(&response.to_bytes()), Ok(response));
+    }
+}
@@ -0,0 +1,44 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::net::ipv4::internet_checksum;
+use crate::net::ipv4::register_protocol_handler;
+use crate::net::ipv4::send_with_timeout;
+use crate::net::ipv4::Ipv4Packet;
+use crate::net::ipv4::PROTOCOL_ICMP;
+use crate::net::Interface;
+use crate::trace;
+use crate::warn;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+// エコー要求 (ping) にだけ応える
+fn handle_icmp(_iface: &Arc<Interface>, packet: &Ipv4Packet) {
+    let message = packet.payload;
+    if message.len() < 8 || internet_checksum(message) != 0 {
+        return;
+    }
+    if message[0] != TYPE_ECHO_REQUEST {
+        trace!("icmp: ignored type {} from {}", message[0], packet.src);
+        return;
+    }
+    // 識別子・シーケンス番号・データはそのまま返す
+    let mut reply: Vec<u8> = message.to_vec();
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].fill(0);
+    let checksum = internet_checksum(&reply);
+    reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+    if let Err(e) = send_with_timeout(packet.src, PROTOCOL_ICMP, &reply, Duration::ZERO) {
+        trace!("icmp: failed to reply to {}: {e}", packet.src);
+    }
+}
+
+pub fn init_icmp() {
+    if let Err(e) = register_protocol_handler(PROTOCOL_ICMP, handle_icmp) {
+        warn!("icmp: {e}");
+    }
+}
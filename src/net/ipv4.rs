@@ -0,0 +1,387 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::hpet::global_timestamp;
+use crate::mutex::Mutex;
+use crate::net::arp;
+use crate::net::ethernet::register_ethertype_handler;
+use crate::net::ethernet::send_frame;
+use crate::net::ethernet::EthernetFrame;
+use crate::net::ethernet::ETHERTYPE_IPV4;
+use crate::net::interfaces;
+use crate::net::Interface;
+use crate::net::Ipv4Addr;
+use crate::result::Result;
+use crate::trace;
+use crate::warn;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+// Ethernetで送れるIPパケットの最大の長さ
+pub const MTU: usize = 1500;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+const MAX_PACKET_SIZE: usize = 65535;
+
+// 揃わないフラグメントを捨てるまでの時間と、同時に組み立てる数の上限
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REASSEMBLIES: usize = 16;
+
+// RFC 1071 のインターネットチェックサム
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [hi, lo] => u16::from_be_bytes([hi, lo]),
+            [hi] => u16::from_be_bytes([hi, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub id: u16,
+    pub more_fragments: bool,
+    // バイト単位
+    pub fragment_offset: usize,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err("IPv4 packet too short");
+        }
+        if data[0] >> 4 != 4 {
+            return Err("Not an IPv4 packet");
+        }
+        let header_len = (data[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > data.len() {
+            return Err("Invalid IPv4 header length");
+        }
+        if internet_checksum(&data[..header_len]) != 0 {
+            return Err("IPv4 header checksum mismatch");
+        }
+        let flags_and_offset = u16::from_be_bytes([data[6], data[7]]);
+        let ip = |i: usize| Ipv4Addr([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self {
+            src: ip(12),
+            dst: ip(16),
+            protocol: data[9],
+            ttl: data[8],
+            id: u16::from_be_bytes([data[4], data[5]]),
+            more_fragments: flags_and_offset & FLAG_MORE_FRAGMENTS != 0,
+            fragment_offset: (flags_and_offset & FRAGMENT_OFFSET_MASK) as usize * 8,
+            // Ethernetの埋め草を含めないように、total_lenで切る
+            payload: &data[header_len..total_len],
+        })
+    }
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments || self.fragment_offset != 0
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let total_len = HEADER_SIZE + self.payload.len();
+        let flags_and_offset = (self.fragment_offset / 8) as u16
+            | if self.more_fragments {
+                FLAG_MORE_FRAGMENTS
+            } else {
+                0
+            };
+        let mut data = Vec::with_capacity(total_len);
+        data.extend_from_slice(&[0x45, 0]);
+        data.extend_from_slice(&(total_len as u16).to_be_bytes());
+        data.extend_from_slice(&self.id.to_be_bytes());
+        data.extend_from_slice(&flags_and_offset.to_be_bytes());
+        data.extend_from_slice(&[self.ttl, self.protocol, 0, 0]);
+        data.extend_from_slice(&self.src.0);
+        data.extend_from_slice(&self.dst.0);
+        let checksum = internet_checksum(&data);
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+        data.extend_from_slice(self.payload);
+        data
+    }
+}
+
+// (送信元, 宛先, プロトコル, ID) ごとにフラグメントを集める
+type ReassemblyKey = (Ipv4Addr, Ipv4Addr, u8, u16);
+
+struct Reassembly {
+    data: Vec<u8>,
+    // 8バイトの塊ごとに、受け取ったかどうか
+    received: Vec<bool>,
+    total_len: Option<usize>,
+    expires: Duration,
+}
+
+impl Reassembly {
+    fn is_complete(&self) -> bool {
+        match self.total_len {
+            Some(len) => self.received[..len.div_ceil(8)].iter().all(|r| *r),
+            None => false,
+        }
+    }
+}
+
+struct Reassembler {
+    entries: BTreeMap<ReassemblyKey, Reassembly>,
+}
+
+impl Reassembler {
+    const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+    // 全部揃ったらペイロード全体を返す
+    fn add(&mut self, packet: &Ipv4Packet, now: Duration) -> Option<Vec<u8>> {
+        self.entries.retain(|_, r| r.expires > now);
+        let start = packet.fragment_offset;
+        let end = start + packet.payload.len();
+        if end + HEADER_SIZE > MAX_PACKET_SIZE || (packet.more_fragments && end % 8 != 0) {
+            return None;
+        }
+        let key = (packet.src, packet.dst, packet.protocol, packet.id);
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_REASSEMBLIES {
+            return None;
+        }
+        let r = self.entries.entry(key).or_insert_with(|| Reassembly {
+            data: Vec::new(),
+            received: vec![false; MAX_PACKET_SIZE.div_ceil(8)],
+            total_len: None,
+            expires: now + REASSEMBLY_TIMEOUT,
+        });
+        if r.data.len() < end {
+            r.data.resize(end, 0);
+        }
+        r.data[start..end].copy_from_slice(packet.payload);
+        r.received[start / 8..end.div_ceil(8)].fill(true);
+        if !packet.more_fragments {
+            r.total_len = Some(end);
+        }
+        if !r.is_complete() {
+            return None;
+        }
+        let mut r = self.entries.remove(&key)?;
+        r.data.truncate(r.total_len?);
+        Some(r.data)
+    }
+}
+
+static REASSEMBLER: Mutex<Reassembler> = Mutex::new(Reassembler::new());
+
+pub type ProtocolHandler = fn(iface: &Arc<Interface>, packet: &Ipv4Packet);
+
+static HANDLERS: Mutex<Vec<(u8, ProtocolHandler)>> = Mutex::new(Vec::new());
+
+pub fn register_protocol_handler(protocol: u8, handler: ProtocolHandler) -> Result<()> {
+    let mut handlers = HANDLERS.lock();
+    if handlers.iter().any(|(p, _)| *p == protocol) {
+        return Err("IP protocol is already handled");
+    }
+    handlers.push((protocol, handler));
+    Ok(())
+}
+
+fn is_for_me(iface: &Interface, dst: Ipv4Addr) -> bool {
+    if dst == Ipv4Addr::BROADCAST {
+        return true;
+    }
+    match iface.ipv4_config() {
+        Some(config) => dst == config.addr || dst == config.broadcast_addr(),
+        None => false,
+    }
+}
+
+fn dispatch(iface: &Arc<Interface>, packet: &Ipv4Packet) {
+    let handler = HANDLERS
+        .lock()
+        .iter()
+        .find(|(p, _)| *p == packet.protocol)
+        .map(|(_, h)| *h);
+    match handler {
+        Some(handler) => handler(iface, packet),
+        None => {
+            trace!("ipv4: dropped a packet of protocol {}", packet.protocol);
+        }
+    }
+}
+
+fn handle_ipv4(iface: &Arc<Interface>, frame: &EthernetFrame) {
+    let packet = match Ipv4Packet::parse(frame.payload) {
+        Ok(packet) => packet,
+        Err(e) => {
+            trace!("ipv4: {e}");
+            return;
+        }
+    };
+    if !is_for_me(iface, packet.dst) {
+        return;
+    }
+    if !packet.is_fragment() {
+        dispatch(iface, &packet);
+        return;
+    }
+    let Some(payload) = REASSEMBLER.lock().add(&packet, global_timestamp()) else {
+        return;
+    };
+    dispatch(
+        iface,
+        &Ipv4Packet {
+            more_fragments: false,
+            fragment_offset: 0,
+            payload: &payload,
+            ..packet
+        },
+    );
+}
+
+// dstへ送るのに使うインタフェースと、次に渡す相手 (同じネットワークならdst自身、それ以外はゲートウェイ)
+pub fn route(dst: Ipv4Addr) -> Result<(Arc<Interface>, Ipv4Addr)> {
+    let configured: Vec<_> = interfaces()
+        .into_iter()
+        .filter_map(|iface| iface.ipv4_config().map(|c| (iface, c)))
+        .collect();
+    if let Some((iface, _)) = configured
+        .iter()
+        .find(|(_, c)| dst == Ipv4Addr::BROADCAST || c.is_local(dst))
+    {
+        return Ok((iface.clone(), dst));
+    }
+    configured
+        .into_iter()
+        .find_map(|(iface, c)| c.gateway.map(|gw| (iface, gw)))
+        .ok_or("No route to host")
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+// 大きすぎるペイロードはMTUに収まるように分割して送る
+// 受信スレッドの中から返信する時は、ARPで待たないようにarp_timeoutを0にする
+pub fn send_with_timeout(
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+    arp_timeout: Duration,
+) -> Result<()> {
+    if HEADER_SIZE + payload.len() > MAX_PACKET_SIZE {
+        return Err("IPv4 payload too large");
+    }
+    let (iface, next_hop) = route(dst)?;
+    let src = iface.ipv4_addr().ok_or("Interface has no IPv4 address")?;
+    let dst_mac = match iface.ipv4_config() {
+        Some(c) if dst == c.broadcast_addr() => {
+            arp::resolve(&iface, Ipv4Addr::BROADCAST, arp_timeout)?
+        }
+        _ => arp::resolve(&iface, next_hop, arp_timeout)?,
+    };
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // 最後以外のフラグメントの長さは8の倍数にする
+    let max_fragment = (MTU - HEADER_SIZE) & !7;
+    let mut offset = 0;
+    loop {
+        let end = (offset + max_fragment).min(payload.len());
+        let packet = Ipv4Packet {
+            src,
+            dst,
+            protocol,
+            ttl: DEFAULT_TTL,
+            id,
+            more_fragments: end < payload.len(),
+            fragment_offset: offset,
+            payload: &payload[offset..end],
+        };
+        send_frame(
+            iface.nic.as_ref(),
+            dst_mac,
+            ETHERTYPE_IPV4,
+            &packet.to_bytes(),
+        )?;
+        if end == payload.len() {
+            return Ok(());
+        }
+        offset = end;
+    }
+}
+
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<()> {
+    send_with_timeout(dst, protocol, payload, arp::DEFAULT_RESOLVE_TIMEOUT)
+}
+
+pub fn init_ipv4() {
+    if let Err(e) = register_ethertype_handler(ETHERTYPE_IPV4, handle_ipv4) {
+        warn!("ipv4: {e}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(offset: usize, more_fragments: bool, payload: &[u8]) -> Ipv4Packet {
+        Ipv4Packet {
+            src: Ipv4Addr([10, 0, 2, 2]),
+            dst: Ipv4Addr([10, 0, 2, 15]),
+            protocol: PROTOCOL_UDP,
+            ttl: DEFAULT_TTL,
+            id: 42,
+            more_fragments,
+            fragment_offset: offset,
+            payload,
+        }
+    }
+
+    #[test_case]
+    fn ipv4_header_round_trip() {
+        let bytes = fragment(16, true, &[1, 2, 3, 4, 5, 6, 7, 8]).to_bytes();
+        assert_eq!(internet_checksum(&bytes[..HEADER_SIZE]), 0);
+        let parsed = Ipv4Packet::parse(&bytes).unwrap();
+        assert_eq!(parsed.src, Ipv4Addr([10, 0, 2, 2]));
+        assert_eq!(parsed.protocol, PROTOCOL_UDP);
+        assert_eq!(parsed.id, 42);
+        assert!(parsed.more_fragments);
+        assert_eq!(parsed.fragment_offset, 16);
+        assert_eq!(parsed.payload, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut broken = bytes.clone();
+        broken[8] ^= 1;
+        assert!(Ipv4Packet::parse(&broken).is_err());
+    }
+
+    #[test_case]
+    fn reassemble_out_of_order_fragments() {
+        let payload: Vec<u8> = (0..20).collect();
+        let mut r = Reassembler::new();
+        let now = Duration::from_secs(1);
+        assert_eq!(r.add(&fragment(16, false, &payload[16..]), now), None);
+        assert_eq!(r.add(&fragment(0, true, &payload[..8]), now), None);
+        assert_eq!(
+            r.add(&fragment(8, true, &payload[8..16]), now),
+            Some(payload)
+        );
+        assert!(r.entries.is_empty());
+        // 期限を過ぎた断片は捨てられる
+        assert_eq!(r.add(&fragment(8, false, &[0; 4]), now), None);
+        assert_eq!(
+            r.add(&fragment(0, true, &[0; 8]), now + REASSEMBLY_TIMEOUT),
+            None
+        );
+    }
+}
@@ -0,0 +1,321 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use core::time::Duration;
+
+use crate::condvar::CondVar;
+use crate::executor::AtomicWaker;
+use crate::info;
+use crate::kthread;
+use crate::mutex::Mutex;
+use crate::net::ipv4;
+use crate::net::ipv4::internet_checksum;
+use crate::net::ipv4::register_protocol_handler;
+use crate::net::ipv4::Ipv4Packet;
+use crate::net::ipv4::PROTOCOL_UDP;
+use crate::net::Interface;
+use crate::net::Ipv4Addr;
+use crate::result::Result;
+use crate::trace;
+use crate::warn;
+
+pub const HEADER_SIZE: usize = 8;
+// bind(0)で割り当てるポートの範囲 (RFC 6335 の動的ポート)
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+// 読まれずに溜まったデータグラムがこれを超えたら、新しく来たものを捨てる
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+// 擬似ヘッダを含めたチェックサム。受け取ったセグメントならチェックサム欄ごと計算して0になれば正しい
+fn pseudo_header_checksum(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut data = Vec::with_capacity(12 + segment.len());
+    data.extend_from_slice(&src.0);
+    data.extend_from_slice(&dst.0);
+    data.extend_from_slice(&[0, PROTOCOL_UDP]);
+    data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    data.extend_from_slice(segment);
+    internet_checksum(&data)
+}
+
+pub fn build_segment(
+    src: Ipv4Addr,
+    src_port: u16,
+    dst: Ipv4Addr,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let len = HEADER_SIZE + payload.len();
+    let mut segment = Vec::with_capacity(len);
+    segment.extend_from_slice(&src_port.to_be_bytes());
+    segment.extend_from_slice(&dst_port.to_be_bytes());
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(payload);
+    // 0は「計算していない」の意味なので、代わりに0xffffを入れる
+    let sum = match pseudo_header_checksum(src, dst, &segment) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    segment[6..8].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+// (宛先ポート, データグラム) を返す
+pub fn parse_segment(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) -> Result<(u16, Datagram)> {
+    if segment.len() < HEADER_SIZE {
+        return Err("UDP segment too short");
+    }
+    let be16 = |i: usize| u16::from_be_bytes([segment[i], segment[i + 1]]);
+    let len = be16(4) as usize;
+    if len < HEADER_SIZE || len > segment.len() {
+        return Err("Invalid UDP length");
+    }
+    let segment = &segment[..len];
+    if be16(6) != 0 && pseudo_header_checksum(src, dst, segment) != 0 {
+        return Err("UDP checksum mismatch");
+    }
+    Ok((
+        be16(2),
+        Datagram {
+            src,
+            src_port: be16(0),
+            data: segment[HEADER_SIZE..].to_vec(),
+        },
+    ))
+}
+
+struct SocketInner {
+    port: u16,
+    queue: Mutex<VecDeque<Datagram>>,
+    // 同期版の受信で待つタスクと、非同期版の受信で待つFuture
+    readable: CondVar,
+    waker: AtomicWaker,
+}
+
+impl SocketInner {
+    fn deliver(&self, datagram: Datagram) {
+        {
+            let mut queue = self.queue.lock();
+            if queue.len() >= MAX_QUEUED_DATAGRAMS {
+                trace!("udp: port {} queue is full", self.port);
+                return;
+            }
+            queue.push_back(datagram);
+        }
+        self.readable.notify_one();
+        self.waker.wake();
+    }
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Arc<SocketInner>>> = Mutex::new(BTreeMap::new());
+
+pub struct UdpSocket {
+    inner: Arc<SocketInner>,
+    // Noneならrecv_fromは届くまで待ち続ける
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl UdpSocket {
+    // port=0なら空いている動的ポートを割り当てる
+    pub fn bind(port: u16) -> Result<Self> {
+        let mut sockets = SOCKETS.lock();
+        let port = if port == 0 {
+            EPHEMERAL_PORTS
+                .clone()
+                .find(|p| !sockets.contains_key(p))
+                .ok_or("No ephemeral UDP port is available")?
+        } else if sockets.contains_key(&port) {
+            return Err("UDP port is already in use");
+        } else {
+            port
+        };
+        let inner = Arc::new(SocketInner {
+            port,
+            queue: Mutex::new(VecDeque::new()),
+            readable: CondVar::new(),
+            waker: AtomicWaker::new(),
+        });
+        sockets.insert(port, inner.clone());
+        Ok(Self {
+            inner,
+            read_timeout: Mutex::new(None),
+        })
+    }
+    pub fn local_port(&self) -> u16 {
+        self.inner.port
+    }
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *self.read_timeout.lock() = timeout;
+    }
+    pub fn send_to(&self, data: &[u8], dst: Ipv4Addr, dst_port: u16) -> Result<usize> {
+        let (iface, _) = ipv4::route(dst)?;
+        let src = iface.ipv4_addr().ok_or("Interface has no IPv4 address")?;
+        let segment = build_segment(src, self.inner.port, dst, dst_port, data);
+        ipv4::send(dst, PROTOCOL_UDP, &segment)?;
+        Ok(data.len())
+    }
+    // bufに入りきらない部分は捨てる。(読んだ長さ, 送信元, 送信元ポート) を返す
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Ipv4Addr, u16)> {
+        let datagram = self.inner.queue.lock().pop_front()?;
+        Some(copy_out(datagram, buf))
+    }
+    // データグラムが届くか、read_timeoutを過ぎるまで止まる
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Ipv4Addr, u16)> {
+        let timeout = *self.read_timeout.lock();
+        let queue = self.inner.queue.lock();
+        let mut queue = match timeout {
+            Some(timeout) => {
+                let (queue, timed_out) =
+                    self.inner
+                        .readable
+                        .wait_timeout_while(queue, timeout, |q| q.is_empty());
+                if timed_out {
+                    return Err("UDP receive timed out");
+                }
+                queue
+            }
+            None => self.inner.readable.wait_while(queue, |q| q.is_empty()),
+        };
+        let datagram = queue.pop_front().ok_or("UDP queue is empty")?;
+        drop(queue);
+        Ok(copy_out(datagram, buf))
+    }
+    pub fn recv_from_async<'a>(&'a self, buf: &'a mut [u8]) -> RecvFrom<'a> {
+        RecvFrom { socket: self, buf }
+    }
+}
+
+fn copy_out(datagram: Datagram, buf: &mut [u8]) -> (usize, Ipv4Addr, u16) {
+    let len = datagram.data.len().min(buf.len());
+    buf[..len].copy_from_slice(&datagram.data[..len]);
+    (len, datagram.src, datagram.src_port)
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.inner.port);
+    }
+}
+
+pub struct RecvFrom<'a> {
+    socket: &'a UdpSocket,
+    buf: &'a mut [u8],
+}
+
+impl Future for RecvFrom<'_> {
+    type Output = Result<(usize, Ipv4Addr, u16)>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(r) = this.socket.try_recv_from(this.buf) {
+            return Poll::Ready(Ok(r));
+        }
+        this.socket.inner.waker.register(cx.waker());
+        // 登録する前に届いていたかもしれないので、もう一度見る
+        match this.socket.try_recv_from(this.buf) {
+            Some(r) => Poll::Ready(Ok(r)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+fn handle_udp(_iface: &Arc<Interface>, packet: &Ipv4Packet) {
+    let (dst_port, datagram) = match parse_segment(packet.src, packet.dst, packet.payload) {
+        Ok(v) => v,
+        Err(e) => {
+            trace!("udp: {e}");
+            return;
+        }
+    };
+    let socket = SOCKETS.lock().get(&dst_port).cloned();
+    match socket {
+        Some(socket) => socket.deliver(datagram),
+        None => {
+            trace!("udp: no socket on port {dst_port}");
+        }
+    }
+}
+
+// 受け取ったデータグラムを送り主に返し続けるスレッドを動かす
+// 1つのソケットで全部のクライアントに応え、届くまでは止まっている
+pub fn start_echo_server(port: u16) -> Result<()> {
+    let socket = UdpSocket::bind(port)?;
+    info!("udp: echo server listening on port {}", socket.local_port());
+    kthread::spawn("udp-echo", move || {
+        let mut buf = [0u8; 2048];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, src, src_port)) => {
+                    if let Err(e) = socket.send_to(&buf[..len], src, src_port) {
+                        warn!("udp: echo to {src}:{src_port} failed: {e}");
+                    }
+                }
+                Err(e) => {
+                    warn!("udp: echo server: {e}");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+pub fn init_udp() {
+    if let Err(e) = register_protocol_handler(PROTOCOL_UDP, handle_udp) {
+        warn!("udp: {e}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+    const DST: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+
+    #[test_case]
+    fn udp_segment_round_trip() {
+        let segment = build_segment(SRC, 53, DST, 40000, b"hello");
+        let (dst_port, datagram) = parse_segment(SRC, DST, &segment).unwrap();
+        assert_eq!(dst_port, 40000);
+        assert_eq!(datagram.src_port, 53);
+        assert_eq!(datagram.data, b"hello");
+        // 擬似ヘッダのアドレスが違えばチェックサムが合わない
+        assert!(parse_segment(SRC, Ipv4Addr([10, 0, 2, 16]), &segment).is_err());
+    }
+
+    #[test_case]
+    fn bound_socket_receives_datagrams() {
+        let socket = UdpSocket::bind(0).unwrap();
+        assert!(EPHEMERAL_PORTS.contains(&socket.local_port()));
+        assert!(UdpSocket::bind(socket.local_port()).is_err());
+        let segment = build_segment(SRC, 7, DST, socket.local_port(), b"ping");
+        let (dst_port, datagram) = parse_segment(SRC, DST, &segment).unwrap();
+        SOCKETS
+            .lock()
+            .get(&dst_port)
+            .cloned()
+            .unwrap()
+            .deliver(datagram);
+        let mut buf = [0u8; 2];
+        assert_eq!(socket.recv_from(&mut buf), Ok((2, SRC, 7)));
+        assert_eq!(&buf, b"pi");
+        assert_eq!(socket.try_recv_from(&mut buf), None);
+        socket.set_read_timeout(Some(Duration::from_millis(10)));
+        assert!(socket.recv_from(&mut buf).is_err());
+        let port = socket.local_port();
+        drop(socket);
+        assert!(UdpSocket::bind(port).is_ok());
+    }
+}
@@ -0,0 +1,430 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::time::Duration;
+
+use crate::block::check_block_range;
+use crate::block::register_block_device;
+use crate::block::BlockDevice;
+use crate::dma::DmaBuffer;
+use crate::error;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::pci::MmioRegion;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::PAGE_SIZE;
+
+// NVM Express Base Specification 2.0
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_INTMS: usize = 0x0C;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELL_BASE: usize = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+// I/O Submission Queue Entry Size = 2^6, I/O Completion Queue Entry Size = 2^4
+const CC_IOSQES_IOCQES: u32 = (6 << 16) | (4 << 20);
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const ADMIN_OPCODE_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_OPCODE_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_OPCODE_IDENTIFY: u8 = 0x06;
+const NVM_OPCODE_WRITE: u8 = 0x01;
+const NVM_OPCODE_READ: u8 = 0x02;
+
+const IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+const IDENTIFY_CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const ADMIN_QUEUE_SIZE: u16 = 16;
+const IO_QUEUE_SIZE: u16 = 64;
+const IO_QUEUE_ID: u16 = 1;
+// PRPリストを使わずに済むように、1コマンドあたり2ページまでに分割する
+const MAX_TRANSFER_SIZE: usize = PAGE_SIZE * 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct SubmissionQueueEntry {
+    cdw0: u32,
+    nsid: u32,
+    _cdw2: u32,
+    _cdw3: u32,
+    _mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+const _: () = assert!(size_of::<SubmissionQueueEntry>() == 64);
+
+impl SubmissionQueueEntry {
+    fn new(opcode: u8) -> Self {
+        Self {
+            cdw0: opcode as u32,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct CompletionQueueEntry {
+    dw0: u32,
+    _dw1: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+const _: () = assert!(size_of::<CompletionQueueEntry>() == 16);
+
+impl CompletionQueueEntry {
+    fn phase(&self) -> bool {
+        self.status & 1 != 0
+    }
+    fn status_code(&self) -> u16 {
+        self.status >> 1
+    }
+}
+
+struct QueuePair {
+    id: u16,
+    size: u16,
+    sq: DmaBuffer,
+    cq: DmaBuffer,
+    sq_tail: u16,
+    cq_head: u16,
+    // 完了キューのエントリが新しいかどうかはPhase Tagで判定する
+    phase: bool,
+    next_cid: u16,
+}
+
+impl QueuePair {
+    fn new(id: u16, size: u16) -> Result<Self> {
+        Ok(Self {
+            id,
+            size,
+            sq: DmaBuffer::new(size as usize * size_of::<SubmissionQueueEntry>(), PAGE_SIZE)?,
+            cq: DmaBuffer::new(size as usize * size_of::<CompletionQueueEntry>(), PAGE_SIZE)?,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_cid: 0,
+        })
+    }
+    // コントローラを止めた後に呼ぶ。返ってきていないコマンドの完了はもう来ない
+    fn clear(&mut self) {
+        self.sq_tail = 0;
+        self.cq_head = 0;
+        self.phase = true;
+        self.cq.as_mut_slice().fill(0);
+    }
+}
+
+const ERR_COMMAND_TIMED_OUT: &str = "nvme: command timed out";
+
+pub struct Nvme {
+    regs: MmioRegion,
+    doorbell_stride: usize,
+    timeout: Duration,
+    admin: QueuePair,
+    io: QueuePair,
+    nsid: u32,
+    block_size: usize,
+    num_of_blocks: u64,
+    bounce: DmaBuffer,
+    // タイムアウトから戻せなかった。デバイスがまだbounceを読み書きするかもしれないので使わない
+    failed: bool,
+}
+unsafe impl Send for Nvme {}
+
+impl Nvme {
+    fn new(pci: &PciDevice) -> Result<Self> {
+        pci.enable_memory_space();
+        pci.enable_bus_master();
+        let regs = pci.bar(0)?.map()?;
+        let cap = regs.read_u64(REG_CAP);
+        let vs = regs.read_u32(REG_VS);
+        info!(
+            "nvme: version {}.{}.{}",
+            vs >> 16,
+            (vs >> 8) & 0xFF,
+            vs & 0xFF
+        );
+        let max_queue_entries = (cap & 0xFFFF) as u16 + 1;
+        let mut nvme = Self {
+            regs,
+            doorbell_stride: 4 << ((cap >> 32) & 0xF),
+            timeout: Duration::from_millis(500 * ((cap >> 24) & 0xFF).max(1)),
+            admin: QueuePair::new(0, ADMIN_QUEUE_SIZE.min(max_queue_entries))?,
+            io: QueuePair::new(IO_QUEUE_ID, IO_QUEUE_SIZE.min(max_queue_entries))?,
+            nsid: 0,
+            block_size: 0,
+            num_of_blocks: 0,
+            bounce: DmaBuffer::new(MAX_TRANSFER_SIZE, PAGE_SIZE)?,
+            failed: false,
+        };
+        nvme.reset()?;
+        nvme.identify()?;
+        nvme.create_io_queues()?;
+        Ok(nvme)
+    }
+    fn wait_ready(&self, ready: bool) -> Result<()> {
+        let deadline = global_timestamp() + self.timeout;
+        loop {
+            let csts = self.regs.read_u32(REG_CSTS);
+            if csts & CSTS_FATAL != 0 {
+                return Err("nvme: controller fatal status");
+            }
+            if (csts & CSTS_READY != 0) == ready {
+                return Ok(());
+            }
+            if global_timestamp() > deadline {
+                return Err("nvme: timed out waiting for CSTS.RDY");
+            }
+            busy_loop_hint();
+        }
+    }
+    // コントローラを止めてAdmin Queueを設定し直してから有効にする
+    fn reset(&mut self) -> Result<()> {
+        let cc = self.regs.read_u32(REG_CC);
+        self.regs.write_u32(REG_CC, cc & !CC_ENABLE);
+        self.wait_ready(false)?;
+        // 止めるとI/O Queueも消える
+        self.admin.clear();
+        self.io.clear();
+        // 割り込みは使わずポーリングする
+        self.regs.write_u32(REG_INTMS, 0xFFFF_FFFF);
+        let qsize = (self.admin.size - 1) as u32;
+        self.regs.write_u32(REG_AQA, (qsize << 16) | qsize);
+        self.regs.write_u64(REG_ASQ, self.admin.sq.phys_addr());
+        self.regs.write_u64(REG_ACQ, self.admin.cq.phys_addr());
+        self.regs.write_u32(REG_CC, CC_IOSQES_IOCQES | CC_ENABLE);
+        self.wait_ready(true)
+    }
+    fn doorbell_offset(&self, qid: u16, is_completion: bool) -> usize {
+        DOORBELL_BASE + (2 * qid as usize + is_completion as usize) * self.doorbell_stride
+    }
+    fn submit_and_wait(
+        &mut self,
+        is_admin: bool,
+        mut cmd: SubmissionQueueEntry,
+    ) -> Result<CompletionQueueEntry> {
+        let deadline = global_timestamp() + self.timeout;
+        let regs = self.regs;
+        let sq_doorbell;
+        let cq_doorbell;
+        let q = if is_admin {
+            sq_doorbell = self.doorbell_offset(0, false);
+            cq_doorbell = self.doorbell_offset(0, true);
+            &mut self.admin
+        } else {
+            sq_doorbell = self.doorbell_offset(IO_QUEUE_ID, false);
+            cq_doorbell = self.doorbell_offset(IO_QUEUE_ID, true);
+            &mut self.io
+        };
+        let cid = q.next_cid;
+        q.next_cid = q.next_cid.wrapping_add(1);
+        cmd.cdw0 |= (cid as u32) << 16;
+        unsafe {
+            write_volatile(
+                (q.sq.as_ptr() as *mut SubmissionQueueEntry).add(q.sq_tail as usize),
+                cmd,
+            );
+        }
+        q.sq_tail = (q.sq_tail + 1) % q.size;
+        regs.write_u32(sq_doorbell, q.sq_tail as u32);
+        loop {
+            let entry = unsafe {
+                read_volatile(
+                    (q.cq.as_ptr() as *const CompletionQueueEntry).add(q.cq_head as usize),
+                )
+            };
+            if entry.phase() == q.phase {
+                q.cq_head = (q.cq_head + 1) % q.size;
+                if q.cq_head == 0 {
+                    q.phase = !q.phase;
+                }
+                regs.write_u32(cq_doorbell, q.cq_head as u32);
+                if entry.cid != cid {
+                    continue;
+                }
+                return if entry.status_code() == 0 {
+                    Ok(entry)
+                } else {
+                    Err("nvme: command failed")
+                };
+            }
+            if global_timestamp() > deadline {
+                return Err(ERR_COMMAND_TIMED_OUT);
+            }
+            busy_loop_hint();
+        }
+    }
+    fn ascii_field(b: &[u8]) -> &str {
+        core::str::from_utf8(b).map(|s| s.trim_end()).unwrap_or("?")
+    }
+    fn identify_raw(&mut self, cns: u32, nsid: u32) -> Result<DmaBuffer> {
+        let buf = DmaBuffer::new(PAGE_SIZE, PAGE_SIZE)?;
+        let mut cmd = SubmissionQueueEntry::new(ADMIN_OPCODE_IDENTIFY);
+        cmd.nsid = nsid;
+        cmd.prp1 = buf.phys_addr();
+        cmd.cdw10 = cns;
+        self.submit_and_wait(true, cmd)?;
+        Ok(buf)
+    }
+    fn identify(&mut self) -> Result<()> {
+        let ctrl = self.identify_raw(IDENTIFY_CNS_CONTROLLER, 0)?;
+        let serial = Self::ascii_field(&ctrl.as_slice()[4..24]);
+        let model = Self::ascii_field(&ctrl.as_slice()[24..64]);
+        info!("nvme: model {model}, serial {serial}");
+        // 有効なネームスペースのうち最初のものを使う
+        let list = self.identify_raw(IDENTIFY_CNS_ACTIVE_NAMESPACES, 0)?;
+        let nsid = u32::from_le_bytes(list.as_slice()[0..4].try_into().unwrap());
+        if nsid == 0 {
+            return Err("nvme: no active namespace");
+        }
+        let ns = self.identify_raw(IDENTIFY_CNS_NAMESPACE, nsid)?;
+        let ns = ns.as_slice();
+        let nsze = u64::from_le_bytes(ns[0..8].try_into().unwrap());
+        let flbas = (ns[26] & 0xF) as usize;
+        let lbaf = u32::from_le_bytes(ns[128 + flbas * 4..132 + flbas * 4].try_into().unwrap());
+        let lbads = (lbaf >> 16) & 0xFF;
+        if !(9..=12).contains(&lbads) {
+            return Err("nvme: unsupported LBA size");
+        }
+        self.nsid = nsid;
+        self.block_size = 1 << lbads;
+        self.num_of_blocks = nsze;
+        info!(
+            "nvme: namespace {nsid}: {} blocks x {} bytes",
+            self.num_of_blocks, self.block_size
+        );
+        Ok(())
+    }
+    fn create_io_queues(&mut self) -> Result<()> {
+        let qsize = (self.io.size - 1) as u32;
+        let mut cmd = SubmissionQueueEntry::new(ADMIN_OPCODE_CREATE_IO_CQ);
+        cmd.prp1 = self.io.cq.phys_addr();
+        cmd.cdw10 = (qsize << 16) | self.io.id as u32;
+        // Physically Contiguous, 割り込みは無効
+        cmd.cdw11 = 1;
+        self.submit_and_wait(true, cmd)?;
+        let mut cmd = SubmissionQueueEntry::new(ADMIN_OPCODE_CREATE_IO_SQ);
+        cmd.prp1 = self.io.sq.phys_addr();
+        cmd.cdw10 = (qsize << 16) | self.io.id as u32;
+        cmd.cdw11 = ((self.io.id as u32) << 16) | 1;
+        self.submit_and_wait(true, cmd)?;
+        Ok(())
+    }
+    fn rw_chunk(&mut self, opcode: u8, lba: u64, num_of_blocks: usize) -> Result<()> {
+        let mut cmd = SubmissionQueueEntry::new(opcode);
+        cmd.nsid = self.nsid;
+        cmd.prp1 = self.bounce.phys_addr();
+        if num_of_blocks * self.block_size > PAGE_SIZE {
+            cmd.prp2 = self.bounce.phys_addr() + PAGE_SIZE as u64;
+        }
+        cmd.cdw10 = lba as u32;
+        cmd.cdw11 = (lba >> 32) as u32;
+        cmd.cdw12 = (num_of_blocks - 1) as u32;
+        self.submit_io(cmd)?;
+        Ok(())
+    }
+    // タイムアウトしたコマンドは、まだデバイスがbounceを読み書きしているかもしれない
+    // コントローラをリセットして打ち切ってから使い直す。リセットもできなければ、以後は使わない
+    fn submit_io(&mut self, cmd: SubmissionQueueEntry) -> Result<CompletionQueueEntry> {
+        if self.failed {
+            return Err("nvme: controller is unusable after a timeout");
+        }
+        let result = self.submit_and_wait(false, cmd);
+        if result.is_err_and(|e| e == ERR_COMMAND_TIMED_OUT) {
+            if let Err(e) = self.reset().and_then(|()| self.create_io_queues()) {
+                error!("nvme: failed to recover from a timeout: {e}");
+                self.failed = true;
+            }
+        }
+        result
+    }
+    pub fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        let blocks_per_chunk = MAX_TRANSFER_SIZE / self.block_size;
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER_SIZE).enumerate() {
+            let n = chunk.len() / self.block_size;
+            self.rw_chunk(NVM_OPCODE_READ, lba + (i * blocks_per_chunk) as u64, n)?;
+            chunk.copy_from_slice(&self.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+    pub fn write(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        let blocks_per_chunk = MAX_TRANSFER_SIZE / self.block_size;
+        for (i, chunk) in buf.chunks(MAX_TRANSFER_SIZE).enumerate() {
+            let n = chunk.len() / self.block_size;
+            self.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.rw_chunk(NVM_OPCODE_WRITE, lba + (i * blocks_per_chunk) as u64, n)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct NvmeBlockDevice {
+    nvme: Mutex<Nvme>,
+    block_size: usize,
+    num_of_blocks: u64,
+}
+
+impl BlockDevice for NvmeBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn num_of_blocks(&self) -> u64 {
+        self.num_of_blocks
+    }
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        self.nvme.lock().read(lba, buf)
+    }
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        self.nvme.lock().write(lba, buf)
+    }
+}
+
+pub struct NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        const MATCHES: [PciDeviceMatch; 1] = [PciDeviceMatch::class(0x01, 0x08, Some(0x02))];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        let nvme = Nvme::new(device)?;
+        let dev = NvmeBlockDevice {
+            block_size: nvme.block_size,
+            num_of_blocks: nvme.num_of_blocks,
+            nvme: Mutex::new(nvme),
+        };
+        register_block_device(Arc::new(dev));
+        Ok(())
+    }
+}
+
+pub static NVME_DRIVER: NvmeDriver = NvmeDriver;
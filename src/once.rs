@@ -0,0 +1,174 @@
+use crate::result::Result;
+
+use core::cell::Cell;
+use core::cell::SyncUnsafeCell;
+use core::fmt::Debug;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::panic::Location;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+const UNINITIALIZED: u8 = 0;
+const INITIALIZING: u8 = 1;
+const INITIALIZED: u8 = 2;
+
+// 一度だけ値を設定できる入れ物。設定した後はロックを取らずに読める
+// Mutex<Option<T>>と違って、二度目の設定はパニックせずにエラーになる
+pub struct Once<T> {
+    value: SyncUnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+    created_at_file: &'static str,
+    created_at_line: u32,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    #[track_caller]
+    pub const fn new() -> Self {
+        let location = Location::caller();
+        Self {
+            value: SyncUnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU8::new(UNINITIALIZED),
+            created_at_file: location.file(),
+            created_at_line: location.line(),
+        }
+    }
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == INITIALIZED
+    }
+    // 既に設定されていたら、valueは捨ててエラーを返す
+    pub fn set(&self, value: T) -> Result<()> {
+        let mut value = Some(value);
+        self.try_init(|| value.take().expect("set called twice"))?;
+        Ok(())
+    }
+    // まだ設定されていなければfで作る。どちらの場合も設定された値を返す
+    #[track_caller]
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if let Ok(v) = self.try_init(f) {
+            return v;
+        }
+        for _ in 0..100000 {
+            if let Some(v) = self.get() {
+                return v;
+            }
+            spin_loop();
+        }
+        // CPUは1つなので、待っても終わらないのはfの中から同じOnceを使った時
+        panic!(
+            "Once at {}:{} is initialized recursively, caller: {:?}",
+            self.created_at_file,
+            self.created_at_line,
+            Location::caller(),
+        )
+    }
+    fn try_init(&self, f: impl FnOnce() -> T) -> Result<&T> {
+        if self
+            .state
+            .compare_exchange(
+                UNINITIALIZED,
+                INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return Err("Already initialized");
+        }
+        let value = unsafe { (*self.value.get()).write(f()) };
+        self.state.store(INITIALIZED, Ordering::Release);
+        Ok(value)
+    }
+}
+
+impl<T> Default for Once<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == INITIALIZED {
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+impl<T: Debug> Debug for Once<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Once @ {}:{} = {:?}",
+            self.created_at_file,
+            self.created_at_line,
+            self.get()
+        )
+    }
+}
+
+// 最初に使われた時にfで値を作るグローバル変数
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    #[track_caller]
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+    #[track_caller]
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let init = this.init.take().expect("Lazy is initialized recursively");
+            init()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn once_is_set_only_once() {
+        let once = Once::new();
+        assert_eq!(once.get(), None);
+        assert_eq!(once.set(1), Ok(()));
+        assert!(once.set(2).is_err());
+        assert_eq!(*once.call_once(|| 3), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test_case]
+    fn lazy_runs_init_on_first_use() {
+        static LAZY: Lazy<u64> = Lazy::new(|| 40 + 2);
+        assert_eq!(*LAZY, 42);
+        assert_eq!(*LAZY, 42);
+    }
+}
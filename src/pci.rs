@@ -0,0 +1,1153 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+use crate::acpi::AcpiRsdp;
+use crate::apic::local_apic_id;
+use crate::apic::route_gsi;
+use crate::apic::Polarity;
+use crate::apic::TriggerMode;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::mutex::RwLock;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::allocate_interrupt_vector;
+use crate::x86::free_interrupt_vector;
+use crate::x86::map_mmio;
+use crate::x86::read_io_port_u32;
+use crate::x86::write_io_port_u32;
+use crate::x86::InterruptHandler;
+
+// https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_#1
+const CONFIG_ADDRESS: u16 = 0x0CF8;
+const CONFIG_DATA: u16 = 0x0CFC;
+
+const CONFIG_OFFSET_VENDOR_ID: u16 = 0x00;
+const CONFIG_OFFSET_CLASS_CODE: u16 = 0x08;
+const CONFIG_OFFSET_COMMAND: u16 = 0x04;
+const CONFIG_OFFSET_HEADER_TYPE: u16 = 0x0C;
+const CONFIG_OFFSET_BAR0: u16 = 0x10;
+const CONFIG_OFFSET_BRIDGE_BUS_NUMBERS: u16 = 0x18;
+const CONFIG_OFFSET_CAPABILITIES_POINTER: u16 = 0x34;
+const CONFIG_OFFSET_INTERRUPT: u16 = 0x3C;
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+const STATUS_CAPABILITIES_LIST: u32 = 1 << (16 + 4);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BusDeviceFunction {
+    // bus(8bit) | device(5bit) | function(3bit)
+    id: u16,
+}
+
+impl BusDeviceFunction {
+    pub fn new(bus: usize, device: usize, function: usize) -> Option<Self> {
+        if bus < 256 && device < 32 && function < 8 {
+            Some(Self {
+                id: ((bus << 8) | (device << 3) | function) as u16,
+            })
+        } else {
+            None
+        }
+    }
+    pub fn bus(&self) -> usize {
+        (self.id >> 8) as usize
+    }
+    pub fn device(&self) -> usize {
+        ((self.id >> 3) & 0b11111) as usize
+    }
+    pub fn function(&self) -> usize {
+        (self.id & 0b111) as usize
+    }
+}
+
+impl fmt::Debug for BusDeviceFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02X}:{:02X}.{:01X}",
+            self.bus(),
+            self.device(),
+            self.function()
+        )
+    }
+}
+
+impl fmt::Display for BusDeviceFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+// CONFIG_ADDRESSに書き込む値
+// bit31: enable, bit23-16: bus, bit15-11: device, bit10-8: function, bit7-2: register
+fn config_address(bdf: BusDeviceFunction, offset: u16) -> u32 {
+    (1 << 31) | ((bdf.id as u32) << 8) | (offset as u32 & 0xFC)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ConfigAccessMethod {
+    // レガシーなI/Oポート経由のアクセスでは先頭256バイトまでしか読み書きできない
+    PortIo,
+    // PCIeのECAM(Enhanced Configuration Access Mechanism)
+    // 4KiBの拡張コンフィギュレーション空間全体がメモリにマップされている
+    Ecam {
+        base_address: u64,
+        start_bus: u8,
+        end_bus: u8,
+    },
+}
+
+impl ConfigAccessMethod {
+    fn config_space_size(&self) -> u16 {
+        match self {
+            Self::PortIo => 0x100,
+            Self::Ecam { .. } => 0x1000,
+        }
+    }
+    fn ecam_address(&self, bdf: BusDeviceFunction, offset: u16) -> Option<*mut u32> {
+        match *self {
+            Self::Ecam {
+                base_address,
+                start_bus,
+                end_bus,
+            } if (start_bus as usize..=end_bus as usize).contains(&bdf.bus()) => {
+                // bus(8bit) | device(5bit) | function(3bit) | offset(12bit)
+                let bdf_offset = ((bdf.id as u64) - ((start_bus as u64) << 8)) << 12;
+                Some((base_address + bdf_offset + offset as u64) as *mut u32)
+            }
+            _ => None,
+        }
+    }
+    fn read(&self, bdf: BusDeviceFunction, offset: u16) -> u32 {
+        assert!(offset < self.config_space_size() && offset & 0b11 == 0);
+        if let Some(addr) = self.ecam_address(bdf, offset) {
+            unsafe { read_volatile(addr) }
+        } else if offset < 0x100 {
+            write_io_port_u32(CONFIG_ADDRESS, config_address(bdf, offset));
+            read_io_port_u32(CONFIG_DATA)
+        } else {
+            // ECAMの範囲外のバスの拡張領域は存在しないものとして扱う
+            0xFFFF_FFFF
+        }
+    }
+    fn write(&self, bdf: BusDeviceFunction, offset: u16, data: u32) {
+        assert!(offset < self.config_space_size() && offset & 0b11 == 0);
+        if let Some(addr) = self.ecam_address(bdf, offset) {
+            unsafe { write_volatile(addr, data) }
+        } else if offset < 0x100 {
+            write_io_port_u32(CONFIG_ADDRESS, config_address(bdf, offset));
+            write_io_port_u32(CONFIG_DATA, data)
+        }
+    }
+}
+
+static CONFIG_ACCESS_METHOD: Mutex<ConfigAccessMethod> = Mutex::new(ConfigAccessMethod::PortIo);
+
+// MCFGが見つかればECAMを、なければI/Oポート経由のアクセスを使う
+pub fn init_config_access(acpi: &AcpiRsdp) {
+    let ecam = acpi.mcfg().and_then(|mcfg| {
+        (0..mcfg.num_of_entries())
+            .filter_map(|i| mcfg.entry(i))
+            .find(|e| e.segment_group() == 0)
+    });
+    let method = if let Some(e) = ecam {
+        let num_of_buses = e.end_bus() as u64 - e.start_bus() as u64 + 1;
+        map_mmio(e.base_address(), num_of_buses << 20).expect("Failed to map ECAM region");
+        ConfigAccessMethod::Ecam {
+            base_address: e.base_address(),
+            start_bus: e.start_bus(),
+            end_bus: e.end_bus(),
+        }
+    } else {
+        ConfigAccessMethod::PortIo
+    };
+    info!("PCI config access method: {method:X?}");
+    *CONFIG_ACCESS_METHOD.lock() = method;
+}
+
+pub fn config_space_size() -> u16 {
+    CONFIG_ACCESS_METHOD.lock().config_space_size()
+}
+
+pub fn read_config(bdf: BusDeviceFunction, offset: u16) -> u32 {
+    let method = *CONFIG_ACCESS_METHOD.lock();
+    method.read(bdf, offset)
+}
+
+pub fn write_config(bdf: BusDeviceFunction, offset: u16, data: u32) {
+    let method = *CONFIG_ACCESS_METHOD.lock();
+    method.write(bdf, offset, data)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VendorDeviceId {
+    pub vendor: u16,
+    pub device: u16,
+}
+
+impl VendorDeviceId {
+    fn from_config_value(value: u32) -> Option<Self> {
+        let vendor = value as u16;
+        // 存在しないデバイスを読むと全ビットが1になる
+        if vendor == 0xFFFF {
+            None
+        } else {
+            Some(Self {
+                vendor,
+                device: (value >> 16) as u16,
+            })
+        }
+    }
+}
+
+impl fmt::Debug for VendorDeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}:{:04X}", self.vendor, self.device)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ClassCode {
+    pub base: u8,
+    pub sub: u8,
+    pub interface: u8,
+}
+
+impl ClassCode {
+    fn from_config_value(value: u32) -> Self {
+        // 下位8bitはRevision ID
+        Self {
+            base: (value >> 24) as u8,
+            sub: (value >> 16) as u8,
+            interface: (value >> 8) as u8,
+        }
+    }
+}
+
+impl fmt::Debug for ClassCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}",
+            self.base, self.sub, self.interface
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderType {
+    Endpoint,
+    PciToPciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+
+impl HeaderType {
+    fn from_config_value(value: u32) -> Self {
+        match (value >> 16) as u8 & 0x7F {
+            0x00 => Self::Endpoint,
+            0x01 => Self::PciToPciBridge,
+            0x02 => Self::CardBusBridge,
+            t => Self::Unknown(t),
+        }
+    }
+}
+
+// メモリにマップされたレジスタ領域
+#[derive(Clone, Copy, Debug)]
+pub struct MmioRegion {
+    base: *mut u8,
+    size: usize,
+}
+
+impl MmioRegion {
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.size,
+            "MMIO access out of range"
+        );
+        unsafe { self.base.add(offset) as *mut T }
+    }
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile(self.ptr(offset)) }
+    }
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { write_volatile(self.ptr(offset), value) }
+    }
+    pub fn subregion(&self, offset: usize, size: usize) -> Result<MmioRegion> {
+        if offset.checked_add(size).ok_or("Overflow")? > self.size {
+            Err("Subregion out of range")
+        } else {
+            Ok(MmioRegion {
+                base: unsafe { self.base.add(offset) },
+                size,
+            })
+        }
+    }
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        self.read(offset)
+    }
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        self.write(offset, value)
+    }
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        self.read(offset)
+    }
+    pub fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value)
+    }
+}
+
+// Base Address Register
+// https://wiki.osdev.org/PCI#Base_Address_Registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory32 {
+        addr: u32,
+        size: u32,
+        prefetchable: bool,
+    },
+    Memory64 {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+const BAR_IO_SPACE: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b110;
+const BAR_TYPE_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+// 全ビット1を書き込んだあとに読み戻した値から領域のサイズを求める
+// 書き換え可能なビットの最下位ビットがサイズになる
+fn bar_size_from_mask(mask: u64) -> u64 {
+    (!mask).wrapping_add(1)
+}
+
+impl Bar {
+    pub fn is_memory(&self) -> bool {
+        !matches!(self, Self::Io { .. })
+    }
+    pub fn is_64bit(&self) -> bool {
+        matches!(self, Self::Memory64 { .. })
+    }
+    pub fn is_prefetchable(&self) -> bool {
+        match self {
+            Self::Memory32 { prefetchable, .. } | Self::Memory64 { prefetchable, .. } => {
+                *prefetchable
+            }
+            Self::Io { .. } => false,
+        }
+    }
+    pub fn base(&self) -> u64 {
+        match self {
+            Self::Memory32 { addr, .. } => *addr as u64,
+            Self::Memory64 { addr, .. } => *addr,
+            Self::Io { port, .. } => *port as u64,
+        }
+    }
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Memory32 { size, .. } => *size as u64,
+            Self::Memory64 { size, .. } => *size,
+            Self::Io { size, .. } => *size as u64,
+        }
+    }
+    pub fn map(&self) -> Result<MmioRegion> {
+        if !self.is_memory() {
+            return Err("I/O space BAR cannot be mapped");
+        }
+        if self.base() == 0 || self.size() == 0 {
+            return Err("BAR is not assigned");
+        }
+        Ok(MmioRegion {
+            base: map_mmio(self.base(), self.size())?,
+            size: self.size() as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PciDevice {
+    bdf: BusDeviceFunction,
+    id: VendorDeviceId,
+    class: ClassCode,
+    revision: u8,
+    header_type: HeaderType,
+    is_multi_function: bool,
+    // このデバイスの上流にあるPCI-to-PCIブリッジ
+    parent_bridge: Option<BusDeviceFunction>,
+}
+
+impl PciDevice {
+    pub fn probe(bdf: BusDeviceFunction) -> Option<Self> {
+        let id = VendorDeviceId::from_config_value(read_config(bdf, CONFIG_OFFSET_VENDOR_ID))?;
+        let class = read_config(bdf, CONFIG_OFFSET_CLASS_CODE);
+        let header = read_config(bdf, CONFIG_OFFSET_HEADER_TYPE);
+        Some(Self {
+            bdf,
+            id,
+            class: ClassCode::from_config_value(class),
+            revision: class as u8,
+            header_type: HeaderType::from_config_value(header),
+            is_multi_function: header & (1 << 23) != 0,
+            parent_bridge: None,
+        })
+    }
+    pub fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+    pub fn id(&self) -> VendorDeviceId {
+        self.id
+    }
+    pub fn vendor_id(&self) -> u16 {
+        self.id.vendor
+    }
+    pub fn device_id(&self) -> u16 {
+        self.id.device
+    }
+    pub fn class(&self) -> ClassCode {
+        self.class
+    }
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+    pub fn header_type(&self) -> HeaderType {
+        self.header_type
+    }
+    pub fn is_multi_function(&self) -> bool {
+        self.is_multi_function
+    }
+    pub fn parent_bridge(&self) -> Option<BusDeviceFunction> {
+        self.parent_bridge
+    }
+    pub fn is_pci_to_pci_bridge(&self) -> bool {
+        self.header_type == HeaderType::PciToPciBridge
+    }
+    // ブリッジの下流側のバス番号 (primary, secondary, subordinate)
+    pub fn bridge_bus_numbers(&self) -> Option<(u8, u8, u8)> {
+        if !self.is_pci_to_pci_bridge() {
+            return None;
+        }
+        let v = self.read_config(CONFIG_OFFSET_BRIDGE_BUS_NUMBERS);
+        Some((v as u8, (v >> 8) as u8, (v >> 16) as u8))
+    }
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.bdf, offset)
+    }
+    pub fn write_config(&self, offset: u16, data: u32) {
+        write_config(self.bdf, offset, data)
+    }
+    pub fn capabilities(&self) -> CapabilityIterator {
+        CapabilityIterator::new(*self)
+    }
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|c| c.id() == id)
+    }
+    pub fn msi(&self) -> Option<MsiCapability> {
+        self.find_capability(CAPABILITY_ID_MSI).map(MsiCapability)
+    }
+    pub fn msix(&self) -> Option<MsixCapability> {
+        self.find_capability(CAPABILITY_ID_MSIX).map(MsixCapability)
+    }
+    pub fn power_management(&self) -> Option<PowerManagementCapability> {
+        self.find_capability(CAPABILITY_ID_POWER_MANAGEMENT)
+            .map(PowerManagementCapability)
+    }
+    fn command(&self) -> u32 {
+        self.read_config(CONFIG_OFFSET_COMMAND) & 0xFFFF
+    }
+    // 上位16bitのStatusレジスタは1を書き込むとクリアされるビットがあるので0を書き込む
+    fn set_command(&self, command: u32) {
+        self.write_config(CONFIG_OFFSET_COMMAND, command & 0xFFFF)
+    }
+    fn update_command(&self, set: u32, clear: u32) {
+        self.set_command((self.command() & !clear) | set)
+    }
+    // DMAを行うデバイスはこれを有効にしないとメモリにアクセスできない
+    pub fn enable_bus_master(&self) {
+        self.update_command(COMMAND_BUS_MASTER, 0)
+    }
+    pub fn enable_memory_space(&self) {
+        self.update_command(COMMAND_MEMORY_SPACE, 0)
+    }
+    pub fn enable_io_space(&self) {
+        self.update_command(COMMAND_IO_SPACE, 0)
+    }
+    pub fn is_bus_master_enabled(&self) -> bool {
+        self.command() & COMMAND_BUS_MASTER != 0
+    }
+    // MSI/MSI-Xを使うときはレガシーなINTxを止めておく
+    pub fn disable_intx(&self) {
+        self.update_command(COMMAND_INTERRUPT_DISABLE, 0)
+    }
+    // 0: なし, 1-4: INTA#-INTD#
+    pub fn interrupt_pin(&self) -> u8 {
+        (self.read_config(CONFIG_OFFSET_INTERRUPT) >> 8) as u8
+    }
+    // ファームウェアが設定したIRQ番号
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_config(CONFIG_OFFSET_INTERRUPT) as u8
+    }
+    // Interrupt LineがそのままGSIになっているものとしてIOAPICに繋ぐ
+    // PCIの割り込みはレベルトリガ・アクティブローなので、ハンドラ内でデバイス側の要因を消すこと
+    pub fn enable_intx(&self, handler: InterruptHandler) -> Result<u8> {
+        if self.interrupt_pin() == 0 {
+            return Err("Device does not use INTx");
+        }
+        let line = self.interrupt_line();
+        if line == 0xFF {
+            return Err("Interrupt line is not assigned");
+        }
+        let vector = route_gsi(
+            line as u32,
+            TriggerMode::Level,
+            Polarity::ActiveLow,
+            handler,
+        )?;
+        self.update_command(0, COMMAND_INTERRUPT_DISABLE);
+        Ok(vector)
+    }
+    pub fn num_of_bars(&self) -> usize {
+        match self.header_type {
+            HeaderType::Endpoint => 6,
+            HeaderType::PciToPciBridge => 2,
+            _ => 0,
+        }
+    }
+    // BARに全ビット1を書き込み、読み戻した後に元の値に戻す
+    fn probe_bar_mask(&self, offset: u16) -> u32 {
+        let original = self.read_config(offset);
+        self.write_config(offset, 0xFFFF_FFFF);
+        let mask = self.read_config(offset);
+        self.write_config(offset, original);
+        mask
+    }
+    // 上位半分の値は下位ビットが何でもよいので、1つ前だけを見ても区別できない。BAR0から順にたどる
+    fn is_upper_half_of_64bit_bar(&self, index: usize) -> bool {
+        let mut i = 0;
+        while i < index {
+            let value = self.read_config(CONFIG_OFFSET_BAR0 + i as u16 * 4);
+            let is_64bit = value & BAR_IO_SPACE == 0 && value & BAR_TYPE_MASK == BAR_TYPE_64;
+            i += if is_64bit { 2 } else { 1 };
+        }
+        i > index
+    }
+    pub fn bar(&self, index: usize) -> Result<Bar> {
+        if index >= self.num_of_bars() {
+            return Err("BAR index out of range");
+        }
+        if self.is_upper_half_of_64bit_bar(index) {
+            return Err("BAR is the upper half of a 64-bit BAR");
+        }
+        let offset = CONFIG_OFFSET_BAR0 + index as u16 * 4;
+        let value = self.read_config(offset);
+        // サイズを調べている間はデコードを止めておく
+        let command = self.command();
+        self.update_command(0, COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE);
+        let bar = if value & BAR_IO_SPACE != 0 {
+            let mask = self.probe_bar_mask(offset) & !0b11;
+            Ok(Bar::Io {
+                port: (value & !0b11) as u16,
+                size: bar_size_from_mask(mask as u64 | 0xFFFF_FFFF_FFFF_0000) as u16,
+            })
+        } else {
+            let prefetchable = value & BAR_PREFETCHABLE != 0;
+            match value & BAR_TYPE_MASK {
+                0b000 => {
+                    let mask = self.probe_bar_mask(offset) & !0b1111;
+                    Ok(Bar::Memory32 {
+                        addr: value & !0b1111,
+                        size: bar_size_from_mask(mask as u64 | 0xFFFF_FFFF_0000_0000) as u32,
+                        prefetchable,
+                    })
+                }
+                BAR_TYPE_64 if index + 1 < self.num_of_bars() => {
+                    let upper = self.read_config(offset + 4);
+                    let mask_lower = self.probe_bar_mask(offset) & !0b1111;
+                    let mask_upper = self.probe_bar_mask(offset + 4);
+                    Ok(Bar::Memory64 {
+                        addr: ((upper as u64) << 32) | (value & !0b1111) as u64,
+                        size: bar_size_from_mask(((mask_upper as u64) << 32) | mask_lower as u64),
+                        prefetchable,
+                    })
+                }
+                _ => Err("Unsupported BAR type"),
+            }
+        };
+        self.set_command(command);
+        bar
+    }
+}
+
+impl fmt::Debug for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PciDevice {{ {:?} id: {:?}, class: {:?}, rev: {:#04X}, header: {:?} }}",
+            self.bdf, self.id, self.class, self.revision, self.header_type
+        )
+    }
+}
+
+// バス0から始めて、PCI-to-PCIブリッジの先のバスを再帰的にたどる
+// バス番号はファームウェアが割り当て済みであることを前提とする
+pub struct HierarchicalScanner {
+    devices: Vec<PciDevice>,
+    visited_buses: [bool; 256],
+}
+
+impl HierarchicalScanner {
+    pub fn scan() -> Vec<PciDevice> {
+        let mut scanner = Self {
+            devices: Vec::new(),
+            visited_buses: [false; 256],
+        };
+        let host_bridge = BusDeviceFunction::new(0, 0, 0).and_then(PciDevice::probe);
+        if host_bridge.map(|d| d.is_multi_function()).unwrap_or(false) {
+            // ホストブリッジが複数ある場合はfunction番号がそのままルートのバス番号になる
+            for function in 0..8 {
+                if BusDeviceFunction::new(0, 0, function)
+                    .and_then(PciDevice::probe)
+                    .is_some()
+                {
+                    scanner.scan_bus(function as u8, None);
+                }
+            }
+        } else {
+            scanner.scan_bus(0, None);
+        }
+        scanner.devices
+    }
+    fn scan_bus(&mut self, bus: u8, parent_bridge: Option<BusDeviceFunction>) {
+        if self.visited_buses[bus as usize] {
+            return;
+        }
+        self.visited_buses[bus as usize] = true;
+        for device in 0..32 {
+            for function in 0..8 {
+                let Some(mut d) = BusDeviceFunction::new(bus as usize, device, function)
+                    .and_then(PciDevice::probe)
+                else {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                };
+                d.parent_bridge = parent_bridge;
+                self.devices.push(d);
+                if let Some((_, secondary, _)) = d.bridge_bus_numbers() {
+                    // 未設定のブリッジ(secondary == 0)は辿らない
+                    if secondary > bus {
+                        self.scan_bus(secondary, Some(d.bdf()));
+                    }
+                }
+                if function == 0 && !d.is_multi_function() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// 全てのbus/device/functionを総当たりで調べる
+pub struct BruteForceScanner {
+    next: Option<BusDeviceFunction>,
+}
+
+impl BruteForceScanner {
+    pub fn new() -> Self {
+        Self {
+            next: BusDeviceFunction::new(0, 0, 0),
+        }
+    }
+    fn advance(bdf: BusDeviceFunction, skip_functions: bool) -> Option<BusDeviceFunction> {
+        let (bus, device, function) = (bdf.bus(), bdf.device(), bdf.function());
+        if !skip_functions && function < 7 {
+            BusDeviceFunction::new(bus, device, function + 1)
+        } else if device < 31 {
+            BusDeviceFunction::new(bus, device + 1, 0)
+        } else {
+            BusDeviceFunction::new(bus + 1, 0, 0)
+        }
+    }
+}
+
+impl Default for BruteForceScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for BruteForceScanner {
+    type Item = PciDevice;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(bdf) = self.next {
+            let device = PciDevice::probe(bdf);
+            // function 0が存在しない、もしくはシングルファンクションなら残りのfunctionは調べない
+            let skip_functions =
+                bdf.function() == 0 && device.map(|d| !d.is_multi_function()).unwrap_or(true);
+            self.next = Self::advance(bdf, skip_functions);
+            if device.is_some() {
+                return device;
+            }
+        }
+        None
+    }
+}
+
+// https://wiki.osdev.org/PCI#IRQ_Handling
+pub const CAPABILITY_ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_ID_MSI: u8 = 0x05;
+pub const CAPABILITY_ID_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_ID_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_ID_MSIX: u8 = 0x11;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Capability {
+    device: PciDevice,
+    offset: u16,
+    id: u8,
+    next: u8,
+}
+
+impl Capability {
+    fn read(device: PciDevice, offset: u16) -> Self {
+        let header = device.read_config(offset);
+        Self {
+            device,
+            offset,
+            id: header as u8,
+            next: (header >> 8) as u8 & !0b11,
+        }
+    }
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+    pub fn device(&self) -> &PciDevice {
+        &self.device
+    }
+    // ケーパビリティの先頭からの相対オフセットでアクセスする
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        self.device.read_config(self.offset + offset)
+    }
+    pub fn write_u32(&self, offset: u16, data: u32) {
+        self.device.write_config(self.offset + offset, data)
+    }
+    // 先頭のdwordの上位16bitはケーパビリティ固有のMessage Controlなどに使われる
+    fn control(&self) -> u16 {
+        (self.read_u32(0) >> 16) as u16
+    }
+    fn set_control(&self, control: u16) {
+        let header = self.read_u32(0) & 0xFFFF;
+        self.write_u32(0, header | (control as u32) << 16)
+    }
+}
+
+pub struct CapabilityIterator {
+    device: PciDevice,
+    next: u8,
+    count: usize,
+}
+
+impl CapabilityIterator {
+    fn new(device: PciDevice) -> Self {
+        let next = if device.read_config(CONFIG_OFFSET_COMMAND) & STATUS_CAPABILITIES_LIST != 0 {
+            device.read_config(CONFIG_OFFSET_CAPABILITIES_POINTER) as u8 & !0b11
+        } else {
+            0
+        };
+        Self {
+            device,
+            next,
+            count: 0,
+        }
+    }
+}
+
+impl Iterator for CapabilityIterator {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 壊れたリストでループし続けないように上限を設ける
+        if self.next < 0x40 || self.count >= 48 {
+            return None;
+        }
+        let cap = Capability::read(self.device, self.next as u16);
+        self.next = cap.next;
+        self.count += 1;
+        Some(cap)
+    }
+}
+
+// https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+// 宛先のLocal APIC IDをbit19-12に入れる
+fn msi_message_address(apic_id: u8) -> u64 {
+    0xFEE0_0000 | ((apic_id as u64) << 12)
+}
+
+// Fixed delivery mode, edge trigger
+fn msi_message_data(vector: u8) -> u32 {
+    vector as u32
+}
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE_MASK: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsiCapability(Capability);
+
+impl MsiCapability {
+    pub fn is_64bit(&self) -> bool {
+        self.0.control() & MSI_CONTROL_64BIT != 0
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.0.control() & MSI_CONTROL_ENABLE != 0
+    }
+    // ベクタを1つ確保してこのデバイスの割り込みを受け取る
+    pub fn enable(&self, handler: InterruptHandler) -> Result<u8> {
+        let vector = allocate_interrupt_vector(handler)?;
+        let address = msi_message_address(local_apic_id());
+        self.0.write_u32(0x04, address as u32);
+        if self.is_64bit() {
+            self.0.write_u32(0x08, (address >> 32) as u32);
+            self.0.write_u32(0x0C, msi_message_data(vector));
+        } else {
+            self.0.write_u32(0x08, msi_message_data(vector));
+        }
+        self.0.device().disable_intx();
+        let control = self.0.control() & !MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE_MASK;
+        self.0.set_control(control | MSI_CONTROL_ENABLE);
+        Ok(vector)
+    }
+    pub fn disable(&self) {
+        self.0.set_control(self.0.control() & !MSI_CONTROL_ENABLE)
+    }
+}
+
+const MSIX_CONTROL_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsixCapability(Capability);
+
+impl MsixCapability {
+    pub fn table_size(&self) -> usize {
+        (self.0.control() & MSIX_CONTROL_TABLE_SIZE_MASK) as usize + 1
+    }
+    fn table_location(&self) -> (usize, usize) {
+        let v = self.0.read_u32(0x04);
+        ((v & 0b111) as usize, (v & !0b111) as usize)
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.0.control() & MSIX_CONTROL_ENABLE != 0
+    }
+    // テーブルの全エントリをマスクした状態でMSI-Xを有効にする
+    pub fn enable(&self) -> Result<MsixTable> {
+        let (bar, offset) = self.table_location();
+        let size = self.table_size();
+        let region = self
+            .0
+            .device()
+            .bar(bar)?
+            .map()?
+            .subregion(offset, size * MSIX_TABLE_ENTRY_SIZE)?;
+        let table = MsixTable { region, size };
+        for i in 0..size {
+            table.mask(i)?;
+        }
+        self.0.device().disable_intx();
+        let control = self.0.control() & !MSIX_CONTROL_FUNCTION_MASK;
+        self.0.set_control(control | MSIX_CONTROL_ENABLE);
+        Ok(table)
+    }
+    pub fn disable(&self) {
+        self.0.set_control(self.0.control() & !MSIX_CONTROL_ENABLE)
+    }
+}
+
+pub struct MsixTable {
+    region: MmioRegion,
+    size: usize,
+}
+
+impl MsixTable {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    fn entry_offset(&self, index: usize) -> Result<usize> {
+        if index < self.size {
+            Ok(index * MSIX_TABLE_ENTRY_SIZE)
+        } else {
+            Err("MSI-X table index out of range")
+        }
+    }
+    pub fn mask(&self, index: usize) -> Result<()> {
+        let ofs = self.entry_offset(index)?;
+        let control = self.region.read_u32(ofs + 12);
+        self.region
+            .write_u32(ofs + 12, control | MSIX_VECTOR_CONTROL_MASKED);
+        Ok(())
+    }
+    pub fn set_vector(&self, index: usize, vector: u8) -> Result<()> {
+        let ofs = self.entry_offset(index)?;
+        let address = msi_message_address(local_apic_id());
+        self.region.write_u32(ofs, address as u32);
+        self.region.write_u32(ofs + 4, (address >> 32) as u32);
+        self.region.write_u32(ofs + 8, msi_message_data(vector));
+        let control = self.region.read_u32(ofs + 12);
+        self.region
+            .write_u32(ofs + 12, control & !MSIX_VECTOR_CONTROL_MASKED);
+        Ok(())
+    }
+    // ベクタを確保してindex番目のエントリに割り当てる
+    pub fn allocate(&self, index: usize, handler: InterruptHandler) -> Result<u8> {
+        self.entry_offset(index)?;
+        let vector = allocate_interrupt_vector(handler)?;
+        self.set_vector(index, vector)?;
+        Ok(vector)
+    }
+    // allocateで確保したものを戻す。エントリは割り込みが来ないようにマスクしておく
+    pub fn free(&self, index: usize, vector: u8) -> Result<()> {
+        self.mask(index)?;
+        free_interrupt_vector(vector);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PowerManagementCapability(Capability);
+
+impl PowerManagementCapability {
+    pub fn power_state(&self) -> PowerState {
+        match self.0.read_u32(0x04) & 0b11 {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+    pub fn set_power_state(&self, state: PowerState) {
+        let pmcsr = self.0.read_u32(0x04) & !0b11;
+        self.0.write_u32(0x04, pmcsr | state as u32)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PciDeviceMatch {
+    Id(VendorDeviceId),
+    // interfaceがNoneならbase/subが一致すればよい
+    Class {
+        base: u8,
+        sub: u8,
+        interface: Option<u8>,
+    },
+}
+
+impl PciDeviceMatch {
+    pub const fn id(vendor: u16, device: u16) -> Self {
+        Self::Id(VendorDeviceId { vendor, device })
+    }
+    pub const fn class(base: u8, sub: u8, interface: Option<u8>) -> Self {
+        Self::Class {
+            base,
+            sub,
+            interface,
+        }
+    }
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            Self::Id(id) => device.id() == id,
+            Self::Class {
+                base,
+                sub,
+                interface,
+            } => {
+                let class = device.class();
+                class.base == base
+                    && class.sub == sub
+                    && interface.map(|i| i == class.interface).unwrap_or(true)
+            }
+        }
+    }
+}
+
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+    fn matches(&self) -> &'static [PciDeviceMatch];
+    // 対応するデバイスが見つかるとバススキャン後に呼ばれる
+    fn probe(&self, device: &PciDevice) -> Result<()>;
+}
+
+static PCI_DRIVERS: RwLock<Vec<&'static dyn PciDriver>> = RwLock::new(Vec::new());
+static PCI_DEVICES: RwLock<Vec<PciDevice>> = RwLock::new(Vec::new());
+
+pub fn register_driver(driver: &'static dyn PciDriver) {
+    info!("PCI driver registered: {}", driver.name());
+    PCI_DRIVERS.write().push(driver);
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    PCI_DEVICES.read().clone()
+}
+
+// バスをスキャンして見つかったデバイスを記録し、対応するドライバのprobeを呼ぶ
+pub fn scan_devices() {
+    *PCI_DEVICES.write() = HierarchicalScanner::scan();
+}
+
+pub fn probe_devices() {
+    let devices = PCI_DEVICES.read().clone();
+    // probeの中からドライバが登録されることもあるので、ロックを持ったまま呼ばない
+    let drivers = PCI_DRIVERS.read().clone();
+    for device in devices.iter() {
+        let driver = drivers
+            .iter()
+            .find(|d| d.matches().iter().any(|m| m.matches(device)));
+        if let Some(driver) = driver {
+            info!("{}: probing {}", device.bdf(), driver.name());
+            if let Err(e) = driver.probe(device) {
+                warn!("{}: {} failed to probe: {}", device.bdf(), driver.name(), e);
+            }
+        }
+    }
+}
+
+pub fn vendor_name(vendor: u16) -> &'static str {
+    match vendor {
+        0x1022 => "AMD",
+        0x10DE => "NVIDIA",
+        0x10EC => "Realtek",
+        0x1234 => "QEMU",
+        0x1AF4 => "Red Hat (virtio)",
+        0x1B36 => "Red Hat",
+        0x8086 => "Intel",
+        _ => "Unknown vendor",
+    }
+}
+
+// https://pci-ids.ucw.cz/read/PD/
+pub fn class_name(class: ClassCode) -> &'static str {
+    match (class.base, class.sub) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0xFF, _) => "Unassigned class",
+        _ => "Unknown class",
+    }
+}
+
+// lspciのような形式でデバイスの一覧をログに出す
+// BARのサイズを調べる間はデコードを止めるので、ドライバが動き出す前 (probe_devicesの前) に呼ぶ
+pub fn dump_devices() {
+    for device in PCI_DEVICES.read().iter() {
+        info!(
+            "{} {} [{:02X}{:02X}]: {} {:?} (rev {:02X})",
+            device.bdf(),
+            class_name(device.class()),
+            device.class().base,
+            device.class().sub,
+            vendor_name(device.vendor_id()),
+            device.id(),
+            device.revision()
+        );
+        if let Some(parent) = device.parent_bridge() {
+            info!("    Behind bridge {parent}");
+        }
+        if let Some((primary, secondary, subordinate)) = device.bridge_bus_numbers() {
+            info!("    Bus: primary={primary:02X}, secondary={secondary:02X}, subordinate={subordinate:02X}");
+        }
+        for i in 0..device.num_of_bars() {
+            match device.bar(i) {
+                Ok(bar) if bar.size() == 0 => {}
+                Ok(Bar::Io { port, size }) => {
+                    info!("    BAR{i}: I/O ports at {port:#06X} [size={size:#X}]");
+                }
+                Ok(bar) => {
+                    info!(
+                        "    BAR{i}: Memory at {:#018X} ({}-bit, {}) [size={:#X}]",
+                        bar.base(),
+                        if bar.is_64bit() { 64 } else { 32 },
+                        if bar.is_prefetchable() {
+                            "prefetchable"
+                        } else {
+                            "non-prefetchable"
+                        },
+                        bar.size()
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn bar_size_from_mask_test() {
+        assert_eq!(bar_size_from_mask(0xFFFF_FFFF_FFFF_F000), 0x1000);
+        assert_eq!(bar_size_from_mask(0xFFFF_FFFF_F000_0000), 0x1000_0000);
+        assert_eq!(bar_size_from_mask(0xFFFF_FFF0_0000_0000), 0x10_0000_0000);
+        assert_eq!(bar_size_from_mask(0xFFFF_FFFF_FFFF_FFE0), 0x20);
+    }
+}
@@ -0,0 +1,161 @@
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use crate::fs::FileType;
+use crate::fs::Inode;
+use crate::fs::Metadata;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::task::WaitQueue;
+
+// これ以上溜まったら、書く側は読まれるまで待つ
+const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    data: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct Pipe {
+    state: Mutex<PipeState>,
+    // データが来た時と、書く側が全部閉じた時に起こす
+    readable: WaitQueue,
+    // 空きができた時と、読む側が全部閉じた時に起こす
+    writable: WaitQueue,
+}
+
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+// 読む側と書く側の組を作る。どちらも位置を持たないので、offsetは無視する
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            data: VecDeque::new(),
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl Pipe {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Fifo,
+            size: self.state.lock().data.len() as u64,
+            mtime: None,
+        }
+    }
+}
+
+impl Inode for PipeReader {
+    fn metadata(&self) -> Metadata {
+        self.pipe.metadata()
+    }
+    // 何か来るまで待って、来ている分だけ返す。書く側が全部閉じていれば0を返す
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.pipe.readable.wait_until(|| {
+            let mut state = self.pipe.state.lock();
+            if state.data.is_empty() {
+                return (state.writers == 0).then_some(0);
+            }
+            let n = buf.len().min(state.data.len());
+            for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+                *dst = src;
+            }
+            Some(n)
+        });
+        if n > 0 {
+            self.pipe.writable.notify_all();
+        }
+        Ok(n)
+    }
+}
+
+impl Inode for PipeWriter {
+    fn metadata(&self) -> Metadata {
+        self.pipe.metadata()
+    }
+    // 全部書けるまで待つ。読む側が全部閉じていたらエラーにする
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let n = self.pipe.writable.wait_until(|| {
+                let mut state = self.pipe.state.lock();
+                if state.readers == 0 {
+                    return Some(None);
+                }
+                let n = (PIPE_CAPACITY - state.data.len()).min(buf.len() - written);
+                if n == 0 {
+                    return None;
+                }
+                state.data.extend(&buf[written..written + n]);
+                Some(Some(n))
+            });
+            let Some(n) = n else {
+                // 途中まで書けていれば、そこまでを返す
+                return if written > 0 {
+                    Ok(written)
+                } else {
+                    Err("Broken pipe")
+                };
+            };
+            written += n;
+            self.pipe.readable.notify_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.state.lock().readers -= 1;
+        self.pipe.writable.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.state.lock().writers -= 1;
+        self.pipe.readable.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn read_until_writer_is_closed() {
+        let (reader, writer) = pipe();
+        assert_eq!(writer.write_at(0, b"hello"), Ok(5));
+        let mut buf = [0u8; 3];
+        assert_eq!(reader.read_at(0, &mut buf), Ok(3));
+        assert_eq!(&buf, b"hel");
+        drop(writer);
+        assert_eq!(reader.read_at(0, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(reader.read_at(0, &mut buf), Ok(0));
+    }
+
+    #[test_case]
+    fn write_to_closed_pipe_fails() {
+        let (reader, writer) = pipe();
+        drop(reader);
+        assert!(writer.write_at(0, b"x").is_err());
+    }
+}
@@ -1,28 +1,424 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
 use core::mem::size_of;
 use core::slice;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
 
+use crate::debugcon::debugcon_print;
 use crate::graphics::BitmapTextWriter;
-use crate::mutex::Mutex;
+use crate::hpet::global_timestamp;
+use crate::klog::KLOG_SINK;
+use crate::mutex::SpinLockIrqSave;
+use crate::result::Result;
 use crate::serial::SerialPort;
 use crate::uefi::VramBufferInfo;
+use crate::virtio_console::virtio_console_print;
+use crate::wall_clock::wall_clock_now;
+use crate::wall_clock::DateTime;
+use crate::x86::read_cr3;
+use crate::x86::TranslationResult;
+use crate::x86::PAGE_SIZE;
 
-static GLOBAL_VRAM_WRITER: Mutex<Option<BitmapTextWriter<VramBufferInfo>>> = Mutex::new(None);
+static GLOBAL_VRAM_WRITER: SpinLockIrqSave<Option<BitmapTextWriter<VramBufferInfo>>> =
+    SpinLockIrqSave::new(None);
 
-pub fn set_global_vram(vram: VramBufferInfo) {
-    assert!(GLOBAL_VRAM_WRITER.lock().is_none());
-    let w = BitmapTextWriter::new(vram);
-    *GLOBAL_VRAM_WRITER.lock() = Some(w);
+pub fn set_global_vram(vram: VramBufferInfo) -> Result<()> {
+    let mut writer = GLOBAL_VRAM_WRITER.lock();
+    if writer.is_some() {
+        return Err("VRAM is already initialized");
+    }
+    *writer = Some(BitmapTextWriter::new(vram));
+    Ok(())
 }
 
-pub fn global_print(args: fmt::Arguments) {
+pub fn with_global_vram<R>(f: impl FnOnce(&VramBufferInfo) -> R) -> Option<R> {
+    GLOBAL_VRAM_WRITER.lock().as_ref().map(|w| f(w.bitmap()))
+}
+
+pub fn with_global_vram_mut<R>(f: impl FnOnce(&mut VramBufferInfo) -> R) -> Option<R> {
+    GLOBAL_VRAM_WRITER
+        .lock()
+        .as_mut()
+        .map(|w| f(w.bitmap_mut()))
+}
+
+// パニック後はロックを取らない出力先 (シリアルなど) にだけ出力する
+static IS_PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn enter_panic_mode() {
+    IS_PANICKING.store(true, Ordering::SeqCst);
+}
+
+// シリアルポートは初期化前でも書き込めるので、VRAMの準備ができる前のログもここには出る
+pub fn serial_print(args: fmt::Arguments) {
     let mut writer = SerialPort::default();
-    fmt::write(&mut writer, args).unwrap();
-    if let Some(w) = &mut *GLOBAL_VRAM_WRITER.lock() {
-        fmt::write(w, args).expect("Failed to write to GLOBAL_VRAM_WRITER");
+    let _ = fmt::write(&mut writer, args);
+}
+
+// ロックを一切取らずに、デバッグコンソールとシリアルに直接書く
+// NMIやダブルフォルトのように、どのロックを持っている最中にも来るところで使う
+pub fn emergency_print(args: fmt::Arguments) {
+    debugcon_print(args);
+    serial_print(args);
+}
+
+// ログの出力先。print!の出力はwriteに、ログ1行はwrite_logに渡される
+pub trait LogSink: Sync {
+    fn name(&self) -> &'static str;
+    fn write(&self, args: fmt::Arguments);
+    // 普通はレベルのタグをANSIエスケープで色付けして、writeに渡す
+    fn write_log(&self, record: &LogRecord) {
+        self.write(format_args!(
+            "{} {}[{}]{ANSI_RESET} {}:{:<3}: {}\n",
+            record.timestamp,
+            record.level.ansi_color(),
+            record.level.tag(),
+            record.file,
+            record.line,
+            record.args
+        ))
+    }
+    // ロックを取らずに書けるなら、パニックの後も使う
+    fn is_panic_safe(&self) -> bool {
+        false
+    }
+}
+
+pub struct LogRecord<'a> {
+    pub level: LogLevel,
+    pub timestamp: Timestamp,
+    pub file: &'a str,
+    pub line: u32,
+    pub args: fmt::Arguments<'a>,
+}
+
+// 出力する関数を包んだだけの出力先
+struct FnSink {
+    name: &'static str,
+    print: fn(fmt::Arguments),
+    panic_safe: bool,
+}
+
+impl LogSink for FnSink {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn write(&self, args: fmt::Arguments) {
+        (self.print)(args)
+    }
+    fn is_panic_safe(&self) -> bool {
+        self.panic_safe
+    }
+}
+
+// VRAMには文字色を変えてタグを描く
+// 割り込みを止めて取るので、取れないのはこの中でログを出した時だけ。その分は捨てる
+struct VramSink;
+
+impl LogSink for VramSink {
+    fn name(&self) -> &'static str {
+        "vram"
+    }
+    fn write(&self, args: fmt::Arguments) {
+        if let Some(Some(w)) = GLOBAL_VRAM_WRITER.try_lock().as_deref_mut() {
+            let _ = fmt::write(w, args);
+        }
+    }
+    fn write_log(&self, record: &LogRecord) {
+        if let Some(Some(w)) = GLOBAL_VRAM_WRITER.try_lock().as_deref_mut() {
+            let _ = fmt::write(w, format_args!("{} ", record.timestamp));
+            w.set_fg_color(record.level.vram_color());
+            let _ = fmt::write(w, format_args!("[{}]", record.level.tag()));
+            w.reset_fg_color();
+            let _ = fmt::write(
+                w,
+                format_args!(" {}:{:<3}: {}\n", record.file, record.line, record.args),
+            );
+        }
+    }
+    // パニックの表示を画面でも見られるようにする
+    fn is_panic_safe(&self) -> bool {
+        true
+    }
+}
+
+static DEBUGCON_SINK: FnSink = FnSink {
+    name: "debugcon",
+    print: debugcon_print,
+    panic_safe: true,
+};
+static SERIAL_SINK: FnSink = FnSink {
+    name: "serial",
+    print: serial_print,
+    panic_safe: true,
+};
+static VIRTIO_CONSOLE_SINK: FnSink = FnSink {
+    name: "virtio-console",
+    print: virtio_console_print,
+    panic_safe: false,
+};
+static VRAM_SINK: VramSink = VramSink;
+
+const MAX_LOG_SINKS: usize = 8;
+type LogSinks = [Option<&'static dyn LogSink>; MAX_LOG_SINKS];
+
+// 出力先の表が使えない時 (表を書き換えている最中に割り込まれた時など) に使う
+static EMERGENCY_LOG_SINKS: LogSinks = [
+    Some(&KLOG_SINK),
+    Some(&DEBUGCON_SINK),
+    Some(&SERIAL_SINK),
+    Some(&VRAM_SINK),
+    None,
+    None,
+    None,
+    None,
+];
+
+// アロケータが使えるようになる前から出力するので、固定長の配列で持つ
+static LOG_SINKS: SpinLockIrqSave<LogSinks> = SpinLockIrqSave::new([
+    Some(&KLOG_SINK),
+    Some(&DEBUGCON_SINK),
+    Some(&SERIAL_SINK),
+    Some(&VIRTIO_CONSOLE_SINK),
+    Some(&VRAM_SINK),
+    None,
+    None,
+    None,
+]);
+
+pub fn register_log_sink(sink: &'static dyn LogSink) -> Result<()> {
+    let mut sinks = LOG_SINKS.lock();
+    if sinks.iter().flatten().any(|s| s.name() == sink.name()) {
+        return Err("Log sink is already registered");
+    }
+    let slot = sinks
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or("Too many log sinks")?;
+    *slot = Some(sink);
+    Ok(())
+}
+
+pub fn unregister_log_sink(name: &str) -> Result<()> {
+    let mut sinks = LOG_SINKS.lock();
+    let slot = sinks
+        .iter_mut()
+        .find(|s| s.is_some_and(|s| s.name() == name))
+        .ok_or("Log sink not found")?;
+    *slot = None;
+    Ok(())
+}
+
+pub fn log_sink_names() -> impl Iterator<Item = &'static str> {
+    let sinks = *LOG_SINKS.lock();
+    sinks.into_iter().flatten().map(|s| s.name())
+}
+
+// 出力先の中でログを出すこともあるので、コピーしてからロックを外して使う
+// 割り込みハンドラから呼ばれることもあるので、待たずに取れなければEMERGENCY_LOG_SINKSに出す
+fn for_each_log_sink(f: impl Fn(&dyn LogSink)) {
+    let panicking = IS_PANICKING.load(Ordering::SeqCst);
+    let sinks: LogSinks = LOG_SINKS
+        .try_lock()
+        .map(|s| *s)
+        .unwrap_or(EMERGENCY_LOG_SINKS);
+    for sink in sinks.into_iter().flatten() {
+        if !panicking || sink.is_panic_safe() {
+            f(sink)
+        }
+    }
+}
+
+pub fn global_print(args: fmt::Arguments) {
+    for_each_log_sink(|sink| sink.write(args));
+}
+
+// 後ろほど重要。設定したレベル以上のものだけ出力する
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 5] = [
+        LogLevel::Trace,
+        LogLevel::Debug,
+        LogLevel::Info,
+        LogLevel::Warn,
+        LogLevel::Error,
+    ];
+    pub fn tag(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        }
+    }
+    // 大文字小文字は区別しない
+    pub fn parse(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|l| l.tag().eq_ignore_ascii_case(s))
+            .ok_or("Unknown log level")
+    }
+    fn from_u8(v: u8) -> Self {
+        Self::ALL[(v as usize).min(Self::ALL.len() - 1)]
+    }
+    // シリアルなどの端末向けのANSIエスケープシーケンス
+    fn ansi_color(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "\x1b[90m",
+            LogLevel::Debug => "\x1b[36m",
+            LogLevel::Info => "\x1b[32m",
+            LogLevel::Warn => "\x1b[33m",
+            LogLevel::Error => "\x1b[31m",
+        }
+    }
+    fn vram_color(&self) -> u32 {
+        match self {
+            LogLevel::Trace => 0x808080,
+            LogLevel::Debug => 0x40c0ff,
+            LogLevel::Info => 0x00ff00,
+            LogLevel::Warn => 0xffff00,
+            LogLevel::Error => 0xff4040,
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+// モジュールごとのレベル。"x86"なら"wasabi::x86"とその下のモジュールに効く
+static MODULE_LOG_LEVELS: SpinLockIrqSave<Vec<(String, LogLevel)>> =
+    SpinLockIrqSave::new(Vec::new());
+static HAS_MODULE_LOG_LEVELS: AtomicBool = AtomicBool::new(false);
+
+pub fn log_level() -> LogLevel {
+    LogLevel::from_u8(LOG_LEVEL.load(Ordering::SeqCst))
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+// Noneならそのモジュールの設定を消して、全体のレベルに戻す
+pub fn set_module_log_level(module: &str, level: Option<LogLevel>) {
+    let mut levels = MODULE_LOG_LEVELS.lock();
+    levels.retain(|(m, _)| m != module);
+    if let Some(level) = level {
+        levels.push((String::from(module), level));
+    }
+    HAS_MODULE_LOG_LEVELS.store(!levels.is_empty(), Ordering::SeqCst);
+}
+
+// "warn,x86=trace,pci=debug" のように、全体のレベルとモジュールごとのレベルを並べたもの
+// カーネルコマンドラインの loglevel= で渡される
+pub fn configure_log_levels(spec: &str) -> Result<()> {
+    for item in spec.split(',').filter(|s| !s.is_empty()) {
+        match item.split_once('=') {
+            Some((module, level)) => set_module_log_level(module, Some(LogLevel::parse(level)?)),
+            None => set_log_level(LogLevel::parse(item)?),
+        }
+    }
+    Ok(())
+}
+
+// module_path!() の先頭のクレート名は見ない
+fn module_matches(module_path: &str, module: &str) -> bool {
+    let path = module_path
+        .split_once("::")
+        .map_or(module_path, |(_, rest)| rest);
+    [module_path, path].into_iter().any(|p| {
+        p.strip_prefix(module)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    })
+}
+
+// 重いトレースを組み立てる前に、出力されるかどうかを確かめるのに使う
+pub fn log_enabled(level: LogLevel, module_path: &str) -> bool {
+    // パニック中はロックを取らず、全体のレベルだけを見る
+    if HAS_MODULE_LOG_LEVELS.load(Ordering::SeqCst) && !IS_PANICKING.load(Ordering::SeqCst) {
+        // 設定を書き換えている最中なら、全体のレベルを使う
+        let levels = MODULE_LOG_LEVELS.try_lock();
+        // 一番詳しく指定されたものを使う
+        if let Some((_, threshold)) = levels
+            .iter()
+            .flat_map(|levels| levels.iter())
+            .filter(|(m, _)| module_matches(module_path, m))
+            .max_by_key(|(m, _)| m.len())
+        {
+            return level >= *threshold;
+        }
+    }
+    level >= log_level()
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+
+// 壁時計が使えるなら日時を、そうでなければ起動からの時間を出す
+pub struct Timestamp {
+    uptime: Duration,
+    wall_clock: Option<Duration>,
+}
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.wall_clock {
+            Some(t) => write!(
+                f,
+                "[{}.{:06}]",
+                DateTime::from_unix_time(t),
+                t.subsec_micros()
+            ),
+            None => write!(
+                f,
+                "[{:5}.{:06}]",
+                self.uptime.as_secs(),
+                self.uptime.subsec_micros()
+            ),
+        }
     }
 }
 
+// trace!/debug!/info!/warn!/error!の出力はここで整形する
+// 端末にはレベルのタグをANSIエスケープで色付けし、VRAMには文字色を変えて描く
+pub fn log(level: LogLevel, module_path: &str, file: &str, line: u32, args: fmt::Arguments) {
+    if !log_enabled(level, module_path) {
+        return;
+    }
+    let panicking = IS_PANICKING.load(Ordering::SeqCst);
+    // パニック中はHPETのロックを取らない
+    let ts = if panicking {
+        Timestamp {
+            uptime: Duration::ZERO,
+            wall_clock: None,
+        }
+    } else {
+        Timestamp {
+            uptime: global_timestamp(),
+            wall_clock: wall_clock_now(),
+        }
+    };
+    let record = LogRecord {
+        level,
+        timestamp: ts,
+        file,
+        line,
+        args,
+    };
+    for_each_log_sink(|sink| sink.write_log(&record));
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
@@ -36,79 +432,199 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+#[macro_export]
+macro_rules! emergency_println {
+    ($($arg:tt)*) => (
+      $crate::print::emergency_print(format_args!("{}\n", format_args!($($arg)*)));
+    );
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => (
+      $crate::print::log(
+        $crate::print::LogLevel::Trace,
+        module_path!(),
+        file!(),
+        line!(),
+        format_args!($($arg)*),
+      );
+    );
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => (
+      $crate::print::log(
+        $crate::print::LogLevel::Debug,
+        module_path!(),
+        file!(),
+        line!(),
+        format_args!($($arg)*),
+      );
+    );
+}
+
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => (
-      $crate::print!("[INFO] {}:{:<3}: {}\n", file!(), line!(), format_args!($($arg)*));
+      $crate::print::log(
+        $crate::print::LogLevel::Info,
+        module_path!(),
+        file!(),
+        line!(),
+        format_args!($($arg)*),
+      );
     );
 }
 
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)*) => (
-      $crate::print!("[WARN] {}:{:<3}: {}\n", file!(), line!(), format_args!($($arg)*));
+      $crate::print::log(
+        $crate::print::LogLevel::Warn,
+        module_path!(),
+        file!(),
+        line!(),
+        format_args!($($arg)*),
+      );
     );
 }
 
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => (
-      $crate::print!("[ERROR] {}:{:<3}: {}\n", file!(), line!(), format_args!($($arg)*));
+      $crate::print::log(
+        $crate::print::LogLevel::Error,
+        module_path!(),
+        file!(),
+        line!(),
+        format_args!($($arg)*),
+      );
     );
 }
 
-fn hexdump_bytes(bytes: &[u8]) {
-    let mut i = 0;
-    let mut ascii = [0u8; 16];
-    let mut offset = 0;
-    for v in bytes.iter() {
-        if i == 0 {
-            print!("{offset:08X}: ");
-        }
-        print!("{:02X}", v);
-        ascii[i] = *v;
-        i += 1;
-        if i == 16 {
-            print!("|");
-            for c in ascii.iter() {
-                print!(
-                    "{}",
-                    match c {
-                        0x20..=0x7e => {
-                            *c as char
-                        }
-                        _ => {
-                            '.'
-                        }
-                    }
-                );
-            }
-            println!("|");
-            offset += 16;
-            i = 0;
+#[derive(Clone, Copy, Debug)]
+pub struct HexdumpOptions {
+    // 1行に出すバイト数
+    pub width: usize,
+    // 左端に出すアドレスは、先頭をこの値として数える
+    pub base: u64,
+    // 右側に文字として読める部分を出す
+    pub ascii: bool,
+    // 直前と同じ内容の行が続いたら "*" 1行にまとめる (最後の行は必ず出す)
+    pub fold_duplicates: bool,
+}
+
+impl Default for HexdumpOptions {
+    fn default() -> Self {
+        Self {
+            width: 16,
+            base: 0,
+            ascii: true,
+            fold_duplicates: true,
         }
     }
-    if i != 0 {
-        let old_i = i;
-        while i < 16 {
-            print!(" ");
-            i += 1;
+}
+
+fn hexdump_line(addr: u64, line: &[u8], opts: &HexdumpOptions) {
+    print!("{addr:08X}:");
+    for v in line {
+        print!(" {v:02X}");
+    }
+    if opts.ascii {
+        // 短い最後の行も、文字の列の位置を揃える
+        for _ in line.len()..opts.width {
+            print!("   ");
         }
-        print!("|");
-        for c in ascii[0..old_i].iter() {
+        print!("  |");
+        for c in line {
             print!(
                 "{}",
-                if (0x20u8..=0x7fu8).contains(c) {
+                if (0x20u8..=0x7eu8).contains(c) {
                     *c as char
                 } else {
                     '.'
                 }
             );
         }
+        print!("|");
     }
-    println!("|");
+    println!();
+}
+
+pub fn hexdump_bytes_with(bytes: &[u8], opts: &HexdumpOptions) {
+    assert!(opts.width > 0, "hexdump width must not be 0");
+    let num_of_lines = bytes.chunks(opts.width).len();
+    let mut prev: Option<&[u8]> = None;
+    let mut folding = false;
+    for (i, line) in bytes.chunks(opts.width).enumerate() {
+        let addr = opts.base + (i * opts.width) as u64;
+        let is_last = i + 1 == num_of_lines;
+        if opts.fold_duplicates && !is_last && prev == Some(line) {
+            if !folding {
+                println!("*");
+                folding = true;
+            }
+            continue;
+        }
+        folding = false;
+        prev = Some(line);
+        hexdump_line(addr, line, opts);
+    }
+}
+
+pub fn hexdump_bytes(bytes: &[u8]) {
+    hexdump_bytes_with(bytes, &HexdumpOptions::default())
 }
 
 pub fn hexdump<T: Sized>(data: &T) {
     hexdump_bytes(unsafe { slice::from_raw_parts(data as *const T as *const u8, size_of::<T>()) })
 }
+
+// 物理メモリをカーネルのストレートマップ (物理アドレス = 仮想アドレス) 越しに表示する
+// マップされていないページが含まれていたら、何も出さずにエラーにする
+pub fn hexdump_phys(paddr: u64, len: usize, opts: &HexdumpOptions) -> Result<()> {
+    let end = paddr
+        .checked_add(len as u64)
+        .ok_or("Physical range overflows")?;
+    let table = unsafe { &*read_cr3() };
+    let mut page = paddr & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        let phys = match table.translate(page)? {
+            TranslationResult::PageMapped4K { phys }
+            | TranslationResult::PageMapped2M { phys }
+            | TranslationResult::PageMapped1G { phys } => phys,
+        };
+        if phys != page {
+            return Err("Physical address is not in the straight map");
+        }
+        page += PAGE_SIZE as u64;
+    }
+    let bytes = unsafe { slice::from_raw_parts(paddr as *const u8, len) };
+    hexdump_bytes_with(
+        bytes,
+        &HexdumpOptions {
+            base: paddr,
+            ..*opts
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_levels_and_match_modules() {
+        assert_eq!(LogLevel::parse("warn"), Ok(LogLevel::Warn));
+        assert_eq!(LogLevel::parse("TRACE"), Ok(LogLevel::Trace));
+        assert!(LogLevel::parse("verbose").is_err());
+        assert!(module_matches("wasabi::x86", "x86"));
+        assert!(module_matches("wasabi::uefi::vars", "uefi"));
+        assert!(module_matches("wasabi::uefi::vars", "wasabi::uefi::vars"));
+        assert!(!module_matches("wasabi::x86_64", "x86"));
+        assert!(!module_matches("wasabi::pci", "x86"));
+    }
+}
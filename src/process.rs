@@ -0,0 +1,604 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::AtomicI64;
+use core::sync::atomic::Ordering;
+
+use crate::elf::Elf;
+use crate::elf::Page;
+use crate::error;
+use crate::fs::absolute_path;
+use crate::fs::read_file;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::mutex::LOCK_ORDER_PROCESSES;
+use crate::result::Result;
+use crate::task::current_address_space;
+use crate::task::current_cpu_times;
+use crate::task::current_task_id;
+use crate::task::exit_current_task;
+use crate::task::kernel_cr3;
+use crate::task::spawn_user;
+use crate::task::task_list;
+use crate::task::TaskId;
+use crate::task::WaitQueue;
+use crate::x86::current_kernel_stack_top;
+use crate::x86::enter_user_mode;
+use crate::x86::flush_tlb;
+use crate::x86::read_cr3;
+use crate::x86::sti;
+use crate::x86::switch_stack_and_call;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+
+pub type Pid = TaskId;
+
+// ユーザ空間はPML4の128番目から255番目のエントリを使う
+// それより下はカーネルのストレートマッピングやMMIOと共有する
+const USER_PML4_INDICES: Range<usize> = 128..256;
+pub const USER_SPACE_START: u64 = 0x4000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x8000_0000_0000;
+// スタックはここまで、触られた時に伸びる
+const USER_STACK_SIZE: u64 = 1024 * 1024;
+// 一番上のページは空けておく
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE as u64;
+const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_SIZE;
+// mmapで割り当てる範囲。ヒープはここまで伸ばせる
+// スタックとの間には、スタックのはみ出しに気付けるように1ページ空けておく
+pub const USER_MMAP_START: u64 = 0x6000_0000_0000;
+const USER_MMAP_END: u64 = USER_STACK_BOTTOM - PAGE_SIZE as u64;
+// 1つのプロセスが持てるページ数の上限 (64MiB)
+const MAX_USER_PAGES: usize = 16 * 1024;
+// スタックの上に積む引数の文字列の合計の上限
+const MAX_ARGS_SIZE: usize = PAGE_SIZE;
+// exitを呼ばずに終わったプロセスの終了コード
+pub const EXIT_CODE_KILLED: i64 = -1;
+// シグナルで止められたプロセスの終了コードは、これにシグナルの番号を足したもの
+pub const EXIT_CODE_SIGNAL_BASE: i64 = 128;
+
+// ユーザモードの例外で止められた理由。番号はPOSIXのシグナルに揃える
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Ill = 4,
+    Trap = 5,
+    Bus = 7,
+    Fpe = 8,
+    Segv = 11,
+}
+
+impl Signal {
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Ill => "SIGILL",
+            Signal::Trap => "SIGTRAP",
+            Signal::Bus => "SIGBUS",
+            Signal::Fpe => "SIGFPE",
+            Signal::Segv => "SIGSEGV",
+        }
+    }
+}
+
+// 使ってよいと決めた範囲。ページは触られた時に割り当てる
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    end: u64,
+    writable: bool,
+}
+
+// プロセスごとのページテーブルと、そこにマップしたユーザのページ
+pub struct AddressSpace {
+    table: *mut PML4,
+    // 先頭のアドレスから引く。ロックはregions, pagesの順に取る
+    regions: Mutex<BTreeMap<u64, Region>>,
+    pages: Mutex<BTreeMap<u64, Box<Page>>>,
+    // ヒープの終わり (sbrkで伸ばす)。最初は実行ファイルの終わり
+    brk: Mutex<u64>,
+}
+
+// tableはpagesのロックを取ってから触る
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+fn page_floor(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE as u64 - 1)
+}
+
+fn page_ceil(addr: u64) -> u64 {
+    page_floor(addr + PAGE_SIZE as u64 - 1)
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        let kernel = unsafe { &*(kernel_cr3() as *const PML4) };
+        let table = PML4::new_with_kernel_mappings(kernel, USER_PML4_INDICES);
+        Self {
+            table: Box::into_raw(table),
+            regions: Mutex::new(BTreeMap::new()),
+            pages: Mutex::new(BTreeMap::new()),
+            brk: Mutex::new(0),
+        }
+    }
+    pub fn cr3(&self) -> u64 {
+        self.table as u64
+    }
+    fn check_user_range(start: u64, end: u64) -> Result<()> {
+        if start < USER_SPACE_START || end > USER_SPACE_END || start >= end {
+            Err("Address is out of the user space")
+        } else {
+            Ok(())
+        }
+    }
+    // PT_LOADのセグメントを読み込んで、エントリポイントを返す
+    pub fn load_elf(&self, elf: &Elf) -> Result<u64> {
+        for ph in elf.program_headers().filter(|ph| ph.is_load()) {
+            let end = ph
+                .vaddr
+                .checked_add(ph.memsz)
+                .ok_or("ELF: segment overflow")?;
+            Self::check_user_range(ph.vaddr, end)?;
+            if end > USER_MMAP_START {
+                return Err("ELF: segment overlaps the mmap area");
+            }
+        }
+        let mut pages = self.pages.lock();
+        let loaded = elf.load(unsafe { &mut *self.table }, true)?;
+        for (virt, page) in loaded.pages {
+            if pages.insert(virt, page).is_some() {
+                return Err("ELF: segment overlaps existing pages");
+            }
+        }
+        *self.brk.lock() = loaded.end;
+        Ok(loaded.entry)
+    }
+    // プロセスのスタックの範囲を用意する。ページは使われた時に割り当てる
+    pub fn reserve_stack(&self) -> Result<()> {
+        self.reserve(USER_STACK_BOTTOM, USER_STACK_SIZE, true)
+    }
+    // [start, start + size)を使えるようにする。すぐ前の範囲と続いていればまとめる
+    fn reserve(&self, start: u64, size: u64, writable: bool) -> Result<()> {
+        let end = start.checked_add(size).ok_or("Range overflow")?;
+        if start % PAGE_SIZE as u64 != 0 || end % PAGE_SIZE as u64 != 0 {
+            return Err("Range is not page aligned");
+        }
+        Self::check_user_range(start, end)?;
+        let mut regions = self.regions.lock();
+        if regions.range(..end).any(|(_, r)| r.end > start) {
+            return Err("Range is already reserved");
+        }
+        if let Some((_, prev)) = regions.range_mut(..start).next_back() {
+            if prev.end == start && prev.writable == writable {
+                prev.end = end;
+                return Ok(());
+            }
+        }
+        regions.insert(start, Region { end, writable });
+        Ok(())
+    }
+    // virtを含むページが予約された範囲にあれば、ゼロ埋めしたページを割り当てる
+    // 既に割り当てられていれば何もしない
+    fn populate(&self, virt: u64, write: bool) -> Result<()> {
+        let virt = page_floor(virt);
+        let regions = self.regions.lock();
+        let region = regions
+            .range(..=virt)
+            .next_back()
+            .map(|(_, r)| *r)
+            .filter(|r| r.end > virt)
+            .ok_or("Address is not mapped")?;
+        if write && !region.writable {
+            return Err("Page is not writable");
+        }
+        let mut pages = self.pages.lock();
+        if pages.contains_key(&virt) {
+            return Ok(());
+        }
+        if pages.len() >= MAX_USER_PAGES {
+            return Err("Out of user memory");
+        }
+        let attr = if region.writable {
+            PageAttr::ReadWriteUser
+        } else {
+            PageAttr::ReadOnlyUser
+        };
+        let page = Page::new_zeroed();
+        let table = unsafe { &mut *self.table };
+        table.create_mapping(virt, virt + PAGE_SIZE as u64, page.phys_addr(), attr)?;
+        pages.insert(virt, page);
+        Ok(())
+    }
+    // ユーザから渡された[addr, addr + len)がユーザモードから読める (writeなら書ける) かを確かめる
+    // まだ割り当てていないページは、ここで割り当てる
+    pub fn check_user_access(&self, addr: u64, len: usize, write: bool) -> Result<()> {
+        let end = addr.checked_add(len as u64).ok_or("Invalid user pointer")?;
+        // 長さ0でも、アドレスはユーザ空間の中になければならない
+        Self::check_user_range(addr, end.max(addr.saturating_add(1)))?;
+        for page in (page_floor(addr)..end).step_by(PAGE_SIZE) {
+            let accessible = {
+                let _pages = self.pages.lock();
+                unsafe { &*self.table }.is_user_accessible(page, write)
+            };
+            if !accessible {
+                self.populate(page, write)
+                    .map_err(|_| "Invalid user pointer")?;
+            }
+        }
+        Ok(())
+    }
+    // ユーザモードでのページフォルトを処理する。ページを割り当てて続けられるならtrue
+    pub fn handle_page_fault(&self, addr: u64, write: bool) -> bool {
+        self.populate(addr, write).is_ok()
+    }
+    // ヒープをincrementバイト伸ばして、伸ばす前の終わりを返す
+    pub fn sbrk(&self, increment: u64) -> Result<u64> {
+        let mut brk = self.brk.lock();
+        let old = *brk;
+        if old == 0 {
+            return Err("No program is loaded");
+        }
+        let new = old.checked_add(increment).ok_or("Heap overflow")?;
+        if new > USER_MMAP_START {
+            return Err("Out of user heap");
+        }
+        let reserved_end = page_ceil(old);
+        let new_end = page_ceil(new);
+        if new_end > reserved_end {
+            self.reserve(reserved_end, new_end - reserved_end, true)?;
+        }
+        *brk = new;
+        Ok(old)
+    }
+    // 匿名のメモリをsizeバイト分mmapの範囲に用意して、先頭のアドレスを返す
+    pub fn mmap(&self, size: u64, writable: bool) -> Result<u64> {
+        if size == 0 {
+            return Err("Size is zero");
+        }
+        if size > (MAX_USER_PAGES * PAGE_SIZE) as u64 {
+            return Err("Size is too large");
+        }
+        let size = page_ceil(size);
+        let start = {
+            let regions = self.regions.lock();
+            let mut start = USER_MMAP_START;
+            for (s, r) in regions.range(USER_MMAP_START..) {
+                if *s >= start + size {
+                    break;
+                }
+                start = start.max(r.end);
+            }
+            start
+        };
+        if start + size > USER_MMAP_END {
+            return Err("Out of user address space");
+        }
+        self.reserve(start, size, writable)?;
+        Ok(start)
+    }
+    // mmapの範囲の[start, start + size)を使えなくして、割り当てたページを解放する
+    pub fn munmap(&self, start: u64, size: u64) -> Result<()> {
+        let end = start.checked_add(size).ok_or("Range overflow")?;
+        if start % PAGE_SIZE as u64 != 0 {
+            return Err("Address is not page aligned");
+        }
+        let end = page_ceil(end);
+        if start < USER_MMAP_START || end > USER_MMAP_END || start >= end {
+            return Err("Range is out of the mmap area");
+        }
+        let mut regions = self.regions.lock();
+        let overlapping: Vec<(u64, Region)> = regions
+            .range(..end)
+            .filter(|(_, r)| r.end > start)
+            .map(|(s, r)| (*s, *r))
+            .collect();
+        for (s, r) in overlapping {
+            regions.remove(&s);
+            if s < start {
+                regions.insert(s, Region { end: start, ..r });
+            }
+            if r.end > end {
+                regions.insert(end, Region { end: r.end, ..r });
+            }
+        }
+        let mut pages = self.pages.lock();
+        let virts: Vec<u64> = pages.range(start..end).map(|(v, _)| *v).collect();
+        let table = unsafe { &mut *self.table };
+        for virt in &virts {
+            table.create_mapping(*virt, *virt + PAGE_SIZE as u64, 0, PageAttr::NotPresent)?;
+        }
+        // 古いマッピングがTLBに残っている間はページを解放できない
+        if read_cr3() as u64 == self.cr3() {
+            flush_tlb();
+        }
+        for virt in virts {
+            pages.remove(&virt);
+        }
+        Ok(())
+    }
+    // dataをこのアドレス空間の[addr, addr + data.len())に書き込む
+    // ページを直接書き換えるので、今のCR3がこのアドレス空間でなくてもよい
+    pub fn copy_to_user(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let end = addr
+            .checked_add(data.len() as u64)
+            .ok_or("Range overflow")?;
+        Self::check_user_range(addr, end)?;
+        for virt in (page_floor(addr)..end).step_by(PAGE_SIZE) {
+            self.populate(virt, false)?;
+        }
+        let mut pages = self.pages.lock();
+        for (i, b) in data.iter().enumerate() {
+            let virt = addr + i as u64;
+            let page = pages
+                .get_mut(&page_floor(virt))
+                .ok_or("Page is not mapped")?;
+            page.bytes[(virt as usize) & (PAGE_SIZE - 1)] = *b;
+        }
+        Ok(())
+    }
+    // 割り当て済みのページ数
+    pub fn num_of_pages(&self) -> usize {
+        self.pages.lock().len()
+    }
+}
+
+// 今のプロセスでユーザモードのページフォルトが起きた時に、例外ハンドラから呼ばれる
+pub fn handle_user_page_fault(addr: u64, write: bool) -> bool {
+    current_address_space().is_some_and(|a| a.handle_page_fault(addr, write))
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    // このページテーブルが使われていない時 (終了したタスクを片付ける時) に呼ばれる
+    fn drop(&mut self) {
+        unsafe {
+            (*self.table).free_tables(USER_PML4_INDICES);
+            drop(Box::from_raw(self.table));
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    // 終わったが、まだ親にwaitされていない (ゾンビ)
+    Exited(i64),
+}
+
+pub struct Process {
+    pid: Pid,
+    // Noneならカーネルが作ったプロセスで、カーネルがwaitする
+    parent: Option<Pid>,
+    path: String,
+    args: Vec<String>,
+    // 終わったらNoneにして、ゾンビの間ページを持ち続けないようにする
+    address_space: Mutex<Option<Arc<AddressSpace>>>,
+    // exitで渡された終了コード
+    exit_code: AtomicI64,
+    state: Mutex<ProcessState>,
+    exited: WaitQueue,
+    // 終わった時点のユーザモード・カーネルモードのtick数。動いている間はタスクから読む
+    final_cpu_times: Mutex<(u64, u64)>,
+}
+
+// ps/topで表示する、プロセスごとの情報
+#[derive(Clone, Debug)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub path: String,
+    pub state: ProcessState,
+    // タイマ割り込みで数えたtick数 (TIMER_HZで秒に直せる)
+    pub user_ticks: u64,
+    pub system_ticks: u64,
+    // 割り当て済みのユーザのページ数
+    pub pages: usize,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> =
+    Mutex::new_with_order(BTreeMap::new(), LOCK_ORDER_PROCESSES);
+
+impl Process {
+    // pathの実行ファイルを読み込んで、新しいアドレス空間のユーザモードで動かす
+    // argsはargv[1..]になる (argv[0]はpath)
+    // 今のタスクがプロセスならその子になり、終了コードはwaitで受け取る
+    pub fn spawn(path: &str, args: &[&str]) -> Result<Pid> {
+        let path = absolute_path(path)?;
+        let image = read_file(&path)?;
+        let elf = Elf::parse(&image)?;
+        let address_space = Arc::new(AddressSpace::new());
+        let entry = address_space.load_elf(&elf)?;
+        address_space.reserve_stack()?;
+        let mut argv: Vec<String> = Vec::new();
+        argv.push(path.clone());
+        argv.extend(args.iter().map(|a| String::from(*a)));
+        let rsp = push_args(&address_space, &argv)?;
+        let parent = process(current_task_id()).map(|p| p.pid);
+        let name = path.rsplit('/').next().unwrap_or(&path);
+        // 登録する前にプロセスが終わってしまわないように、ロックを持ったまま作る
+        let mut processes = PROCESSES.lock();
+        let pid = spawn_user(name, address_space.clone(), move || unsafe {
+            enter_user_mode(entry, rsp)
+        });
+        processes.insert(
+            pid,
+            Arc::new(Process {
+                pid,
+                parent,
+                path: path.clone(),
+                args: argv,
+                address_space: Mutex::new(Some(address_space)),
+                exit_code: AtomicI64::new(EXIT_CODE_KILLED),
+                state: Mutex::new(ProcessState::Running),
+                exited: WaitQueue::new(),
+                final_cpu_times: Mutex::new((0, 0)),
+            }),
+        );
+        info!("Process {pid} created from {path} (entry = {entry:#X})");
+        Ok(pid)
+    }
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.address_space.lock().clone()
+    }
+    pub fn state(&self) -> ProcessState {
+        *self.state.lock()
+    }
+    fn is_running(&self) -> bool {
+        self.state() == ProcessState::Running
+    }
+}
+
+// System V ABIと同じように、スタックにargc, argv[], NULL, 文字列の順で積んで、rspを返す
+fn push_args(address_space: &AddressSpace, argv: &[String]) -> Result<u64> {
+    let strings_size: usize = argv.iter().map(|a| a.len() + 1).sum();
+    if strings_size > MAX_ARGS_SIZE {
+        return Err("Arguments are too long");
+    }
+    let mut addr = USER_STACK_TOP - strings_size as u64;
+    let mut ptrs: Vec<u64> = Vec::new();
+    for arg in argv {
+        if arg.contains('\0') {
+            return Err("Argument contains NUL");
+        }
+        address_space.copy_to_user(addr, arg.as_bytes())?;
+        address_space.copy_to_user(addr + arg.len() as u64, &[0])?;
+        ptrs.push(addr);
+        addr += arg.len() as u64 + 1;
+    }
+    ptrs.push(0);
+    let strings_start = USER_STACK_TOP - strings_size as u64;
+    // argcの位置が16バイト境界になるようにする
+    let rsp = (strings_start - (ptrs.len() as u64 + 1) * 8) & !15;
+    address_space.copy_to_user(rsp, &(argv.len() as u64).to_le_bytes())?;
+    for (i, ptr) in ptrs.iter().enumerate() {
+        address_space.copy_to_user(rsp + 8 * (i as u64 + 1), &ptr.to_le_bytes())?;
+    }
+    Ok(rsp)
+}
+
+pub fn process(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+// ゾンビも含めたプロセスの一覧。topは2回呼んだ差からCPU使用率を出せる
+pub fn process_list() -> Vec<ProcessInfo> {
+    let tasks = task_list();
+    processes()
+        .iter()
+        .map(|p| {
+            let state = p.state();
+            let (user_ticks, system_ticks) = match state {
+                ProcessState::Running => tasks
+                    .iter()
+                    .find(|t| t.id == p.pid)
+                    .map_or((0, 0), |t| (t.user_ticks, t.system_ticks)),
+                ProcessState::Exited(_) => *p.final_cpu_times.lock(),
+            };
+            ProcessInfo {
+                pid: p.pid,
+                parent: p.parent,
+                path: p.path.clone(),
+                state,
+                user_ticks,
+                system_ticks,
+                pages: p.address_space().map_or(0, |a| a.num_of_pages()),
+            }
+        })
+        .collect()
+}
+
+// pidのプロセスが終わるまで待って、終了コードを返す。終わったプロセスはここで片付ける
+// 待てるのは親のプロセスだけ (親がいないプロセスはカーネルのタスクから待つ)
+pub fn wait(pid: Pid) -> Result<i64> {
+    let child = process(pid).ok_or("No such process")?;
+    let caller = process(current_task_id()).map(|p| p.pid);
+    if child.parent != caller {
+        return Err("Not a child of the caller");
+    }
+    let code = child.exited.wait_until(|| match child.state() {
+        ProcessState::Exited(code) => Some(code),
+        ProcessState::Running => None,
+    });
+    PROCESSES.lock().remove(&pid);
+    Ok(code)
+}
+
+// exitシステムコールから呼ばれる
+pub fn exit_current_process(code: i64) -> ! {
+    if let Some(p) = process(current_task_id()) {
+        p.exit_code.store(code, Ordering::SeqCst);
+    }
+    exit_current_task()
+}
+
+// ユーザモードで処理できない例外が起きた時に、例外ハンドラから呼ばれる。戻らない
+pub fn kill_current_process(signal: Signal, description: &str) -> ! {
+    let pid = current_task_id();
+    let path = process(pid).map(|p| {
+        p.exit_code
+            .store(EXIT_CODE_SIGNAL_BASE + signal as i64, Ordering::SeqCst);
+        p.path.clone()
+    });
+    error!(
+        "Process {pid} ({}) killed by {}: {description}",
+        path.as_deref().unwrap_or("?"),
+        signal.name()
+    );
+    // 例外のスタック (IST) は他のタスクと共有しているので、その上で切り替えない
+    // ユーザモードにいたので、このタスクのカーネルのスタックは空いている
+    unsafe { switch_stack_and_call(current_kernel_stack_top(), exit_killed_process) }
+}
+
+extern "sysv64" fn exit_killed_process() -> ! {
+    sti();
+    exit_current_task()
+}
+
+// タスクが終わる時に呼ばれる。アドレス空間はタスクが片付けられる時に解放される
+pub(crate) fn on_process_exit(pid: Pid) {
+    let mut processes = PROCESSES.lock();
+    let Some(p) = processes.get(&pid).cloned() else {
+        return;
+    };
+    let code = p.exit_code.load(Ordering::SeqCst);
+    *p.final_cpu_times.lock() = current_cpu_times();
+    *p.state.lock() = ProcessState::Exited(code);
+    p.address_space.lock().take();
+    info!("Process {pid} exited with code {code}");
+    // 親がもう終わっていたら誰もwaitしないので、すぐに片付ける
+    let parent_alive = match p.parent {
+        Some(parent) => processes.get(&parent).is_some_and(|p| p.is_running()),
+        None => true,
+    };
+    if !parent_alive {
+        processes.remove(&pid);
+    }
+    // 既に終わっている子も、もう待たれることは無いので片付ける
+    processes.retain(|_, c| c.parent != Some(pid) || c.is_running());
+    drop(processes);
+    p.exited.notify_all();
+}
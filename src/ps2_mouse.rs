@@ -0,0 +1,199 @@
+use core::time::Duration;
+
+use crate::apic::route_isa_irq;
+use crate::hpet::global_timestamp;
+use crate::mpsc::MpscQueue;
+use crate::mutex::SpinLockIrqSave;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+
+// i8042 (PS/2コントローラ) の2つ目のポートにつながったマウス
+// https://wiki.osdev.org/I8042_PS/2_Controller
+const DATA_PORT: u16 = 0x60;
+// 読むとステータス、書くとコントローラへのコマンド
+const STATUS_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_AUX_DATA: u8 = 1 << 5;
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_DISABLE_KEYBOARD: u8 = 0xAD;
+const CMD_WRITE_AUX: u8 = 0xD4;
+const CONFIG_KEYBOARD_INTERRUPT: u8 = 1 << 0;
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+// https://wiki.osdev.org/PS/2_Mouse
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_ACK: u8 = 0xFA;
+const MOUSE_IRQ: u8 = 12;
+// コントローラがない時は、ステータスが変わらないのでここで諦める
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+// 前回からの移動量。dyは画面と同じく下向きが正
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MouseMotion {
+    pub dx: i64,
+    pub dy: i64,
+    pub left_button: bool,
+    pub right_button: bool,
+}
+
+// byte 0: ボタンと符号とオーバーフロー, byte 1: X, byte 2: Y (上向きが正)
+struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; 3],
+            len: 0,
+        }
+    }
+    fn feed(&mut self, b: u8) -> Option<MouseMotion> {
+        // 1バイト目はbit 3が必ず1。ずれていたら読み捨てて揃え直す
+        if self.len == 0 && b & 0x08 == 0 {
+            return None;
+        }
+        self.bytes[self.len] = b;
+        self.len += 1;
+        if self.len < self.bytes.len() {
+            return None;
+        }
+        self.len = 0;
+        let [flags, x, y] = self.bytes;
+        // 溢れた時の移動量は当てにならないので捨てる
+        if flags & 0xC0 != 0 {
+            return None;
+        }
+        let dx = x as i64 - if flags & 0x10 != 0 { 256 } else { 0 };
+        let dy = y as i64 - if flags & 0x20 != 0 { 256 } else { 0 };
+        Some(MouseMotion {
+            dx,
+            dy: -dy,
+            left_button: flags & 0x01 != 0,
+            right_button: flags & 0x02 != 0,
+        })
+    }
+}
+
+const MOUSE_EVENT_QUEUE_SIZE: usize = 64;
+
+static MOUSE_EVENTS: MpscQueue<MouseMotion, MOUSE_EVENT_QUEUE_SIZE> = MpscQueue::new();
+static DECODER: SpinLockIrqSave<PacketDecoder> = SpinLockIrqSave::new(PacketDecoder::new());
+
+// 割り込みハンドラから積まれるので、待たずにポーリングで取り出す
+pub fn pop_mouse_motion() -> Option<MouseMotion> {
+    MOUSE_EVENTS.pop()
+}
+
+fn wait_status(mask: u8, set: bool) -> Result<()> {
+    let deadline = global_timestamp() + TIMEOUT;
+    while (read_io_port_u8(STATUS_PORT) & mask != 0) != set {
+        if global_timestamp() > deadline {
+            return Err("PS/2 controller did not respond");
+        }
+        busy_loop_hint();
+    }
+    Ok(())
+}
+
+fn write_command(cmd: u8) -> Result<()> {
+    wait_status(STATUS_INPUT_FULL, false)?;
+    write_io_port_u8(STATUS_PORT, cmd);
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<()> {
+    wait_status(STATUS_INPUT_FULL, false)?;
+    write_io_port_u8(DATA_PORT, data);
+    Ok(())
+}
+
+fn read_data() -> Result<u8> {
+    wait_status(STATUS_OUTPUT_FULL, true)?;
+    Ok(read_io_port_u8(DATA_PORT))
+}
+
+fn send_to_mouse(cmd: u8) -> Result<()> {
+    write_command(CMD_WRITE_AUX)?;
+    write_data(cmd)?;
+    if read_data()? != MOUSE_ACK {
+        return Err("PS/2 mouse did not acknowledge the command");
+    }
+    Ok(())
+}
+
+fn mouse_interrupt_handler(_vector: u8) {
+    let mut decoder = DECODER.lock();
+    loop {
+        let status = read_io_port_u8(STATUS_PORT);
+        if status & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        let b = read_io_port_u8(DATA_PORT);
+        if status & STATUS_AUX_DATA == 0 {
+            continue;
+        }
+        if let Some(m) = decoder.feed(b) {
+            // 溢れたら捨てる
+            let _ = MOUSE_EVENTS.push(m);
+        }
+    }
+}
+
+pub fn init_ps2_mouse() -> Result<()> {
+    // キーボードのドライバはないので止めておく。キーボードのデータが残るとマウスのデータが読めなくなる
+    write_command(CMD_DISABLE_KEYBOARD)?;
+    while read_io_port_u8(STATUS_PORT) & STATUS_OUTPUT_FULL != 0 {
+        read_io_port_u8(DATA_PORT);
+    }
+    write_command(CMD_READ_CONFIG)?;
+    let config = read_data()?
+        & !(CONFIG_KEYBOARD_INTERRUPT | CONFIG_AUX_INTERRUPT | CONFIG_AUX_CLOCK_DISABLED);
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+    write_command(CMD_ENABLE_AUX)?;
+    // ACKはポーリングで読みたいので、割り込みは設定が終わってから有効にする
+    send_to_mouse(MOUSE_SET_DEFAULTS)?;
+    send_to_mouse(MOUSE_ENABLE_REPORTING)?;
+    route_isa_irq(MOUSE_IRQ, mouse_interrupt_handler)?;
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config | CONFIG_AUX_INTERRUPT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn decode_mouse_packets() {
+        let mut d = PacketDecoder::new();
+        // 左ボタンを押して右下へ (Yは上向きが正なので負の値)
+        assert_eq!(d.feed(0x29), None);
+        assert_eq!(d.feed(0x05), None);
+        assert_eq!(
+            d.feed(0xFD),
+            Some(MouseMotion {
+                dx: 5,
+                dy: 3,
+                left_button: true,
+                right_button: false,
+            })
+        );
+        // bit 3が立っていないバイトは先頭として扱わない
+        assert_eq!(d.feed(0x00), None);
+        assert_eq!(d.feed(0x18), None);
+        assert_eq!(d.feed(0xFF), None);
+        assert_eq!(d.feed(0x01).map(|m| (m.dx, m.dy)), Some((-1, -1)));
+        // 溢れたパケットは捨てる
+        d.feed(0x48);
+        d.feed(0x00);
+        assert_eq!(d.feed(0x00), None);
+    }
+}
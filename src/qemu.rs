@@ -1,3 +1,6 @@
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
 use crate::x86::hlt;
 use crate::x86::write_io_port_u8;
 
@@ -6,6 +9,40 @@ use crate::x86::write_io_port_u8;
 pub enum QemuExitCode {
     Success = 0x1, // QEMU will exit with status 3
     Fail = 0x2,    // QEMU will exit with status 5
+    // kassert!などが失敗した (テストの時だけ)
+    AssertionFailed = 0x3, // QEMU will exit with status 7
+    Panic = 0x4,           // QEMU will exit with status 9
+    DoubleFault = 0x5,     // QEMU will exit with status 11
+    // ロックを持ったまま長い間切り替えられなかった
+    WatchdogTimeout = 0x6, // QEMU will exit with status 13
+    // test_timeoutを超えても終わらないテストがあった
+    TestTimeout = 0x7, // QEMU will exit with status 15
+}
+
+impl QemuExitCode {
+    fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            0x1 => Some(Self::Success),
+            0x2 => Some(Self::Fail),
+            0x3 => Some(Self::AssertionFailed),
+            0x4 => Some(Self::Panic),
+            0x5 => Some(Self::DoubleFault),
+            0x6 => Some(Self::WatchdogTimeout),
+            0x7 => Some(Self::TestTimeout),
+            _ => None,
+        }
+    }
+}
+
+// パニックの前に設定しておくと、パニックハンドラがこのコードでQEMUを終了する
+static PANIC_EXIT_CODE: AtomicU8 = AtomicU8::new(QemuExitCode::Panic as u8);
+
+pub fn set_panic_exit_code(exit_code: QemuExitCode) {
+    PANIC_EXIT_CODE.store(exit_code as u8, Ordering::SeqCst);
+}
+
+pub fn panic_exit_code() -> QemuExitCode {
+    QemuExitCode::from_u8(PANIC_EXIT_CODE.load(Ordering::SeqCst)).unwrap_or(QemuExitCode::Panic)
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
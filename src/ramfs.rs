@@ -0,0 +1,213 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::fs::lookup;
+use crate::fs::mount;
+use crate::fs::DirEntry;
+use crate::fs::FileSystem;
+use crate::fs::FileType;
+use crate::fs::Inode;
+use crate::fs::Metadata;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::wall_clock::wall_clock_now;
+
+enum RamNodeData {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamNode>>),
+}
+
+struct RamNode {
+    data: Mutex<RamNodeData>,
+    mtime: Mutex<Option<Duration>>,
+}
+
+impl RamNode {
+    fn new(data: RamNodeData) -> Arc<Self> {
+        Arc::new(Self {
+            data: Mutex::new(data),
+            mtime: Mutex::new(wall_clock_now()),
+        })
+    }
+    fn touch(&self) {
+        *self.mtime.lock() = wall_clock_now();
+    }
+    fn file_type(&self) -> FileType {
+        match &*self.data.lock() {
+            RamNodeData::File(_) => FileType::File,
+            RamNodeData::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+impl Inode for RamNode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size) = match &*self.data.lock() {
+            RamNodeData::File(data) => (FileType::File, data.len() as u64),
+            RamNodeData::Directory(_) => (FileType::Directory, 0),
+        };
+        Metadata {
+            file_type,
+            size,
+            mtime: *self.mtime.lock(),
+        }
+    }
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let RamNodeData::File(data) = &*self.data.lock() else {
+            return Err("Not a file");
+        };
+        let Some(src) = data.get(offset as usize..) else {
+            return Ok(0);
+        };
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        Ok(n)
+    }
+    // ファイルの末尾より後ろに書いたら、間は0で埋める
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        {
+            let RamNodeData::File(data) = &mut *self.data.lock() else {
+                return Err("Not a file");
+            };
+            let offset = offset as usize;
+            let end = offset.checked_add(buf.len()).ok_or("File too large")?;
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset..end].copy_from_slice(buf);
+        }
+        self.touch();
+        Ok(buf.len())
+    }
+    fn truncate(&self, size: u64) -> Result<()> {
+        {
+            let RamNodeData::File(data) = &mut *self.data.lock() else {
+                return Err("Not a file");
+            };
+            data.resize(size as usize, 0);
+        }
+        self.touch();
+        Ok(())
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let RamNodeData::Directory(children) = &*self.data.lock() else {
+            return Err("Not a directory");
+        };
+        let child = children.get(name).ok_or("File not found")?;
+        Ok(child.clone())
+    }
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let RamNodeData::Directory(children) = &*self.data.lock() else {
+            return Err("Not a directory");
+        };
+        Ok(children
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                file_type: node.file_type(),
+            })
+            .collect())
+    }
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err("Invalid file name");
+        }
+        let node = {
+            let RamNodeData::Directory(children) = &mut *self.data.lock() else {
+                return Err("Not a directory");
+            };
+            if children.contains_key(name) {
+                return Err("File exists");
+            }
+            let node = match file_type {
+                FileType::File => RamNode::new(RamNodeData::File(Vec::new())),
+                FileType::Directory => RamNode::new(RamNodeData::Directory(BTreeMap::new())),
+                _ => return Err("Unsupported file type"),
+            };
+            children.insert(name.to_string(), node.clone());
+            node
+        };
+        self.touch();
+        Ok(node)
+    }
+    fn remove(&self, name: &str) -> Result<()> {
+        {
+            let RamNodeData::Directory(children) = &mut *self.data.lock() else {
+                return Err("Not a directory");
+            };
+            let child = children.get(name).ok_or("File not found")?;
+            if let RamNodeData::Directory(grandchildren) = &*child.data.lock() {
+                if !grandchildren.is_empty() {
+                    return Err("Directory not empty");
+                }
+            }
+            children.remove(name);
+        }
+        self.touch();
+        Ok(())
+    }
+}
+
+pub struct RamFs {
+    root: Arc<RamNode>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        Self {
+            root: RamNode::new(RamNodeData::Directory(BTreeMap::new())),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+// /tmpにramfsを置く。ルートに何もマウントされていなければルートにも置く
+pub fn init_ramfs() {
+    if lookup("/").is_err() {
+        mount("/", Arc::new(RamFs::new())).expect("Failed to mount ramfs at /");
+    }
+    mount("/tmp", Arc::new(RamFs::new())).expect("Failed to mount ramfs at /tmp");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn create_write_remove() {
+        let fs = RamFs::new();
+        let root = fs.root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        let file = dir.create("a.txt", FileType::File).unwrap();
+        assert_eq!(file.write_at(2, b"xy").unwrap(), 2);
+        let mut buf = [0xFFu8; 8];
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"\0\0xy");
+        assert_eq!(file.metadata().size, 4);
+        assert!(dir.create("a.txt", FileType::File).is_err());
+        assert!(root.remove("dir").is_err());
+        dir.remove("a.txt").unwrap();
+        root.remove("dir").unwrap();
+        assert!(root.read_dir().unwrap().is_empty());
+    }
+}
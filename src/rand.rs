@@ -0,0 +1,165 @@
+use crate::hpet::global_timestamp;
+use crate::mutex::Mutex;
+use crate::x86::rdrand64;
+use crate::x86::read_tsc;
+
+// ChaCha20 (RFC 8439) をキーストリーム生成器として使うCSPRNG
+// キーはエントロピーを混ぜ込むたびに更新する
+const KEY_WORDS: usize = 8;
+const BLOCK_SIZE: usize = 64;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u32; KEY_WORDS], counter: u64, nonce: u32) -> [u8; BLOCK_SIZE] {
+    let mut input = [0u32; 16];
+    // "expand 32-byte k"
+    input[0..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce;
+    let mut s = input;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; BLOCK_SIZE];
+    for (i, chunk) in out.chunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&s[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    out
+}
+
+struct EntropyPool {
+    key: [u32; KEY_WORDS],
+    counter: u64,
+    // 混ぜ込んだエントロピーのバイト数 (強い乱数源からのもののみ)
+    entropy_bytes: usize,
+    seeded: bool,
+}
+
+impl EntropyPool {
+    const fn new() -> Self {
+        Self {
+            key: [0; KEY_WORDS],
+            counter: 0,
+            entropy_bytes: 0,
+            seeded: false,
+        }
+    }
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(KEY_WORDS * 4) {
+            let mut words = [0u32; KEY_WORDS];
+            for (i, b) in chunk.iter().enumerate() {
+                words[i / 4] |= (*b as u32) << ((i % 4) * 8);
+            }
+            for (k, w) in self.key.iter_mut().zip(words.iter()) {
+                *k ^= *w;
+            }
+            // 新しいキーは混ぜた後の状態から作り直す (前の出力からキーを推測できないように)
+            let block = chacha20_block(&self.key, self.counter, 1);
+            self.counter = self.counter.wrapping_add(1);
+            for (i, k) in self.key.iter_mut().enumerate() {
+                *k = u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+            }
+        }
+    }
+    // 時刻の揺らぎは弱いが、他の乱数源が無いときの最後の手段として混ぜる
+    fn seed_from_cpu(&mut self) {
+        let mut buf = [0u8; 32];
+        let mut strong = true;
+        for chunk in buf.chunks_mut(8) {
+            let v = match rdrand64() {
+                Some(v) => v,
+                None => {
+                    strong = false;
+                    read_tsc() ^ (global_timestamp().as_nanos() as u64).rotate_left(32)
+                }
+            };
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        self.mix(&buf);
+        if strong {
+            self.entropy_bytes += buf.len();
+        }
+        self.seeded = true;
+    }
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        if !self.seeded {
+            self.seed_from_cpu();
+        }
+        for chunk in buf.chunks_mut(BLOCK_SIZE) {
+            let block = chacha20_block(&self.key, self.counter, 0);
+            self.counter = self.counter.wrapping_add(1);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        // 出力した後にキーを進めて、過去の出力を再現できないようにする
+        let tsc = read_tsc().to_le_bytes();
+        self.mix(&tsc);
+    }
+}
+
+static ENTROPY_POOL: Mutex<EntropyPool> = Mutex::new(EntropyPool::new());
+
+// virtio-rngなどのハードウェア乱数源から得たバイト列を混ぜ込む
+pub fn add_entropy(data: &[u8]) {
+    let mut pool = ENTROPY_POOL.lock();
+    pool.mix(data);
+    pool.entropy_bytes += data.len();
+    pool.seeded = true;
+}
+
+pub fn entropy_bytes() -> usize {
+    ENTROPY_POOL.lock().entropy_bytes
+}
+
+pub fn fill_bytes(buf: &mut [u8]) {
+    ENTROPY_POOL.lock().fill_bytes(buf)
+}
+
+pub fn random_u32() -> u32 {
+    let mut buf = [0u8; 4];
+    fill_bytes(&mut buf);
+    u32::from_le_bytes(buf)
+}
+
+pub fn random_u64() -> u64 {
+    let mut buf = [0u8; 8];
+    fill_bytes(&mut buf);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // RFC 8439 2.3.2 Test Vector for the ChaCha20 Block Function
+    #[test_case]
+    fn chacha20_block_test_vector() {
+        let mut key = [0u32; KEY_WORDS];
+        for (i, k) in key.iter_mut().enumerate() {
+            let b = (i * 4) as u8;
+            *k = u32::from_le_bytes([b, b + 1, b + 2, b + 3]);
+        }
+        // counter = 1, nonce = 00:00:00:09:00:00:00:4a:00:00:00:00
+        let counter = 1 | (0x09000000u64 << 32);
+        let block = chacha20_block(&key, counter, 0x4a000000);
+        assert_eq!(block[0..4], [0x10, 0xf1, 0xe7, 0xe4]);
+        assert_eq!(block[60..64], [0xa2, 0x50, 0x3c, 0x4e]);
+    }
+}
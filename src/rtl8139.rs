@@ -0,0 +1,340 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::task::Poll;
+
+use crate::dma::DmaBuffer;
+use crate::executor::AtomicWaker;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::net::register_nic;
+use crate::net::MacAddress;
+use crate::net::NicDriver;
+use crate::pci::MmioRegion;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::result::Result;
+use crate::warn;
+use crate::x86::busy_loop_hint;
+
+// RTL8139 (A/B/C) Programming Guide / Datasheet
+// https://wiki.osdev.org/RTL8139
+const REG_IDR0: usize = 0x00;
+const REG_MAR0: usize = 0x08;
+const REG_TSD0: usize = 0x10;
+const REG_TSAD0: usize = 0x20;
+const REG_RBSTART: usize = 0x30;
+const REG_CR: usize = 0x37;
+const REG_CAPR: usize = 0x38;
+const REG_IMR: usize = 0x3C;
+const REG_ISR: usize = 0x3E;
+const REG_TCR: usize = 0x40;
+const REG_RCR: usize = 0x44;
+const REG_CONFIG1: usize = 0x52;
+const REG_MSR: usize = 0x58;
+
+const CR_BUFE: u8 = 1 << 0;
+const CR_TE: u8 = 1 << 2;
+const CR_RE: u8 = 1 << 3;
+const CR_RST: u8 = 1 << 4;
+
+const INT_ROK: u16 = 1 << 0;
+const INT_RER: u16 = 1 << 1;
+const INT_TOK: u16 = 1 << 2;
+const INT_TER: u16 = 1 << 3;
+const INT_RXOVW: u16 = 1 << 4;
+const INT_LINKCHG: u16 = 1 << 5;
+const INT_FOVW: u16 = 1 << 6;
+
+// 自分宛て, マルチキャスト, ブロードキャストを受け付ける
+// WRAPを立てるとバッファ末尾を越えたパケットが連続して書かれる
+const RCR_APM: u32 = 1 << 1;
+const RCR_AM: u32 = 1 << 2;
+const RCR_AB: u32 = 1 << 3;
+const RCR_WRAP: u32 = 1 << 7;
+// 最大DMAバーストサイズ = 1024バイト
+const TCR_MXDMA_1024: u32 = 0b110 << 8;
+
+const TSD_SIZE_MASK: u32 = 0x1FFF;
+const TSD_OWN: u32 = 1 << 13;
+const RX_STATUS_ROK: u16 = 1 << 0;
+const MSR_LINKB: u8 = 1 << 2;
+
+const NUM_OF_TX_SLOTS: usize = 4;
+// RBLEN = 00 (8K + 16バイト) にWRAP用の余白を足す
+const RX_RING_SIZE: usize = 8192;
+const RX_BUFFER_SIZE: usize = RX_RING_SIZE + 16 + 1536;
+const TX_BUFFER_SIZE: usize = 1536;
+const MIN_FRAME_SIZE: usize = 60;
+const MAX_FRAME_SIZE: usize = 1514;
+const CRC_SIZE: usize = 4;
+
+pub struct Rtl8139 {
+    regs: MmioRegion,
+    mac: MacAddress,
+    rx_buffer: DmaBuffer,
+    tx_buffers: [DmaBuffer; NUM_OF_TX_SLOTS],
+    rx_offset: usize,
+    tx_next: usize,
+    link_up: bool,
+}
+unsafe impl Send for Rtl8139 {}
+
+static RTL8139: Mutex<Option<Rtl8139>> = Mutex::new(None);
+static RTL8139_REGS: AtomicUsize = AtomicUsize::new(0);
+static RX_PENDING: AtomicBool = AtomicBool::new(false);
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+static LINK_CHANGED: AtomicBool = AtomicBool::new(false);
+
+// INTxはレベルトリガなので、ISRをクリアしてから戻る
+fn interrupt_handler(_vector: u8) {
+    let base = RTL8139_REGS.load(Ordering::SeqCst);
+    if base == 0 {
+        return;
+    }
+    let isr = (base + REG_ISR) as *mut u16;
+    let status = unsafe { core::ptr::read_volatile(isr) };
+    unsafe { core::ptr::write_volatile(isr, status) };
+    if status & (INT_ROK | INT_RER | INT_RXOVW | INT_FOVW) != 0 {
+        RX_PENDING.store(true, Ordering::SeqCst);
+        RX_WAKER.wake();
+    }
+    if status & INT_LINKCHG != 0 {
+        LINK_CHANGED.store(true, Ordering::SeqCst);
+    }
+}
+
+// TSADとRBSTARTは32ビットなので4GiB未満のバッファしか渡せない
+fn dma_buffer_below_4g(size: usize) -> Result<DmaBuffer> {
+    let buf = DmaBuffer::new(size, 16)?;
+    if buf.phys_addr() + buf.len() as u64 > u32::MAX as u64 {
+        return Err("rtl8139: DMA buffer is above 4GiB");
+    }
+    Ok(buf)
+}
+
+impl Rtl8139 {
+    fn new(pci: &PciDevice) -> Result<Self> {
+        pci.enable_memory_space();
+        pci.enable_bus_master();
+        // BAR0はI/O空間, BAR1が同じレジスタのMMIO
+        let regs = pci.bar(1)?.map()?;
+        let mut nic = Self {
+            regs,
+            mac: [0; 6],
+            rx_buffer: dma_buffer_below_4g(RX_BUFFER_SIZE)?,
+            tx_buffers: [
+                dma_buffer_below_4g(TX_BUFFER_SIZE)?,
+                dma_buffer_below_4g(TX_BUFFER_SIZE)?,
+                dma_buffer_below_4g(TX_BUFFER_SIZE)?,
+                dma_buffer_below_4g(TX_BUFFER_SIZE)?,
+            ],
+            rx_offset: 0,
+            tx_next: 0,
+            link_up: false,
+        };
+        nic.reset()?;
+        for (i, e) in nic.mac.iter_mut().enumerate() {
+            *e = nic.regs.read(REG_IDR0 + i);
+        }
+        nic.regs
+            .write_u32(REG_RBSTART, nic.rx_buffer.phys_addr() as u32);
+        for i in 0..NUM_OF_TX_SLOTS {
+            nic.regs
+                .write_u32(REG_TSAD0 + i * 4, nic.tx_buffers[i].phys_addr() as u32);
+        }
+        // マルチキャストは全部受け取る
+        nic.regs.write_u32(REG_MAR0, 0xFFFF_FFFF);
+        nic.regs.write_u32(REG_MAR0 + 4, 0xFFFF_FFFF);
+        nic.regs.write::<u8>(REG_CR, CR_RE | CR_TE);
+        nic.regs
+            .write_u32(REG_RCR, RCR_APM | RCR_AM | RCR_AB | RCR_WRAP);
+        nic.regs.write_u32(REG_TCR, TCR_MXDMA_1024);
+        nic.link_up = nic.is_link_up();
+        Ok(nic)
+    }
+    fn reset(&mut self) -> Result<()> {
+        // LWAKE + LWPTN をLowにして電源を入れる
+        self.regs.write::<u8>(REG_CONFIG1, 0);
+        self.regs.write::<u8>(REG_CR, CR_RST);
+        let mut retry = 0;
+        while self.regs.read::<u8>(REG_CR) & CR_RST != 0 {
+            retry += 1;
+            if retry > 1_000_000 {
+                return Err("rtl8139: reset timed out");
+            }
+            busy_loop_hint();
+        }
+        self.regs.write::<u16>(REG_IMR, 0);
+        self.regs.write::<u16>(REG_ISR, 0xFFFF);
+        Ok(())
+    }
+    fn enable_interrupt(&mut self, pci: &PciDevice) -> Result<()> {
+        pci.enable_intx(interrupt_handler)?;
+        RTL8139_REGS.store(self.regs.base() as usize, Ordering::SeqCst);
+        self.regs.write::<u16>(
+            REG_IMR,
+            INT_ROK | INT_RER | INT_TOK | INT_TER | INT_RXOVW | INT_LINKCHG | INT_FOVW,
+        );
+        Ok(())
+    }
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+    pub fn is_link_up(&self) -> bool {
+        self.regs.read::<u8>(REG_MSR) & MSR_LINKB == 0
+    }
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err("Frame too large");
+        }
+        let slot = self.tx_next;
+        let tsd = REG_TSD0 + slot * 4;
+        // OWNが立っていればDMAが終わっていて再利用できる
+        if self.regs.read_u32(tsd) & TSD_OWN == 0 {
+            return Err("rtl8139: tx slots are busy");
+        }
+        // 短いフレームはパディングする (ハードウェアは自動でパディングしない)
+        let len = frame.len().max(MIN_FRAME_SIZE);
+        let buf = self.tx_buffers[slot].as_mut_slice();
+        buf[..frame.len()].copy_from_slice(frame);
+        buf[frame.len()..len].fill(0);
+        // サイズを書いてOWNを落とすと送信が始まる
+        self.regs.write_u32(tsd, len as u32 & TSD_SIZE_MASK);
+        self.tx_next = (slot + 1) % NUM_OF_TX_SLOTS;
+        Ok(())
+    }
+    fn check_link(&mut self) {
+        if !LINK_CHANGED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let link_up = self.is_link_up();
+        if link_up != self.link_up {
+            info!("rtl8139: link {}", if link_up { "up" } else { "down" });
+            self.link_up = link_up;
+        }
+    }
+    // 受信リングには (status: u16, length: u16, data...) が4バイト境界で並んでいる
+    pub fn poll(&mut self, rx: &mut dyn FnMut(&[u8])) -> usize {
+        RX_PENDING.store(false, Ordering::SeqCst);
+        self.check_link();
+        let mut count = 0;
+        while self.regs.read::<u8>(REG_CR) & CR_BUFE == 0 {
+            let ring = self.rx_buffer.as_slice();
+            let header = &ring[self.rx_offset..self.rx_offset + 4];
+            let status = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            if status & RX_STATUS_ROK == 0 || !(CRC_SIZE..=MAX_FRAME_SIZE + CRC_SIZE).contains(&len)
+            {
+                // リングの状態が壊れているので受信部をやり直す
+                warn!("rtl8139: bad rx header (status {status:#X}, len {len}), resetting rx");
+                self.restart_rx();
+                break;
+            }
+            let data = self.rx_offset + 4;
+            rx(&ring[data..data + len - CRC_SIZE]);
+            count += 1;
+            self.rx_offset = (data + len + 3) & !3;
+            self.rx_offset %= RX_RING_SIZE;
+            // CAPRは読んだ位置より16バイト手前を指す (ハードウェアの癖)
+            self.regs
+                .write::<u16>(REG_CAPR, (self.rx_offset as u16).wrapping_sub(16));
+        }
+        count
+    }
+    fn restart_rx(&mut self) {
+        self.regs.write::<u8>(REG_CR, CR_TE);
+        self.regs
+            .write_u32(REG_RBSTART, self.rx_buffer.phys_addr() as u32);
+        self.rx_offset = 0;
+        self.regs.write::<u8>(REG_CR, CR_RE | CR_TE);
+        self.regs
+            .write_u32(REG_RCR, RCR_APM | RCR_AM | RCR_AB | RCR_WRAP);
+    }
+}
+
+pub fn with_rtl8139<R>(f: impl FnOnce(&mut Rtl8139) -> R) -> Option<R> {
+    RTL8139.lock().as_mut().map(f)
+}
+
+pub fn has_pending_rx() -> bool {
+    RX_PENDING.load(Ordering::SeqCst)
+}
+
+// 受信割り込みが来るまで待つ
+pub async fn wait_for_rx() {
+    poll_fn(|cx| {
+        if has_pending_rx() {
+            return Poll::Ready(());
+        }
+        RX_WAKER.register(cx.waker());
+        if has_pending_rx() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// ネットワークスタックから使うためのもの。デバイスはRTL8139に入っているものを使う
+pub struct Rtl8139Nic;
+
+impl NicDriver for Rtl8139Nic {
+    fn name(&self) -> &'static str {
+        "rtl8139"
+    }
+    fn mac_address(&self) -> MacAddress {
+        with_rtl8139(|n| n.mac_address()).unwrap_or_default()
+    }
+    fn is_link_up(&self) -> bool {
+        with_rtl8139(|n| n.is_link_up()).unwrap_or(false)
+    }
+    fn transmit(&self, frame: &[u8]) -> Result<()> {
+        with_rtl8139(|n| n.transmit(frame)).ok_or("rtl8139 is not initialized")?
+    }
+    fn receive(&self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        with_rtl8139(|n| n.poll(&mut |frame| frames.push(frame.to_vec())));
+        frames
+    }
+}
+
+pub struct Rtl8139Driver;
+
+impl PciDriver for Rtl8139Driver {
+    fn name(&self) -> &'static str {
+        "rtl8139"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        const MATCHES: [PciDeviceMatch; 1] = [PciDeviceMatch::id(0x10EC, 0x8139)];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        let mut global = RTL8139.lock();
+        if global.is_some() {
+            return Err("Only one rtl8139 device is supported");
+        }
+        let mut nic = Rtl8139::new(device)?;
+        if let Err(e) = nic.enable_interrupt(device) {
+            warn!("rtl8139: interrupts unavailable, falling back to polling: {e}");
+        }
+        info!(
+            "rtl8139: MAC address {:02X?}, link {}",
+            nic.mac_address(),
+            if nic.is_link_up() { "up" } else { "down" }
+        );
+        *global = Some(nic);
+        drop(global);
+        register_nic(Arc::new(Rtl8139Nic));
+        Ok(())
+    }
+}
+
+pub static RTL8139_DRIVER: Rtl8139Driver = Rtl8139Driver;
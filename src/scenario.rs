@@ -0,0 +1,144 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use core::time::Duration;
+
+use crate::bench::run_benchmarks;
+use crate::block::block_device;
+use crate::block::block_device_names;
+use crate::boot::cmdline;
+use crate::error;
+use crate::fw_cfg::read_fw_cfg_file;
+use crate::info;
+use crate::kthread;
+use crate::qemu::exit_qemu;
+use crate::qemu::QemuExitCode;
+use crate::result::Result;
+use crate::task::sleep;
+use crate::task::ticks;
+use crate::x86::read_cr3;
+use crate::x86::TranslationResult;
+
+// 結合テストの1つ分。カーネルを全部初期化した後に動かして、結果でQEMUを終了する
+// scripts/run_scenarios.sh が1つずつQEMUを起動して順に試す
+pub struct Scenario {
+    pub name: &'static str,
+    run: fn() -> Result<()>,
+}
+
+pub static SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "paging",
+        run: paging,
+    },
+    Scenario {
+        name: "timer",
+        run: timer,
+    },
+    Scenario {
+        name: "kthread",
+        run: kthread_join,
+    },
+    Scenario {
+        name: "block",
+        run: block_read,
+    },
+    Scenario {
+        name: "bench",
+        run: bench,
+    },
+];
+
+// -fw_cfg name=opt/wasabi/scenario,string=... で渡す。コマンドラインの scenario= でもよい
+const SCENARIO_FW_CFG_FILE: &str = "opt/wasabi/scenario";
+
+// 動かすシナリオの名前。通常の起動ならNone
+pub fn selected_scenario() -> Option<String> {
+    if let Some(name) = cmdline().get("scenario") {
+        return Some(name.into());
+    }
+    let name = read_fw_cfg_file(SCENARIO_FW_CFG_FILE).ok()?;
+    let name = core::str::from_utf8(&name)
+        .ok()?
+        .trim_end_matches(['\0', '\n']);
+    (!name.is_empty()).then(|| name.into())
+}
+
+pub fn run_scenario(name: &str) -> ! {
+    let Some(scenario) = SCENARIOS.iter().find(|s| s.name == name) else {
+        error!("SCENARIO {name}: unknown scenario");
+        for s in SCENARIOS {
+            info!("  {}", s.name);
+        }
+        exit_qemu(QemuExitCode::Fail)
+    };
+    info!("SCENARIO {name}: start");
+    match (scenario.run)() {
+        Ok(()) => {
+            info!("SCENARIO {name}: PASS");
+            exit_qemu(QemuExitCode::Success)
+        }
+        Err(e) => {
+            error!("SCENARIO {name}: FAIL: {e}");
+            exit_qemu(QemuExitCode::Fail)
+        }
+    }
+}
+
+// 有効にしたページングの上で、カーネルのコードとスタックがそのままの番地で見えている
+fn paging() -> Result<()> {
+    let table = unsafe { &*read_cr3() };
+    let stack_var = 0u64;
+    for addr in [paging as *const () as u64, &stack_var as *const u64 as u64] {
+        let phys = match table.translate(addr)? {
+            TranslationResult::PageMapped4K { phys }
+            | TranslationResult::PageMapped2M { phys }
+            | TranslationResult::PageMapped1G { phys } => phys,
+        };
+        if phys != addr {
+            return Err("Kernel memory is not identity mapped");
+        }
+    }
+    Ok(())
+}
+
+// タイマ割り込みでtickが進み、sleepから戻ってこられる
+fn timer() -> Result<()> {
+    let t0 = ticks();
+    sleep(Duration::from_millis(200));
+    if ticks() <= t0 {
+        return Err("Timer ticks did not advance");
+    }
+    Ok(())
+}
+
+fn kthread_join() -> Result<()> {
+    let handle = kthread::spawn("scenario", || {
+        sleep(Duration::from_millis(10));
+        42
+    });
+    if handle.join() != 42 {
+        return Err("Unexpected value from the thread");
+    }
+    Ok(())
+}
+
+// 結果はログに出るので、比べるのはrun_scenarios.shのログで行う
+fn bench() -> Result<()> {
+    run_benchmarks();
+    Ok(())
+}
+
+// ディスクを付けて起動した時に、最初のブロックデバイスの先頭を読めるか
+fn block_read() -> Result<()> {
+    let name = block_device_names()
+        .into_iter()
+        .next()
+        .ok_or("No block device")?;
+    let dev = block_device(&name).ok_or("Block device disappeared")?;
+    let mut buf = vec![0u8; dev.block_size()];
+    dev.read_blocks(0, &mut buf)?;
+    info!("{name}: {} blocks, read block 0", dev.num_of_blocks());
+    Ok(())
+}
@@ -0,0 +1,155 @@
+extern crate alloc;
+
+use alloc::alloc::alloc;
+use alloc::alloc::dealloc;
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::time::Duration;
+
+use crate::error;
+use crate::hpet::busy_wait;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::result::Result;
+use crate::task::ticks;
+use crate::x86::breakpoint_count;
+use crate::x86::PageAttr;
+use crate::x86::TranslationResult;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+
+// 実機ではcargo testのハーネスが使えないので、起動の途中でカーネル自身の基本的な動作を確かめる
+// カーネルコマンドラインの selftest で有効になる
+struct SelfTest {
+    name: &'static str,
+    run: fn() -> Result<()>,
+}
+
+static SELF_TESTS: &[SelfTest] = &[
+    SelfTest {
+        name: "allocator",
+        run: allocator_integrity,
+    },
+    SelfTest {
+        name: "page table",
+        run: page_table_round_trip,
+    },
+    SelfTest {
+        name: "int3",
+        run: int3_delivery,
+    },
+    SelfTest {
+        name: "timer",
+        run: timer_monotonicity,
+    },
+];
+
+// 失敗した数を返す
+pub fn run_all() -> usize {
+    let mut failed = 0;
+    for test in SELF_TESTS {
+        match (test.run)() {
+            Ok(()) => {
+                info!("selftest {}: ok", test.name);
+            }
+            Err(e) => {
+                error!("selftest {}: FAILED: {e}", test.name);
+                failed += 1;
+            }
+        }
+    }
+    info!(
+        "selftest: {} passed, {failed} failed",
+        SELF_TESTS.len() - failed
+    );
+    failed
+}
+
+// いろいろな大きさの領域を同時に確保して、書いた値が他の領域に壊されていないか確かめる
+fn allocator_integrity() -> Result<()> {
+    let mut blocks: Vec<Vec<u8>> = Vec::new();
+    for (i, size) in [1, 7, 64, 100, 4096, 5000, 65536].iter().enumerate() {
+        blocks.push(alloc::vec![i as u8 + 1; *size]);
+    }
+    // 間を空けてから、その穴に入る大きさでもう一度確保する
+    blocks.remove(2);
+    blocks.push(alloc::vec![0xaa; 48]);
+    for block in &blocks {
+        let Some(&first) = block.first() else {
+            continue;
+        };
+        if block.iter().any(|b| *b != first) {
+            return Err("Allocated memory was overwritten");
+        }
+    }
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).map_err(|_| "Invalid layout")?;
+    let p = unsafe { alloc(layout) };
+    if p.is_null() || p as usize % PAGE_SIZE != 0 {
+        return Err("Aligned allocation failed");
+    }
+    unsafe { dealloc(p, layout) };
+    Ok(())
+}
+
+// 使っていないテーブルにマッピングを作り、translateで同じ物理アドレスに戻ってくるか
+fn page_table_round_trip() -> Result<()> {
+    const VIRT: u64 = 0x0000_4000_0000_0000;
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).map_err(|_| "Invalid layout")?;
+    let page = unsafe { alloc(layout) };
+    if page.is_null() {
+        return Err("Failed to allocate a page");
+    }
+    let phys = page as u64;
+    let mut table = PML4::new();
+    let result = table
+        .create_mapping(
+            VIRT,
+            VIRT + PAGE_SIZE as u64,
+            phys,
+            PageAttr::ReadWriteKernel,
+        )
+        .and_then(|_| table.translate(VIRT + 0x123))
+        .and_then(|r| match r {
+            TranslationResult::PageMapped4K { phys: p } if p == phys + 0x123 => Ok(()),
+            _ => Err("Translated to a wrong address"),
+        });
+    let unmapped = table.translate(VIRT + PAGE_SIZE as u64).is_err();
+    unsafe {
+        table.free_tables(0..512);
+        dealloc(page, layout);
+    }
+    result?;
+    if !unmapped {
+        return Err("The next page was mapped unexpectedly");
+    }
+    Ok(())
+}
+
+// int3で例外ハンドラまで届き、そこから戻ってこられるか
+fn int3_delivery() -> Result<()> {
+    let before = breakpoint_count();
+    unsafe { asm!("int3") };
+    if breakpoint_count() != before + 1 {
+        return Err("Breakpoint exception was not delivered");
+    }
+    Ok(())
+}
+
+// HPETの時刻とタイマ割り込みのtickが戻らずに進むか
+fn timer_monotonicity() -> Result<()> {
+    let t0 = ticks();
+    let mut prev = global_timestamp();
+    for _ in 0..100 {
+        busy_wait(Duration::from_millis(1));
+        let now = global_timestamp();
+        if now <= prev {
+            return Err("HPET timestamp did not advance");
+        }
+        prev = now;
+    }
+    if ticks() <= t0 {
+        return Err("Timer ticks did not advance");
+    }
+    Ok(())
+}
@@ -1,9 +1,67 @@
 use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
 
+use crate::apic::route_isa_irq;
+use crate::mutex::SpinLockIrqSave;
+use crate::result::Result;
 use crate::x86::busy_loop_hint;
 use crate::x86::read_io_port_u8;
 use crate::x86::write_io_port_u8;
 
+// https://wiki.osdev.org/Serial_Ports
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMITTER_EMPTY: u8 = 1 << 5;
+const INTERRUPT_ENABLE_RECEIVED_DATA: u8 = 1 << 0;
+const MODEM_CONTROL_OUT2: u8 = 1 << 3;
+
+const COM1_BASE: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+
+// 割り込みハンドラで受信したバイトを溜めておくリングバッファ
+struct RxRing {
+    buf: [u8; 256],
+    read: usize,
+    write: usize,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; 256],
+            read: 0,
+            write: 0,
+        }
+    }
+    fn push(&mut self, c: u8) {
+        let next = (self.write + 1) % self.buf.len();
+        // 溢れたら捨てる
+        if next != self.read {
+            self.buf[self.write] = c;
+            self.write = next;
+        }
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.read == self.write {
+            None
+        } else {
+            let c = self.buf[self.read];
+            self.read = (self.read + 1) % self.buf.len();
+            Some(c)
+        }
+    }
+}
+
+static COM1_RX: SpinLockIrqSave<RxRing> = SpinLockIrqSave::new(RxRing::new());
+static COM1_RX_INTERRUPT_ENABLED: AtomicBool = AtomicBool::new(false);
+
 pub struct SerialPort {
     base: u16,
 }
@@ -14,34 +72,88 @@ impl SerialPort {
     }
 
     pub fn new_for_com1() -> Self {
-        Self::new(0x3f8)
+        Self::new(COM1_BASE)
     }
 
     pub fn init(&mut self) {
-        write_io_port_u8(self.base + 1, 0x00);
-        write_io_port_u8(self.base + 3, 0x80);
-        const BAUD_DIVISOR: u16 = 0x0001;
+        write_io_port_u8(self.base + REG_INTERRUPT_ENABLE, 0x00);
+        // DLAB=1にして分周比を設定する
+        write_io_port_u8(self.base + REG_LINE_CONTROL, 0x80);
+        const BAUD_DIVISOR: u16 = 0x0001; // 115200 baud
         write_io_port_u8(self.base, (BAUD_DIVISOR & 0xff) as u8);
-        write_io_port_u8(self.base, (BAUD_DIVISOR >> 8) as u8);
-        write_io_port_u8(self.base, 0x03);
-        write_io_port_u8(self.base + 2, 0xC7);
-        write_io_port_u8(self.base + 4, 0x0B);
+        write_io_port_u8(self.base + 1, (BAUD_DIVISOR >> 8) as u8);
+        // 8bit, no parity, 1 stop bit (DLAB=0)
+        write_io_port_u8(self.base + REG_LINE_CONTROL, 0x03);
+        write_io_port_u8(self.base + REG_FIFO_CONTROL, 0xC7);
+        write_io_port_u8(self.base + REG_MODEM_CONTROL, 0x0B);
     }
 
     pub fn send_char(&self, c: char) {
-        while (read_io_port_u8(self.base + 5) & 0x20) == 0 {
+        self.send_byte(c as u8)
+    }
+
+    pub fn send_byte(&self, c: u8) {
+        while (read_io_port_u8(self.base + REG_LINE_STATUS) & LINE_STATUS_TRANSMITTER_EMPTY) == 0 {
             busy_loop_hint();
         }
-        write_io_port_u8(self.base, c as u8)
+        write_io_port_u8(self.base + REG_DATA, c)
     }
 
     pub fn send_str(&self, s: &str) {
-        let mut sc = s.chars();
-        let slen = s.chars().count();
-        for _ in 0..slen {
-            self.send_char(sc.next().unwrap());
+        for c in s.bytes() {
+            self.send_byte(c);
         }
     }
+
+    pub fn poll_byte(&self) -> Option<u8> {
+        if read_io_port_u8(self.base + REG_LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+            Some(read_io_port_u8(self.base + REG_DATA))
+        } else {
+            None
+        }
+    }
+
+    // 受信割り込みが有効ならバッファから、そうでなければ直接ポーリングして読む
+    pub fn try_read_byte(&self) -> Option<u8> {
+        if self.base == COM1_BASE && COM1_RX_INTERRUPT_ENABLED.load(Ordering::SeqCst) {
+            COM1_RX.lock().pop()
+        } else {
+            self.poll_byte()
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(c) = self.try_read_byte() {
+                return c;
+            }
+            busy_loop_hint();
+        }
+    }
+
+    // COM1の受信をIRQ4経由の割り込みで受け取るようにする
+    pub fn enable_rx_interrupt(&self) -> Result<()> {
+        if self.base != COM1_BASE {
+            return Err("Only COM1 supports interrupts");
+        }
+        route_isa_irq(COM1_IRQ, com1_interrupt_handler)?;
+        COM1_RX_INTERRUPT_ENABLED.store(true, Ordering::SeqCst);
+        let mcr = read_io_port_u8(self.base + REG_MODEM_CONTROL);
+        write_io_port_u8(self.base + REG_MODEM_CONTROL, mcr | MODEM_CONTROL_OUT2);
+        write_io_port_u8(
+            self.base + REG_INTERRUPT_ENABLE,
+            INTERRUPT_ENABLE_RECEIVED_DATA,
+        );
+        Ok(())
+    }
+}
+
+fn com1_interrupt_handler(_vector: u8) {
+    let port = SerialPort::new_for_com1();
+    let mut rx = COM1_RX.lock();
+    while let Some(c) = port.poll_byte() {
+        rx.push(c);
+    }
 }
 
 impl Default for SerialPort {
@@ -52,8 +164,7 @@ impl Default for SerialPort {
 
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let serial = Self::default();
-        serial.send_str(s);
+        self.send_str(s);
         Ok(())
     }
 }
@@ -0,0 +1,209 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use crate::keyboard::pop_key_event;
+use crate::kthread;
+use crate::print::global_print;
+use crate::println;
+use crate::result::Result;
+use crate::serial::SerialPort;
+use crate::task::sleep;
+use crate::virtio_console::virtio_console_read_byte;
+
+mod diag;
+pub mod line_editor;
+mod peek;
+
+use line_editor::EditKey;
+use line_editor::LineEditor;
+use line_editor::Vt100Decoder;
+
+// カーネルの中で動く、調べものをするためのシェル
+// シリアルと画面のコンソールの両方から入力を受け付け、出力はglobal_printに出す
+// カーネルコマンドラインの shell で有効になる
+
+const PROMPT: &str = "wasabi> ";
+// シリアルの受信には待つ仕組みが無いので、入力が無い間はこの間隔で見に行く
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(shell: &Shell, args: &[&str]) -> Result<()>,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "history",
+        help: "show the input history",
+        run: history,
+    },
+    Command {
+        name: "hexdump",
+        help: "[-p] <addr> [len] dump virtual (or physical with -p) memory",
+        run: peek::hexdump,
+    },
+    Command {
+        name: "in",
+        help: "<port> [width] read an I/O port (width 1, 2 or 4 bytes)",
+        run: peek::port_in,
+    },
+    Command {
+        name: "irqstat",
+        help: "show interrupt and exception counts per vector",
+        run: diag::irqstat,
+    },
+    Command {
+        name: "lsacpi",
+        help: "list ACPI tables",
+        run: diag::lsacpi,
+    },
+    Command {
+        name: "lspci",
+        help: "list PCI devices",
+        run: diag::lspci,
+    },
+    Command {
+        name: "mem",
+        help: "show heap usage",
+        run: diag::mem,
+    },
+    Command {
+        name: "out",
+        help: "<port> <value> [width] write an I/O port",
+        run: peek::port_out,
+    },
+    Command {
+        name: "peek",
+        help: "<addr> [width] read memory (width 1, 2, 4 or 8 bytes)",
+        run: peek::peek,
+    },
+    Command {
+        name: "poke",
+        help: "<addr> <value> [width] write memory",
+        run: peek::poke,
+    },
+    Command {
+        name: "ps",
+        help: "list tasks",
+        run: diag::ps,
+    },
+    Command {
+        name: "top",
+        help: "[secs] show CPU usage of tasks every secs seconds until a key is pressed",
+        run: diag::top,
+    },
+    Command {
+        name: "uptime",
+        help: "show the time since boot",
+        run: diag::uptime,
+    },
+];
+
+struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        global_print(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+pub struct Shell {
+    editor: LineEditor,
+    decoder: Vt100Decoder,
+    serial: SerialPort,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Self {
+            editor: LineEditor::new(),
+            decoder: Vt100Decoder::new(),
+            serial: SerialPort::new_for_com1(),
+        }
+    }
+    fn next_key(&mut self) -> EditKey {
+        loop {
+            while let Some(b) = self
+                .serial
+                .try_read_byte()
+                .or_else(virtio_console_read_byte)
+            {
+                if let Some(key) = self.decoder.feed(b) {
+                    return key;
+                }
+            }
+            while let Some(e) = pop_key_event() {
+                if let Some(key) = EditKey::from_key_event(&e) {
+                    return key;
+                }
+            }
+            sleep(INPUT_POLL_INTERVAL);
+        }
+    }
+    // 何かキーが押されていたら、それを読み捨ててtrueを返す (topなどを止めるのに使う)
+    fn key_pressed(&self) -> bool {
+        self.serial.try_read_byte().is_some()
+            || virtio_console_read_byte().is_some()
+            || pop_key_event().is_some_and(|e| e.pressed)
+    }
+    fn read_line(&mut self) -> String {
+        global_print(format_args!("{PROMPT}"));
+        loop {
+            let key = self.next_key();
+            if let Some(line) = self.editor.feed(key, &mut Console) {
+                return line;
+            }
+        }
+    }
+    fn execute(&self, line: &str) {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(name) = args.first() else {
+            return;
+        };
+        match COMMANDS.iter().find(|c| c.name == *name) {
+            Some(command) => {
+                if let Err(e) = (command.run)(self, &args[1..]) {
+                    println!("{name}: {e}");
+                }
+            }
+            None => {
+                println!("{name}: command not found (try help)");
+            }
+        }
+    }
+    fn run(mut self) -> ! {
+        loop {
+            let line = self.read_line();
+            self.execute(&line);
+        }
+    }
+}
+
+pub fn start_shell() {
+    kthread::spawn("shell", || Shell::new().run());
+}
+
+fn help(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    for c in COMMANDS {
+        println!("  {:<12} {}", c.name, c.help);
+    }
+    Ok(())
+}
+
+fn history(shell: &Shell, _args: &[&str]) -> Result<()> {
+    for (i, line) in shell.editor.history().enumerate() {
+        println!("{:>4}  {line}", i + 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,198 @@
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::Shell;
+use crate::acpi::global_rsdp;
+use crate::allocator::ALLOCATOR;
+use crate::hpet::global_timestamp;
+use crate::pci;
+use crate::pci::class_name;
+use crate::pci::vendor_name;
+use crate::println;
+use crate::result::Result;
+use crate::task::sleep;
+use crate::task::task_list;
+use crate::task::ticks;
+use crate::task::TaskInfo;
+use crate::task::KERNEL_STACK_SIZE;
+use crate::task::TIMER_HZ;
+use crate::x86::interrupt_counts;
+use crate::x86::FIRST_EXTERNAL_VECTOR;
+
+// 各所にあるダンプ用の関数や統計を、シェルから見られるようにしたもの
+
+pub(super) fn mem(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    let stats = ALLOCATOR.stats();
+    let kib = |bytes: usize| bytes / 1024;
+    println!(
+        "used: {:>10} KiB in {} chunks",
+        kib(stats.used_bytes),
+        stats.used_chunks
+    );
+    println!(
+        "free: {:>10} KiB in {} chunks (largest {} KiB)",
+        kib(stats.free_bytes),
+        stats.free_chunks,
+        kib(stats.largest_free_chunk)
+    );
+    Ok(())
+}
+
+pub(super) fn uptime(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    let t = global_timestamp();
+    let secs = t.as_secs();
+    println!(
+        "up {}:{:02}:{:02}.{:03} ({} timer ticks)",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        (t - Duration::from_secs(secs)).as_millis(),
+        ticks()
+    );
+    Ok(())
+}
+
+pub(super) fn lspci(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    for device in pci::devices() {
+        println!(
+            "{} {} [{:02X}{:02X}]: {} {:?} (rev {:02X})",
+            device.bdf(),
+            class_name(device.class()),
+            device.class().base,
+            device.class().sub,
+            vendor_name(device.vendor_id()),
+            device.id(),
+            device.revision()
+        );
+    }
+    Ok(())
+}
+
+pub(super) fn lsacpi(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    let rsdp = global_rsdp().ok_or("ACPI is not available")?;
+    for table in rsdp.tables() {
+        println!(
+            "{} {:#018X} {:>6} bytes",
+            core::str::from_utf8(&table.signature).unwrap_or("????"),
+            table.address,
+            table.length
+        );
+    }
+    Ok(())
+}
+
+// 例外の名前 (Intel SDM Vol.3 6.15)
+fn exception_name(vector: usize) -> &'static str {
+    match vector {
+        0 => "Divide Error",
+        1 => "Debug",
+        2 => "NMI",
+        3 => "Breakpoint",
+        6 => "Invalid Opcode",
+        8 => "Double Fault",
+        13 => "General Protection",
+        14 => "Page Fault",
+        _ => "Exception",
+    }
+}
+
+pub(super) fn irqstat(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    println!("vector      count");
+    for (vector, count) in interrupt_counts() {
+        let kind = if vector < FIRST_EXTERNAL_VECTOR {
+            exception_name(vector)
+        } else {
+            "External"
+        };
+        println!("  {vector:#04X} {count:>10}  {kind}");
+    }
+    Ok(())
+}
+
+fn stack_usage(t: &TaskInfo) -> String {
+    match t.stack_peak {
+        Some(peak) => format!("{:>3}K/{}K", peak / 1024, KERNEL_STACK_SIZE / 1024),
+        None => String::from("-"),
+    }
+}
+
+pub(super) fn ps(_shell: &Shell, _args: &[&str]) -> Result<()> {
+    println!("  ID NAME             STATE    PRIORITY      CPU(s)  STACK");
+    for t in task_list() {
+        println!(
+            "{:>4} {:<16} {:<8} {:<12} {:>7}.{:02} {}",
+            t.id,
+            t.name,
+            format!("{:?}", t.state),
+            format!("{:?}", t.priority),
+            t.cpu_ticks / TIMER_HZ as u64,
+            t.cpu_ticks % TIMER_HZ as u64 * 100 / TIMER_HZ as u64,
+            stack_usage(&t)
+        );
+    }
+    Ok(())
+}
+
+const TOP_DEFAULT_INTERVAL_SECS: u64 = 2;
+// キーが押されたかをこの間隔で見る
+const TOP_KEY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// 2回のtask_listの差から、その間に各タスクが使ったCPU時間の割合を出す
+pub(super) fn top(shell: &Shell, args: &[&str]) -> Result<()> {
+    let interval_secs: u64 = match args.first() {
+        Some(s) => s.parse().map_err(|_| "Invalid interval")?,
+        None => TOP_DEFAULT_INTERVAL_SECS,
+    };
+    if interval_secs == 0 {
+        return Err("Interval must be at least 1 second");
+    }
+    println!("Press any key to stop");
+    let mut prev = task_list();
+    let mut prev_tick = ticks();
+    loop {
+        let deadline = global_timestamp() + Duration::from_secs(interval_secs);
+        while global_timestamp() < deadline {
+            if shell.key_pressed() {
+                return Ok(());
+            }
+            sleep(TOP_KEY_POLL_INTERVAL);
+        }
+        let now = ticks();
+        let elapsed = (now - prev_tick).max(1);
+        let tasks = task_list();
+        let mut rows: Vec<(u64, &TaskInfo)> = tasks
+            .iter()
+            .map(|t| {
+                let before = prev
+                    .iter()
+                    .find(|p| p.id == t.id)
+                    .map_or(0, |p| p.cpu_ticks);
+                (t.cpu_ticks.saturating_sub(before), t)
+            })
+            .collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.id.cmp(&b.1.id)));
+        println!();
+        println!(
+            "top - up {}s, {} tasks",
+            global_timestamp().as_secs(),
+            tasks.len()
+        );
+        println!("  ID NAME             STATE     CPU%  STACK");
+        for (used, t) in rows {
+            println!(
+                "{:>4} {:<16} {:<8} {:>4}%  {}",
+                t.id,
+                t.name,
+                format!("{:?}", t.state),
+                used * 100 / elapsed,
+                stack_usage(t)
+            );
+        }
+        prev = tasks;
+        prev_tick = now;
+    }
+}
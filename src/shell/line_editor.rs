@@ -0,0 +1,323 @@
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+
+use crate::keyboard::KeyCode;
+use crate::keyboard::KeyEvent;
+
+// 覚えておく入力行の数
+const HISTORY_SIZE: usize = 32;
+const BACKSPACE: char = '\x08';
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditKey {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    // Ctrl-A
+    Home,
+    // Ctrl-E
+    End,
+    // Ctrl-K
+    KillToEnd,
+}
+
+impl EditKey {
+    // 画面のコンソール (USBキーボードなど) からの入力
+    pub fn from_key_event(e: &KeyEvent) -> Option<Self> {
+        if !e.pressed {
+            return None;
+        }
+        let key = match e.code {
+            KeyCode::Char(c) if e.modifiers.ctrl() => match c.to_ascii_lowercase() {
+                'a' => Self::Home,
+                'e' => Self::End,
+                'k' => Self::KillToEnd,
+                _ => return None,
+            },
+            KeyCode::Char(c) => Self::Char(c),
+            KeyCode::Enter => Self::Enter,
+            KeyCode::Backspace => Self::Backspace,
+            KeyCode::Delete => Self::Delete,
+            KeyCode::Left => Self::Left,
+            KeyCode::Right => Self::Right,
+            KeyCode::Up => Self::Up,
+            KeyCode::Down => Self::Down,
+            KeyCode::Home => Self::Home,
+            KeyCode::End => Self::End,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Vt100State {
+    #[default]
+    Ground,
+    Escape,
+    // ESC [ の後。数字の引数を溜める
+    Csi(u8),
+    // ESC O の後
+    Ss3,
+}
+
+// シリアルコンソールからの入力。矢印キーなどはVT100のエスケープシーケンスで来る
+#[derive(Default)]
+pub struct Vt100Decoder {
+    state: Vt100State,
+    last_was_cr: bool,
+}
+
+impl Vt100Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn feed(&mut self, b: u8) -> Option<EditKey> {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, b == b'\r');
+        match self.state {
+            Vt100State::Ground => match b {
+                0x1b => {
+                    self.state = Vt100State::Escape;
+                    None
+                }
+                b'\r' => Some(EditKey::Enter),
+                // CR LF で送ってくる端末もある
+                b'\n' if last_was_cr => None,
+                b'\n' => Some(EditKey::Enter),
+                0x08 | 0x7f => Some(EditKey::Backspace),
+                0x01 => Some(EditKey::Home),
+                0x05 => Some(EditKey::End),
+                0x0b => Some(EditKey::KillToEnd),
+                0x20..=0x7e => Some(EditKey::Char(b as char)),
+                _ => None,
+            },
+            Vt100State::Escape => {
+                self.state = match b {
+                    b'[' => Vt100State::Csi(0),
+                    b'O' => Vt100State::Ss3,
+                    _ => Vt100State::Ground,
+                };
+                None
+            }
+            Vt100State::Csi(arg) => {
+                if b.is_ascii_digit() {
+                    self.state = Vt100State::Csi(arg.saturating_mul(10).saturating_add(b - b'0'));
+                    return None;
+                }
+                self.state = Vt100State::Ground;
+                match (b, arg) {
+                    (b'A', _) => Some(EditKey::Up),
+                    (b'B', _) => Some(EditKey::Down),
+                    (b'C', _) => Some(EditKey::Right),
+                    (b'D', _) => Some(EditKey::Left),
+                    (b'H', _) | (b'~', 1 | 7) => Some(EditKey::Home),
+                    (b'F', _) | (b'~', 4 | 8) => Some(EditKey::End),
+                    (b'~', 3) => Some(EditKey::Delete),
+                    _ => None,
+                }
+            }
+            Vt100State::Ss3 => {
+                self.state = Vt100State::Ground;
+                match b {
+                    b'H' => Some(EditKey::Home),
+                    b'F' => Some(EditKey::End),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+// 1行の入力を編集する。画面の書き換えにはBSと空白と文字しか使わないので、
+// シリアルの端末でも画面のコンソールでも同じ出力で済む
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    history: VecDeque<String>,
+    // 履歴を辿っている時の位置。Noneなら新しい行を編集している
+    history_pos: Option<usize>,
+    // 履歴を辿る前に編集していた行
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_pos: None,
+            draft: Vec::new(),
+        }
+    }
+    pub fn history(&self) -> impl Iterator<Item = &String> {
+        self.history.iter()
+    }
+    // Enterで確定した行を返す。画面に出す文字はoutに書く
+    pub fn feed(&mut self, key: EditKey, out: &mut dyn Write) -> Option<String> {
+        let prev_cursor = self.cursor;
+        let prev_len = self.line.len();
+        match key {
+            EditKey::Enter => {
+                let _ = out.write_char('\n');
+                return Some(self.finish_line());
+            }
+            EditKey::Char(c) if self.cursor == self.line.len() => {
+                // よくある場合なので、書き直さずにそのまま出す
+                self.line.push(c);
+                self.cursor += 1;
+                let _ = out.write_char(c);
+                return None;
+            }
+            EditKey::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            EditKey::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            EditKey::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            EditKey::Left if self.cursor > 0 => self.cursor -= 1,
+            EditKey::Right if self.cursor < self.line.len() => self.cursor += 1,
+            EditKey::Home => self.cursor = 0,
+            EditKey::End => self.cursor = self.line.len(),
+            EditKey::KillToEnd => self.line.truncate(self.cursor),
+            EditKey::Up => self.recall(true),
+            EditKey::Down => self.recall(false),
+            _ => return None,
+        }
+        let _ = self.redraw(out, prev_cursor, prev_len);
+        None
+    }
+    fn finish_line(&mut self) -> String {
+        let line: String = core::mem::take(&mut self.line).into_iter().collect();
+        self.cursor = 0;
+        self.history_pos = None;
+        self.draft.clear();
+        // 空の行と、直前と同じ行は覚えない
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+    // olderなら1つ古い行、そうでなければ1つ新しい行に入れ替える
+    fn recall(&mut self, older: bool) {
+        let pos = match (self.history_pos, older) {
+            (None, true) if !self.history.is_empty() => {
+                self.draft = self.line.clone();
+                Some(self.history.len() - 1)
+            }
+            (Some(p), true) => Some(p.saturating_sub(1)),
+            (Some(p), false) if p + 1 < self.history.len() => Some(p + 1),
+            (Some(_), false) => None,
+            (None, _) => return,
+        };
+        self.line = match pos {
+            Some(p) => self.history[p].chars().collect(),
+            None => core::mem::take(&mut self.draft),
+        };
+        self.history_pos = pos;
+        self.cursor = self.line.len();
+    }
+    // カーソルを行頭に戻して行全体を書き直し、短くなった分は空白で消す
+    fn redraw(&self, out: &mut dyn Write, prev_cursor: usize, prev_len: usize) -> fmt::Result {
+        for _ in 0..prev_cursor {
+            out.write_char(BACKSPACE)?;
+        }
+        for c in &self.line {
+            out.write_char(*c)?;
+        }
+        let erased = prev_len.saturating_sub(self.line.len());
+        for _ in 0..erased {
+            out.write_char(' ')?;
+        }
+        for _ in self.cursor..self.line.len() + erased {
+            out.write_char(BACKSPACE)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn type_keys(editor: &mut LineEditor, keys: &[EditKey]) -> Option<String> {
+        let mut out = String::new();
+        keys.iter().find_map(|k| editor.feed(*k, &mut out))
+    }
+
+    #[test_case]
+    fn edit_line_with_cursor_movement() {
+        let mut editor = LineEditor::new();
+        let mut keys: Vec<EditKey> = "helo".chars().map(EditKey::Char).collect();
+        keys.extend([EditKey::Left, EditKey::Char('l'), EditKey::End]);
+        keys.extend([EditKey::Home, EditKey::Delete, EditKey::Char('H')]);
+        keys.extend([EditKey::Right, EditKey::KillToEnd, EditKey::Enter]);
+        assert_eq!(type_keys(&mut editor, &keys).as_deref(), Some("He"));
+    }
+
+    #[test_case]
+    fn recall_history_with_up_and_down() {
+        let mut editor = LineEditor::new();
+        for line in ["ls", "ps", "ps", ""] {
+            let mut keys: Vec<EditKey> = line.chars().map(EditKey::Char).collect();
+            keys.push(EditKey::Enter);
+            type_keys(&mut editor, &keys);
+        }
+        assert_eq!(editor.history().count(), 2);
+        let keys = [
+            EditKey::Char('x'),
+            EditKey::Up,
+            EditKey::Up,
+            EditKey::Up,
+            EditKey::Down,
+            EditKey::Down,
+            EditKey::Enter,
+        ];
+        // 一番新しい行より先に進むと、編集していた行に戻る
+        assert_eq!(type_keys(&mut editor, &keys).as_deref(), Some("x"));
+    }
+
+    #[test_case]
+    fn decode_vt100_sequences() {
+        let mut decoder = Vt100Decoder::new();
+        let keys: Vec<EditKey> = b"a\x1b[D\x1b[3~\x01\x7f\r\n"
+            .iter()
+            .filter_map(|b| decoder.feed(*b))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                EditKey::Char('a'),
+                EditKey::Left,
+                EditKey::Delete,
+                EditKey::Home,
+                EditKey::Backspace,
+                EditKey::Enter,
+            ]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+use super::Shell;
+use crate::monitor::parse_u64;
+use crate::print::hexdump_bytes_with;
+use crate::print::hexdump_phys;
+use crate::print::HexdumpOptions;
+use crate::println;
+use crate::result::Result;
+use crate::x86::read_cr3;
+use crate::x86::read_io_port_u16;
+use crate::x86::read_io_port_u32;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u32;
+use crate::x86::write_io_port_u8;
+use crate::x86::PAGE_SIZE;
+
+// 実機で動かし始める時に、メモリやI/Oポートを直接覗いたり書いたりするためのコマンド
+// 読み書きする前にページがマップされているかと境界を確かめて、例外で止まらないようにする
+
+const HEXDUMP_DEFAULT_LEN: u64 = 256;
+const HEXDUMP_MAX_LEN: u64 = 4096;
+
+fn parse_arg(args: &[&str], i: usize, name: &'static str) -> Result<u64> {
+    let s = args.get(i).ok_or(name)?;
+    parse_u64(s).ok_or("Invalid number")
+}
+
+// 省略されたらdefaultにする。1, 2, 4, (allow_u64なら) 8 バイトのどれか
+fn parse_width(arg: Option<&&str>, allow_u64: bool) -> Result<usize> {
+    let Some(s) = arg else {
+        return Ok(if allow_u64 { 8 } else { 1 });
+    };
+    match s.parse() {
+        Ok(w @ (1 | 2 | 4)) => Ok(w),
+        Ok(8) if allow_u64 => Ok(8),
+        _ => Err("Width must be 1, 2, 4 or 8 (1, 2 or 4 for I/O ports)"),
+    }
+}
+
+fn check_fits(value: u64, width: usize) -> Result<()> {
+    if width < 8 && value >> (width * 8) != 0 {
+        return Err("Value does not fit in the width");
+    }
+    Ok(())
+}
+
+// 47ビット目より上が全部同じでないアドレスに触ると#GPになる
+fn is_canonical(addr: u64) -> bool {
+    matches!((addr as i64) >> 47, 0 | -1)
+}
+
+// [addr, addr + len) のページが全部マップされているか確かめる
+// translateは各段のインデックスを9ビットで切るので、正規でないアドレスは先に弾く
+fn check_mapped(addr: u64, len: u64) -> Result<()> {
+    let end = addr.checked_add(len).ok_or("Address range overflows")?;
+    let table = unsafe { &*read_cr3() };
+    let mut page = addr & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        if !is_canonical(page) {
+            return Err("Address is not canonical");
+        }
+        table.translate(page).map_err(|_| "Address is not mapped")?;
+        page += PAGE_SIZE as u64;
+    }
+    Ok(())
+}
+
+fn check_access(addr: u64, width: usize) -> Result<()> {
+    // 揃っていればページをまたがないし、MMIOのレジスタも1回で読み書きできる
+    if addr % width as u64 != 0 {
+        return Err("Address is not aligned to the width");
+    }
+    check_mapped(addr, width as u64)
+}
+
+pub(super) fn hexdump(_shell: &Shell, args: &[&str]) -> Result<()> {
+    let (phys, args) = match args.first() {
+        Some(&"-p") => (true, &args[1..]),
+        _ => (false, args),
+    };
+    let addr = parse_arg(args, 0, "Usage: hexdump [-p] <addr> [len]")?;
+    let len = match args.get(1) {
+        Some(s) => parse_u64(s).ok_or("Invalid length")?,
+        None => HEXDUMP_DEFAULT_LEN,
+    };
+    if len == 0 || len > HEXDUMP_MAX_LEN {
+        return Err("Length must be between 1 and 4096");
+    }
+    let opts = HexdumpOptions::default();
+    if phys {
+        return hexdump_phys(addr, len as usize, &opts);
+    }
+    check_mapped(addr, len)?;
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+    hexdump_bytes_with(bytes, &HexdumpOptions { base: addr, ..opts });
+    Ok(())
+}
+
+pub(super) fn peek(_shell: &Shell, args: &[&str]) -> Result<()> {
+    let addr = parse_arg(args, 0, "Usage: peek <addr> [width]")?;
+    let width = parse_width(args.get(1), true)?;
+    check_access(addr, width)?;
+    let value = unsafe {
+        match width {
+            1 => read_volatile(addr as *const u8) as u64,
+            2 => read_volatile(addr as *const u16) as u64,
+            4 => read_volatile(addr as *const u32) as u64,
+            _ => read_volatile(addr as *const u64),
+        }
+    };
+    println!("{addr:#018X}: {value:#0w$X}", w = width * 2 + 2);
+    Ok(())
+}
+
+pub(super) fn poke(_shell: &Shell, args: &[&str]) -> Result<()> {
+    let usage = "Usage: poke <addr> <value> [width]";
+    let addr = parse_arg(args, 0, usage)?;
+    let value = parse_arg(args, 1, usage)?;
+    let width = parse_width(args.get(2), true)?;
+    check_fits(value, width)?;
+    check_access(addr, width)?;
+    unsafe {
+        match width {
+            1 => write_volatile(addr as *mut u8, value as u8),
+            2 => write_volatile(addr as *mut u16, value as u16),
+            4 => write_volatile(addr as *mut u32, value as u32),
+            _ => write_volatile(addr as *mut u64, value),
+        }
+    }
+    Ok(())
+}
+
+fn parse_port(args: &[&str], usage: &'static str) -> Result<u16> {
+    let port = parse_arg(args, 0, usage)?;
+    u16::try_from(port).map_err(|_| "Port must be below 0x10000")
+}
+
+pub(super) fn port_in(_shell: &Shell, args: &[&str]) -> Result<()> {
+    let port = parse_port(args, "Usage: in <port> [width]")?;
+    let width = parse_width(args.get(1), false)?;
+    let value = match width {
+        1 => read_io_port_u8(port) as u32,
+        2 => read_io_port_u16(port) as u32,
+        _ => read_io_port_u32(port),
+    };
+    println!("{port:#06X}: {value:#0w$X}", w = width * 2 + 2);
+    Ok(())
+}
+
+pub(super) fn port_out(_shell: &Shell, args: &[&str]) -> Result<()> {
+    let usage = "Usage: out <port> <value> [width]";
+    let port = parse_port(args, usage)?;
+    let value = parse_arg(args, 1, usage)?;
+    let width = parse_width(args.get(2), false)?;
+    check_fits(value, width)?;
+    match width {
+        1 => write_io_port_u8(port, value as u8),
+        2 => write_io_port_u16(port, value as u16),
+        _ => write_io_port_u32(port, value as u32),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn reject_bad_widths_and_addresses() {
+        assert_eq!(parse_width(None, false), Ok(1));
+        assert_eq!(parse_width(Some(&"8"), true), Ok(8));
+        assert!(parse_width(Some(&"8"), false).is_err());
+        assert!(check_fits(0x100, 1).is_err());
+        assert!(check_fits(u64::MAX, 8).is_ok());
+        let value = 0u64;
+        let addr = &value as *const u64 as u64;
+        assert!(check_access(addr, 8).is_ok());
+        assert!(check_access(addr + 1, 2).is_err());
+        // 上位半分の先頭はカーネルがマップしていない
+        assert!(check_mapped(0xffff_8000_0000_0000, 1).is_err());
+        // PML4の0番目を指すが、正規でないので触れない
+        assert!(check_mapped(0x0001_0000_0000_0000, 1).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+use core::slice;
+
+use crate::info;
+use crate::mutex::Mutex;
+use crate::result::Result;
+use crate::uefi::EfiSystemTable;
+
+// https://www.dmtf.org/sites/default/files/standards/documents/DSP0134_3.7.0.pdf
+#[derive(Debug, Copy, Clone)]
+struct SmbiosTable {
+    version: (u8, u8),
+    addr: u64,
+    len: usize,
+}
+
+impl SmbiosTable {
+    fn from_entry_point(ep: *const u8) -> Result<Self> {
+        let anchor = unsafe { slice::from_raw_parts(ep, 5) };
+        if anchor == b"_SM3_" {
+            let ep = unsafe { slice::from_raw_parts(ep, 0x18) };
+            Ok(Self {
+                version: (ep[7], ep[8]),
+                len: u32::from_le_bytes(ep[0x0C..0x10].try_into().unwrap()) as usize,
+                addr: u64::from_le_bytes(ep[0x10..0x18].try_into().unwrap()),
+            })
+        } else if &anchor[..4] == b"_SM_" {
+            let ep = unsafe { slice::from_raw_parts(ep, 0x1F) };
+            Ok(Self {
+                version: (ep[6], ep[7]),
+                len: u16::from_le_bytes(ep[0x16..0x18].try_into().unwrap()) as usize,
+                addr: u32::from_le_bytes(ep[0x18..0x1C].try_into().unwrap()) as u64,
+            })
+        } else {
+            Err("Invalid SMBIOS entry point anchor")
+        }
+    }
+    fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+struct SmbiosStructure<'a> {
+    ty: u8,
+    formatted: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> SmbiosStructure<'a> {
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(
+            self.formatted.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(
+            self.formatted.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    }
+    // 文字列は1始まりの番号で参照される。0は「無し」
+    fn string_at(&self, offset: usize) -> Option<&'a str> {
+        let index = self.u8_at(offset)? as usize;
+        if index == 0 {
+            return None;
+        }
+        let s = self.strings.split(|b| *b == 0).nth(index - 1)?;
+        core::str::from_utf8(s).ok().map(|s| s.trim())
+    }
+}
+
+struct SmbiosStructureIter<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for SmbiosStructureIter<'a> {
+    type Item = SmbiosStructure<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let ty = *self.table.first()?;
+        let len = *self.table.get(1)? as usize;
+        if len < 4 || len > self.table.len() {
+            return None;
+        }
+        // 文字列の並びは2つ連続したNULで終わる
+        let rest = &self.table[len..];
+        let end = rest.windows(2).position(|w| w == [0, 0])?;
+        let s = SmbiosStructure {
+            ty,
+            formatted: &self.table[..len],
+            strings: &rest[..end],
+        };
+        // End-of-Table
+        self.table = if ty == 127 { &[] } else { &rest[end + 2..] };
+        Some(s)
+    }
+}
+
+fn structures(table: &[u8]) -> SmbiosStructureIter {
+    SmbiosStructureIter { table }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryDevice {
+    pub locator: String,
+    pub size_mib: u64,
+    pub speed_mts: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemInfo {
+    pub smbios_version: (u8, u8),
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_release_date: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product_name: Option<String>,
+    pub serial_number: Option<String>,
+    pub uuid: Option<[u8; 16]>,
+    pub memory_devices: Vec<MemoryDevice>,
+}
+
+impl SystemInfo {
+    fn parse(version: (u8, u8), table: &[u8]) -> Self {
+        let mut info = Self {
+            smbios_version: version,
+            ..Default::default()
+        };
+        let owned = |s: Option<&str>| s.map(|s| s.to_string());
+        for s in structures(table) {
+            match s.ty {
+                // BIOS Information
+                0 => {
+                    info.bios_vendor = owned(s.string_at(0x04));
+                    info.bios_version = owned(s.string_at(0x05));
+                    info.bios_release_date = owned(s.string_at(0x08));
+                }
+                // System Information
+                1 => {
+                    info.manufacturer = owned(s.string_at(0x04));
+                    info.product_name = owned(s.string_at(0x05));
+                    info.serial_number = owned(s.string_at(0x07));
+                    info.uuid = s
+                        .formatted
+                        .get(0x08..0x18)
+                        .and_then(|u| u.try_into().ok())
+                        .filter(|u: &[u8; 16]| u.iter().any(|b| *b != 0));
+                }
+                // Memory Device
+                17 => {
+                    let size_mib = match s.u16_at(0x0C) {
+                        None | Some(0) | Some(0xFFFF) => continue,
+                        Some(0x7FFF) => s.u32_at(0x1C).unwrap_or(0) as u64 & 0x7FFF_FFFF,
+                        Some(v) if v & 0x8000 != 0 => (v & 0x7FFF) as u64 / 1024,
+                        Some(v) => v as u64,
+                    };
+                    info.memory_devices.push(MemoryDevice {
+                        locator: s.string_at(0x10).unwrap_or("?").to_string(),
+                        size_mib,
+                        speed_mts: s.u16_at(0x15).filter(|v| *v != 0),
+                    });
+                }
+                _ => {}
+            }
+        }
+        info
+    }
+}
+
+impl fmt::Display for SystemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_unknown = |s: &Option<String>| s.clone().unwrap_or_else(|| "unknown".to_string());
+        writeln!(
+            f,
+            "SMBIOS {}.{}: {} {} (serial: {})",
+            self.smbios_version.0,
+            self.smbios_version.1,
+            or_unknown(&self.manufacturer),
+            or_unknown(&self.product_name),
+            or_unknown(&self.serial_number),
+        )?;
+        write!(
+            f,
+            "BIOS: {} {} ({})",
+            or_unknown(&self.bios_vendor),
+            or_unknown(&self.bios_version),
+            or_unknown(&self.bios_release_date),
+        )?;
+        for d in &self.memory_devices {
+            write!(f, "\nMemory: {}: {} MiB", d.locator, d.size_mib)?;
+            if let Some(speed) = d.speed_mts {
+                write!(f, " @ {speed} MT/s")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+static SMBIOS_TABLE: Mutex<Option<SmbiosTable>> = Mutex::new(None);
+
+// エントリポイントの場所だけ覚えておき、中身はアロケータが使えるようになってから読む
+pub fn init_smbios(efi_system_table: &EfiSystemTable) {
+    let Some(ep) = efi_system_table.smbios_entry_point() else {
+        info!("SMBIOS: not found");
+        return;
+    };
+    match SmbiosTable::from_entry_point(ep) {
+        Ok(table) => *SMBIOS_TABLE.lock() = Some(table),
+        Err(e) => {
+            info!("SMBIOS: {e}");
+        }
+    }
+}
+
+pub fn system_info() -> Option<SystemInfo> {
+    let table = (*SMBIOS_TABLE.lock())?;
+    Some(SystemInfo::parse(table.version, table.bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_system_info() {
+        let mut table = Vec::new();
+        // Type 1 (System Information)
+        table.extend_from_slice(&[1, 0x1B, 0x01, 0x00, 1, 2, 0, 0]);
+        table.extend_from_slice(&[0xAB; 16]);
+        table.extend_from_slice(&[0, 0, 0]);
+        table.extend_from_slice(b"QEMU\0Standard PC\0\0");
+        // Type 17 (Memory Device), 4096 MiB
+        let mut mem = [0u8; 0x17];
+        mem[0] = 17;
+        mem[1] = 0x17;
+        mem[0x0C..0x0E].copy_from_slice(&4096u16.to_le_bytes());
+        mem[0x10] = 1;
+        table.extend_from_slice(&mem);
+        table.extend_from_slice(b"DIMM 0\0\0");
+        // Type 127 (End-of-Table)
+        table.extend_from_slice(&[127, 4, 0xFF, 0xFF, 0, 0]);
+
+        let info = SystemInfo::parse((3, 0), &table);
+        assert_eq!(info.manufacturer.as_deref(), Some("QEMU"));
+        assert_eq!(info.product_name.as_deref(), Some("Standard PC"));
+        assert_eq!(info.serial_number, None);
+        assert_eq!(info.uuid, Some([0xAB; 16]));
+        assert_eq!(info.memory_devices.len(), 1);
+        assert_eq!(info.memory_devices[0].locator, "DIMM 0");
+        assert_eq!(info.memory_devices[0].size_mib, 4096);
+    }
+}
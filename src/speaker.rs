@@ -0,0 +1,48 @@
+use core::time::Duration;
+
+use crate::executor::TimeoutFuture;
+use crate::hpet::busy_wait;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+
+// https://wiki.osdev.org/PC_Speaker
+// PITのチャネル2の出力がスピーカーにつながっている
+const PIT_FREQUENCY: u32 = 1_193_182;
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// チャネル2, lobyte/hibyte, モード3 (矩形波), バイナリ
+const PIT_COMMAND_CHANNEL2_SQUARE_WAVE: u8 = 0b1011_0110;
+// bit 0: タイマ2のゲート, bit 1: スピーカーへの出力
+const SPEAKER_PORT: u16 = 0x61;
+const SPEAKER_GATE_AND_DATA: u8 = 0b11;
+
+pub fn start_tone(freq: u32) {
+    if freq == 0 {
+        stop_tone();
+        return;
+    }
+    let divisor = (PIT_FREQUENCY / freq).clamp(1, u16::MAX as u32) as u16;
+    write_io_port_u8(PIT_COMMAND, PIT_COMMAND_CHANNEL2_SQUARE_WAVE);
+    write_io_port_u8(PIT_CHANNEL2_DATA, divisor as u8);
+    write_io_port_u8(PIT_CHANNEL2_DATA, (divisor >> 8) as u8);
+    let v = read_io_port_u8(SPEAKER_PORT);
+    write_io_port_u8(SPEAKER_PORT, v | SPEAKER_GATE_AND_DATA);
+}
+
+pub fn stop_tone() {
+    let v = read_io_port_u8(SPEAKER_PORT);
+    write_io_port_u8(SPEAKER_PORT, v & !SPEAKER_GATE_AND_DATA);
+}
+
+// 鳴り終わるまで戻らない (パニック時などタスクが動いていない場面向け)
+pub fn beep(freq: u32, duration: Duration) {
+    start_tone(freq);
+    busy_wait(duration);
+    stop_tone();
+}
+
+pub async fn beep_async(freq: u32, duration: Duration) {
+    start_tone(freq);
+    TimeoutFuture::new(duration).await;
+    stop_tone();
+}
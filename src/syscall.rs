@@ -0,0 +1,289 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::slice;
+use core::str;
+use core::sync::atomic::AtomicU64;
+use core::time::Duration;
+
+use crate::fd;
+use crate::fd::OpenFlags;
+use crate::info;
+use crate::process::exit_current_process;
+use crate::process::wait;
+use crate::process::Process;
+use crate::result::Result;
+use crate::task::current_address_space;
+use crate::task::sleep;
+use crate::warn;
+use crate::x86::cli;
+use crate::x86::read_msr;
+use crate::x86::sti;
+use crate::x86::write_msr;
+use crate::x86::KERNEL_CS;
+
+// システムコールのABI
+// syscall命令で呼ぶ。raxに番号、引数はrdi, rsi, rdx, r10, r8, r9の順に入れる
+// 戻り値はraxに入り、失敗したら負の値になる。rcxとr11は壊れる
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_READ: u64 = 1;
+pub const SYS_WRITE: u64 = 2;
+pub const SYS_OPEN: u64 = 3;
+pub const SYS_CLOSE: u64 = 4;
+pub const SYS_SLEEP: u64 = 5;
+pub const SYS_SBRK: u64 = 6;
+// spawn(path, path_len, args, args_len): argsは'\0'で終わる引数を並べたもの
+pub const SYS_SPAWN: u64 = 7;
+// waitpid(pid, status): statusに終了コード (i64) を書いてpidを返す
+pub const SYS_WAITPID: u64 = 8;
+// mmap(size, flags): 匿名のメモリを用意して先頭のアドレスを返す。ページは触った時に割り当てる
+pub const SYS_MMAP: u64 = 9;
+// munmap(addr, size)
+pub const SYS_MUNMAP: u64 = 10;
+// pipe(fds): fds[0]に読む側、fds[1]に書く側のfdを書く (u64が2つ)
+pub const SYS_PIPE: u64 = 11;
+pub const SYS_DUP: u64 = 12;
+// dup2(oldfd, newfd): newfdをoldfdと同じファイルにする
+pub const SYS_DUP2: u64 = 13;
+
+// openのflags
+pub const OPEN_CREATE: u64 = 1 << 0;
+pub const OPEN_TRUNCATE: u64 = 1 << 1;
+pub const OPEN_APPEND: u64 = 1 << 2;
+
+// mmapのflags
+pub const MMAP_WRITABLE: u64 = 1 << 0;
+
+pub const ERR_FAILED: i64 = -1;
+pub const ERR_UNKNOWN_SYSCALL: i64 = -2;
+
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_STAR: u32 = 0xC000_0081;
+const MSR_LSTAR: u32 = 0xC000_0082;
+const MSR_FMASK: u32 = 0xC000_0084;
+const EFER_SCE: u64 = 1 << 0;
+// syscallで入った時に落とすRFLAGSのビット (TF, IF, DF)
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10);
+// sysretではこの値+8がSS、+16がCSになる (USER_DS, USER_CS)
+const SYSRET_BASE_SELECTOR: u64 = 4 << 3;
+
+// 割り込みを止めている間だけ使う、ユーザのrspの一時的な置き場所
+#[no_mangle]
+static SYSCALL_USER_RSP: AtomicU64 = AtomicU64::new(0);
+
+// syscall_entryがスタックに積むレジスタ
+#[repr(C)]
+#[derive(Debug)]
+struct SyscallFrame {
+    rax: u64,
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    r11: u64,
+    rcx: u64,
+    rsp: u64,
+}
+
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    // ここではまだユーザのスタックなので、カーネルのスタックに切り替える
+    mov [rip + SYSCALL_USER_RSP], rsp
+    mov rsp, [rip + SYSCALL_KERNEL_RSP]
+    push qword ptr [rip + SYSCALL_USER_RSP]
+    push rcx
+    push r11
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    push rax
+    // 10個積んだので16バイト境界のまま
+    mov rdi, rsp
+    call syscall_handler
+    // ユーザのrspに戻してからsysretするまでに割り込まれないようにする
+    cli
+    add rsp, 8
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+"#
+);
+
+extern "sysv64" {
+    fn syscall_entry();
+}
+
+pub fn init_syscall() {
+    unsafe {
+        write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_SCE);
+        write_msr(
+            MSR_STAR,
+            (SYSRET_BASE_SELECTOR << 48) | ((KERNEL_CS as u64) << 32),
+        );
+        write_msr(MSR_LSTAR, syscall_entry as *const () as u64);
+        write_msr(MSR_FMASK, FMASK);
+    }
+    info!("syscall is enabled");
+}
+
+#[no_mangle]
+extern "sysv64" fn syscall_handler(frame: &mut SyscallFrame) -> i64 {
+    // 待つシステムコールもあるので、割り込みを許可しておく
+    sti();
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    let ret = match dispatch(frame.rax, &args) {
+        Some(Ok(v)) => v as i64,
+        Some(Err(e)) => {
+            warn!("syscall {} failed: {e}", frame.rax);
+            ERR_FAILED
+        }
+        None => {
+            warn!("Unknown syscall {}", frame.rax);
+            ERR_UNKNOWN_SYSCALL
+        }
+    };
+    cli();
+    ret
+}
+
+fn dispatch(nr: u64, args: &[u64; 6]) -> Option<Result<u64>> {
+    let result = match nr {
+        SYS_EXIT => sys_exit(args[0] as i64),
+        SYS_READ => sys_read(args[0] as usize, args[1], args[2] as usize),
+        SYS_WRITE => sys_write(args[0] as usize, args[1], args[2] as usize),
+        SYS_OPEN => sys_open(args[0], args[1] as usize, args[2]),
+        SYS_CLOSE => fd::close(args[0] as usize).map(|_| 0),
+        SYS_SLEEP => {
+            sleep(Duration::from_millis(args[0]));
+            Ok(0)
+        }
+        SYS_SBRK => sys_sbrk(args[0]),
+        SYS_SPAWN => sys_spawn(args[0], args[1] as usize, args[2], args[3] as usize),
+        SYS_WAITPID => sys_waitpid(args[0], args[1]),
+        SYS_MMAP => sys_mmap(args[0], args[1]),
+        SYS_MUNMAP => sys_munmap(args[0], args[1]),
+        SYS_PIPE => sys_pipe(args[0]),
+        SYS_DUP => fd::dup(args[0] as usize).map(|fd| fd as u64),
+        SYS_DUP2 => fd::dup2(args[0] as usize, args[1] as usize).map(|fd| fd as u64),
+        _ => return None,
+    };
+    Some(result)
+}
+
+// ユーザ空間のバッファを借りる。呼び出し中はそのプロセスのページテーブルが使われている
+fn user_slice<'a>(addr: u64, len: usize) -> Result<&'a [u8]> {
+    let address_space = current_address_space().ok_or("Not a user process")?;
+    address_space.check_user_access(addr, len, false)?;
+    Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+}
+
+fn user_slice_mut<'a>(addr: u64, len: usize) -> Result<&'a mut [u8]> {
+    let address_space = current_address_space().ok_or("Not a user process")?;
+    address_space.check_user_access(addr, len, true)?;
+    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
+
+fn sys_exit(code: i64) -> Result<u64> {
+    exit_current_process(code)
+}
+
+fn sys_sbrk(increment: u64) -> Result<u64> {
+    current_address_space()
+        .ok_or("Not a user process")?
+        .sbrk(increment)
+}
+
+fn sys_mmap(size: u64, flags: u64) -> Result<u64> {
+    current_address_space()
+        .ok_or("Not a user process")?
+        .mmap(size, flags & MMAP_WRITABLE != 0)
+}
+
+fn sys_munmap(addr: u64, size: u64) -> Result<u64> {
+    current_address_space()
+        .ok_or("Not a user process")?
+        .munmap(addr, size)
+        .map(|_| 0)
+}
+
+fn sys_pipe(fds: u64) -> Result<u64> {
+    let dst = user_slice_mut(fds, 16)?;
+    let (reader, writer) = fd::pipe();
+    dst[0..8].copy_from_slice(&(reader as u64).to_le_bytes());
+    dst[8..16].copy_from_slice(&(writer as u64).to_le_bytes());
+    Ok(0)
+}
+
+fn sys_read(fd: usize, buf: u64, len: usize) -> Result<u64> {
+    // 長さ0ならポインタには触らない
+    if len == 0 {
+        return Ok(0);
+    }
+    // 先にバッファを確かめておく (マップされている分より大きなlenでは確保しない)
+    let dst = user_slice_mut(buf, len)?;
+    // fdテーブルのロックを持ったままユーザのページに触らないように、カーネルのバッファを挟む
+    let mut kbuf = vec![0u8; len];
+    let n = fd::read(fd, &mut kbuf)?;
+    dst[..n].copy_from_slice(&kbuf[..n]);
+    Ok(n as u64)
+}
+
+fn sys_write(fd: usize, buf: u64, len: usize) -> Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+    let data = user_slice(buf, len)?;
+    fd::write(fd, data).map(|n| n as u64)
+}
+
+fn sys_open(path: u64, len: usize, flags: u64) -> Result<u64> {
+    let path = str::from_utf8(user_slice(path, len)?).map_err(|_| "Path is not UTF-8")?;
+    let path = String::from(path);
+    let flags = OpenFlags {
+        create: flags & OPEN_CREATE != 0,
+        truncate: flags & OPEN_TRUNCATE != 0,
+        append: flags & OPEN_APPEND != 0,
+    };
+    fd::open(&path, flags).map(|fd| fd as u64)
+}
+
+fn sys_spawn(path: u64, path_len: usize, args: u64, args_len: usize) -> Result<u64> {
+    let path = str::from_utf8(user_slice(path, path_len)?).map_err(|_| "Path is not UTF-8")?;
+    let path = String::from(path);
+    // 引数が無い時はポインタを確かめない (NULLでもよい)
+    let args = if args_len == 0 {
+        String::new()
+    } else {
+        let args = str::from_utf8(user_slice(args, args_len)?).map_err(|_| "Args is not UTF-8")?;
+        String::from(args)
+    };
+    let args: Vec<&str> = args.split_terminator('\0').collect();
+    Process::spawn(&path, &args)
+}
+
+fn sys_waitpid(pid: u64, status: u64) -> Result<u64> {
+    // 待ってから書き込めないと分かっても困るので、先に確かめる
+    user_slice_mut(status, 8)?;
+    let code = wait(pid)?;
+    user_slice_mut(status, 8)?.copy_from_slice(&code.to_le_bytes());
+    Ok(pid)
+}
@@ -0,0 +1,867 @@
+extern crate alloc;
+
+use alloc::alloc::alloc;
+use alloc::alloc::dealloc;
+use alloc::alloc::handle_alloc_error;
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::apic::init_apic_timer;
+use crate::backtrace::print_backtrace;
+use crate::backtrace::print_backtrace_from;
+use crate::error;
+use crate::fd::FdTable;
+use crate::hpet::busy_wait;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::mutex::num_of_held_locks;
+use crate::mutex::Mutex;
+use crate::mutex::MutexGuard;
+use crate::mutex::SpinLockIrqSave;
+use crate::mutex::LOCK_ORDER_SCHEDULER;
+use crate::process::on_process_exit;
+use crate::process::AddressSpace;
+use crate::watchdog;
+use crate::x86::cli;
+use crate::x86::hlt;
+use crate::x86::interrupted_user_mode;
+use crate::x86::interrupts_enabled;
+use crate::x86::read_cr3;
+use crate::x86::set_kernel_stack;
+use crate::x86::set_page_attr;
+use crate::x86::sti;
+use crate::x86::sti_and_hlt;
+use crate::x86::switch_context;
+use crate::x86::write_cr3;
+use crate::x86::Context;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+
+pub type TaskId = u64;
+
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+// 使用量を測るために、確保したスタックをこの値で埋めておく
+const STACK_FILL_BYTE: u8 = 0xCD;
+pub const TIMER_HZ: u32 = 100;
+// 1つのタスクが続けて動ける最大のtick数
+const TIME_SLICE_TICKS: u32 = 2;
+// これだけ待たされたタスクは優先度を1段上げて扱う
+const AGING_TICKS: u64 = 10;
+
+// 小さいほど優先される
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // 割り込みの後始末 (受信したパケットの処理など)
+    BottomHalf = 0,
+    // シェルなど、ユーザの入力に応える処理
+    Interactive = 1,
+    Normal = 2,
+    // 他に動けるタスクが無い時だけ動く
+    Idle = 3,
+}
+const NUM_OF_PRIORITIES: usize = 4;
+
+// タスクのカーネルスタック
+// 一番下のページ (ガードページ) はマップしないので、あふれるとヒープを壊す前にページフォルトになる
+struct KernelStack {
+    guard: *mut u8,
+}
+
+impl KernelStack {
+    fn layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE + KERNEL_STACK_SIZE, PAGE_SIZE).unwrap()
+    }
+    fn new() -> Self {
+        let guard = unsafe { alloc(Self::layout()) };
+        if guard.is_null() {
+            handle_alloc_error(Self::layout());
+        }
+        unsafe {
+            guard
+                .add(PAGE_SIZE)
+                .write_bytes(STACK_FILL_BYTE, KERNEL_STACK_SIZE)
+        };
+        let stack = Self { guard };
+        let guard = guard as u64;
+        set_page_attr(guard, guard + PAGE_SIZE as u64, PageAttr::NotPresent)
+            .expect("Failed to unmap the stack guard page");
+        stack
+    }
+    fn guard_page(&self) -> u64 {
+        self.guard as u64
+    }
+    fn top(&self) -> u64 {
+        self.guard_page() + (PAGE_SIZE + KERNEL_STACK_SIZE) as u64
+    }
+    // これまでに使われた最大のバイト数。下から見て埋めた値のまま残っている所は使われていない
+    // 動いているタスクのスタックも見るので、volatileで読む
+    fn peak_usage(&self) -> usize {
+        let bottom = unsafe { self.guard.add(PAGE_SIZE) };
+        let untouched = (0..KERNEL_STACK_SIZE)
+            .take_while(|i| unsafe { bottom.add(*i).read_volatile() } == STACK_FILL_BYTE)
+            .count();
+        KERNEL_STACK_SIZE - untouched
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // アロケータに返す前にガードページを元に戻す
+        let guard = self.guard_page();
+        set_page_attr(guard, guard + PAGE_SIZE as u64, PageAttr::ReadWriteKernel)
+            .expect("Failed to remap the stack guard page");
+        unsafe { dealloc(self.guard, Self::layout()) };
+    }
+}
+
+pub struct TaskControlBlock {
+    id: TaskId,
+    name: String,
+    context: Context,
+    // 最初のタスク (efi_main) はUEFIが用意したスタックで動くのでNone
+    stack: Option<KernelStack>,
+    entry: Option<Box<dyn FnOnce()>>,
+    fd_table: Arc<Mutex<FdTable>>,
+    cwd: Arc<Mutex<String>>,
+    // ユーザプロセスならそのアドレス空間。カーネルのタスクはNone
+    address_space: Option<Arc<AddressSpace>>,
+    // このタスクが動いていた間のtick数
+    cpu_ticks: u64,
+    // タイマ割り込みがユーザモード・カーネルモードで動いているところに来た回数
+    user_ticks: u64,
+    system_ticks: u64,
+    // ブロックする前に起こされていたらtrue。次のブロックはすぐに戻る
+    wakeup_pending: bool,
+    priority: Priority,
+    // run_queueに入った時のtick数
+    enqueued_at: u64,
+}
+
+impl TaskControlBlock {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+struct Scheduler {
+    current: Box<TaskControlBlock>,
+    // 優先度ごとのrun_queue
+    run_queues: [VecDeque<Box<TaskControlBlock>>; NUM_OF_PRIORITIES],
+    // WaitQueueやsleepで止まっているタスク
+    blocked: BTreeMap<TaskId, Box<TaskControlBlock>>,
+    // (起こす時刻, タスクID)
+    sleeping: Vec<(Duration, TaskId)>,
+    // 終了したタスク。自分のスタックは解放できないので、別のタスクが動いている時に捨てる
+    // 切り替え中もContextのアドレスが変わらないようにBoxに入れておく
+    #[allow(clippy::vec_box)]
+    dead: Vec<Box<TaskControlBlock>>,
+}
+
+impl Scheduler {
+    fn enqueue(&mut self, mut task: Box<TaskControlBlock>) -> &mut TaskControlBlock {
+        task.enqueued_at = ticks();
+        let queue = &mut self.run_queues[task.priority as usize];
+        queue.push_back(task);
+        queue.back_mut().unwrap()
+    }
+    fn runnable_tasks(&self) -> impl Iterator<Item = &Box<TaskControlBlock>> {
+        self.run_queues.iter().flatten()
+    }
+    fn has_runnable_tasks(&self) -> bool {
+        self.run_queues.iter().any(|q| !q.is_empty())
+    }
+    // 優先度の高いものから選ぶ。ただし長く待たされたタスクは優先度を上げて扱い、飢餓を防ぐ
+    fn pick_next(&mut self) -> Option<Box<TaskControlBlock>> {
+        let now = ticks();
+        let mut best: Option<(usize, usize)> = None;
+        for (level, queue) in self.run_queues.iter().enumerate() {
+            let Some(task) = queue.front() else {
+                continue;
+            };
+            let effective = if task.priority == Priority::Idle {
+                level
+            } else {
+                level.saturating_sub(((now - task.enqueued_at) / AGING_TICKS) as usize)
+            };
+            if !matches!(best, Some((e, _)) if e <= effective) {
+                best = Some((effective, level));
+            }
+        }
+        best.and_then(|(_, level)| self.run_queues[level].pop_front())
+    }
+    fn wake(&mut self, id: TaskId) {
+        if let Some(task) = self.blocked.remove(&id) {
+            if task.priority < self.current.priority {
+                // 次の割り込みで切り替える
+                NEED_RESCHED.store(true, Ordering::SeqCst);
+            }
+            self.enqueue(task);
+        } else if self.current.id == id {
+            self.current.wakeup_pending = true;
+        } else if let Some(task) = self.run_queues.iter_mut().flatten().find(|t| t.id == id) {
+            task.wakeup_pending = true;
+        }
+    }
+    fn wake_deferred(&mut self) {
+        let deferred = core::mem::take(&mut *DEFERRED_WAKEUPS.lock());
+        for id in &deferred.ids[..deferred.len] {
+            self.wake(*id);
+        }
+    }
+    fn wake_expired_sleepers(&mut self) {
+        if self.sleeping.is_empty() {
+            return;
+        }
+        let now = global_timestamp();
+        let mut expired = Vec::new();
+        self.sleeping.retain(|(deadline, id)| {
+            if *deadline <= now {
+                expired.push(*id);
+                false
+            } else {
+                true
+            }
+        });
+        for id in expired {
+            self.wake(id);
+        }
+    }
+}
+
+const MAX_DEFERRED_WAKEUPS: usize = 32;
+
+#[derive(Default)]
+struct DeferredWakeups {
+    ids: [TaskId; MAX_DEFERRED_WAKEUPS],
+    len: usize,
+}
+
+// 割り込みハンドラから起こされたタスク。SCHEDULERのロックは取れないので、次に切り替える時に起こす
+static DEFERRED_WAKEUPS: SpinLockIrqSave<DeferredWakeups> = SpinLockIrqSave::new(DeferredWakeups {
+    ids: [0; MAX_DEFERRED_WAKEUPS],
+    len: 0,
+});
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new_with_order(None, LOCK_ORDER_SCHEDULER);
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+// カーネルのタスクが使うページテーブル
+static KERNEL_CR3: AtomicU64 = AtomicU64::new(0);
+
+pub fn kernel_cr3() -> u64 {
+    KERNEL_CR3.load(Ordering::SeqCst)
+}
+
+// 今動いている流れ (efi_main) を最初のタスクにする
+pub fn init_task() {
+    let mut scheduler = SCHEDULER.lock();
+    assert!(scheduler.is_none());
+    KERNEL_CR3.store(read_cr3() as u64, Ordering::SeqCst);
+    *scheduler = Some(Scheduler {
+        current: Box::new(TaskControlBlock {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst),
+            name: String::from("main"),
+            context: Context::empty(),
+            stack: None,
+            entry: None,
+            fd_table: Arc::new(Mutex::new(FdTable::new_with_console())),
+            cwd: Arc::new(Mutex::new(String::from("/"))),
+            address_space: None,
+            cpu_ticks: 0,
+            user_ticks: 0,
+            system_ticks: 0,
+            wakeup_pending: false,
+            priority: Priority::Normal,
+            enqueued_at: 0,
+        }),
+        run_queues: Default::default(),
+        blocked: BTreeMap::new(),
+        sleeping: Vec::new(),
+        dead: Vec::new(),
+    });
+}
+
+extern "sysv64" fn task_entry() -> ! {
+    let entry = SCHEDULER
+        .lock()
+        .as_mut()
+        .and_then(|s| s.current.entry.take())
+        .expect("Task started without an entry");
+    // 新しいタスクは切り替えから戻ってくるわけではないので、ここでも片付ける
+    reap_dead_tasks();
+    entry();
+    exit_current_task()
+}
+
+pub fn spawn(f: impl FnOnce() + 'static) -> TaskId {
+    spawn_with_priority(Priority::Normal, f)
+}
+
+pub fn spawn_with_priority(priority: Priority, f: impl FnOnce() + 'static) -> TaskId {
+    spawn_task(None, priority, None, None, Box::new(f))
+}
+
+// 名前を付けたい時はkthread::spawnを使う
+pub(crate) fn spawn_named(name: &str, priority: Priority, f: impl FnOnce() + 'static) -> TaskId {
+    spawn_task(Some(String::from(name)), priority, None, None, Box::new(f))
+}
+
+// address_spaceに切り替えてfを動かすタスクを作る。fの中でユーザモードに移る
+// fdは今のタスクのものを引き継ぐので、親が用意したパイプやリダイレクトがそのまま使える
+pub(crate) fn spawn_user(
+    name: &str,
+    address_space: Arc<AddressSpace>,
+    f: impl FnOnce() + 'static,
+) -> TaskId {
+    let fd_table = current_fd_table().map(|t| t.lock().inherit());
+    spawn_task(
+        Some(String::from(name)),
+        Priority::Normal,
+        Some(address_space),
+        fd_table,
+        Box::new(f),
+    )
+}
+
+fn spawn_task(
+    name: Option<String>,
+    priority: Priority,
+    address_space: Option<Arc<AddressSpace>>,
+    fd_table: Option<FdTable>,
+    f: Box<dyn FnOnce()>,
+) -> TaskId {
+    let id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+    let stack = KernelStack::new();
+    let stack_top = stack.top();
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().expect("Task is not initialized");
+    let cwd = scheduler.current.cwd.lock().clone();
+    scheduler.enqueue(Box::new(TaskControlBlock {
+        id,
+        name: name.unwrap_or_else(|| format!("task{id}")),
+        context: Context::new(task_entry, stack_top),
+        stack: Some(stack),
+        entry: Some(f),
+        fd_table: Arc::new(Mutex::new(
+            fd_table.unwrap_or_else(FdTable::new_with_console),
+        )),
+        cwd: Arc::new(Mutex::new(cwd)),
+        address_space,
+        cpu_ticks: 0,
+        user_ticks: 0,
+        system_ticks: 0,
+        wakeup_pending: false,
+        priority,
+        enqueued_at: 0,
+    }));
+    id
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static SLICE_START_TICK: AtomicU64 = AtomicU64::new(0);
+static SLICE_REMAINING: AtomicU32 = AtomicU32::new(TIME_SLICE_TICKS);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+// 今のタスクに切り替わってから、ユーザモード・カーネルモードで受けたtick数
+static SLICE_USER_TICKS: AtomicU64 = AtomicU64::new(0);
+static SLICE_SYSTEM_TICKS: AtomicU64 = AtomicU64::new(0);
+// 今動いているタスクのガードページ。ページフォルトのハンドラからロックを取らずに見る
+static CURRENT_STACK_GUARD: AtomicU64 = AtomicU64::new(0);
+// 今動いているタスクのID。Mutexが持ち主を記録するのに使うので、SCHEDULERのロックを取らずに読めるようにしておく
+static CURRENT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+static PREEMPTION_ENABLED: AtomicBool = AtomicBool::new(false);
+// アイドルタスクがhltで止まっていた時間の合計 (ns)
+static IDLE_NANOS: AtomicU64 = AtomicU64::new(0);
+// hltで止まっている間はtrue。割り込みから戻ればすぐに次のタスクを探すので、そこでは切り替えない
+static IN_IDLE_HLT: AtomicBool = AtomicBool::new(false);
+
+// 割り込みの中から呼ばれるので、ロックは取らない
+fn timer_tick(_vector: u8) {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    watchdog::check(now);
+    // hltで止まっている間は、どちらの時間にも数えない
+    if interrupted_user_mode() {
+        SLICE_USER_TICKS.fetch_add(1, Ordering::SeqCst);
+    } else if !IN_IDLE_HLT.load(Ordering::SeqCst) {
+        SLICE_SYSTEM_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+    // ユーザモードではロックを持っていないので、タイムスライスを使い切れば必ず切り替わる
+    let remaining = SLICE_REMAINING.load(Ordering::SeqCst);
+    if remaining <= 1 {
+        NEED_RESCHED.store(true, Ordering::SeqCst);
+    } else {
+        SLICE_REMAINING.store(remaining - 1, Ordering::SeqCst);
+    }
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+pub fn start_preemption() {
+    match init_apic_timer(TIMER_HZ, timer_tick) {
+        Ok(()) => PREEMPTION_ENABLED.store(true, Ordering::SeqCst),
+        Err(e) => {
+            info!("Preemption is disabled: {e}");
+        }
+    }
+}
+
+// 割り込みハンドラの最後 (EOIの後) で呼ばれる
+// ロックを持ったまま横取りすると、他のタスクがそのロックを取ろうとして止まるので、その間は切り替えない
+pub fn preempt_if_needed() {
+    if !NEED_RESCHED.load(Ordering::SeqCst)
+        || num_of_held_locks() != 0
+        || IN_IDLE_HLT.load(Ordering::SeqCst)
+    {
+        return;
+    }
+    NEED_RESCHED.store(false, Ordering::SeqCst);
+    yield_now();
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Switch {
+    // 今のタスクをrun_queuesの最後に戻す
+    Yield,
+    // 今のタスクをblockedに移す。wakeされるまで動かない
+    Block,
+    // 今のタスクは二度と動かない
+    Exit,
+}
+
+// 次のタスクに切り替える。他に動けるタスクが無くて切り替えられなかった場合はfalseを返す
+fn switch_to_next(how: Switch) -> bool {
+    // 切り替えの途中で割り込まれないようにする。戻ってきたら元に戻す
+    let interrupts_were_enabled = interrupts_enabled();
+    cli();
+    let switched = switch_to_next_with_interrupts_disabled(how);
+    if interrupts_were_enabled {
+        sti();
+    }
+    switched
+}
+
+fn switch_to_next_with_interrupts_disabled(how: Switch) -> bool {
+    let (from, to): (*mut Context, *const Context) = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return false;
+        };
+        scheduler.wake_deferred();
+        scheduler.wake_expired_sleepers();
+        if how == Switch::Block && core::mem::take(&mut scheduler.current.wakeup_pending) {
+            // ブロックする前に起こされていたので、止まらずにそのまま続ける
+            return true;
+        }
+        let now = ticks();
+        SLICE_REMAINING.store(TIME_SLICE_TICKS, Ordering::SeqCst);
+        // スケジューラまで戻ってこられているので、止まってはいない
+        watchdog::pet();
+        let Some(next) = scheduler.pick_next() else {
+            assert!(how != Switch::Exit, "No task to run");
+            return false;
+        };
+        let mut prev = core::mem::replace(&mut scheduler.current, next);
+        prev.cpu_ticks += now - SLICE_START_TICK.swap(now, Ordering::SeqCst);
+        prev.user_ticks += SLICE_USER_TICKS.swap(0, Ordering::SeqCst);
+        prev.system_ticks += SLICE_SYSTEM_TICKS.swap(0, Ordering::SeqCst);
+        let guard = scheduler
+            .current
+            .stack
+            .as_ref()
+            .map_or(0, |s| s.guard_page());
+        CURRENT_STACK_GUARD.store(guard, Ordering::SeqCst);
+        CURRENT_TASK_ID.store(scheduler.current.id, Ordering::SeqCst);
+        if let Some(stack) = &scheduler.current.stack {
+            set_kernel_stack(stack.top());
+        }
+        // カーネルの部分はどのページテーブルでも同じなので、ここで切り替えても動き続けられる
+        let cr3 = scheduler
+            .current
+            .address_space
+            .as_ref()
+            .map_or(kernel_cr3(), |a| a.cr3());
+        if read_cr3() as u64 != cr3 {
+            unsafe { write_cr3(cr3 as *const PML4) };
+        }
+        let to = &scheduler.current.context as *const Context;
+        // Boxの中身は動かないので、キューに移した後もポインタは有効
+        let from = match how {
+            Switch::Yield => &mut scheduler.enqueue(prev).context as *mut Context,
+            Switch::Block => {
+                let id = prev.id;
+                &mut scheduler.blocked.entry(id).or_insert(prev).context as *mut Context
+            }
+            Switch::Exit => {
+                scheduler.dead.push(prev);
+                &mut scheduler.dead.last_mut().unwrap().context as *mut Context
+            }
+        };
+        (from, to)
+    };
+    unsafe { switch_context(&mut *from, &*to) };
+    // 戻ってきたら、終了済みのタスクを片付ける (今のタスクはdeadには居ない)
+    reap_dead_tasks();
+    true
+}
+
+// 終了したタスクのスタックなどを解放する。終了したタスク自身のスタック上では呼べない
+fn reap_dead_tasks() {
+    let dead = SCHEDULER
+        .lock()
+        .as_mut()
+        .map(|s| core::mem::take(&mut s.dead));
+    // ロックを外してから捨てる (ファイルを閉じる時に他のロックを取ることがある)
+    drop(dead);
+}
+
+// 他に動けるタスクがあれば譲る
+pub fn yield_now() {
+    switch_to_next(Switch::Yield);
+}
+
+// wake_taskされるまで今のタスクを止める
+// 止まっている間に他のタスクが同じロックを取ろうとすると止まってしまうので、ロックを持ったまま呼んではいけない
+pub(crate) fn block_current_task() {
+    assert_eq!(num_of_held_locks(), 0, "Blocking while holding a lock");
+    if !switch_to_next(Switch::Block) {
+        // 他に動けるタスクが無いので、次の割り込みまで待ってから戻る (呼び出し側で条件を見直す)
+        hlt();
+    }
+}
+
+// 起こされるか、deadline (global_timestamp()の値) を過ぎるまで止まる
+pub(crate) fn block_current_task_until(deadline: Duration) {
+    if !PREEMPTION_ENABLED.load(Ordering::SeqCst) {
+        // タイマ割り込みが無いと起こしてもらえないので、止まらずに返る (呼び出し側で時刻を見直す)
+        return;
+    }
+    let id = current_task_id();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.sleeping.push((deadline, id));
+    }
+    block_current_task();
+    // 期限より先に起こされた時は、残った予定を消しておく
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler
+            .sleeping
+            .retain(|(d, t)| !(*t == id && *d == deadline));
+    }
+}
+
+pub fn is_stack_guard_page_of_current_task(addr: u64) -> bool {
+    let guard = CURRENT_STACK_GUARD.load(Ordering::SeqCst);
+    guard != 0 && (guard..guard + PAGE_SIZE as u64).contains(&addr)
+}
+
+// 割り込みハンドラからは呼ばないこと (SCHEDULERのロックを取る)
+pub fn wake_task(id: TaskId) {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.wake(id);
+    }
+}
+
+// 割り込みハンドラからも呼べるwake_task。実際に起こすのは次にタスクを切り替える時
+pub fn wake_task_from_interrupt(id: TaskId) {
+    let mut deferred = DEFERRED_WAKEUPS.lock();
+    let len = deferred.len;
+    if !deferred.ids[..len].contains(&id) {
+        assert!(len < MAX_DEFERRED_WAKEUPS, "Too many deferred wakeups");
+        deferred.ids[len] = id;
+        deferred.len += 1;
+    }
+    // 割り込みから戻る時に切り替えて、起こしたタスクを動かす
+    NEED_RESCHED.store(true, Ordering::SeqCst);
+}
+
+// 少なくともdurationの間、今のタスクを止める
+pub fn sleep(duration: Duration) {
+    let deadline = global_timestamp() + duration;
+    if !PREEMPTION_ENABLED.load(Ordering::SeqCst) {
+        // タイマ割り込みが無いとhltから戻ってこられないので、その場で待つ
+        busy_wait(duration);
+        return;
+    }
+    while global_timestamp() < deadline {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            let id = scheduler.current.id;
+            scheduler.sleeping.push((deadline, id));
+        }
+        block_current_task();
+    }
+}
+
+// notify_one/notify_allされるまでタスクを止めておく場所
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+    // fがSomeを返すまで待つ。fはnotifyされるたびに呼ばれる
+    pub fn wait_until<T>(&self, mut f: impl FnMut() -> Option<T>) -> T {
+        loop {
+            if let Some(v) = f() {
+                return v;
+            }
+            let id = current_task_id();
+            self.waiters.lock().push_back(id);
+            // 登録する前にnotifyされていたかもしれないので、もう一度見る
+            if let Some(v) = f() {
+                self.waiters.lock().retain(|w| *w != id);
+                return v;
+            }
+            block_current_task();
+        }
+    }
+    // guardを離してから止まる。離すまでにnotifyされていれば止まらずに返る
+    // 他の理由で起こされることもあるので、呼び出し側で条件を見直すこと
+    pub fn wait_with_guard<T>(&self, guard: MutexGuard<T>) {
+        let id = current_task_id();
+        self.waiters.lock().push_back(id);
+        drop(guard);
+        block_current_task();
+        self.waiters.lock().retain(|w| *w != id);
+    }
+    // wait_with_guardと同じだが、deadlineを過ぎたら起こされなくても返る
+    pub fn wait_with_guard_until<T>(&self, guard: MutexGuard<T>, deadline: Duration) {
+        let id = current_task_id();
+        self.waiters.lock().push_back(id);
+        drop(guard);
+        block_current_task_until(deadline);
+        self.waiters.lock().retain(|w| *w != id);
+    }
+    pub fn notify_one(&self) {
+        let id = self.waiters.lock().pop_front();
+        if let Some(id) = id {
+            wake_task(id);
+        }
+    }
+    pub fn notify_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for id in waiters {
+            wake_task(id);
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 今のタスクを終わらせる。スタックは次に動くタスクが解放する
+pub fn exit_current_task() -> ! {
+    let (id, fd_table) = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("Task is not initialized");
+        let id = scheduler.current.id;
+        // もう起こされることは無いので、sleepの予定も消す
+        scheduler.sleeping.retain(|(_, t)| *t != id);
+        (id, scheduler.current.fd_table.clone())
+    };
+    // 他のタスクとfdテーブルを共有していることは無いので、ここで全部閉じる
+    fd_table.lock().close_all();
+    drop(fd_table);
+    on_process_exit(id);
+    info!("Task {id} exited");
+    switch_to_next(Switch::Exit);
+    unreachable!("Exited task was resumed")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Runnable,
+    Blocked,
+}
+
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub priority: Priority,
+    pub state: TaskState,
+    // 動いていたtick数
+    pub cpu_ticks: u64,
+    // そのうちユーザモード・カーネルモードで動いていたtick数 (タイマ割り込みで数えたもの)
+    pub user_ticks: u64,
+    pub system_ticks: u64,
+    // カーネルスタックの最大使用量 (バイト)。UEFIのスタックで動くタスクはNone
+    pub stack_peak: Option<usize>,
+}
+
+// 終了していないタスクの一覧
+pub fn task_list() -> Vec<TaskInfo> {
+    let scheduler = SCHEDULER.lock();
+    let Some(scheduler) = scheduler.as_ref() else {
+        return Vec::new();
+    };
+    // 今のタスクには、まだ足し込んでいない分を足す
+    let running = (
+        ticks() - SLICE_START_TICK.load(Ordering::SeqCst),
+        SLICE_USER_TICKS.load(Ordering::SeqCst),
+        SLICE_SYSTEM_TICKS.load(Ordering::SeqCst),
+    );
+    core::iter::once((&scheduler.current, TaskState::Running, running))
+        .chain(
+            scheduler
+                .runnable_tasks()
+                .map(|t| (t, TaskState::Runnable, (0, 0, 0))),
+        )
+        .chain(
+            scheduler
+                .blocked
+                .values()
+                .map(|t| (t, TaskState::Blocked, (0, 0, 0))),
+        )
+        .map(|(t, state, (cpu, user, system))| TaskInfo {
+            id: t.id,
+            name: t.name.clone(),
+            priority: t.priority,
+            state,
+            cpu_ticks: t.cpu_ticks + cpu,
+            user_ticks: t.user_ticks + user,
+            system_ticks: t.system_ticks + system,
+            stack_peak: t.stack.as_ref().map(|s| s.peak_usage()),
+        })
+        .collect()
+}
+
+// ウォッチドッグが止まったことを見つけた時などに、全部のタスクの状態とバックトレースを出す
+// 割り込みの中から呼ぶので、スケジューラのロックが取れなければ諦める
+pub fn dump_tasks() {
+    let Some(scheduler) = SCHEDULER.try_lock() else {
+        error!("dump_tasks: the scheduler is locked");
+        return;
+    };
+    let Some(scheduler) = scheduler.as_ref() else {
+        return;
+    };
+    let tasks = core::iter::once((&scheduler.current, TaskState::Running))
+        .chain(scheduler.runnable_tasks().map(|t| (t, TaskState::Runnable)))
+        .chain(scheduler.blocked.values().map(|t| (t, TaskState::Blocked)));
+    for (t, state) in tasks {
+        error!(
+            "task {} {:?}: {state:?}, priority {:?}, {} ticks",
+            t.id, t.name, t.priority, t.cpu_ticks
+        );
+        if state == TaskState::Running {
+            print_backtrace();
+        } else {
+            // 切り替えた時に保存したrbpからたどる
+            print_backtrace_from(t.context.rbp());
+        }
+    }
+}
+
+// 今のタスクがユーザモード・カーネルモードで動いていたtick数
+pub fn current_cpu_times() -> (u64, u64) {
+    SCHEDULER.lock().as_ref().map_or((0, 0), |s| {
+        (
+            s.current.user_ticks + SLICE_USER_TICKS.load(Ordering::SeqCst),
+            s.current.system_ticks + SLICE_SYSTEM_TICKS.load(Ordering::SeqCst),
+        )
+    })
+}
+
+pub fn current_task_id() -> TaskId {
+    CURRENT_TASK_ID.load(Ordering::SeqCst)
+}
+
+pub fn current_priority() -> Priority {
+    SCHEDULER
+        .lock()
+        .as_ref()
+        .map(|s| s.current.priority)
+        .unwrap_or(Priority::Normal)
+}
+
+pub fn set_current_priority(priority: Priority) {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.current.priority = priority;
+    }
+}
+
+pub fn has_runnable_tasks() -> bool {
+    SCHEDULER.lock().as_mut().is_some_and(|s| {
+        s.wake_deferred();
+        s.wake_expired_sleepers();
+        s.has_runnable_tasks()
+    })
+}
+
+pub fn current_fd_table() -> Option<Arc<Mutex<FdTable>>> {
+    SCHEDULER
+        .lock()
+        .as_ref()
+        .map(|s| s.current.fd_table.clone())
+}
+
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    SCHEDULER
+        .lock()
+        .as_ref()
+        .and_then(|s| s.current.address_space.clone())
+}
+
+pub fn current_cwd() -> Option<Arc<Mutex<String>>> {
+    SCHEDULER.lock().as_ref().map(|s| s.current.cwd.clone())
+}
+
+// efi_mainの最後で呼ぶ。このタスクはアイドルタスクになり、他のタスクが無い時だけ割り込みを待つ
+pub fn run_idle() -> ! {
+    set_current_priority(Priority::Idle);
+    loop {
+        watchdog::pet();
+        // 確認してからhltするまでの間に割り込みが来ても、hltで止まり続けないようにする
+        cli();
+        if has_runnable_tasks() {
+            sti();
+            yield_now();
+            continue;
+        }
+        let start = global_timestamp();
+        IN_IDLE_HLT.store(true, Ordering::SeqCst);
+        sti_and_hlt();
+        IN_IDLE_HLT.store(false, Ordering::SeqCst);
+        let idle = global_timestamp().saturating_sub(start);
+        IDLE_NANOS.fetch_add(idle.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+// アイドルタスクがhltで止まっていた時間の合計
+pub fn idle_time() -> Duration {
+    Duration::from_nanos(IDLE_NANOS.load(Ordering::SeqCst))
+}
+
+// 起動してからのCPU使用率 (%)。HPETが無いと測れないのでNone
+pub fn cpu_usage_percent() -> Option<u64> {
+    let uptime = global_timestamp().as_nanos() as u64;
+    if uptime == 0 {
+        return None;
+    }
+    let idle = IDLE_NANOS.load(Ordering::SeqCst).min(uptime);
+    Some((uptime - idle) * 100 / uptime)
+}
@@ -1,39 +1,264 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::any::type_name;
+use core::arch::asm;
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
 
+use crate::boot::cmdline;
+use crate::boot::Cmdline;
+use crate::error;
+use crate::fw_cfg::read_fw_cfg_file;
+use crate::hpet::global_timestamp;
+use crate::hpet::init_hpet_alarm;
+use crate::hpet::set_hpet_alarm;
+use crate::mutex::num_of_held_locks;
+use crate::mutex::Mutex;
+use crate::print::configure_log_levels;
 use crate::qemu::exit_qemu;
+use crate::qemu::panic_exit_code;
 use crate::qemu::QemuExitCode;
 use crate::serial::SerialPort;
 
 pub trait Testable {
-    fn run(&self, writer: &mut SerialPort);
+    fn name(&self) -> &'static str;
+    fn run(&self);
 }
 
 impl<T> Testable for T
 where
     T: Fn(),
 {
-    fn run(&self, writer: &mut SerialPort) {
-        writeln!(writer, "[RUNNING] >> {}", type_name::<T>()).unwrap();
+    fn name(&self) -> &'static str {
+        type_name::<T>()
+    }
+    fn run(&self) {
         self();
-        writeln!(writer, "[PASS ] <<< {}", type_name::<T>()).unwrap();
     }
 }
 
-pub fn test_runner(tests: &[&dyn Testable]) -> ! {
+// パニックした後に残りのテストを続けるための状態
+static TESTS: AtomicPtr<&'static dyn Testable> = AtomicPtr::new(core::ptr::null_mut());
+static NUM_OF_TESTS: AtomicUsize = AtomicUsize::new(0);
+static NEXT_TEST: AtomicUsize = AtomicUsize::new(0);
+static NUM_OF_FAILED: AtomicUsize = AtomicUsize::new(0);
+// test_runnerのスタックの位置。パニックしたテストのフレームは捨ててここに戻る
+static RUNNER_RSP: AtomicU64 = AtomicU64::new(0);
+static IN_TEST_PANIC: AtomicBool = AtomicBool::new(false);
+static NUM_OF_SKIPPED: AtomicUsize = AtomicUsize::new(0);
+
+// どのテストを動かすかなどの設定。同じイメージのまま、CIのジョブごとに変えられるようにする
+// -fw_cfg name=opt/wasabi/test_config,string=... かカーネルコマンドラインに
+// "test_filter=a,b loglevel=... test_timeout=秒" の形で書く。両方にあればfw_cfgを優先する
+const TEST_CONFIG_FW_CFG_FILE: &str = "opt/wasabi/test_config";
+// 名前にどれかを含むテストだけを動かす。空なら全部
+static TEST_FILTER: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// 1つのテストにかけてよい時間 (ms)。0なら制限なし
+// HPETのアラームで打ち切るが、割り込みを止めたまま固まったテストは打ち切れない。
+// アラームが使えない時は、終わった後で比べて超えていたものを失敗にする
+static TEST_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+fn apply_test_config(args: &Cmdline) {
+    if let Some(filter) = args.get("test_filter") {
+        *TEST_FILTER.lock() = filter
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+    }
+    if let Some(spec) = args.get("loglevel") {
+        if let Err(e) = configure_log_levels(spec) {
+            error!("Invalid loglevel={spec}: {e}");
+        }
+    }
+    if let Some(timeout) = args.get("test_timeout") {
+        match timeout.parse::<u64>() {
+            Ok(secs) => TEST_TIMEOUT_MS.store(secs.saturating_mul(1000), Ordering::SeqCst),
+            Err(_) => {
+                error!("Invalid test_timeout={timeout}");
+            }
+        }
+    }
+}
+
+// init_cmdlineとinit_fw_cfgの後に呼ぶ
+pub fn load_test_config() {
+    apply_test_config(&cmdline());
+    if let Ok(config) = read_fw_cfg_file(TEST_CONFIG_FW_CFG_FILE) {
+        apply_test_config(&Cmdline::new(config.iter().map(|b| *b as char)));
+    }
+}
+
+fn is_selected(name: &str) -> bool {
+    let filter = TEST_FILTER.lock();
+    filter.is_empty() || filter.iter().any(|f| name.contains(f.as_str()))
+}
+
+fn test_timeout() -> Option<Duration> {
+    match TEST_TIMEOUT_MS.load(Ordering::SeqCst) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+fn current_test_name() -> &'static str {
+    NEXT_TEST
+        .load(Ordering::SeqCst)
+        .checked_sub(1)
+        .and_then(|i| tests().get(i))
+        .map(|test| test.name())
+        .unwrap_or("(outside of tests)")
+}
+
+// 今のテストがtest_timeoutを超えた。テストは割り込まれたまま戻ってこないので、ここで終える
+fn test_timeout_handler(_vector: u8) {
+    let mut sw = SerialPort::new_for_com1();
+    let timeout = test_timeout().unwrap_or_default();
+    writeln!(
+        sw,
+        "[FAIL ] <<< {} (timed out after {timeout:?})",
+        current_test_name()
+    )
+    .unwrap();
+    NUM_OF_FAILED.fetch_add(1, Ordering::SeqCst);
+    print_summary();
+    exit_qemu(QemuExitCode::TestTimeout)
+}
+
+fn tests() -> &'static [&'static dyn Testable] {
+    let tests = TESTS.load(Ordering::SeqCst);
+    if tests.is_null() {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(tests, NUM_OF_TESTS.load(Ordering::SeqCst)) }
+}
+
+pub fn test_runner(tests: &'static [&'static dyn Testable]) -> ! {
     let mut sw = SerialPort::new_for_com1();
     writeln!(sw, "Running {} tests...", tests.len()).unwrap();
-    for test in tests {
-        test.run(&mut sw);
+    for (i, test) in tests.iter().enumerate() {
+        writeln!(sw, "  {i:>3}: {}", test.name()).unwrap();
+    }
+    TESTS.store(tests.as_ptr() as *mut _, Ordering::SeqCst);
+    NUM_OF_TESTS.store(tests.len(), Ordering::SeqCst);
+    if test_timeout().is_some() {
+        if let Err(e) = init_hpet_alarm(test_timeout_handler) {
+            writeln!(sw, "test_timeout is checked only after each test: {e}").unwrap();
+        }
+    }
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+    RUNNER_RSP.store(rsp, Ordering::SeqCst);
+    run_remaining_tests()
+}
+
+extern "C" fn run_remaining_tests() -> ! {
+    let mut sw = SerialPort::new_for_com1();
+    let tests = tests();
+    loop {
+        let i = NEXT_TEST.fetch_add(1, Ordering::SeqCst);
+        let Some(test) = tests.get(i) else {
+            break;
+        };
+        if !is_selected(test.name()) {
+            NUM_OF_SKIPPED.fetch_add(1, Ordering::SeqCst);
+            writeln!(sw, "[SKIP ] --- {}", test.name()).unwrap();
+            continue;
+        }
+        writeln!(sw, "[RUNNING] >> {}", test.name()).unwrap();
+        let timeout = test_timeout();
+        let start = global_timestamp();
+        set_hpet_alarm(timeout);
+        test.run();
+        set_hpet_alarm(None);
+        let elapsed = global_timestamp() - start;
+        if timeout.is_some_and(|timeout| elapsed > timeout) {
+            NUM_OF_FAILED.fetch_add(1, Ordering::SeqCst);
+            writeln!(
+                sw,
+                "[FAIL ] <<< {} (timed out: took {elapsed:?})",
+                test.name()
+            )
+            .unwrap();
+        } else {
+            writeln!(sw, "[PASS ] <<< {}", test.name()).unwrap();
+        }
+    }
+    finish()
+}
+
+// (実行したテストの数, 失敗したテストの数) を返す
+fn print_summary() -> (usize, usize) {
+    let mut sw = SerialPort::new_for_com1();
+    let total = tests().len();
+    let failed = NUM_OF_FAILED.load(Ordering::SeqCst);
+    let skipped = NUM_OF_SKIPPED.load(Ordering::SeqCst);
+    // 途中で打ち切った場合は実行していないテストが残る
+    let ran = NEXT_TEST.load(Ordering::SeqCst).min(total);
+    writeln!(
+        sw,
+        "Completed {ran}/{total} tests: {} passed, {failed} failed, {skipped} skipped",
+        ran - failed - skipped
+    )
+    .unwrap();
+    (ran, failed)
+}
+
+fn finish() -> ! {
+    set_hpet_alarm(None);
+    let (ran, failed) = print_summary();
+    if failed == 0 && ran == tests().len() {
+        exit_qemu(QemuExitCode::Success)
+    } else {
+        exit_qemu(QemuExitCode::Fail)
     }
-    writeln!(sw, "Completed {} tests!", tests.len()).unwrap();
-    exit_qemu(QemuExitCode::Success)
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     let mut sw = SerialPort::new_for_com1();
-    writeln!(sw, "PANIC: during test: {info:?}").unwrap();
-    exit_qemu(crate::qemu::QemuExitCode::Fail)
+    if IN_TEST_PANIC.swap(true, Ordering::SeqCst) {
+        writeln!(sw, "PANIC: while handling a test failure: {info}").unwrap();
+        exit_qemu(QemuExitCode::Fail)
+    }
+    set_hpet_alarm(None);
+    let name = current_test_name();
+    writeln!(sw, "PANIC: during test: {info}").unwrap();
+    let exit_code = panic_exit_code();
+    if exit_code != QemuExitCode::Panic {
+        // ダブルフォルトやウォッチドッグは、テストの失敗とは区別して終了する
+        exit_qemu(exit_code)
+    }
+    writeln!(sw, "[FAIL ] <<< {name}").unwrap();
+    NUM_OF_FAILED.fetch_add(1, Ordering::SeqCst);
+    let rsp = RUNNER_RSP.load(Ordering::SeqCst);
+    if rsp == 0 {
+        exit_qemu(QemuExitCode::Panic)
+    }
+    if num_of_held_locks() != 0 {
+        // ロックを持ったままのテストを捨てると後のテストが巻き込まれるので、ここで打ち切る
+        writeln!(sw, "Aborting the remaining tests").unwrap();
+        finish()
+    }
+    IN_TEST_PANIC.store(false, Ordering::SeqCst);
+    // パニックしたテストのスタックを捨てて、次のテストから続ける
+    unsafe {
+        asm!(
+            "mov rsp, {rsp}",
+            "and rsp, -16",
+            "xor rbp, rbp",
+            "call {next}",
+            rsp = in(reg) rsp,
+            next = sym run_remaining_tests,
+            options(noreturn)
+        )
+    }
 }
@@ -9,11 +9,25 @@ type EfiVoid = u8;
 pub type EfiHandle = u64;
 type Result<T> = core::result::Result<T, &'static str>;
 
+pub mod fs;
+pub mod vars;
+
+// ファームウェアからはエラーも含めて任意の値が返ってくるので、enumにはしない
+// https://uefi.org/specs/UEFI/2.11/Apx_D_Status_Codes.html
 #[must_use]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-#[repr(u64)]
-pub enum EfiStatus {
-    Success = 0,
+#[repr(transparent)]
+pub struct EfiStatus(u64);
+
+impl EfiStatus {
+    pub const SUCCESS: Self = Self(0);
+    pub const BUFFER_TOO_SMALL: Self = Self(Self::ERROR_BIT | 5);
+    pub const NOT_FOUND: Self = Self(Self::ERROR_BIT | 14);
+    const ERROR_BIT: u64 = 1 << 63;
+
+    pub fn is_error(&self) -> bool {
+        self.0 & Self::ERROR_BIT != 0
+    }
 }
 
 #[repr(i64)]
@@ -113,7 +127,14 @@ impl Default for MemoryMapHolder {
 // https://uefi.org/specs/UEFI/2.11/04_EFI_System_Table.html#efi-image-entry-point
 #[repr(C)]
 pub struct EfiBootServicesTable {
-    _reserved0: [u64; 7],
+    _reserved0: [u64; 5],
+    allocate_pages: extern "win64" fn(
+        allocate_type: u32,
+        memory_type: u32,
+        pages: usize,
+        memory: *mut u64,
+    ) -> EfiStatus,
+    _reserved1: u64,
     get_memory_map: extern "win64" fn(
         memory_map_size: *mut usize,
         memory_map: *mut u8,
@@ -121,13 +142,13 @@ pub struct EfiBootServicesTable {
         descriptor_size: *mut usize,
         descriptor_version: *mut u32,
     ) -> EfiStatus,
-    _reserved1: [u64; 11],
+    _reserved2: [u64; 11],
     handle_protocol: extern "win64" fn(
         handle: EfiHandle,
         protocol: *const EfiGuid,
         interface: *mut *mut EfiVoid,
     ) -> EfiStatus,
-    _reserved2: [u64; 9],
+    _reserved3: [u64; 9],
     // UEFI内で使用したメモリを開放する
     // MemoryMapHolderで取得したmap_keyを指定する
     exit_boot_services: extern "win64" fn(image_handle: EfiHandle, map_key: usize) -> EfiStatus,
@@ -138,7 +159,9 @@ pub struct EfiBootServicesTable {
         interface: *mut *mut EfiVoid,
     ) -> EfiStatus,
 }
+const _: () = assert!(offset_of!(EfiBootServicesTable, allocate_pages) == 40);
 const _: () = assert!(offset_of!(EfiBootServicesTable, get_memory_map) == 56);
+const _: () = assert!(offset_of!(EfiBootServicesTable, handle_protocol) == 152);
 const _: () = assert!(offset_of!(EfiBootServicesTable, exit_boot_services) == 232);
 const _: () = assert!(offset_of!(EfiBootServicesTable, locate_protocol) == 320);
 
@@ -152,6 +175,21 @@ impl EfiBootServicesTable {
             &mut map.descriptor_version,
         )
     }
+    // LOADER_DATAとして確保したページはブートサービス終了後も残り、アロケータにも使われない
+    pub fn allocate_loader_pages(&self, pages: usize) -> Result<*mut u8> {
+        const ALLOCATE_ANY_PAGES: u32 = 0;
+        let mut addr = 0u64;
+        let status = (self.allocate_pages)(
+            ALLOCATE_ANY_PAGES,
+            EfiMemoryType::LOADER_DATA as u32,
+            pages,
+            &mut addr,
+        );
+        if status != EfiStatus::SUCCESS {
+            return Err("Failed to allocate pages");
+        }
+        Ok(addr as *mut u8)
+    }
 }
 const _: () = assert!(offset_of!(EfiBootServicesTable, get_memory_map) == 56);
 const _: () = assert!(offset_of!(EfiBootServicesTable, locate_protocol) == 320);
@@ -165,7 +203,8 @@ pub struct EfiConfigurationTable {
 
 #[repr(C)]
 pub struct EfiSystemTable {
-    _reserved0: [u64; 12],
+    _reserved0: [u64; 11],
+    runtime_services: &'static EfiRuntimeServicesTable,
     boot_services: &'static EfiBootServicesTable,
     number_of_table_entries: usize,
     configuration_table: *const EfiConfigurationTable,
@@ -174,6 +213,9 @@ impl EfiSystemTable {
     pub fn boot_services(&self) -> &EfiBootServicesTable {
         self.boot_services
     }
+    pub fn runtime_services(&self) -> &EfiRuntimeServicesTable {
+        self.runtime_services
+    }
     fn lookup_config_table(&self, guid: &EfiGuid) -> Option<EfiConfigurationTable> {
         for i in 0..self.number_of_table_entries {
             let ct = unsafe { &*self.configuration_table.add(i) };
@@ -188,9 +230,79 @@ impl EfiSystemTable {
         self.lookup_config_table(&EFI_ACPI_TABLE_GUID)
             .map(|t| unsafe { &*(t.vendor_table as *const AcpiRsdp) })
     }
+    // SMBIOS 3.xのエントリポイントがあればそちらを優先する
+    pub fn smbios_entry_point(&self) -> Option<*const u8> {
+        self.lookup_config_table(&EFI_SMBIOS3_TABLE_GUID)
+            .or_else(|| self.lookup_config_table(&EFI_SMBIOS_TABLE_GUID))
+            .map(|t| t.vendor_table)
+    }
 }
+const _: () = assert!(offset_of!(EfiSystemTable, runtime_services) == 88);
 const _: () = assert!(offset_of!(EfiSystemTable, boot_services) == 96);
 
+// https://uefi.org/specs/UEFI/2.11/08_Services_Runtime_Services.html#gettime
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct EfiTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    // UTCからのずれ (分)。0x07FFならローカル時刻として扱う
+    pub time_zone: i16,
+    pub daylight: u8,
+    _pad2: u8,
+}
+const _: () = assert!(size_of::<EfiTime>() == 16);
+
+impl EfiTime {
+    pub const UNSPECIFIED_TIMEZONE: i16 = 0x07FF;
+}
+
+#[repr(C)]
+pub struct EfiRuntimeServicesTable {
+    _reserved0: [u64; 3],
+    get_time: extern "win64" fn(time: *mut EfiTime, capabilities: *mut EfiVoid) -> EfiStatus,
+    _reserved1: [u64; 5],
+    get_variable: extern "win64" fn(
+        variable_name: *const u16,
+        vendor_guid: *const EfiGuid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut EfiVoid,
+    ) -> EfiStatus,
+    get_next_variable_name: extern "win64" fn(
+        variable_name_size: *mut usize,
+        variable_name: *mut u16,
+        vendor_guid: *mut EfiGuid,
+    ) -> EfiStatus,
+    set_variable: extern "win64" fn(
+        variable_name: *const u16,
+        vendor_guid: *const EfiGuid,
+        attributes: u32,
+        data_size: usize,
+        data: *const EfiVoid,
+    ) -> EfiStatus,
+}
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, get_time) == 24);
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, get_variable) == 72);
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, set_variable) == 88);
+
+impl EfiRuntimeServicesTable {
+    pub fn get_time(&self) -> Result<EfiTime> {
+        let mut time = EfiTime::default();
+        let status = (self.get_time)(&mut time, null_mut());
+        if status != EfiStatus::SUCCESS {
+            return Err("GetTime failed");
+        }
+        Ok(time)
+    }
+}
+
 const EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data0: 0x9042a9de,
     data1: 0x23dc,
@@ -205,6 +317,20 @@ const EFI_LOADED_IMAGE_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data3: [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
 };
 
+const EFI_SMBIOS_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xeb9d2d31,
+    data1: 0x2d88,
+    data2: 0x11d3,
+    data3: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+};
+
+const EFI_SMBIOS3_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xf2fd1544,
+    data1: 0x9794,
+    data2: 0x4a2c,
+    data3: [0x99, 0x2e, 0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94],
+};
+
 const EFI_ACPI_TABLE_GUID: EfiGuid = EfiGuid {
     data0: 0x8868e871,
     data1: 0xe4f1,
@@ -212,11 +338,30 @@ const EFI_ACPI_TABLE_GUID: EfiGuid = EfiGuid {
     data3: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
 };
 
+#[repr(C)]
 pub struct EfiLoadedImageProtocol {
-    _reserved0: [u64; 8],
+    _reserved0: [u64; 3],
+    pub device_handle: EfiHandle,
+    _reserved1: [u64; 2],
+    load_options_size: u32,
+    load_options: *const u16,
     pub image_base: u64,
     pub image_size: u64,
 }
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, load_options_size) == 48);
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, image_base) == 64);
+
+impl EfiLoadedImageProtocol {
+    // UEFIシェルなどから起動された場合はUCS-2のコマンドラインが入っている
+    pub fn load_options(&self) -> &[u16] {
+        if self.load_options.is_null() {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(self.load_options, self.load_options_size as usize / 2)
+        }
+    }
+}
 
 pub fn locate_loaded_image_protocol(
     image_handle: EfiHandle,
@@ -228,7 +373,7 @@ pub fn locate_loaded_image_protocol(
         &EFI_LOADED_IMAGE_PROTOCOL_GUID,
         &mut graphic_output_protocol as *mut *mut EfiLoadedImageProtocol as *mut *mut EfiVoid,
     );
-    if status != EfiStatus::Success {
+    if status != EfiStatus::SUCCESS {
         return Err("Failed to locate loaded image protocol");
     }
     Ok(unsafe { &*graphic_output_protocol })
@@ -249,7 +394,7 @@ fn locate_graphic_protocol<'a>(
         null_mut::<EfiVoid>(),
         &mut graphic_output_protocol as *mut *mut EfiGraphicsOoutputProtocol as *mut *mut EfiVoid,
     );
-    if status != EfiStatus::Success {
+    if status != EfiStatus::SUCCESS {
         return Err("Failed to locate graphics output protocol");
     }
     Ok(unsafe { &*graphic_output_protocol })
@@ -277,9 +422,24 @@ struct EfiGraphicsOutputProtocolMode<'a> {
     pub frame_buffer_size: usize,
 }
 
+// UEFIの文字列 (UCS-2, NUL終端) に変換する
+fn encode_ucs2(chars: impl Iterator<Item = char>, buf: &mut [u16]) -> Result<()> {
+    let mut len = 0;
+    for c in chars {
+        let c = u16::try_from(c as u32).or(Err("String contains non-UCS-2 characters"))?;
+        if len + 1 >= buf.len() {
+            return Err("String too long");
+        }
+        buf[len] = c;
+        len += 1;
+    }
+    *buf.get_mut(len).ok_or("String too long")? = 0;
+    Ok(())
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Debug)]
-struct EfiGuid {
+pub struct EfiGuid {
     pub data0: u32,
     pub data1: u16,
     pub data2: u16,
@@ -311,6 +471,10 @@ impl Bitmap for VramBufferInfo {
         self.height
     }
 
+    fn buf(&self) -> *const u8 {
+        self.buf
+    }
+
     fn buf_mut(&mut self) -> *mut u8 {
         self.buf
     }
@@ -334,10 +498,10 @@ pub fn exit_from_efi_boot_services(
     // 最新のメモリマップを取得しメモリを開放する処理を繰り返す
     loop {
         let status = efi_system_table.boot_services.get_memory_map(memory_map);
-        assert_eq!(status, EfiStatus::Success);
+        assert_eq!(status, EfiStatus::SUCCESS);
         let status =
             (efi_system_table.boot_services.exit_boot_services)(image_handle, memory_map.map_key);
-        if status == EfiStatus::Success {
+        if status == EfiStatus::SUCCESS {
             break;
         }
     }
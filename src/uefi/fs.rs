@@ -0,0 +1,183 @@
+use core::mem::offset_of;
+use core::ptr::null_mut;
+use core::slice;
+
+use super::encode_ucs2;
+use super::locate_loaded_image_protocol;
+use super::EfiGuid;
+use super::EfiHandle;
+use super::EfiStatus;
+use super::EfiSystemTable;
+use super::EfiVoid;
+use super::Result;
+use crate::x86::PAGE_SIZE;
+
+// https://uefi.org/specs/UEFI/2.11/13_Protocols_Media_Access.html
+const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: EfiGuid = EfiGuid {
+    data0: 0x964e5b22,
+    data1: 0x6459,
+    data2: 0x11d2,
+    data3: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+const EFI_FILE_INFO_ID: EfiGuid = EfiGuid {
+    data0: 0x09576e92,
+    data1: 0x6d3f,
+    data2: 0x11d2,
+    data3: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+const EFI_FILE_MODE_READ: u64 = 1;
+const MAX_PATH_LEN: usize = 256;
+// EFI_FILE_INFOのヘッダ (80バイト) とファイル名が入る大きさ
+const FILE_INFO_BUFFER_SIZE: usize = 80 + MAX_PATH_LEN * 2;
+
+#[repr(C)]
+struct EfiSimpleFileSystemProtocol {
+    revision: u64,
+    open_volume: extern "win64" fn(
+        this: *mut EfiSimpleFileSystemProtocol,
+        root: *mut *mut EfiFileProtocol,
+    ) -> EfiStatus,
+}
+
+#[repr(C)]
+struct EfiFileProtocol {
+    revision: u64,
+    open: extern "win64" fn(
+        this: *mut EfiFileProtocol,
+        new_handle: *mut *mut EfiFileProtocol,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> EfiStatus,
+    close: extern "win64" fn(this: *mut EfiFileProtocol) -> EfiStatus,
+    _delete: u64,
+    read: extern "win64" fn(
+        this: *mut EfiFileProtocol,
+        buffer_size: *mut usize,
+        buffer: *mut EfiVoid,
+    ) -> EfiStatus,
+    _write: u64,
+    _get_position: u64,
+    _set_position: u64,
+    get_info: extern "win64" fn(
+        this: *mut EfiFileProtocol,
+        information_type: *const EfiGuid,
+        buffer_size: *mut usize,
+        buffer: *mut EfiVoid,
+    ) -> EfiStatus,
+}
+const _: () = assert!(offset_of!(EfiFileProtocol, get_info) == 64);
+
+// 開いたファイルはスコープを抜けたら閉じる
+struct EfiFile(*mut EfiFileProtocol);
+
+impl EfiFile {
+    fn open(&self, path: &[u16]) -> Result<EfiFile> {
+        let mut handle = null_mut::<EfiFileProtocol>();
+        let status =
+            unsafe { ((*self.0).open)(self.0, &mut handle, path.as_ptr(), EFI_FILE_MODE_READ, 0) };
+        match status {
+            EfiStatus::SUCCESS => Ok(EfiFile(handle)),
+            EfiStatus::NOT_FOUND => Err("File not found on the ESP"),
+            _ => Err("Failed to open a file on the ESP"),
+        }
+    }
+    fn size(&self) -> Result<u64> {
+        let mut info = [0u64; FILE_INFO_BUFFER_SIZE / 8];
+        let mut size = FILE_INFO_BUFFER_SIZE;
+        let status = unsafe {
+            ((*self.0).get_info)(
+                self.0,
+                &EFI_FILE_INFO_ID,
+                &mut size,
+                info.as_mut_ptr() as *mut EfiVoid,
+            )
+        };
+        if status != EfiStatus::SUCCESS {
+            return Err("Failed to get file info");
+        }
+        // EFI_FILE_INFO.FileSize
+        Ok(info[1])
+    }
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut size = buf.len();
+        let status = unsafe { ((*self.0).read)(self.0, &mut size, buf.as_mut_ptr()) };
+        if status != EfiStatus::SUCCESS {
+            return Err("Failed to read a file on the ESP");
+        }
+        Ok(size)
+    }
+}
+
+impl Drop for EfiFile {
+    fn drop(&mut self) {
+        let _ = unsafe { ((*self.0).close)(self.0) };
+    }
+}
+
+// "/boot/initramfs.tar" のようなパスをUCS-2の"\boot\initramfs.tar"に変換する
+fn to_efi_path(path: &str, buf: &mut [u16; MAX_PATH_LEN]) -> Result<()> {
+    encode_ucs2(path.chars().map(|c| if c == '/' { '\\' } else { c }), buf)
+}
+
+// ブートサービスの終了前に、イメージが置かれていたボリューム (ESP) からファイルを読む
+pub struct EfiFileSystem<'a> {
+    system_table: &'a EfiSystemTable,
+    root: EfiFile,
+}
+
+impl<'a> EfiFileSystem<'a> {
+    pub fn open_boot_volume(
+        image_handle: EfiHandle,
+        system_table: &'a EfiSystemTable,
+    ) -> Result<Self> {
+        let loaded_image = locate_loaded_image_protocol(image_handle, system_table)?;
+        let mut sfs = null_mut::<EfiSimpleFileSystemProtocol>();
+        let status = (system_table.boot_services.handle_protocol)(
+            loaded_image.device_handle,
+            &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
+            &mut sfs as *mut *mut EfiSimpleFileSystemProtocol as *mut *mut EfiVoid,
+        );
+        if status != EfiStatus::SUCCESS {
+            return Err("Failed to locate simple file system protocol");
+        }
+        let mut root = null_mut::<EfiFileProtocol>();
+        let status = unsafe { ((*sfs).open_volume)(sfs, &mut root) };
+        if status != EfiStatus::SUCCESS {
+            return Err("Failed to open the boot volume");
+        }
+        Ok(Self {
+            system_table,
+            root: EfiFile(root),
+        })
+    }
+    pub fn file_size(&self, path: &str) -> Result<u64> {
+        let mut efi_path = [0u16; MAX_PATH_LEN];
+        to_efi_path(path, &mut efi_path)?;
+        self.root.open(&efi_path)?.size()
+    }
+    // 読み込んだ内容はLOADER_DATAのページに置かれ、カーネルが動いている間ずっと有効
+    pub fn read_file(&self, path: &str) -> Result<&'static [u8]> {
+        let mut efi_path = [0u16; MAX_PATH_LEN];
+        to_efi_path(path, &mut efi_path)?;
+        let file = self.root.open(&efi_path)?;
+        let size = file.size()? as usize;
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        let buf = self
+            .system_table
+            .boot_services
+            .allocate_loader_pages(pages)?;
+        let buf = unsafe { slice::from_raw_parts_mut(buf, size) };
+        let mut done = 0;
+        while done < size {
+            let n = file.read(&mut buf[done..])?;
+            if n == 0 {
+                return Err("Unexpected end of file on the ESP");
+            }
+            done += n;
+        }
+        Ok(buf)
+    }
+}
@@ -0,0 +1,173 @@
+use core::fmt;
+use core::ptr::null;
+use core::ptr::null_mut;
+
+use super::encode_ucs2;
+use super::EfiGuid;
+use super::EfiRuntimeServicesTable;
+use super::EfiStatus;
+use super::EfiSystemTable;
+use super::Result;
+
+// https://uefi.org/specs/UEFI/2.11/08_Services_Runtime_Services.html#variable-services
+pub const EFI_GLOBAL_VARIABLE: EfiGuid = EfiGuid {
+    data0: 0x8be4df61,
+    data1: 0x93ca,
+    data2: 0x11d2,
+    data3: [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+};
+
+// カーネルのオプションを保存するための独自のベンダGUID
+pub const WASABI_VARIABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0x7761_7361,
+    data1: 0x6269,
+    data2: 0x4f53,
+    data3: [0x8a, 0x3c, 0x5e, 0x21, 0x0b, 0x6d, 0x94, 0x17],
+};
+
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x01;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x02;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x04;
+
+const MAX_VARIABLE_NAME_LEN: usize = 128;
+
+pub fn get_variable(
+    rt: &EfiRuntimeServicesTable,
+    name: &str,
+    guid: &EfiGuid,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let mut efi_name = [0u16; MAX_VARIABLE_NAME_LEN];
+    encode_ucs2(name.chars(), &mut efi_name)?;
+    let mut size = buf.len();
+    let status = (rt.get_variable)(
+        efi_name.as_ptr(),
+        guid,
+        null_mut(),
+        &mut size,
+        buf.as_mut_ptr(),
+    );
+    match status {
+        EfiStatus::SUCCESS => Ok(size),
+        EfiStatus::NOT_FOUND => Err("Variable not found"),
+        EfiStatus::BUFFER_TOO_SMALL => Err("Variable is too large for the buffer"),
+        _ => Err("GetVariable failed"),
+    }
+}
+
+// 値が空なら変数を消す
+pub fn set_variable(
+    rt: &EfiRuntimeServicesTable,
+    name: &str,
+    guid: &EfiGuid,
+    attributes: u32,
+    data: &[u8],
+) -> Result<()> {
+    let mut efi_name = [0u16; MAX_VARIABLE_NAME_LEN];
+    encode_ucs2(name.chars(), &mut efi_name)?;
+    let data_ptr = if data.is_empty() {
+        null()
+    } else {
+        data.as_ptr()
+    };
+    let status = (rt.set_variable)(efi_name.as_ptr(), guid, attributes, data.len(), data_ptr);
+    if status != EfiStatus::SUCCESS {
+        return Err("SetVariable failed");
+    }
+    Ok(())
+}
+
+// 変数名はUCS-2のまま渡す (NUL終端は含まない)
+pub fn for_each_variable_name(
+    rt: &EfiRuntimeServicesTable,
+    mut f: impl FnMut(&[u16], &EfiGuid),
+) -> Result<()> {
+    // 最初の呼び出しでは空文字列を渡す
+    let mut name = [0u16; MAX_VARIABLE_NAME_LEN];
+    let mut guid = EFI_GLOBAL_VARIABLE;
+    loop {
+        let mut size = core::mem::size_of_val(&name);
+        let status = (rt.get_next_variable_name)(&mut size, name.as_mut_ptr(), &mut guid);
+        match status {
+            EfiStatus::SUCCESS => {}
+            EfiStatus::NOT_FOUND => return Ok(()),
+            // 長すぎる名前の変数が来たら、それ以降は辿れないのでそこで止める
+            EfiStatus::BUFFER_TOO_SMALL => return Err("Variable name too long"),
+            _ => return Err("GetNextVariableName failed"),
+        }
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        f(&name[..len], &guid);
+    }
+}
+
+// カーネルのオプションはWASABI_VARIABLE_GUIDの下に不揮発で保存する
+pub fn get_kernel_option(
+    rt: &EfiRuntimeServicesTable,
+    name: &str,
+    buf: &mut [u8],
+) -> Result<usize> {
+    get_variable(rt, name, &WASABI_VARIABLE_GUID, buf)
+}
+pub fn set_kernel_option(rt: &EfiRuntimeServicesTable, name: &str, value: &[u8]) -> Result<()> {
+    set_variable(
+        rt,
+        name,
+        &WASABI_VARIABLE_GUID,
+        EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS,
+        value,
+    )
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BootReport {
+    pub boot_current: Option<u16>,
+    pub secure_boot: Option<bool>,
+    pub setup_mode: Option<bool>,
+    pub num_of_variables: usize,
+}
+
+impl BootReport {
+    // ブートサービスの終了前に呼ぶ
+    pub fn collect(efi_system_table: &EfiSystemTable) -> Self {
+        let rt = efi_system_table.runtime_services();
+        let mut buf = [0u8; 2];
+        let boot_current = get_variable(rt, "BootCurrent", &EFI_GLOBAL_VARIABLE, &mut buf)
+            .ok()
+            .filter(|size| *size == 2)
+            .map(|_| u16::from_le_bytes(buf));
+        let mut read_bool = |name| {
+            get_variable(rt, name, &EFI_GLOBAL_VARIABLE, &mut buf)
+                .ok()
+                .filter(|size| *size == 1)
+                .map(|_| buf[0] != 0)
+        };
+        let secure_boot = read_bool("SecureBoot");
+        let setup_mode = read_bool("SetupMode");
+        let mut num_of_variables = 0;
+        let _ = for_each_variable_name(rt, |_, _| num_of_variables += 1);
+        Self {
+            boot_current,
+            secure_boot,
+            setup_mode,
+            num_of_variables,
+        }
+    }
+}
+
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.boot_current {
+            Some(n) => write!(f, "BootCurrent: Boot{n:04X}")?,
+            None => write!(f, "BootCurrent: unknown")?,
+        }
+        match self.secure_boot {
+            Some(true) => write!(f, ", SecureBoot: enabled")?,
+            Some(false) => write!(f, ", SecureBoot: disabled")?,
+            None => write!(f, ", SecureBoot: not supported")?,
+        }
+        if self.setup_mode == Some(true) {
+            write!(f, " (setup mode)")?;
+        }
+        write!(f, ", {} variables", self.num_of_variables)
+    }
+}
@@ -0,0 +1,49 @@
+use crate::result::Result;
+
+// Universal Serial Bus Specification Revision 2.0, 9.3 USB Device Requests
+pub const REQUEST_TYPE_DIR_IN: u8 = 0x80;
+pub const REQUEST_TYPE_CLASS: u8 = 0x20;
+pub const REQUEST_TYPE_RECIPIENT_DEVICE: u8 = 0x00;
+pub const REQUEST_TYPE_RECIPIENT_INTERFACE: u8 = 0x01;
+pub const REQUEST_TYPE_RECIPIENT_OTHER: u8 = 0x03;
+
+pub const REQUEST_GET_STATUS: u8 = 0;
+pub const REQUEST_CLEAR_FEATURE: u8 = 1;
+pub const REQUEST_SET_FEATURE: u8 = 3;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut b = [0u8; 8];
+        b[0] = self.request_type;
+        b[1] = self.request;
+        b[2..4].copy_from_slice(&self.value.to_le_bytes());
+        b[4..6].copy_from_slice(&self.index.to_le_bytes());
+        b[6..8].copy_from_slice(&self.length.to_le_bytes());
+        b
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbSpeed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+// ホストコントローラドライバが提供するデフォルトコントロールパイプ
+pub trait ControlPipe: Send {
+    // 受信したバイト数を返す
+    fn control_in(&mut self, setup: SetupPacket, buf: &mut [u8]) -> Result<usize>;
+    fn control_out(&mut self, setup: SetupPacket, data: &[u8]) -> Result<()>;
+}
@@ -0,0 +1,189 @@
+use crate::keyboard::push_key_event;
+use crate::keyboard::KeyCode;
+use crate::keyboard::KeyEvent;
+use crate::keyboard::Modifiers;
+
+// Device Class Definition for HID 1.11, Appendix B.1 Protocol 1 (Keyboard)
+// byte 0: modifier, byte 1: reserved, byte 2..8: 押されているキーのUsage ID
+pub const BOOT_KEYBOARD_REPORT_SIZE: usize = 8;
+pub type BootKeyboardReport = [u8; BOOT_KEYBOARD_REPORT_SIZE];
+
+// HID Usage Tables 1.12, 10 Keyboard/Keypad Page (0x07)
+const USAGE_ERROR_ROLL_OVER: u8 = 0x01;
+
+fn usage_to_keycode(usage: u8, shift: bool) -> Option<KeyCode> {
+    const DIGITS: &[u8; 10] = b"1234567890";
+    const DIGITS_SHIFTED: &[u8; 10] = b"!@#$%^&*()";
+    // 0x2D..=0x38 (0x32は非USキーボード用なので除く)
+    const SYMBOLS: &[u8; 12] = b"-=[]\\\0;'`,./";
+    const SYMBOLS_SHIFTED: &[u8; 12] = b"_+{}|\0:\"~<>?";
+    let code = match usage {
+        0x04..=0x1D => {
+            let c = (b'a' + usage - 0x04) as char;
+            KeyCode::Char(if shift { c.to_ascii_uppercase() } else { c })
+        }
+        0x1E..=0x27 => {
+            let table = if shift { DIGITS_SHIFTED } else { DIGITS };
+            KeyCode::Char(table[(usage - 0x1E) as usize] as char)
+        }
+        0x28 => KeyCode::Enter,
+        0x29 => KeyCode::Escape,
+        0x2A => KeyCode::Backspace,
+        0x2B => KeyCode::Tab,
+        0x2C => KeyCode::Char(' '),
+        0x2D..=0x38 => {
+            let table = if shift { SYMBOLS_SHIFTED } else { SYMBOLS };
+            match table[(usage - 0x2D) as usize] {
+                0 => return None,
+                c => KeyCode::Char(c as char),
+            }
+        }
+        0x39 => KeyCode::CapsLock,
+        0x3A..=0x45 => KeyCode::F(usage - 0x3A + 1),
+        0x4A => KeyCode::Home,
+        0x4B => KeyCode::PageUp,
+        0x4C => KeyCode::Delete,
+        0x4D => KeyCode::End,
+        0x4E => KeyCode::PageDown,
+        0x4F => KeyCode::Right,
+        0x50 => KeyCode::Left,
+        0x51 => KeyCode::Down,
+        0x52 => KeyCode::Up,
+        _ => return None,
+    };
+    Some(code)
+}
+
+// 左右のキーをまとめたModifiersに変換する
+fn modifiers_from_report(bits: u8) -> Modifiers {
+    let both = bits | (bits >> 4);
+    let mut m = 0;
+    if both & 0b0001 != 0 {
+        m |= Modifiers::CTRL;
+    }
+    if both & 0b0010 != 0 {
+        m |= Modifiers::SHIFT;
+    }
+    if both & 0b0100 != 0 {
+        m |= Modifiers::ALT;
+    }
+    if both & 0b1000 != 0 {
+        m |= Modifiers::META;
+    }
+    Modifiers::new(m)
+}
+
+// ブートプロトコルのレポートは押されているキーの一覧なので、前回との差分から押下/解放を作る
+#[derive(Default)]
+pub struct BootKeyboard {
+    prev: BootKeyboardReport,
+    // prev[2..]の各キーを押した時に出したKeyCode。離した時も同じものを出す
+    prev_codes: [Option<KeyCode>; BOOT_KEYBOARD_REPORT_SIZE - 2],
+    caps_lock: bool,
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn caps_lock(&self) -> bool {
+        self.caps_lock
+    }
+    pub fn handle_report(&mut self, report: &BootKeyboardReport, mut emit: impl FnMut(KeyEvent)) {
+        let keys = &report[2..];
+        if keys.contains(&USAGE_ERROR_ROLL_OVER) {
+            // 押されすぎて状態がわからないので、前回の状態を保つ
+            return;
+        }
+        let modifiers = modifiers_from_report(report[0]);
+        for (&usage, code) in self.prev[2..].iter().zip(self.prev_codes) {
+            if usage == 0 || keys.contains(&usage) {
+                continue;
+            }
+            if let Some(code) = code {
+                emit(KeyEvent {
+                    code,
+                    pressed: false,
+                    modifiers,
+                });
+            }
+        }
+        let mut codes = [None; BOOT_KEYBOARD_REPORT_SIZE - 2];
+        for (&usage, slot) in keys.iter().zip(codes.iter_mut()) {
+            if usage == 0 {
+                continue;
+            }
+            if let Some(i) = self.prev[2..].iter().position(|u| *u == usage) {
+                *slot = self.prev_codes[i];
+                continue;
+            }
+            let is_letter = (0x04..=0x1D).contains(&usage);
+            let shift = modifiers.shift() ^ (is_letter && self.caps_lock);
+            if let Some(code) = usage_to_keycode(usage, shift) {
+                if code == KeyCode::CapsLock {
+                    self.caps_lock = !self.caps_lock;
+                }
+                *slot = Some(code);
+                emit(KeyEvent {
+                    code,
+                    pressed: true,
+                    modifiers,
+                });
+            }
+        }
+        self.prev = *report;
+        self.prev_codes = codes;
+    }
+    // 割り込みINエンドポイントから受け取ったレポートを共通のキーイベントキューに積む
+    pub fn process_report(&mut self, report: &BootKeyboardReport) {
+        self.handle_report(report, push_key_event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn events(kbd: &mut BootKeyboard, report: BootKeyboardReport) -> Vec<(KeyCode, bool)> {
+        let mut v = Vec::new();
+        kbd.handle_report(&report, |e| v.push((e.code, e.pressed)));
+        v
+    }
+
+    #[test_case]
+    fn boot_report_press_and_release() {
+        let mut kbd = BootKeyboard::new();
+        assert_eq!(
+            events(&mut kbd, [0, 0, 0x04, 0, 0, 0, 0, 0]),
+            [(KeyCode::Char('a'), true)]
+        );
+        assert_eq!(
+            events(&mut kbd, [0x02, 0, 0x04, 0x1E, 0, 0, 0, 0]),
+            [(KeyCode::Char('!'), true)]
+        );
+        // 離した時は、押した時と同じKeyCodeになる
+        assert_eq!(
+            events(&mut kbd, [0, 0, 0, 0, 0, 0, 0, 0]),
+            [(KeyCode::Char('a'), false), (KeyCode::Char('!'), false)]
+        );
+        assert!(events(&mut kbd, [0, 0, 1, 1, 1, 1, 1, 1]).is_empty());
+    }
+
+    #[test_case]
+    fn release_matches_press_under_caps_lock() {
+        let mut kbd = BootKeyboard::new();
+        events(&mut kbd, [0, 0, 0x39, 0, 0, 0, 0, 0]);
+        events(&mut kbd, [0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(kbd.caps_lock());
+        assert_eq!(
+            events(&mut kbd, [0, 0, 0x04, 0, 0, 0, 0, 0]),
+            [(KeyCode::Char('A'), true)]
+        );
+        assert_eq!(
+            events(&mut kbd, [0, 0, 0, 0, 0, 0, 0, 0]),
+            [(KeyCode::Char('A'), false)]
+        );
+    }
+}
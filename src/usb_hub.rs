@@ -0,0 +1,305 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::hpet::busy_wait;
+use crate::info;
+use crate::result::Result;
+use crate::usb::ControlPipe;
+use crate::usb::SetupPacket;
+use crate::usb::UsbSpeed;
+use crate::usb::REQUEST_CLEAR_FEATURE;
+use crate::usb::REQUEST_GET_DESCRIPTOR;
+use crate::usb::REQUEST_GET_STATUS;
+use crate::usb::REQUEST_SET_FEATURE;
+use crate::usb::REQUEST_TYPE_CLASS;
+use crate::usb::REQUEST_TYPE_DIR_IN;
+use crate::usb::REQUEST_TYPE_RECIPIENT_DEVICE;
+use crate::usb::REQUEST_TYPE_RECIPIENT_OTHER;
+
+// Universal Serial Bus Specification Revision 2.0, 11.23 / 11.24 Hub Descriptor and Requests
+const DESCRIPTOR_TYPE_HUB: u16 = 0x29;
+const HUB_DESCRIPTOR_MIN_SIZE: usize = 7;
+
+const PORT_FEATURE_RESET: u16 = 4;
+const PORT_FEATURE_POWER: u16 = 8;
+const PORT_FEATURE_C_CONNECTION: u16 = 16;
+const PORT_FEATURE_C_ENABLE: u16 = 17;
+const PORT_FEATURE_C_SUSPEND: u16 = 18;
+const PORT_FEATURE_C_OVER_CURRENT: u16 = 19;
+const PORT_FEATURE_C_RESET: u16 = 20;
+
+const PORT_STATUS_CONNECTION: u16 = 1 << 0;
+const PORT_STATUS_ENABLE: u16 = 1 << 1;
+const PORT_STATUS_OVER_CURRENT: u16 = 1 << 3;
+const PORT_STATUS_RESET: u16 = 1 << 4;
+const PORT_STATUS_POWER: u16 = 1 << 8;
+const PORT_STATUS_LOW_SPEED: u16 = 1 << 9;
+const PORT_STATUS_HIGH_SPEED: u16 = 1 << 10;
+
+const PORT_CHANGE_CONNECTION: u16 = 1 << 0;
+const PORT_CHANGE_ENABLE: u16 = 1 << 1;
+const PORT_CHANGE_SUSPEND: u16 = 1 << 2;
+const PORT_CHANGE_OVER_CURRENT: u16 = 1 << 3;
+const PORT_CHANGE_RESET: u16 = 1 << 4;
+
+// 7.1.7.3 リセットは10ms以上, リセット後は10ms待ってからアクセスする
+const PORT_RESET_TIMEOUT_MS: u64 = 500;
+const PORT_RESET_RECOVERY: Duration = Duration::from_millis(10);
+// 7.1.7.3 接続検出後のデバウンス時間
+const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HubDescriptor {
+    pub num_of_ports: u8,
+    pub characteristics: u16,
+    // 電源を入れてから安定するまでの時間 (2ms単位)
+    pub power_on_to_power_good: u8,
+}
+
+impl HubDescriptor {
+    fn parse(b: &[u8]) -> Result<Self> {
+        if b.len() < HUB_DESCRIPTOR_MIN_SIZE || b[1] as u16 != DESCRIPTOR_TYPE_HUB {
+            return Err("usb-hub: invalid hub descriptor");
+        }
+        Ok(Self {
+            num_of_ports: b[2],
+            characteristics: u16::from_le_bytes([b[3], b[4]]),
+            power_on_to_power_good: b[5],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortStatus {
+    pub status: u16,
+    pub change: u16,
+}
+
+impl PortStatus {
+    fn parse(b: &[u8; 4]) -> Self {
+        Self {
+            status: u16::from_le_bytes([b[0], b[1]]),
+            change: u16::from_le_bytes([b[2], b[3]]),
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        self.status & PORT_STATUS_CONNECTION != 0
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.status & PORT_STATUS_ENABLE != 0
+    }
+    pub fn is_powered(&self) -> bool {
+        self.status & PORT_STATUS_POWER != 0
+    }
+    pub fn is_resetting(&self) -> bool {
+        self.status & PORT_STATUS_RESET != 0
+    }
+    pub fn is_over_current(&self) -> bool {
+        self.status & PORT_STATUS_OVER_CURRENT != 0
+    }
+    // USB 2.0ハブの下ではLow/Full/Highのいずれか
+    pub fn speed(&self) -> UsbSpeed {
+        if self.status & PORT_STATUS_LOW_SPEED != 0 {
+            UsbSpeed::Low
+        } else if self.status & PORT_STATUS_HIGH_SPEED != 0 {
+            UsbSpeed::High
+        } else {
+            UsbSpeed::Full
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortEvent {
+    // リセットが完了して、アドレスを割り当てられる状態になった
+    Connected { port: u8, speed: UsbSpeed },
+    Disconnected { port: u8 },
+    OverCurrent { port: u8 },
+}
+
+pub struct UsbHub<T> {
+    pipe: T,
+    descriptor: HubDescriptor,
+}
+
+impl<T: ControlPipe> UsbHub<T> {
+    // ハブのデバイスが設定済み (SET_CONFIGURATION後) であること
+    pub fn new(pipe: T) -> Result<Self> {
+        let mut hub = Self {
+            pipe,
+            descriptor: HubDescriptor {
+                num_of_ports: 0,
+                characteristics: 0,
+                power_on_to_power_good: 0,
+            },
+        };
+        let mut buf = [0u8; 16];
+        let len = hub.pipe.control_in(
+            SetupPacket {
+                request_type: REQUEST_TYPE_DIR_IN
+                    | REQUEST_TYPE_CLASS
+                    | REQUEST_TYPE_RECIPIENT_DEVICE,
+                request: REQUEST_GET_DESCRIPTOR,
+                value: DESCRIPTOR_TYPE_HUB << 8,
+                index: 0,
+                length: buf.len() as u16,
+            },
+            &mut buf,
+        )?;
+        hub.descriptor = HubDescriptor::parse(&buf[..len])?;
+        info!("usb-hub: {} ports", hub.descriptor.num_of_ports);
+        for port in 1..=hub.descriptor.num_of_ports {
+            hub.set_port_feature(port, PORT_FEATURE_POWER)?;
+        }
+        busy_wait(Duration::from_millis(
+            hub.descriptor.power_on_to_power_good as u64 * 2,
+        ));
+        Ok(hub)
+    }
+    pub fn descriptor(&self) -> &HubDescriptor {
+        &self.descriptor
+    }
+    fn port_request(&mut self, request: u8, port: u8, feature: u16) -> Result<()> {
+        self.pipe.control_out(
+            SetupPacket {
+                request_type: REQUEST_TYPE_CLASS | REQUEST_TYPE_RECIPIENT_OTHER,
+                request,
+                value: feature,
+                index: port as u16,
+                length: 0,
+            },
+            &[],
+        )
+    }
+    fn set_port_feature(&mut self, port: u8, feature: u16) -> Result<()> {
+        self.port_request(REQUEST_SET_FEATURE, port, feature)
+    }
+    fn clear_port_feature(&mut self, port: u8, feature: u16) -> Result<()> {
+        self.port_request(REQUEST_CLEAR_FEATURE, port, feature)
+    }
+    pub fn port_status(&mut self, port: u8) -> Result<PortStatus> {
+        if port == 0 || port > self.descriptor.num_of_ports {
+            return Err("usb-hub: port out of range");
+        }
+        let mut buf = [0u8; 4];
+        let len = self.pipe.control_in(
+            SetupPacket {
+                request_type: REQUEST_TYPE_DIR_IN
+                    | REQUEST_TYPE_CLASS
+                    | REQUEST_TYPE_RECIPIENT_OTHER,
+                request: REQUEST_GET_STATUS,
+                value: 0,
+                index: port as u16,
+                length: buf.len() as u16,
+            },
+            &mut buf,
+        )?;
+        if len != buf.len() {
+            return Err("usb-hub: short port status");
+        }
+        Ok(PortStatus::parse(&buf))
+    }
+    // ポートをリセットしてデバイスを有効にし、その速度を返す
+    pub fn reset_port(&mut self, port: u8) -> Result<UsbSpeed> {
+        self.set_port_feature(port, PORT_FEATURE_RESET)?;
+        for _ in 0..PORT_RESET_TIMEOUT_MS / 10 {
+            busy_wait(Duration::from_millis(10));
+            let status = self.port_status(port)?;
+            if status.change & PORT_CHANGE_RESET != 0 && !status.is_resetting() {
+                self.clear_port_feature(port, PORT_FEATURE_C_RESET)?;
+                if !status.is_enabled() {
+                    return Err("usb-hub: port was not enabled after reset");
+                }
+                busy_wait(PORT_RESET_RECOVERY);
+                return Ok(status.speed());
+            }
+        }
+        Err("usb-hub: port reset timed out")
+    }
+    // 変化ビットを全部クリアし、必要ならポートをリセットしてイベントを返す
+    fn handle_port_change(&mut self, port: u8) -> Result<Option<PortEvent>> {
+        let status = self.port_status(port)?;
+        let changes = [
+            (PORT_CHANGE_CONNECTION, PORT_FEATURE_C_CONNECTION),
+            (PORT_CHANGE_ENABLE, PORT_FEATURE_C_ENABLE),
+            (PORT_CHANGE_SUSPEND, PORT_FEATURE_C_SUSPEND),
+            (PORT_CHANGE_OVER_CURRENT, PORT_FEATURE_C_OVER_CURRENT),
+            (PORT_CHANGE_RESET, PORT_FEATURE_C_RESET),
+        ];
+        for (bit, feature) in changes {
+            if status.change & bit != 0 {
+                self.clear_port_feature(port, feature)?;
+            }
+        }
+        if status.change & PORT_CHANGE_OVER_CURRENT != 0 && status.is_over_current() {
+            return Ok(Some(PortEvent::OverCurrent { port }));
+        }
+        if status.change & PORT_CHANGE_CONNECTION == 0 {
+            return Ok(None);
+        }
+        if !status.is_connected() {
+            return Ok(Some(PortEvent::Disconnected { port }));
+        }
+        busy_wait(DEBOUNCE_INTERVAL);
+        if !self.port_status(port)?.is_connected() {
+            return Ok(None);
+        }
+        let speed = self.reset_port(port)?;
+        Ok(Some(PortEvent::Connected { port, speed }))
+    }
+    // 起動時に既に繋がっているデバイスを見つける
+    pub fn enumerate(&mut self) -> Result<Vec<PortEvent>> {
+        let mut events = Vec::new();
+        for port in 1..=self.descriptor.num_of_ports {
+            let status = self.port_status(port)?;
+            if !status.is_connected() {
+                continue;
+            }
+            if status.change != 0 {
+                if let Some(e) = self.handle_port_change(port)? {
+                    events.push(e);
+                }
+            } else {
+                let speed = self.reset_port(port)?;
+                events.push(PortEvent::Connected { port, speed });
+            }
+        }
+        Ok(events)
+    }
+    // Status Change Endpoint (割り込みIN) のビットマップを処理する
+    // bit 0はハブ自身, bit Nはポート N
+    pub fn handle_status_change(&mut self, bitmap: &[u8]) -> Result<Vec<PortEvent>> {
+        let mut events = Vec::new();
+        for port in 1..=self.descriptor.num_of_ports {
+            let byte = bitmap.get(port as usize / 8).copied().unwrap_or(0);
+            if byte & (1 << (port % 8)) == 0 {
+                continue;
+            }
+            if let Some(e) = self.handle_port_change(port)? {
+                events.push(e);
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_hub_descriptor_and_port_status() {
+        let desc = HubDescriptor::parse(&[9, 0x29, 4, 0x09, 0x00, 50, 0, 0, 0xFF]).unwrap();
+        assert_eq!(desc.num_of_ports, 4);
+        assert_eq!(desc.power_on_to_power_good, 50);
+        assert!(HubDescriptor::parse(&[9, 0x02, 4, 0, 0, 0, 0]).is_err());
+        let status = PortStatus::parse(&[0x03, 0x03, 0x01, 0x00]);
+        assert!(status.is_connected());
+        assert!(status.is_enabled());
+        assert!(status.is_powered());
+        assert_eq!(status.speed(), UsbSpeed::Low);
+        assert_eq!(status.change, PORT_CHANGE_CONNECTION);
+    }
+}
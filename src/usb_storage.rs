@@ -0,0 +1,333 @@
+use core::str::from_utf8;
+
+use crate::block::check_block_range;
+use crate::block::BlockDevice;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::result::Result;
+
+// USB Mass Storage Class Bulk-Only Transport Rev 1.0
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CBW_SIZE: usize = 31;
+const CSW_SIZE: usize = 13;
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+const CSW_STATUS_PASSED: u8 = 0;
+
+// SCSI Block Commands
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const INQUIRY_DATA_SIZE: usize = 36;
+
+// READ(10)/WRITE(10)の転送ブロック数はu16だが、一度に大きく転送しすぎないように分割する
+const MAX_BLOCKS_PER_COMMAND: usize = 64;
+
+// ホストコントローラドライバが提供するBulk IN/OUTエンドポイントの組
+pub trait BulkPipes: Send {
+    fn bulk_out(&mut self, data: &[u8]) -> Result<()>;
+    // 受信したバイト数を返す
+    fn bulk_in(&mut self, buf: &mut [u8]) -> Result<usize>;
+    // Bulk-Only Mass Storage Reset + Clear Feature(HALT)
+    fn reset_recovery(&mut self) -> Result<()>;
+}
+
+enum DataPhase<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+struct BulkOnlyTransport<T> {
+    pipes: T,
+    lun: u8,
+    tag: u32,
+}
+
+impl<T: BulkPipes> BulkOnlyTransport<T> {
+    fn command(&mut self, cb: &[u8], data: DataPhase) -> Result<()> {
+        if cb.is_empty() || cb.len() > 16 {
+            return Err("usb-storage: invalid command block length");
+        }
+        self.tag = self.tag.wrapping_add(1);
+        let (len, flags) = match &data {
+            DataPhase::None => (0, 0),
+            DataPhase::In(buf) => (buf.len(), CBW_FLAG_DATA_IN),
+            DataPhase::Out(buf) => (buf.len(), 0),
+        };
+        let mut cbw = [0u8; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[13] = self.lun;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        self.pipes.bulk_out(&cbw)?;
+        let result = match data {
+            DataPhase::None => Ok(()),
+            DataPhase::In(buf) => self.pipes.bulk_in(buf).map(|_| ()),
+            DataPhase::Out(buf) => self.pipes.bulk_out(buf),
+        };
+        if let Err(e) = result {
+            self.pipes.reset_recovery()?;
+            return Err(e);
+        }
+        let mut csw = [0u8; CSW_SIZE];
+        if self.pipes.bulk_in(&mut csw)? != CSW_SIZE
+            || u32::from_le_bytes(csw[0..4].try_into().unwrap()) != CSW_SIGNATURE
+            || u32::from_le_bytes(csw[4..8].try_into().unwrap()) != self.tag
+        {
+            self.pipes.reset_recovery()?;
+            return Err("usb-storage: invalid CSW");
+        }
+        if csw[12] != CSW_STATUS_PASSED {
+            return Err("usb-storage: command failed");
+        }
+        Ok(())
+    }
+    fn rw10(&mut self, opcode: u8, lba: u64, blocks: usize, data: DataPhase) -> Result<()> {
+        let lba = u32::try_from(lba).or(Err("usb-storage: LBA out of READ(10) range"))?;
+        let mut cb = [0u8; 10];
+        cb[0] = opcode;
+        cb[2..6].copy_from_slice(&lba.to_be_bytes());
+        cb[7..9].copy_from_slice(&(blocks as u16).to_be_bytes());
+        self.command(&cb, data)
+    }
+}
+
+pub struct UsbMassStorage<T> {
+    transport: Mutex<BulkOnlyTransport<T>>,
+    block_size: usize,
+    num_of_blocks: u64,
+}
+
+impl<T: BulkPipes> UsbMassStorage<T> {
+    pub fn new(pipes: T, lun: u8) -> Result<Self> {
+        let mut transport = BulkOnlyTransport { pipes, lun, tag: 0 };
+        let mut inquiry = [0u8; INQUIRY_DATA_SIZE];
+        transport.command(
+            &[SCSI_INQUIRY, 0, 0, 0, INQUIRY_DATA_SIZE as u8, 0],
+            DataPhase::In(&mut inquiry),
+        )?;
+        // Peripheral Device Type 0 = Direct access block device
+        if inquiry[0] & 0x1F != 0 {
+            return Err("usb-storage: not a direct access block device");
+        }
+        info!(
+            "usb-storage: {} {}",
+            from_utf8(&inquiry[8..16]).unwrap_or("?").trim_end(),
+            from_utf8(&inquiry[16..32]).unwrap_or("?").trim_end()
+        );
+        // メディアの準備ができるまで少し待つ
+        for _ in 0..10 {
+            if transport
+                .command(&[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], DataPhase::None)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        let mut capacity = [0u8; 8];
+        transport.command(
+            &[SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            DataPhase::In(&mut capacity),
+        )?;
+        let last_lba = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap()) as usize;
+        if block_size == 0 {
+            return Err("usb-storage: invalid block size");
+        }
+        Ok(Self {
+            transport: Mutex::new(transport),
+            block_size,
+            num_of_blocks: last_lba as u64 + 1,
+        })
+    }
+}
+
+impl<T: BulkPipes> BlockDevice for UsbMassStorage<T> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn num_of_blocks(&self) -> u64 {
+        self.num_of_blocks
+    }
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        let mut transport = self.transport.lock();
+        let chunk_size = MAX_BLOCKS_PER_COMMAND * self.block_size;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let blocks = chunk.len() / self.block_size;
+            let lba = lba + (i * MAX_BLOCKS_PER_COMMAND) as u64;
+            transport.rw10(SCSI_READ_10, lba, blocks, DataPhase::In(chunk))?;
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<()> {
+        check_block_range(self, lba, buf.len())?;
+        let mut transport = self.transport.lock();
+        let chunk_size = MAX_BLOCKS_PER_COMMAND * self.block_size;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let blocks = chunk.len() / self.block_size;
+            let lba = lba + (i * MAX_BLOCKS_PER_COMMAND) as u64;
+            transport.rw10(SCSI_WRITE_10, lba, blocks, DataPhase::Out(chunk))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    const BLOCK_SIZE: usize = 512;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Corruption {
+        None,
+        Signature,
+        Tag,
+    }
+
+    // SCSIのコマンドをRAM上のディスクに対して処理するデバイスのふり
+    struct FakeDevice {
+        disk: Vec<u8>,
+        // bulk_inで返すもの (データ, CSWの順)
+        responses: VecDeque<Vec<u8>>,
+        // WRITE(10)のデータを待っている時の (書き込み先, CSW)
+        pending_write: Option<(usize, Vec<u8>)>,
+        corruption: Corruption,
+        resets: usize,
+    }
+
+    impl FakeDevice {
+        fn new(num_of_blocks: usize) -> Self {
+            Self {
+                disk: vec![0; num_of_blocks * BLOCK_SIZE],
+                responses: VecDeque::new(),
+                pending_write: None,
+                corruption: Corruption::None,
+                resets: 0,
+            }
+        }
+        fn csw(&self, tag: u32) -> Vec<u8> {
+            let mut csw = vec![0u8; CSW_SIZE];
+            let signature = match self.corruption {
+                Corruption::Signature => !CSW_SIGNATURE,
+                _ => CSW_SIGNATURE,
+            };
+            let tag = match self.corruption {
+                Corruption::Tag => tag.wrapping_add(1),
+                _ => tag,
+            };
+            csw[0..4].copy_from_slice(&signature.to_le_bytes());
+            csw[4..8].copy_from_slice(&tag.to_le_bytes());
+            csw[12] = CSW_STATUS_PASSED;
+            csw
+        }
+    }
+
+    impl BulkPipes for FakeDevice {
+        fn bulk_out(&mut self, data: &[u8]) -> Result<()> {
+            if let Some((offset, csw)) = self.pending_write.take() {
+                self.disk[offset..offset + data.len()].copy_from_slice(data);
+                self.responses.push_back(csw);
+                return Ok(());
+            }
+            assert_eq!(data.len(), CBW_SIZE);
+            assert_eq!(data[0..4], CBW_SIGNATURE.to_le_bytes());
+            let tag = u32::from_le_bytes(data[4..8].try_into().unwrap());
+            let cb = &data[15..15 + data[14] as usize];
+            let lba = || u32::from_be_bytes(cb[2..6].try_into().unwrap()) as usize * BLOCK_SIZE;
+            let len = || u16::from_be_bytes([cb[7], cb[8]]) as usize * BLOCK_SIZE;
+            match cb[0] {
+                SCSI_INQUIRY => {
+                    let mut inquiry = vec![0u8; INQUIRY_DATA_SIZE];
+                    inquiry[8..16].copy_from_slice(b"WASABI  ");
+                    inquiry[16..32].copy_from_slice(b"FAKE DISK       ");
+                    self.responses.push_back(inquiry);
+                }
+                SCSI_TEST_UNIT_READY => {}
+                SCSI_READ_CAPACITY_10 => {
+                    let last_lba = (self.disk.len() / BLOCK_SIZE - 1) as u32;
+                    let mut capacity = last_lba.to_be_bytes().to_vec();
+                    capacity.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                    self.responses.push_back(capacity);
+                }
+                SCSI_READ_10 => {
+                    let data = self.disk[lba()..lba() + len()].to_vec();
+                    self.responses.push_back(data);
+                }
+                SCSI_WRITE_10 => {
+                    self.pending_write = Some((lba(), self.csw(tag)));
+                    return Ok(());
+                }
+                _ => return Err("Unknown SCSI command"),
+            }
+            self.responses.push_back(self.csw(tag));
+            Ok(())
+        }
+        fn bulk_in(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let data = self.responses.pop_front().ok_or("Nothing to send")?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+        fn reset_recovery(&mut self) -> Result<()> {
+            self.resets += 1;
+            self.responses.clear();
+            self.pending_write = None;
+            Ok(())
+        }
+    }
+
+    fn corrupt_and_read(corruption: Corruption) -> (Result<()>, usize) {
+        let storage = UsbMassStorage::new(FakeDevice::new(8), 0).unwrap();
+        storage.transport.lock().pipes.corruption = corruption;
+        let mut buf = [0u8; BLOCK_SIZE];
+        let result = storage.read_blocks(0, &mut buf);
+        let resets = storage.transport.lock().pipes.resets;
+        (result, resets)
+    }
+
+    #[test_case]
+    fn read_and_write_through_bulk_only_transport() {
+        let storage = UsbMassStorage::new(FakeDevice::new(128), 0).unwrap();
+        assert_eq!(storage.block_size(), BLOCK_SIZE);
+        assert_eq!(storage.num_of_blocks(), 128);
+        // MAX_BLOCKS_PER_COMMANDを超えるので2回のWRITE(10)に分かれる
+        let data: Vec<u8> = (0..70 * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+        storage.write_blocks(3, &data).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        storage.read_blocks(3, &mut buf).unwrap();
+        assert!(buf == data);
+        assert_eq!(
+            storage.transport.lock().pipes.disk[3 * BLOCK_SIZE + 69 * BLOCK_SIZE],
+            69
+        );
+        assert_eq!(storage.transport.lock().pipes.resets, 0);
+    }
+
+    #[test_case]
+    fn reject_csw_with_bad_signature() {
+        let (result, resets) = corrupt_and_read(Corruption::Signature);
+        assert_eq!(result, Err("usb-storage: invalid CSW"));
+        assert_eq!(resets, 1);
+    }
+
+    #[test_case]
+    fn reject_csw_with_mismatched_tag() {
+        let (result, resets) = corrupt_and_read(Corruption::Tag);
+        assert_eq!(result, Err("usb-storage: invalid CSW"));
+        assert_eq!(resets, 1);
+    }
+}
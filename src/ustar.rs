@@ -0,0 +1,311 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::fs::DirEntry;
+use crate::fs::FileSystem;
+use crate::fs::FileType;
+use crate::fs::Inode;
+use crate::fs::Metadata;
+use crate::result::Result;
+
+// https://www.gnu.org/software/tar/manual/html_node/Standard.html
+const BLOCK_SIZE: usize = 512;
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let mut v = 0u64;
+    for &c in field
+        .iter()
+        .skip_while(|c| **c == b' ')
+        .take_while(|c| **c != 0 && **c != b' ')
+    {
+        if !(b'0'..=b'7').contains(&c) {
+            return Err("Invalid octal number in tar header");
+        }
+        v = v.checked_mul(8).ok_or("Octal number too large")? + (c - b'0') as u64;
+    }
+    Ok(v)
+}
+
+fn parse_str(field: &[u8]) -> Result<&str> {
+    let len = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).or(Err("Invalid UTF-8 in tar header"))
+}
+
+// チェックサムは、チェックサム欄を空白とみなしたヘッダのバイトの和
+fn verify_checksum(header: &[u8]) -> Result<()> {
+    let expected = parse_octal(&header[148..156])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b } as u64)
+        .sum();
+    if sum != expected {
+        return Err("Tar header checksum mismatch");
+    }
+    Ok(())
+}
+
+enum Builder {
+    File(&'static [u8], Option<Duration>),
+    Directory(BTreeMap<String, Builder>, Option<Duration>),
+    Symlink(String),
+}
+
+impl Builder {
+    fn new_dir() -> Self {
+        Builder::Directory(BTreeMap::new(), None)
+    }
+    // 途中のディレクトリは無ければ作る
+    fn insert(&mut self, path: &str, node: Builder) -> Result<()> {
+        let mut parts: Vec<&str> = path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect();
+        let Some(name) = parts.pop() else {
+            // ルートディレクトリ自身
+            return Ok(());
+        };
+        let mut dir = self;
+        for c in parts {
+            let Builder::Directory(children, _) = dir else {
+                return Err("Path component is not a directory");
+            };
+            dir = children
+                .entry(c.to_string())
+                .or_insert_with(Builder::new_dir);
+        }
+        let Builder::Directory(children, _) = dir else {
+            return Err("Path component is not a directory");
+        };
+        match (children.get_mut(name), node) {
+            // 中身より後に来たディレクトリのエントリは時刻だけ反映する
+            (Some(Builder::Directory(_, mtime)), Builder::Directory(_, new_mtime)) => {
+                *mtime = new_mtime;
+            }
+            (_, node) => {
+                children.insert(name.to_string(), node);
+            }
+        }
+        Ok(())
+    }
+    fn get(&self, path: &str) -> Option<&Builder> {
+        let mut node = self;
+        for c in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let Builder::Directory(children, _) = node else {
+                return None;
+            };
+            node = children.get(c)?;
+        }
+        Some(node)
+    }
+    fn build(self) -> Arc<TarNode> {
+        Arc::new(match self {
+            Builder::File(data, mtime) => TarNode::File(data, mtime),
+            Builder::Directory(children, mtime) => TarNode::Directory(
+                children.into_iter().map(|(k, v)| (k, v.build())).collect(),
+                mtime,
+            ),
+            Builder::Symlink(target) => TarNode::Symlink(target),
+        })
+    }
+}
+
+enum TarNode {
+    File(&'static [u8], Option<Duration>),
+    Directory(BTreeMap<String, Arc<TarNode>>, Option<Duration>),
+    Symlink(String),
+}
+
+impl TarNode {
+    fn file_type(&self) -> FileType {
+        match self {
+            TarNode::File(..) => FileType::File,
+            TarNode::Directory(..) => FileType::Directory,
+            TarNode::Symlink(_) => FileType::Symlink,
+        }
+    }
+}
+
+impl Inode for TarNode {
+    fn metadata(&self) -> Metadata {
+        let (size, mtime) = match self {
+            TarNode::File(data, mtime) => (data.len() as u64, *mtime),
+            TarNode::Directory(_, mtime) => (0, *mtime),
+            TarNode::Symlink(target) => (target.len() as u64, None),
+        };
+        Metadata {
+            file_type: self.file_type(),
+            size,
+            mtime,
+        }
+    }
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let TarNode::File(data, _) = self else {
+            return Err("Not a file");
+        };
+        let Some(src) = data.get(offset as usize..) else {
+            return Ok(0);
+        };
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        Ok(n)
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let TarNode::Directory(children, _) = self else {
+            return Err("Not a directory");
+        };
+        let child = children.get(name).ok_or("File not found")?;
+        Ok(child.clone())
+    }
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let TarNode::Directory(children, _) = self else {
+            return Err("Not a directory");
+        };
+        Ok(children
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                file_type: node.file_type(),
+            })
+            .collect())
+    }
+    fn read_link(&self) -> Result<String> {
+        let TarNode::Symlink(target) = self else {
+            return Err("Not a symbolic link");
+        };
+        Ok(target.clone())
+    }
+}
+
+// メモリ上のustarアーカイブをそのまま使う読み込み専用のファイルシステム
+pub struct UstarFs {
+    root: Arc<TarNode>,
+}
+
+impl UstarFs {
+    pub fn new(archive: &'static [u8]) -> Result<Self> {
+        let mut root = Builder::new_dir();
+        let mut offset = 0;
+        let mut long_name: Option<String> = None;
+        while let Some(header) = archive.get(offset..offset + BLOCK_SIZE) {
+            // 0で埋められたブロックがアーカイブの終わり
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            verify_checksum(header)?;
+            let size = parse_octal(&header[124..136])? as usize;
+            let data_start = offset + BLOCK_SIZE;
+            let data = archive
+                .get(data_start..data_start + size)
+                .ok_or("Tar entry exceeds the archive")?;
+            offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+            let name = match long_name.take() {
+                Some(name) => name,
+                None => {
+                    let name = parse_str(&header[0..100])?;
+                    let prefix = if &header[257..262] == b"ustar" {
+                        parse_str(&header[345..500])?
+                    } else {
+                        ""
+                    };
+                    if prefix.is_empty() {
+                        name.to_string()
+                    } else {
+                        format!("{prefix}/{name}")
+                    }
+                }
+            };
+            let mtime = Some(Duration::from_secs(parse_octal(&header[136..148])?));
+            let link_name = parse_str(&header[157..257])?;
+            match header[156] {
+                b'0' | 0 | b'7' => root.insert(&name, Builder::File(data, mtime))?,
+                b'5' => root.insert(&name, Builder::Directory(BTreeMap::new(), mtime))?,
+                b'2' => root.insert(&name, Builder::Symlink(link_name.to_string()))?,
+                // ハードリンクは先に出てきたファイルの中身を共有する
+                b'1' => match root.get(link_name) {
+                    Some(Builder::File(data, mtime)) => {
+                        let node = Builder::File(data, *mtime);
+                        root.insert(&name, node)?
+                    }
+                    _ => return Err("Hard link to a missing file"),
+                },
+                // GNUの長い名前は、次のエントリの名前になる
+                b'L' => long_name = Some(parse_str(data)?.to_string()),
+                // pax拡張ヘッダなどは無視する
+                _ => {}
+            }
+        }
+        Ok(Self { root: root.build() })
+    }
+}
+
+impl FileSystem for UstarFs {
+    fn name(&self) -> &str {
+        "ustar"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    fn header(name: &str, typeflag: u8, size: usize, link_name: &str) -> Vec<u8> {
+        let mut h = vec![0u8; BLOCK_SIZE];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        h[136..147].copy_from_slice(b"00000000000");
+        h[156] = typeflag;
+        h[157..157 + link_name.len()].copy_from_slice(link_name.as_bytes());
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[148..156].fill(b' ');
+        let sum: u32 = h.iter().map(|b| *b as u32).sum();
+        h[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        h
+    }
+
+    #[test_case]
+    fn parse_archive() {
+        let mut tar = Vec::new();
+        tar.extend(header("bin/", b'5', 0, ""));
+        tar.extend(header("bin/hello", b'0', 2, ""));
+        let mut data = vec![0u8; BLOCK_SIZE];
+        data[..2].copy_from_slice(b"hi");
+        tar.extend(data);
+        tar.extend(header("sh", b'2', 0, "bin/hello"));
+        tar.extend(header("etc/motd", b'1', 0, "bin/hello"));
+        tar.extend(vec![0u8; BLOCK_SIZE * 2]);
+        let tar: &'static [u8] = Box::leak(tar.into_boxed_slice());
+
+        let fs = UstarFs::new(tar).unwrap();
+        let root = fs.root();
+        let names: Vec<String> = root
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["bin", "etc", "sh"]);
+        let mut buf = [0u8; 4];
+        let hello = root.lookup("bin").unwrap().lookup("hello").unwrap();
+        assert_eq!(hello.read_at(0, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"hi");
+        let motd = root.lookup("etc").unwrap().lookup("motd").unwrap();
+        assert_eq!(motd.metadata().size, 2);
+        let sh = root.lookup("sh").unwrap();
+        assert_eq!(sh.metadata().file_type, FileType::Symlink);
+        assert_eq!(sh.read_link().unwrap(), "bin/hello");
+    }
+}
@@ -0,0 +1,484 @@
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::fence;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::dma::DmaBuffer;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::pci::Capability;
+use crate::pci::MmioRegion;
+use crate::pci::MsixTable;
+use crate::pci::PciDevice;
+use crate::pci::CAPABILITY_ID_VENDOR_SPECIFIC;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::InterruptHandler;
+
+// Virtual I/O Device (VIRTIO) Version 1.2
+// https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1AF4;
+
+pub const VIRTIO_DEVICE_TYPE_NET: u16 = 1;
+pub const VIRTIO_DEVICE_TYPE_BLOCK: u16 = 2;
+pub const VIRTIO_DEVICE_TYPE_CONSOLE: u16 = 3;
+pub const VIRTIO_DEVICE_TYPE_RNG: u16 = 4;
+pub const VIRTIO_DEVICE_TYPE_GPU: u16 = 16;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+// 4.1.4 Virtio Structure PCI Capabilities
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// 4.1.4.3 Common configuration structure layout
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+// 壊れたデバイスでリセットが終わらなくても、起動は止めない
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+// 4.1.2.1 modernなデバイスは0x1040 + デバイスタイプ
+// transitionalなデバイスはサブシステムIDがデバイスタイプになる
+pub fn virtio_device_type(pci: &PciDevice) -> Option<u16> {
+    if pci.vendor_id() != VIRTIO_PCI_VENDOR_ID {
+        return None;
+    }
+    match pci.device_id() {
+        0x1000..=0x103F => Some((pci.read_config(0x2C) >> 16) as u16),
+        id @ 0x1040..=0x107F => Some(id - 0x1040),
+        _ => None,
+    }
+}
+
+fn map_virtio_capability(cap: &Capability) -> Result<MmioRegion> {
+    let bar = (cap.read_u32(0x04) & 0xFF) as usize;
+    let offset = cap.read_u32(0x08) as usize;
+    let length = cap.read_u32(0x0C) as usize;
+    cap.device().bar(bar)?.map()?.subregion(offset, length)
+}
+
+pub struct VirtioPciDevice {
+    pci: PciDevice,
+    device_type: u16,
+    common: MmioRegion,
+    notify: MmioRegion,
+    notify_off_multiplier: u32,
+    isr: MmioRegion,
+    device_config: Option<MmioRegion>,
+    msix: Option<MsixTable>,
+}
+
+impl VirtioPciDevice {
+    pub fn new(pci: PciDevice) -> Result<Self> {
+        let device_type = virtio_device_type(&pci).ok_or("Not a virtio device")?;
+        pci.enable_memory_space();
+        pci.enable_bus_master();
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_config = None;
+        for cap in pci
+            .capabilities()
+            .filter(|c| c.id() == CAPABILITY_ID_VENDOR_SPECIFIC)
+        {
+            let cfg_type = (cap.read_u32(0) >> 24) as u8;
+            match cfg_type {
+                // 同じ種類のケーパビリティが複数ある場合は最初のものを使う
+                VIRTIO_PCI_CAP_COMMON_CFG if common.is_none() => {
+                    common = Some(map_virtio_capability(&cap)?)
+                }
+                VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some((map_virtio_capability(&cap)?, cap.read_u32(0x10)))
+                }
+                VIRTIO_PCI_CAP_ISR_CFG if isr.is_none() => isr = Some(map_virtio_capability(&cap)?),
+                VIRTIO_PCI_CAP_DEVICE_CFG if device_config.is_none() => {
+                    device_config = Some(map_virtio_capability(&cap)?)
+                }
+                _ => {}
+            }
+        }
+        let (notify, notify_off_multiplier) = notify.ok_or("virtio notify cfg not found")?;
+        Ok(Self {
+            pci,
+            device_type,
+            common: common.ok_or("virtio common cfg not found")?,
+            notify,
+            notify_off_multiplier,
+            isr: isr.ok_or("virtio isr cfg not found")?,
+            device_config,
+            msix: None,
+        })
+    }
+    pub fn pci(&self) -> &PciDevice {
+        &self.pci
+    }
+    pub fn device_type(&self) -> u16 {
+        self.device_type
+    }
+    pub fn device_config(&self) -> Result<&MmioRegion> {
+        self.device_config
+            .as_ref()
+            .ok_or("virtio device cfg not found")
+    }
+    fn status(&self) -> u8 {
+        self.common.read(COMMON_DEVICE_STATUS)
+    }
+    fn set_status(&self, status: u8) {
+        self.common.write(COMMON_DEVICE_STATUS, status)
+    }
+    fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status)
+    }
+    pub fn reset(&self) -> Result<()> {
+        self.set_status(0);
+        // リセットが完了すると0が読めるようになる
+        let deadline = global_timestamp() + RESET_TIMEOUT;
+        while self.status() != 0 {
+            if global_timestamp() > deadline {
+                return Err("virtio device reset timed out");
+            }
+            busy_loop_hint();
+        }
+        Ok(())
+    }
+    pub fn device_features(&self) -> u64 {
+        self.common.write_u32(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low = self.common.read_u32(COMMON_DEVICE_FEATURE);
+        self.common.write_u32(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high = self.common.read_u32(COMMON_DEVICE_FEATURE);
+        ((high as u64) << 32) | low as u64
+    }
+    fn set_driver_features(&self, features: u64) {
+        self.common.write_u32(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.common
+            .write_u32(COMMON_DRIVER_FEATURE, features as u32);
+        self.common.write_u32(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.common
+            .write_u32(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+    // 3.1.1 Driver Requirements: Device Initialization
+    // リセットから機能のネゴシエーションまでを行い、合意した機能を返す
+    pub fn negotiate_features(&self, driver_features: u64) -> Result<u64> {
+        self.reset()?;
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+        let features = self.device_features() & (driver_features | VIRTIO_F_VERSION_1);
+        if features & VIRTIO_F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("virtio device does not support VERSION_1");
+        }
+        self.set_driver_features(features);
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err("virtio device rejected the features");
+        }
+        Ok(features)
+    }
+    // virtqueueの設定が終わったら呼ぶ
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+        info!(
+            "virtio: {} type={} is ready",
+            self.pci.bdf(),
+            self.device_type
+        );
+    }
+    pub fn num_queues(&self) -> u16 {
+        self.common.read(COMMON_NUM_QUEUES)
+    }
+    // 割り込みの要因を読み出す (読むとクリアされる)
+    // MSI-Xを使っていない場合にINTxのハンドラから呼ぶ
+    pub fn read_isr(&self) -> u8 {
+        self.isr.read(0)
+    }
+    // 割り込みを使う場合はsetup_queueより前に呼ぶ
+    // テーブルの0番は設定変更の通知用、1番以降を各virtqueueに割り当てる
+    pub fn enable_msix(&mut self) -> Result<()> {
+        let table = self
+            .pci
+            .msix()
+            .ok_or("virtio device does not support MSI-X")?
+            .enable()?;
+        self.common
+            .write::<u16>(COMMON_MSIX_CONFIG, VIRTIO_MSI_NO_VECTOR);
+        self.msix = Some(table);
+        Ok(())
+    }
+    // handlerを指定しない場合はポーリングで使う
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        max_size: u16,
+        handler: Option<InterruptHandler>,
+    ) -> Result<Virtqueue> {
+        if index >= self.num_queues() {
+            return Err("virtqueue index out of range");
+        }
+        self.common.write::<u16>(COMMON_QUEUE_SELECT, index);
+        let device_max = self.common.read::<u16>(COMMON_QUEUE_SIZE);
+        if device_max == 0 {
+            return Err("virtqueue is not available");
+        }
+        // キューのサイズは2のべき乗
+        let mut size = device_max.min(max_size.max(1));
+        while !size.is_power_of_two() {
+            size &= size - 1;
+        }
+        self.common.write::<u16>(COMMON_QUEUE_SIZE, size);
+        let queue = Virtqueue::new(
+            index,
+            size,
+            self.notify_address(self.common.read(COMMON_QUEUE_NOTIFY_OFF))?,
+        )?;
+        self.common
+            .write_u64(COMMON_QUEUE_DESC, queue.desc.phys_addr());
+        self.common
+            .write_u64(COMMON_QUEUE_DRIVER, queue.avail.phys_addr());
+        self.common
+            .write_u64(COMMON_QUEUE_DEVICE, queue.used.phys_addr());
+        match (handler, &self.msix) {
+            (Some(handler), Some(table)) => {
+                let entry = index as usize + 1;
+                let vector = table.allocate(entry, handler)?;
+                self.common
+                    .write::<u16>(COMMON_QUEUE_MSIX_VECTOR, entry as u16);
+                if self.common.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) != entry as u16 {
+                    // キューは使われないので、確保したベクタも戻す
+                    let _ = table.free(entry, vector);
+                    return Err("virtio device rejected the MSI-X vector");
+                }
+            }
+            (Some(_), None) => return Err("MSI-X is not enabled"),
+            (None, _) => {
+                queue.disable_interrupts();
+                self.common
+                    .write::<u16>(COMMON_QUEUE_MSIX_VECTOR, VIRTIO_MSI_NO_VECTOR);
+            }
+        }
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(queue)
+    }
+    fn notify_address(&self, notify_off: u16) -> Result<MmioRegion> {
+        self.notify.subregion(
+            notify_off as usize * self.notify_off_multiplier as usize,
+            size_of::<u16>(),
+        )
+    }
+}
+
+// 2.7.5 The Virtqueue Descriptor Table
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+const _: () = assert!(size_of::<VirtqDesc>() == 16);
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+const _: () = assert!(size_of::<VirtqUsedElem>() == 8);
+
+// デバイスに渡すバッファ (物理アドレスと長さ)
+#[derive(Clone, Copy, Debug)]
+pub struct VirtqBuffer {
+    pub addr: u64,
+    pub len: u32,
+}
+
+impl VirtqBuffer {
+    pub fn from_dma(buf: &DmaBuffer) -> Self {
+        Self {
+            addr: buf.phys_addr(),
+            len: buf.len() as u32,
+        }
+    }
+}
+
+// 2.7 Split Virtqueues
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: DmaBuffer,
+    avail: DmaBuffer,
+    used: DmaBuffer,
+    notify: MmioRegion,
+    free_head: u16,
+    num_free: u16,
+    last_used_idx: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify: MmioRegion) -> Result<Self> {
+        let n = size as usize;
+        let desc = DmaBuffer::new(size_of::<VirtqDesc>() * n, 16)?;
+        // flags, idx, ring[n], used_event
+        let avail = DmaBuffer::new(2 + 2 + 2 * n + 2, 2)?;
+        // flags, idx, ring[n], avail_event
+        let used = DmaBuffer::new(2 + 2 + size_of::<VirtqUsedElem>() * n + 2, 4)?;
+        let mut queue = Self {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            notify,
+            free_head: 0,
+            num_free: size,
+            last_used_idx: 0,
+        };
+        // 空きディスクリプタを全てつなげておく
+        for i in 0..size {
+            let d = queue.desc_mut(i);
+            d.next = (i + 1) % size;
+        }
+        Ok(queue)
+    }
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+    fn desc_mut(&mut self, i: u16) -> &mut VirtqDesc {
+        assert!(i < self.size);
+        unsafe { &mut *(self.desc.as_ptr() as *mut VirtqDesc).add(i as usize) }
+    }
+    fn avail_field(&self, i: usize) -> *mut u16 {
+        unsafe { (self.avail.as_ptr() as *mut u16).add(i) }
+    }
+    fn used_idx(&self) -> u16 {
+        unsafe { read_volatile((self.used.as_ptr() as *const u16).add(1)) }
+    }
+    fn used_elem(&self, i: u16) -> VirtqUsedElem {
+        unsafe {
+            read_volatile(
+                (self.used.as_ptr().add(4) as *const VirtqUsedElem).add((i % self.size) as usize),
+            )
+        }
+    }
+    fn disable_interrupts(&self) {
+        unsafe { write_volatile(self.avail_field(0), VIRTQ_AVAIL_F_NO_INTERRUPT) }
+    }
+    // readableはデバイスが読むバッファ、writableはデバイスが書き込むバッファ
+    // 先頭のディスクリプタ番号を返すので、pop_usedで返ってきたものと突き合わせる
+    pub fn add_buffers(
+        &mut self,
+        readable: &[VirtqBuffer],
+        writable: &[VirtqBuffer],
+    ) -> Result<u16> {
+        let count = readable.len() + writable.len();
+        if count == 0 {
+            return Err("No buffers to add");
+        }
+        if count > self.num_free as usize {
+            return Err("virtqueue is full");
+        }
+        let head = self.free_head;
+        let mut last = head;
+        let mut next = head;
+        for (i, (buf, flags)) in readable
+            .iter()
+            .map(|b| (b, 0))
+            .chain(writable.iter().map(|b| (b, VIRTQ_DESC_F_WRITE)))
+            .enumerate()
+        {
+            last = next;
+            let d = self.desc_mut(last);
+            d.addr = buf.addr;
+            d.len = buf.len;
+            d.flags = flags;
+            if i + 1 < count {
+                d.flags |= VIRTQ_DESC_F_NEXT;
+            }
+            next = d.next;
+        }
+        self.free_head = self.desc_mut(last).next;
+        self.num_free -= count as u16;
+        unsafe {
+            let idx = read_volatile(self.avail_field(1));
+            write_volatile(self.avail_field(2 + (idx % self.size) as usize), head);
+            // ディスクリプタの内容がidxの更新より先に見えるようにする
+            fence(Ordering::SeqCst);
+            write_volatile(self.avail_field(1), idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+    pub fn notify(&self) {
+        self.notify.write::<u16>(0, self.index)
+    }
+    pub fn has_used(&self) -> bool {
+        self.used_idx() != self.last_used_idx
+    }
+    // デバイスが処理し終えたバッファを (先頭のディスクリプタ番号, 書き込まれた長さ) として取り出す
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = self.used_elem(self.last_used_idx);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        let head = elem.id as u16;
+        // 使い終わったディスクリプタを空きリストに戻す
+        let mut last = head;
+        let mut count = 1;
+        while self.desc_mut(last).flags & VIRTQ_DESC_F_NEXT != 0 {
+            last = self.desc_mut(last).next;
+            count += 1;
+        }
+        let free_head = self.free_head;
+        self.desc_mut(last).next = free_head;
+        self.free_head = head;
+        self.num_free += count;
+        Some((head, elem.len))
+    }
+    // ポーリングで完了を待つ
+    pub fn wait_used(&mut self) -> (u16, u32) {
+        loop {
+            if let Some(used) = self.pop_used() {
+                return used;
+            }
+            busy_loop_hint();
+        }
+    }
+}
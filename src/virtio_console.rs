@@ -0,0 +1,190 @@
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::dma::DmaBuffer;
+use crate::hpet::global_timestamp;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::result::Result;
+use crate::virtio::VirtioPciDevice;
+use crate::virtio::VirtqBuffer;
+use crate::virtio::Virtqueue;
+use crate::virtio::VIRTIO_DEVICE_TYPE_CONSOLE;
+use crate::virtio::VIRTIO_PCI_VENDOR_ID;
+use crate::x86::busy_loop_hint;
+
+// 5.3 Console Device (MULTIPORTは使わず、port 0のみ)
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+const BUFFER_SIZE: usize = 256;
+// ホスト側が読まなくなっても、ログを出すたびにずっと止まらないようにする
+const TX_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct VirtioConsole {
+    #[allow(unused)]
+    device: VirtioPciDevice,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_buffers: Vec<Option<DmaBuffer>>,
+    tx_buffer: DmaBuffer,
+    // 送ったバッファがまだ返ってきていない。返ってくるまでtx_bufferは書き換えられない
+    tx_stalled: bool,
+    received: VecDeque<u8>,
+}
+
+static VIRTIO_CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+static LOG_OUTPUT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+impl VirtioConsole {
+    fn new(pci: PciDevice) -> Result<Self> {
+        let device = VirtioPciDevice::new(pci)?;
+        device.negotiate_features(0)?;
+        let rx = device.setup_queue(RECEIVE_QUEUE, QUEUE_SIZE, None)?;
+        let tx = device.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE, None)?;
+        let mut rx_buffers = Vec::new();
+        rx_buffers.resize_with(rx.size() as usize, || None);
+        let mut console = Self {
+            device,
+            rx,
+            tx,
+            rx_buffers,
+            tx_buffer: DmaBuffer::new(BUFFER_SIZE, 16)?,
+            tx_stalled: false,
+            received: VecDeque::new(),
+        };
+        while console.rx.num_free() > 0 {
+            console.post_rx_buffer(DmaBuffer::new(BUFFER_SIZE, 16)?)?;
+        }
+        console.device.driver_ok();
+        console.rx.notify();
+        Ok(console)
+    }
+    fn post_rx_buffer(&mut self, buf: DmaBuffer) -> Result<()> {
+        let head = self.rx.add_buffers(&[], &[VirtqBuffer::from_dma(&buf)])?;
+        self.rx_buffers[head as usize] = Some(buf);
+        Ok(())
+    }
+    // ホスト側が読むまで待つので、ログ出力に使っても順序が入れ替わらない
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.tx_stalled {
+            if self.tx.pop_used().is_none() {
+                return Err("virtio-console: transmit is stalled");
+            }
+            self.tx_stalled = false;
+        }
+        for chunk in data.chunks(BUFFER_SIZE) {
+            self.tx_buffer.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.tx.add_buffers(
+                &[VirtqBuffer {
+                    addr: self.tx_buffer.phys_addr(),
+                    len: chunk.len() as u32,
+                }],
+                &[],
+            )?;
+            self.tx.notify();
+            self.wait_tx()?;
+        }
+        Ok(())
+    }
+    fn wait_tx(&mut self) -> Result<()> {
+        let deadline = global_timestamp() + TX_TIMEOUT;
+        while self.tx.pop_used().is_none() {
+            if global_timestamp() > deadline {
+                self.tx_stalled = true;
+                return Err("virtio-console: transmit timed out");
+            }
+            busy_loop_hint();
+        }
+        Ok(())
+    }
+    fn poll(&mut self) {
+        let mut reposted = false;
+        while let Some((head, len)) = self.rx.pop_used() {
+            let Some(buf) = self.rx_buffers[head as usize].take() else {
+                continue;
+            };
+            let len = (len as usize).min(buf.len());
+            self.received.extend(&buf.as_slice()[..len]);
+            // 失敗してもバッファが1つ減るだけなので無視する
+            let _ = self.post_rx_buffer(buf);
+            reposted = true;
+        }
+        if reposted {
+            self.rx.notify();
+        }
+    }
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.received.is_empty() {
+            self.poll();
+        }
+        self.received.pop_front()
+    }
+}
+
+impl fmt::Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes()).or(Err(fmt::Error))
+    }
+}
+
+pub fn with_virtio_console<R>(f: impl FnOnce(&mut VirtioConsole) -> R) -> Option<R> {
+    VIRTIO_CONSOLE.lock().as_mut().map(f)
+}
+
+// シェルなどの入力に使う。デバイスがなければNone
+pub fn virtio_console_read_byte() -> Option<u8> {
+    with_virtio_console(|console| console.try_read_byte()).flatten()
+}
+
+pub fn set_virtio_console_log_output(enabled: bool) {
+    LOG_OUTPUT_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+// ドライバ内でログを出すと再帰してしまうので、VirtioConsoleのメソッドはログを出さないこと
+pub fn virtio_console_print(args: fmt::Arguments) {
+    if !LOG_OUTPUT_ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    // 割り込みハンドラのログが、書いている途中に割り込んで来ることがある。その分は捨てる
+    if let Some(Some(console)) = VIRTIO_CONSOLE.try_lock().as_deref_mut() {
+        let _ = fmt::write(console, args);
+    }
+}
+
+pub struct VirtioConsoleDriver;
+
+impl PciDriver for VirtioConsoleDriver {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        const MATCHES: [PciDeviceMatch; 2] = [
+            PciDeviceMatch::id(VIRTIO_PCI_VENDOR_ID, 0x1003),
+            PciDeviceMatch::id(VIRTIO_PCI_VENDOR_ID, 0x1040 + VIRTIO_DEVICE_TYPE_CONSOLE),
+        ];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        let console = VirtioConsole::new(*device)?;
+        let mut global = VIRTIO_CONSOLE.lock();
+        if global.is_some() {
+            return Err("Only one virtio-console device is supported");
+        }
+        *global = Some(console);
+        drop(global);
+        info!("virtio-console: ready");
+        Ok(())
+    }
+}
+
+pub static VIRTIO_CONSOLE_DRIVER: VirtioConsoleDriver = VirtioConsoleDriver;
@@ -0,0 +1,431 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+use crate::dma::DmaBuffer;
+use crate::graphics::Bitmap;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::result::Result;
+use crate::virtio::VirtioPciDevice;
+use crate::virtio::VirtqBuffer;
+use crate::virtio::Virtqueue;
+use crate::virtio::VIRTIO_DEVICE_TYPE_GPU;
+use crate::virtio::VIRTIO_PCI_VENDOR_ID;
+use crate::x86::PAGE_SIZE;
+
+// 5.7 GPU Device
+const CONTROL_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 16;
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1;
+
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+// UEFI GOPのPixelBlueGreenRedReserved8BitPerColorと同じ並び
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const BYTES_PER_PIXEL: usize = 4;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct CtrlHeader {
+    cmd_type: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    _padding: [u8; 3],
+}
+const _: () = assert!(size_of::<CtrlHeader>() == 24);
+
+impl CtrlHeader {
+    fn new(cmd_type: u32) -> Self {
+        Self {
+            cmd_type,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DisplayOne {
+    r: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct RespDisplayInfo {
+    hdr: CtrlHeader,
+    pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ResourceCreate2d {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ResourceUnref {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct SetScanout {
+    hdr: CtrlHeader,
+    r: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ResourceFlush {
+    hdr: CtrlHeader,
+    r: Rect,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct TransferToHost2d {
+    hdr: CtrlHeader,
+    r: Rect,
+    offset: u64,
+    resource_id: u32,
+    _padding: u32,
+}
+
+// バッキングは1つの連続したDmaBufferなのでエントリは1つだけ
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ResourceAttachBacking {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    _padding: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayInfo {
+    pub scanout_id: u32,
+    pub rect: Rect,
+}
+
+struct Framebuffer {
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    buf: DmaBuffer,
+}
+
+pub struct VirtioGpu {
+    device: VirtioPciDevice,
+    control: Virtqueue,
+    request: DmaBuffer,
+    response: DmaBuffer,
+    next_resource_id: u32,
+    framebuffer: Option<Framebuffer>,
+}
+
+static VIRTIO_GPU: Mutex<Option<VirtioGpu>> = Mutex::new(None);
+
+impl VirtioGpu {
+    fn new(pci: PciDevice) -> Result<Self> {
+        let device = VirtioPciDevice::new(pci)?;
+        device.negotiate_features(0)?;
+        let control = device.setup_queue(CONTROL_QUEUE, QUEUE_SIZE, None)?;
+        device.driver_ok();
+        Ok(Self {
+            device,
+            control,
+            request: DmaBuffer::new(PAGE_SIZE, 16)?,
+            response: DmaBuffer::new(PAGE_SIZE, 16)?,
+            next_resource_id: 1,
+            framebuffer: None,
+        })
+    }
+    // コマンドを1つ送って完了をポーリングで待つ
+    fn command<Req: Copy, Resp: Copy + Default>(
+        &mut self,
+        req: &Req,
+        expected: u32,
+    ) -> Result<Resp> {
+        const _: () = assert!(size_of::<RespDisplayInfo>() <= PAGE_SIZE);
+        unsafe {
+            write_volatile(self.request.as_ptr() as *mut Req, *req);
+            write_volatile(self.response.as_ptr() as *mut Resp, Resp::default());
+        }
+        self.control.add_buffers(
+            &[VirtqBuffer {
+                addr: self.request.phys_addr(),
+                len: size_of::<Req>() as u32,
+            }],
+            &[VirtqBuffer {
+                addr: self.response.phys_addr(),
+                len: size_of::<Resp>() as u32,
+            }],
+        )?;
+        self.control.notify();
+        self.control.wait_used();
+        let hdr = unsafe { read_volatile(self.response.as_ptr() as *const CtrlHeader) };
+        if hdr.cmd_type != expected {
+            return Err("virtio-gpu: command failed");
+        }
+        Ok(unsafe { read_volatile(self.response.as_ptr() as *const Resp) })
+    }
+    fn command_nodata<Req: Copy>(&mut self, req: &Req) -> Result<()> {
+        self.command::<Req, CtrlHeader>(req, VIRTIO_GPU_RESP_OK_NODATA)
+            .map(|_| ())
+    }
+    pub fn display_info(&mut self) -> Result<Vec<DisplayInfo>> {
+        let resp: RespDisplayInfo = self.command(
+            &CtrlHeader::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO),
+            VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+        )?;
+        Ok(resp
+            .pmodes
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.enabled != 0)
+            .map(|(i, m)| DisplayInfo {
+                scanout_id: i as u32,
+                rect: m.r,
+            })
+            .collect())
+    }
+    // 2Dリソースを作ってバッキングを付け、スキャンアウトに表示する
+    pub fn set_mode(&mut self, scanout_id: u32, width: u32, height: u32) -> Result<()> {
+        if width == 0 || height == 0 {
+            return Err("virtio-gpu: invalid resolution");
+        }
+        let buf = DmaBuffer::new(
+            width as usize * height as usize * BYTES_PER_PIXEL,
+            PAGE_SIZE,
+        )?;
+        let resource_id = self.next_resource_id;
+        self.next_resource_id += 1;
+        self.command_nodata(&ResourceCreate2d {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+            resource_id,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        })?;
+        let attached = self
+            .command_nodata(&ResourceAttachBacking {
+                hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+                resource_id,
+                nr_entries: 1,
+                addr: buf.phys_addr(),
+                length: buf.len() as u32,
+                _padding: 0,
+            })
+            .and_then(|()| {
+                self.command_nodata(&SetScanout {
+                    hdr: CtrlHeader::new(VIRTIO_GPU_CMD_SET_SCANOUT),
+                    r: Rect {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    },
+                    scanout_id,
+                    resource_id,
+                })
+            });
+        if let Err(e) = attached {
+            // 作りかけのリソースをホスト側に残さない
+            // 消せなかった時はデバイスがまだバッファを読むかもしれないので、手放さずにおく
+            if self.unref_resource(resource_id).is_err() {
+                core::mem::forget(buf);
+            }
+            return Err(e);
+        }
+        // 古いリソースはスキャンアウトから外れたので解放する
+        if let Some(old) = self.framebuffer.take() {
+            self.unref_resource(old.resource_id)?;
+        }
+        self.framebuffer = Some(Framebuffer {
+            scanout_id,
+            resource_id,
+            width,
+            height,
+            buf,
+        });
+        Ok(())
+    }
+    fn unref_resource(&mut self, resource_id: u32) -> Result<()> {
+        self.command_nodata(&ResourceUnref {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_UNREF),
+            resource_id,
+            _padding: 0,
+        })
+    }
+    // ホスト側でウィンドウサイズなどが変わったらdisplay_infoを取り直す
+    pub fn take_display_change(&self) -> bool {
+        let Ok(config) = self.device.device_config() else {
+            return false;
+        };
+        let events = config.read_u32(0);
+        if events & VIRTIO_GPU_EVENT_DISPLAY == 0 {
+            return false;
+        }
+        config.write_u32(4, VIRTIO_GPU_EVENT_DISPLAY);
+        true
+    }
+    pub fn scanout_id(&self) -> Option<u32> {
+        self.framebuffer.as_ref().map(|fb| fb.scanout_id)
+    }
+    // 描画した内容をホスト側に転送して表示を更新する
+    pub fn flush(&mut self, rect: Rect) -> Result<()> {
+        let fb = self
+            .framebuffer
+            .as_ref()
+            .ok_or("virtio-gpu: no framebuffer")?;
+        let resource_id = fb.resource_id;
+        let in_range = |start: u32, len: u32, limit: u32| {
+            start.checked_add(len).is_some_and(|end| end <= limit)
+        };
+        if !in_range(rect.x, rect.width, fb.width) || !in_range(rect.y, rect.height, fb.height) {
+            return Err("virtio-gpu: flush rect out of range");
+        }
+        let offset = (rect.y as u64 * fb.width as u64 + rect.x as u64) * BYTES_PER_PIXEL as u64;
+        self.command_nodata(&TransferToHost2d {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+            r: rect,
+            offset,
+            resource_id,
+            _padding: 0,
+        })?;
+        self.command_nodata(&ResourceFlush {
+            hdr: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+            r: rect,
+            resource_id,
+            _padding: 0,
+        })
+    }
+    pub fn flush_all(&mut self) -> Result<()> {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: self.width() as u32,
+            height: self.height() as u32,
+        };
+        self.flush(rect)
+    }
+}
+
+// フレームバッファが無いときは幅0の画面として扱う
+impl Bitmap for VirtioGpu {
+    fn bytes_per_pixel(&self) -> i64 {
+        BYTES_PER_PIXEL as i64
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.width()
+    }
+    fn width(&self) -> i64 {
+        self.framebuffer
+            .as_ref()
+            .map(|fb| fb.width as i64)
+            .unwrap_or(0)
+    }
+    fn height(&self) -> i64 {
+        self.framebuffer
+            .as_ref()
+            .map(|fb| fb.height as i64)
+            .unwrap_or(0)
+    }
+    fn buf(&self) -> *const u8 {
+        self.framebuffer
+            .as_ref()
+            .map(|fb| fb.buf.as_ptr() as *const u8)
+            .unwrap_or(core::ptr::null())
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.framebuffer
+            .as_ref()
+            .map(|fb| fb.buf.as_ptr())
+            .unwrap_or(core::ptr::null_mut())
+    }
+}
+
+pub fn with_virtio_gpu<R>(f: impl FnOnce(&mut VirtioGpu) -> R) -> Option<R> {
+    VIRTIO_GPU.lock().as_mut().map(f)
+}
+
+pub struct VirtioGpuDriver;
+
+impl PciDriver for VirtioGpuDriver {
+    fn name(&self) -> &'static str {
+        "virtio-gpu"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        // virtio-gpuにはtransitionalなデバイスIDが無い
+        const MATCHES: [PciDeviceMatch; 1] = [PciDeviceMatch::id(
+            VIRTIO_PCI_VENDOR_ID,
+            0x1040 + VIRTIO_DEVICE_TYPE_GPU,
+        )];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        let mut global = VIRTIO_GPU.lock();
+        if global.is_some() {
+            return Err("Only one virtio-gpu device is supported");
+        }
+        let mut gpu = VirtioGpu::new(*device)?;
+        let displays = gpu.display_info()?;
+        for d in displays.iter() {
+            info!(
+                "virtio-gpu: scanout {}: {}x{} at ({}, {})",
+                d.scanout_id, d.rect.width, d.rect.height, d.rect.x, d.rect.y
+            );
+        }
+        if let Some(d) = displays.first() {
+            gpu.set_mode(d.scanout_id, d.rect.width, d.rect.height)?;
+            gpu.flush_all()?;
+        }
+        *global = Some(gpu);
+        Ok(())
+    }
+}
+
+pub static VIRTIO_GPU_DRIVER: VirtioGpuDriver = VirtioGpuDriver;
@@ -0,0 +1,239 @@
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::task::Poll;
+
+use crate::dma::DmaBuffer;
+use crate::executor::AtomicWaker;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::net::register_nic;
+use crate::net::MacAddress;
+use crate::net::NicDriver;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::result::Result;
+use crate::virtio::VirtioPciDevice;
+use crate::virtio::VirtqBuffer;
+use crate::virtio::Virtqueue;
+use crate::virtio::VIRTIO_DEVICE_TYPE_NET;
+use crate::virtio::VIRTIO_PCI_VENDOR_ID;
+use crate::warn;
+
+// 5.1 Network Device
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 64;
+
+// 5.1.6 Device Operation: struct virtio_net_hdr (VERSION_1ではnum_buffersを含めて12バイト)
+const NET_HEADER_SIZE: usize = 12;
+const MAX_FRAME_SIZE: usize = 1514;
+const BUFFER_SIZE: usize = 2048;
+
+pub struct VirtioNet {
+    device: VirtioPciDevice,
+    mac: MacAddress,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    // ディスクリプタ番号ごとにデバイスへ渡しているバッファ
+    rx_buffers: Vec<Option<DmaBuffer>>,
+    tx_buffers: Vec<Option<DmaBuffer>>,
+}
+
+static VIRTIO_NET: Mutex<Option<VirtioNet>> = Mutex::new(None);
+static RX_PENDING: AtomicBool = AtomicBool::new(false);
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn rx_interrupt_handler(_vector: u8) {
+    RX_PENDING.store(true, Ordering::SeqCst);
+    RX_WAKER.wake();
+}
+
+impl VirtioNet {
+    fn new(pci: PciDevice) -> Result<Self> {
+        let mut device = VirtioPciDevice::new(pci)?;
+        let features = device.negotiate_features(VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS)?;
+        if features & VIRTIO_NET_F_MAC == 0 {
+            return Err("virtio-net device has no MAC address");
+        }
+        let use_interrupt = device.enable_msix().is_ok();
+        let rx = device.setup_queue(
+            RECEIVE_QUEUE,
+            QUEUE_SIZE,
+            if use_interrupt {
+                Some(rx_interrupt_handler)
+            } else {
+                None
+            },
+        )?;
+        let tx = device.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE, None)?;
+        let config = device.device_config()?;
+        let mut mac = [0u8; 6];
+        for (i, e) in mac.iter_mut().enumerate() {
+            *e = config.read(i);
+        }
+        let mut rx_buffers = Vec::new();
+        rx_buffers.resize_with(rx.size() as usize, || None);
+        let mut tx_buffers = Vec::new();
+        tx_buffers.resize_with(tx.size() as usize, || None);
+        let mut net = Self {
+            device,
+            mac,
+            rx,
+            tx,
+            rx_buffers,
+            tx_buffers,
+        };
+        while net.rx.num_free() > 0 {
+            net.post_rx_buffer(DmaBuffer::new(BUFFER_SIZE, 16)?)?;
+        }
+        net.device.driver_ok();
+        net.rx.notify();
+        Ok(net)
+    }
+    fn post_rx_buffer(&mut self, buf: DmaBuffer) -> Result<()> {
+        let head = self.rx.add_buffers(&[], &[VirtqBuffer::from_dma(&buf)])?;
+        self.rx_buffers[head as usize] = Some(buf);
+        Ok(())
+    }
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+    pub fn is_link_up(&self) -> bool {
+        // featuresでSTATUSが無い場合は常にリンクアップとみなす
+        self.device
+            .device_config()
+            .map(|c| c.size() < 8 || c.read::<u16>(6) & 1 != 0)
+            .unwrap_or(true)
+    }
+    fn reclaim_tx(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            self.tx_buffers[head as usize] = None;
+        }
+    }
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err("Frame too large");
+        }
+        self.reclaim_tx();
+        let mut buf = DmaBuffer::new(NET_HEADER_SIZE + frame.len(), 16)?;
+        // ヘッダはゼロのまま (チェックサムオフロードやGSOは使わない)
+        buf.as_mut_slice()[NET_HEADER_SIZE..].copy_from_slice(frame);
+        let head = self.tx.add_buffers(&[VirtqBuffer::from_dma(&buf)], &[])?;
+        self.tx_buffers[head as usize] = Some(buf);
+        self.tx.notify();
+        Ok(())
+    }
+    // 受信済みのフレームをrxに渡し、バッファをデバイスに戻す
+    pub fn poll(&mut self, rx: &mut dyn FnMut(&[u8])) -> usize {
+        RX_PENDING.store(false, Ordering::SeqCst);
+        let mut count = 0;
+        while let Some((head, len)) = self.rx.pop_used() {
+            let Some(buf) = self.rx_buffers[head as usize].take() else {
+                warn!("virtio-net: unknown rx descriptor {head}");
+                continue;
+            };
+            let len = (len as usize).min(buf.len());
+            if len > NET_HEADER_SIZE {
+                rx(&buf.as_slice()[NET_HEADER_SIZE..len]);
+                count += 1;
+            }
+            if let Err(e) = self.post_rx_buffer(buf) {
+                warn!("virtio-net: failed to repost rx buffer: {e}");
+            }
+        }
+        if count > 0 {
+            self.rx.notify();
+        }
+        self.reclaim_tx();
+        count
+    }
+}
+
+pub fn with_virtio_net<R>(f: impl FnOnce(&mut VirtioNet) -> R) -> Option<R> {
+    VIRTIO_NET.lock().as_mut().map(f)
+}
+
+pub fn has_pending_rx() -> bool {
+    RX_PENDING.load(Ordering::SeqCst)
+}
+
+// 受信割り込みが来るまで待つ
+pub async fn wait_for_rx() {
+    poll_fn(|cx| {
+        if has_pending_rx() {
+            return Poll::Ready(());
+        }
+        RX_WAKER.register(cx.waker());
+        if has_pending_rx() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// ネットワークスタックから使うためのもの。デバイスはVIRTIO_NETに入っているものを使う
+pub struct VirtioNetNic;
+
+impl NicDriver for VirtioNetNic {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+    fn mac_address(&self) -> MacAddress {
+        with_virtio_net(|n| n.mac_address()).unwrap_or_default()
+    }
+    fn is_link_up(&self) -> bool {
+        with_virtio_net(|n| n.is_link_up()).unwrap_or(false)
+    }
+    fn transmit(&self, frame: &[u8]) -> Result<()> {
+        with_virtio_net(|n| n.transmit(frame)).ok_or("virtio-net is not initialized")?
+    }
+    fn receive(&self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        with_virtio_net(|n| n.poll(&mut |frame| frames.push(frame.to_vec())));
+        frames
+    }
+}
+
+pub struct VirtioNetDriver;
+
+impl PciDriver for VirtioNetDriver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        const MATCHES: [PciDeviceMatch; 2] = [
+            PciDeviceMatch::id(VIRTIO_PCI_VENDOR_ID, 0x1000),
+            PciDeviceMatch::id(VIRTIO_PCI_VENDOR_ID, 0x1040 + VIRTIO_DEVICE_TYPE_NET),
+        ];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        let mut global = VIRTIO_NET.lock();
+        if global.is_some() {
+            return Err("Only one virtio-net device is supported");
+        }
+        let net = VirtioNet::new(*device)?;
+        info!(
+            "virtio-net: MAC address {:02X?}, link {}",
+            net.mac_address(),
+            if net.is_link_up() { "up" } else { "down" }
+        );
+        *global = Some(net);
+        drop(global);
+        register_nic(Arc::new(VirtioNetNic));
+        Ok(())
+    }
+}
+
+pub static VIRTIO_NET_DRIVER: VirtioNetDriver = VirtioNetDriver;
@@ -0,0 +1,96 @@
+use crate::dma::DmaBuffer;
+use crate::info;
+use crate::mutex::Mutex;
+use crate::pci::PciDevice;
+use crate::pci::PciDeviceMatch;
+use crate::pci::PciDriver;
+use crate::rand::add_entropy;
+use crate::result::Result;
+use crate::virtio::VirtioPciDevice;
+use crate::virtio::VirtqBuffer;
+use crate::virtio::Virtqueue;
+use crate::virtio::VIRTIO_DEVICE_TYPE_RNG;
+use crate::virtio::VIRTIO_PCI_VENDOR_ID;
+
+// 5.4 Entropy Device
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 4;
+const REQUEST_SIZE: usize = 64;
+
+pub struct VirtioRng {
+    #[allow(unused)]
+    device: VirtioPciDevice,
+    queue: Virtqueue,
+    buf: DmaBuffer,
+}
+
+static VIRTIO_RNG: Mutex<Option<VirtioRng>> = Mutex::new(None);
+
+impl VirtioRng {
+    fn new(pci: PciDevice) -> Result<Self> {
+        let device = VirtioPciDevice::new(pci)?;
+        device.negotiate_features(0)?;
+        let queue = device.setup_queue(REQUEST_QUEUE, QUEUE_SIZE, None)?;
+        device.driver_ok();
+        Ok(Self {
+            device,
+            queue,
+            buf: DmaBuffer::new(REQUEST_SIZE, 16)?,
+        })
+    }
+    // デバイスが書き込んだ分だけを返す
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        let len = out.len().min(self.buf.len());
+        self.queue.add_buffers(
+            &[],
+            &[VirtqBuffer {
+                addr: self.buf.phys_addr(),
+                len: len as u32,
+            }],
+        )?;
+        self.queue.notify();
+        let (_, written) = self.queue.wait_used();
+        let written = (written as usize).min(len);
+        out[..written].copy_from_slice(&self.buf.as_slice()[..written]);
+        Ok(written)
+    }
+}
+
+// デバイスから読み出してエントロピープールに混ぜ込む
+pub fn reseed_from_virtio_rng() -> Result<usize> {
+    let mut rng = VIRTIO_RNG.lock();
+    let rng = rng.as_mut().ok_or("virtio-rng is not available")?;
+    let mut buf = [0u8; REQUEST_SIZE];
+    let len = rng.read(&mut buf)?;
+    add_entropy(&buf[..len]);
+    Ok(len)
+}
+
+pub struct VirtioRngDriver;
+
+impl PciDriver for VirtioRngDriver {
+    fn name(&self) -> &'static str {
+        "virtio-rng"
+    }
+    fn matches(&self) -> &'static [PciDeviceMatch] {
+        const MATCHES: [PciDeviceMatch; 2] = [
+            PciDeviceMatch::id(VIRTIO_PCI_VENDOR_ID, 0x1005),
+            PciDeviceMatch::id(VIRTIO_PCI_VENDOR_ID, 0x1040 + VIRTIO_DEVICE_TYPE_RNG),
+        ];
+        &MATCHES
+    }
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        {
+            let mut global = VIRTIO_RNG.lock();
+            if global.is_some() {
+                return Err("Only one virtio-rng device is supported");
+            }
+            *global = Some(VirtioRng::new(*device)?);
+        }
+        let len = reseed_from_virtio_rng()?;
+        info!("virtio-rng: seeded entropy pool with {len} bytes");
+        Ok(())
+    }
+}
+
+pub static VIRTIO_RNG_DRIVER: VirtioRngDriver = VirtioRngDriver;
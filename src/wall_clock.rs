@@ -0,0 +1,119 @@
+use core::fmt;
+use core::time::Duration;
+
+use crate::hpet::global_timestamp;
+use crate::mutex::SpinLockIrqSave;
+
+// UTCの日時
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+// 1970-01-01からの日数
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+impl DateTime {
+    pub fn from_unix_time(t: Duration) -> Self {
+        let secs = t.as_secs() as i64;
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        let sod = secs.rem_euclid(86400);
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (sod / 3600) as u8,
+            minute: (sod / 60 % 60) as u8,
+            second: (sod % 60) as u8,
+            nanosecond: t.subsec_nanos(),
+        }
+    }
+    // 1970年より前の日時は扱わない
+    pub fn to_unix_time(&self) -> Option<Duration> {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        let secs = u64::try_from(secs).ok()?;
+        Some(Duration::new(secs, self.nanosecond))
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// (シード時のUNIX時刻, シード時のglobal_timestamp)
+// UEFIの段階ではHPETがまだ動いていないので、後者はZEROになる。
+// その場合はHPETの初期化までの時間だけずれるが、ブート中のずれなので気にしない
+// 割り込みハンドラの中のログからも読まれる
+static WALL_CLOCK_BASE: SpinLockIrqSave<Option<(Duration, Duration)>> = SpinLockIrqSave::new(None);
+
+pub fn seed_wall_clock(unix_time: Duration) {
+    *WALL_CLOCK_BASE.lock() = Some((unix_time, global_timestamp()));
+}
+
+// 1970-01-01 00:00:00 UTCからの経過時間
+pub fn wall_clock_now() -> Option<Duration> {
+    let (base, ts) = (*WALL_CLOCK_BASE.lock())?;
+    Some(base + global_timestamp().saturating_sub(ts))
+}
+
+pub fn now() -> Option<DateTime> {
+    wall_clock_now().map(DateTime::from_unix_time)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn unix_time_round_trip() {
+        let t = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 34,
+            second: 56,
+            nanosecond: 0,
+        };
+        let unix = t.to_unix_time().unwrap();
+        assert_eq!(unix.as_secs(), 1709210096);
+        assert_eq!(DateTime::from_unix_time(unix), t);
+        assert_eq!(DateTime::from_unix_time(Duration::ZERO).year, 1970);
+    }
+}
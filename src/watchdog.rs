@@ -0,0 +1,57 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::error;
+use crate::mutex::num_of_held_locks;
+use crate::qemu::set_panic_exit_code;
+use crate::qemu::QemuExitCode;
+use crate::task::current_task_id;
+use crate::task::dump_tasks;
+use crate::task::ticks;
+use crate::task::TIMER_HZ;
+
+// スケジューラとアイドルループが定期的にpetする。タイマ割り込みで見て、
+// 長い間petされていなければ止まっているとみなして、状態を出してからQEMUを終了する
+// ロックを持ったまま回り続けるなどで、タスクが切り替わらなくなった時に気づける
+
+// 0なら無効
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_PET_TICK: AtomicU64 = AtomicU64::new(0);
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+// タイマ割り込み (start_preemption) が動いていないと何もしない
+pub fn enable_watchdog(timeout: Duration) {
+    let timeout_ticks = timeout.as_millis() as u64 * TIMER_HZ as u64 / 1000;
+    LAST_PET_TICK.store(ticks(), Ordering::SeqCst);
+    TIMEOUT_TICKS.store(timeout_ticks.max(1), Ordering::SeqCst);
+}
+
+pub fn disable_watchdog() {
+    TIMEOUT_TICKS.store(0, Ordering::SeqCst);
+}
+
+pub fn pet() {
+    LAST_PET_TICK.store(ticks(), Ordering::SeqCst);
+}
+
+// タイマ割り込みから呼ばれるので、ロックは取らない
+pub fn check(now: u64) {
+    let timeout = TIMEOUT_TICKS.load(Ordering::SeqCst);
+    if timeout == 0 {
+        return;
+    }
+    let starved = now.saturating_sub(LAST_PET_TICK.load(Ordering::SeqCst));
+    if starved <= timeout || FIRED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    error!(
+        "Watchdog: no progress for {starved} ticks (task {}, {} locks held)",
+        current_task_id(),
+        num_of_held_locks()
+    );
+    dump_tasks();
+    set_panic_exit_code(QemuExitCode::WatchdogTimeout);
+    panic!("Watchdog timeout")
+}
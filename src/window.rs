@@ -0,0 +1,496 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use crate::error;
+use crate::graphics::blit;
+use crate::graphics::draw_str_fg;
+use crate::graphics::fill_rect;
+use crate::graphics::Bitmap;
+use crate::graphics::BitmapBuffer;
+use crate::graphics::BitmapTextWriter;
+use crate::graphics::Rect;
+use crate::kthread;
+use crate::mutex::SpinLockIrqSave;
+use crate::print::register_log_sink;
+use crate::print::unregister_log_sink;
+use crate::print::with_global_vram;
+use crate::print::with_global_vram_mut;
+use crate::print::LogRecord;
+use crate::print::LogSink;
+use crate::ps2_mouse::init_ps2_mouse;
+use crate::ps2_mouse::pop_mouse_motion;
+use crate::ps2_mouse::MouseMotion;
+use crate::result::Result;
+use crate::task::sleep;
+
+const TITLE_BAR_HEIGHT: i64 = 20;
+const BORDER_WIDTH: i64 = 1;
+const BACKGROUND_COLOR: u32 = 0x204060;
+const BORDER_COLOR: u32 = 0xc0c0c0;
+const TITLE_BAR_COLOR: u32 = 0x404040;
+const ACTIVE_TITLE_BAR_COLOR: u32 = 0x2060c0;
+const TITLE_COLOR: u32 = 0xffffff;
+const CURSOR_SIZE: i64 = 8;
+const CURSOR_COLOR: u32 = 0xffffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WindowId(u64);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub x: i64,
+    pub y: i64,
+    pub left_button: bool,
+}
+
+pub struct Window {
+    id: WindowId,
+    title: String,
+    // 画面上での枠を含めた位置と大きさ
+    rect: Rect,
+    // 枠とタイトルバー (中身の部分は使わない)
+    frame: BitmapBuffer,
+    content: BitmapTextWriter<BitmapBuffer>,
+}
+
+impl Window {
+    fn new(id: WindowId, title: &str, rect: Rect) -> Self {
+        let content = BitmapBuffer::new(
+            rect.w - BORDER_WIDTH * 2,
+            rect.h - TITLE_BAR_HEIGHT - BORDER_WIDTH,
+        );
+        let mut w = Self {
+            id,
+            title: title.into(),
+            rect,
+            frame: BitmapBuffer::new(rect.w, rect.h),
+            content: BitmapTextWriter::new(content),
+        };
+        w.content.clear();
+        w.draw_frame(false);
+        w
+    }
+    pub fn id(&self) -> WindowId {
+        self.id
+    }
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+    // 画面上での中身の領域
+    pub fn content_rect(&self) -> Rect {
+        Rect::new(
+            self.rect.x + BORDER_WIDTH,
+            self.rect.y + TITLE_BAR_HEIGHT,
+            self.content.bitmap().width(),
+            self.content.bitmap().height(),
+        )
+    }
+    fn title_bar_rect(&self) -> Rect {
+        Rect::new(self.rect.x, self.rect.y, self.rect.w, TITLE_BAR_HEIGHT)
+    }
+    pub fn content_mut(&mut self) -> &mut BitmapBuffer {
+        self.content.bitmap_mut()
+    }
+    pub fn console(&mut self) -> &mut BitmapTextWriter<BitmapBuffer> {
+        &mut self.content
+    }
+    fn draw_frame(&mut self, active: bool) {
+        let (w, h) = (self.rect.w, self.rect.h);
+        let _ = fill_rect(&mut self.frame, BORDER_COLOR, 0, 0, w, h);
+        let bar_color = if active {
+            ACTIVE_TITLE_BAR_COLOR
+        } else {
+            TITLE_BAR_COLOR
+        };
+        let _ = fill_rect(
+            &mut self.frame,
+            bar_color,
+            BORDER_WIDTH,
+            BORDER_WIDTH,
+            w - BORDER_WIDTH * 2,
+            TITLE_BAR_HEIGHT - BORDER_WIDTH * 2,
+        );
+        draw_str_fg(&mut self.frame, 4, 2, TITLE_COLOR, &self.title);
+    }
+    // damageの範囲だけを画面に描く
+    fn draw<T: Bitmap>(&self, screen: &mut T, damage: &Rect) {
+        let content_rect = self.content_rect();
+        // 中身の部分は後で上書きされるので、枠は上下左右の帯に分けて描く
+        let bands = [
+            self.title_bar_rect(),
+            Rect::new(self.rect.x, content_rect.y, BORDER_WIDTH, content_rect.h),
+            Rect::new(
+                content_rect.right(),
+                content_rect.y,
+                BORDER_WIDTH,
+                content_rect.h,
+            ),
+            Rect::new(
+                self.rect.x,
+                content_rect.bottom(),
+                self.rect.w,
+                self.rect.bottom() - content_rect.bottom(),
+            ),
+        ];
+        for band in bands.iter() {
+            if let Some(r) = band.intersection(damage) {
+                blit(
+                    screen,
+                    r.x,
+                    r.y,
+                    &self.frame,
+                    r.translated(-self.rect.x, -self.rect.y),
+                );
+            }
+        }
+        if let Some(r) = content_rect.intersection(damage) {
+            blit(
+                screen,
+                r.x,
+                r.y,
+                self.content.bitmap(),
+                r.translated(-content_rect.x, -content_rect.y),
+            );
+        }
+    }
+}
+
+struct DragState {
+    id: WindowId,
+    offset_x: i64,
+    offset_y: i64,
+}
+
+// windowsの後ろにあるものほど手前に表示される
+pub struct WindowManager {
+    screen: Rect,
+    windows: Vec<Window>,
+    damage: Vec<Rect>,
+    next_id: u64,
+    drag: Option<DragState>,
+    last_mouse: MouseEvent,
+}
+
+impl WindowManager {
+    pub fn new(width: i64, height: i64) -> Self {
+        let screen = Rect::new(0, 0, width, height);
+        Self {
+            screen,
+            windows: Vec::new(),
+            damage: alloc::vec![screen],
+            next_id: 1,
+            drag: None,
+            last_mouse: MouseEvent::default(),
+        }
+    }
+    fn add_damage(&mut self, rect: Rect) {
+        if let Some(r) = rect.intersection(&self.screen) {
+            // 既存の領域に含まれていれば追加しない
+            if !self.damage.iter().any(|d| d.intersection(&r) == Some(r)) {
+                self.damage.push(r);
+            }
+        }
+    }
+    fn index_of(&self, id: WindowId) -> Result<usize> {
+        self.windows
+            .iter()
+            .position(|w| w.id == id)
+            .ok_or("No such window")
+    }
+    pub fn window_mut(&mut self, id: WindowId) -> Option<&mut Window> {
+        self.windows.iter_mut().find(|w| w.id == id)
+    }
+    pub fn create_window(
+        &mut self,
+        title: &str,
+        x: i64,
+        y: i64,
+        w: i64,
+        h: i64,
+    ) -> Result<WindowId> {
+        if w <= BORDER_WIDTH * 2 || h <= TITLE_BAR_HEIGHT + BORDER_WIDTH {
+            return Err("Window is too small");
+        }
+        let id = WindowId(self.next_id);
+        self.next_id += 1;
+        let window = Window::new(id, title, Rect::new(x, y, w, h));
+        self.add_damage(window.rect());
+        self.windows.push(window);
+        self.update_active();
+        Ok(id)
+    }
+    pub fn close_window(&mut self, id: WindowId) -> Result<()> {
+        let w = self.windows.remove(self.index_of(id)?);
+        self.add_damage(w.rect());
+        if self.drag.as_ref().map(|d| d.id) == Some(id) {
+            self.drag = None;
+        }
+        self.update_active();
+        Ok(())
+    }
+    pub fn move_window(&mut self, id: WindowId, x: i64, y: i64) -> Result<()> {
+        let i = self.index_of(id)?;
+        let old = self.windows[i].rect;
+        if (old.x, old.y) == (x, y) {
+            return Ok(());
+        }
+        self.windows[i].rect = Rect::new(x, y, old.w, old.h);
+        self.add_damage(old);
+        self.add_damage(self.windows[i].rect);
+        Ok(())
+    }
+    // 一番手前に持ってくる
+    pub fn raise(&mut self, id: WindowId) -> Result<()> {
+        let i = self.index_of(id)?;
+        if i + 1 == self.windows.len() {
+            return Ok(());
+        }
+        let w = self.windows.remove(i);
+        self.add_damage(w.rect());
+        self.windows.push(w);
+        self.update_active();
+        Ok(())
+    }
+    // 最前面のウィンドウだけタイトルバーの色を変える
+    fn update_active(&mut self) {
+        let n = self.windows.len();
+        let mut changed = Vec::new();
+        for (i, w) in self.windows.iter_mut().enumerate() {
+            w.draw_frame(i + 1 == n);
+            changed.push(w.title_bar_rect());
+        }
+        for r in changed {
+            self.add_damage(r);
+        }
+    }
+    pub fn window_at(&self, x: i64, y: i64) -> Option<WindowId> {
+        self.windows
+            .iter()
+            .rev()
+            .find(|w| w.rect.contains(x, y))
+            .map(|w| w.id)
+    }
+    // ウィンドウのコンソールに文字を書く
+    pub fn write_fmt_to(&mut self, id: WindowId, args: fmt::Arguments) -> Result<()> {
+        let i = self.index_of(id)?;
+        fmt::write(self.windows[i].console(), args).or(Err("Failed to write to window"))?;
+        self.add_damage(self.windows[i].content_rect());
+        Ok(())
+    }
+    // content_mut()で直接描いたあとに呼ぶ
+    pub fn invalidate(&mut self, id: WindowId) -> Result<()> {
+        let i = self.index_of(id)?;
+        self.add_damage(self.windows[i].content_rect());
+        Ok(())
+    }
+    // タイトルバーをドラッグすると移動し、クリックしたウィンドウは手前に来る
+    pub fn handle_mouse(&mut self, e: MouseEvent) -> Result<()> {
+        let pressed = e.left_button && !self.last_mouse.left_button;
+        if (e.x, e.y) != (self.last_mouse.x, self.last_mouse.y) {
+            self.add_damage(self.cursor_rect());
+            self.last_mouse = e;
+            self.add_damage(self.cursor_rect());
+        }
+        self.last_mouse = e;
+        if !e.left_button {
+            self.drag = None;
+            return Ok(());
+        }
+        if pressed {
+            let Some(id) = self.window_at(e.x, e.y) else {
+                return Ok(());
+            };
+            self.raise(id)?;
+            let w = &self.windows[self.index_of(id)?];
+            if w.title_bar_rect().contains(e.x, e.y) {
+                self.drag = Some(DragState {
+                    id,
+                    offset_x: e.x - w.rect.x,
+                    offset_y: e.y - w.rect.y,
+                });
+            }
+            return Ok(());
+        }
+        if let Some(d) = &self.drag {
+            let (id, x, y) = (d.id, e.x - d.offset_x, e.y - d.offset_y);
+            self.move_window(id, x, y)?;
+        }
+        Ok(())
+    }
+    fn cursor_rect(&self) -> Rect {
+        Rect::new(
+            self.last_mouse.x,
+            self.last_mouse.y,
+            CURSOR_SIZE,
+            CURSOR_SIZE,
+        )
+    }
+    pub fn has_damage(&self) -> bool {
+        !self.damage.is_empty()
+    }
+    // 変化した領域だけを奥のウィンドウから順に描き直す。カーソルは一番手前に描く
+    pub fn compose<T: Bitmap>(&mut self, screen: &mut T) {
+        let damage = core::mem::take(&mut self.damage);
+        let bounds = screen.bounds();
+        let cursor = self.cursor_rect();
+        for d in damage.iter().filter_map(|d| d.intersection(&bounds)) {
+            let _ = fill_rect(screen, BACKGROUND_COLOR, d.x, d.y, d.w, d.h);
+            for w in self.windows.iter() {
+                w.draw(screen, &d);
+            }
+            if let Some(r) = cursor.intersection(&d) {
+                let _ = fill_rect(screen, CURSOR_COLOR, r.x, r.y, r.w, r.h);
+            }
+        }
+    }
+}
+
+// 画面全体をWindowManagerで描く。ログはlogウィンドウに出し、PS/2マウスでウィンドウを動かせる
+struct Desktop {
+    wm: WindowManager,
+    log_window: WindowId,
+}
+
+impl Desktop {
+    // マウスは移動量しか送ってこないので、画面の中に収めながら足していく
+    fn handle_motion(&mut self, m: MouseMotion) -> Result<()> {
+        let last = self.wm.last_mouse;
+        let screen = self.wm.screen;
+        self.wm.handle_mouse(MouseEvent {
+            x: (last.x + m.dx).clamp(0, screen.w - 1),
+            y: (last.y + m.dy).clamp(0, screen.h - 1),
+            left_button: m.left_button,
+        })
+    }
+}
+
+const DESKTOP_FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+static DESKTOP: SpinLockIrqSave<Option<Desktop>> = SpinLockIrqSave::new(None);
+
+// VRAMのログと同じく、取れない時 (この中でログを出した時) の分は捨てる
+struct DesktopLogSink;
+
+impl LogSink for DesktopLogSink {
+    fn name(&self) -> &'static str {
+        "desktop"
+    }
+    fn write(&self, args: fmt::Arguments) {
+        if let Some(Some(d)) = DESKTOP.try_lock().as_deref_mut() {
+            let _ = d.wm.write_fmt_to(d.log_window, args);
+        }
+    }
+    // BitmapTextWriterはANSIエスケープを解釈しないので、色は付けない
+    fn write_log(&self, record: &LogRecord) {
+        self.write(format_args!(
+            "{} [{}] {}:{:<3}: {}\n",
+            record.timestamp,
+            record.level.tag(),
+            record.file,
+            record.line,
+            record.args
+        ))
+    }
+}
+
+static DESKTOP_LOG_SINK: DesktopLogSink = DesktopLogSink;
+
+fn run_desktop() {
+    loop {
+        if let Some(d) = DESKTOP.lock().as_mut() {
+            while let Some(m) = pop_mouse_motion() {
+                if let Err(e) = d.handle_motion(m) {
+                    error!("desktop: {e}");
+                }
+            }
+            if d.wm.has_damage() {
+                with_global_vram_mut(|vram| d.wm.compose(vram));
+            }
+        }
+        sleep(DESKTOP_FRAME_INTERVAL);
+    }
+}
+
+// VRAMへの直接のログ出力をやめて、画面をWindowManagerに任せる
+pub fn start_desktop() -> Result<()> {
+    let (w, h) =
+        with_global_vram(|vram| (vram.width(), vram.height())).ok_or("VRAM is not initialized")?;
+    let mut wm = WindowManager::new(w, h);
+    let log_window = wm.create_window("log", 16, 16, w * 3 / 4, h * 3 / 4)?;
+    // カーソルは画面の真ん中から始める
+    wm.handle_mouse(MouseEvent {
+        x: w / 2,
+        y: h / 2,
+        left_button: false,
+    })?;
+    *DESKTOP.lock() = Some(Desktop { wm, log_window });
+    if let Err(e) = init_ps2_mouse() {
+        error!("desktop: PS/2 mouse is not available: {e}");
+    }
+    // 失敗しても、VRAMに描いたログがウィンドウの上に重なるだけ
+    let _ = unregister_log_sink("vram");
+    register_log_sink(&DESKTOP_LOG_SINK)?;
+    kthread::spawn("desktop", run_desktop);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn window_stacking_and_drag() {
+        let mut wm = WindowManager::new(200, 200);
+        let a = wm.create_window("a", 10, 10, 100, 80).unwrap();
+        let b = wm.create_window("b", 50, 50, 100, 80).unwrap();
+        assert_eq!(wm.window_at(60, 60), Some(b));
+        let mut screen = BitmapBuffer::new(200, 200);
+        wm.compose(&mut screen);
+        assert!(!wm.has_damage());
+        // aのタイトルバーをクリックすると手前に来て、ドラッグで動く
+        wm.handle_mouse(MouseEvent {
+            x: 20,
+            y: 15,
+            left_button: true,
+        })
+        .unwrap();
+        assert_eq!(wm.window_at(60, 60), Some(a));
+        wm.handle_mouse(MouseEvent {
+            x: 30,
+            y: 25,
+            left_button: true,
+        })
+        .unwrap();
+        assert_eq!(wm.window_mut(a).unwrap().rect(), Rect::new(20, 20, 100, 80));
+        assert!(wm.has_damage());
+        wm.compose(&mut screen);
+        // 元の位置の左上は背景に戻っている
+        assert_eq!(screen.pixels()[10 * 200 + 10], BACKGROUND_COLOR);
+        assert_eq!(screen.pixels()[20 * 200 + 20], BORDER_COLOR);
+    }
+
+    #[test_case]
+    fn cursor_follows_mouse() {
+        let mut wm = WindowManager::new(100, 100);
+        let mut screen = BitmapBuffer::new(100, 100);
+        wm.compose(&mut screen);
+        wm.handle_mouse(MouseEvent {
+            x: 50,
+            y: 50,
+            left_button: false,
+        })
+        .unwrap();
+        assert!(wm.has_damage());
+        wm.compose(&mut screen);
+        assert_eq!(screen.pixels()[50 * 100 + 50], CURSOR_COLOR);
+        // 前の位置は背景に戻る
+        assert_eq!(screen.pixels()[0], BACKGROUND_COLOR);
+    }
+}
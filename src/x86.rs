@@ -1,10 +1,24 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
+use alloc::format;
 
+use crate::apic::send_eoi;
+use crate::emergency_println;
 use crate::error;
 use crate::info;
+use crate::monitor::enter_monitor;
+use crate::monitor::monitor_enabled;
+use crate::monitor::MonitorAction;
+use crate::mutex::SpinLockIrqSave;
+use crate::process::handle_user_page_fault;
+use crate::process::kill_current_process;
+use crate::process::Signal;
+use crate::qemu::set_panic_exit_code;
+use crate::qemu::QemuExitCode;
 use crate::result::Result;
+use crate::task::is_stack_guard_page_of_current_task;
+use crate::task::preempt_if_needed;
 use core::arch::asm;
 use core::arch::global_asm;
 use core::fmt;
@@ -13,7 +27,11 @@ use core::mem::offset_of;
 use core::mem::size_of;
 use core::mem::size_of_val;
 use core::mem::MaybeUninit;
+use core::ops::Range;
 use core::pin::Pin;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
@@ -23,6 +41,31 @@ pub fn busy_loop_hint() {
     unsafe { asm!("pause") }
 }
 
+pub fn cli() {
+    unsafe { asm!("cli") }
+}
+
+pub fn sti() {
+    unsafe { asm!("sti") }
+}
+
+// stiの直後の命令までは割り込みが入らないので、確認してから止まるまでの間に来た割り込みを取りこぼさない
+pub fn sti_and_hlt() {
+    unsafe { asm!("sti", "hlt") }
+}
+
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!(
+          "pushfq",
+          "pop {}",
+          out(reg) rflags
+        )
+    }
+    rflags & RFLAGS_IF != 0
+}
+
 pub fn read_io_port_u8(port: u16) -> u8 {
     let mut data: u8;
     unsafe {
@@ -43,6 +86,116 @@ pub fn write_io_port_u8(port: u16, data: u8) {
     }
 }
 
+pub fn read_io_port_u16(port: u16) -> u16 {
+    let mut data: u16;
+    unsafe {
+        asm!(
+          "in ax, dx",
+          out("ax") data,
+          in("dx") port
+        )
+    }
+    data
+}
+
+pub fn write_io_port_u16(port: u16, data: u16) {
+    unsafe {
+        asm!("out dx, ax",
+        in("ax") data,
+        in("dx") port)
+    }
+}
+
+pub fn read_io_port_u32(port: u16) -> u32 {
+    let mut data: u32;
+    unsafe {
+        asm!(
+          "in eax, dx",
+          out("eax") data,
+          in("dx") port
+        )
+    }
+    data
+}
+
+pub fn write_io_port_u32(port: u16, data: u32) {
+    unsafe {
+        asm!("out dx, eax",
+        in("eax") data,
+        in("dx") port)
+    }
+}
+
+pub fn read_msr(msr: u32) -> u64 {
+    let mut high: u32;
+    let mut low: u32;
+    unsafe {
+        asm!("rdmsr",
+        in("ecx") msr,
+        out("edx") high,
+        out("eax") low)
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// # Safety
+/// msrはこのCPUに存在するMSRで、dataはそのMSRに書いてよい値であること (違えば#GPになる)
+/// EFER, LSTAR, STAR, FMASKなどは動作が変わるので、カーネルが前提としている設定を壊さないこと
+pub unsafe fn write_msr(msr: u32, data: u64) {
+    asm!("wrmsr",
+    in("ecx") msr,
+    in("edx") (data >> 32) as u32,
+    in("eax") data as u32)
+}
+
+pub fn read_tsc() -> u64 {
+    let mut high: u32;
+    let mut low: u32;
+    unsafe {
+        asm!("rdtsc",
+        out("edx") high,
+        out("eax") low)
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+// CPUID.01H:ECX.RDRAND[bit 30]
+pub fn has_rdrand() -> bool {
+    let ecx: u32;
+    unsafe {
+        // rbxはLLVMが使うので退避しておく
+        asm!("mov {tmp:r}, rbx",
+        "cpuid",
+        "mov rbx, {tmp:r}",
+        tmp = out(reg) _,
+        inout("eax") 1 => _,
+        inout("ecx") 0 => ecx,
+        out("edx") _)
+    }
+    ecx & (1 << 30) != 0
+}
+
+// 失敗することがあるので何度か試す (Intel DRNG Software Implementation Guide 5.2.1)
+pub fn rdrand64() -> Option<u64> {
+    if !has_rdrand() {
+        return None;
+    }
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {value}",
+            "setc {ok}",
+            value = out(reg) value,
+            ok = out(reg_byte) ok)
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
 pub fn read_cr3() -> *mut PML4 {
     let mut cr3: *mut PML4;
     unsafe {
@@ -56,14 +209,19 @@ pub const PAGE_SIZE: usize = 4096;
 const ATTR_MASK: u64 = 0xFFF;
 const ATTR_PRESENT: u64 = 1 << 0;
 const ATTR_WRITABLE: u64 = 1 << 1;
+const ATTR_USER: u64 = 1 << 2;
 const ATTR_WRITE_THROUGH: u64 = 1 << 3;
 const ATTR_CACHE_DISABLED: u64 = 1 << 4;
+const ATTR_PAGE_SIZE: u64 = 1 << 7;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u64)]
 pub enum PageAttr {
     NotPresent = 0,
+    ReadOnlyKernel = ATTR_PRESENT,
     ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
+    ReadOnlyUser = ATTR_PRESENT | ATTR_USER,
+    ReadWriteUser = ATTR_PRESENT | ATTR_WRITABLE | ATTR_USER,
     ReadWriteIo = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLED,
 }
 
@@ -149,6 +307,21 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
             self.populate()
         }
     }
+    // 途中のテーブルは、ユーザのページが1つでもあればユーザからも辿れるようにする
+    // 実際の権限は最後のページのエントリで決まる
+    fn allow_user(&mut self, user: bool) -> &mut Self {
+        if user {
+            self.value |= ATTR_USER;
+        }
+        self
+    }
+    fn is_large_page(&self) -> bool {
+        self.read_value() & ATTR_PAGE_SIZE != 0
+    }
+    fn page_addr(&self) -> u64 {
+        // 最上位のNXビットなども落とす
+        self.read_value() & 0x000F_FFFF_FFFF_F000
+    }
 }
 
 impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Display for Entry<LEVEL, SHIFT, NEXT> {
@@ -209,6 +382,48 @@ impl PML4 {
     pub fn new() -> Box<Self> {
         Box::new(Self::default())
     }
+    // user以外のエントリをkernelからコピーした新しいテーブルを作る
+    // コピーしたエントリの先のテーブルはkernelと共有する
+    pub fn new_with_kernel_mappings(kernel: &PML4, user: Range<usize>) -> Box<Self> {
+        let mut table = Self::new();
+        for (i, e) in table.entry.iter_mut().enumerate() {
+            if !user.contains(&i) {
+                e.value = kernel.entry[i].value;
+            }
+        }
+        table
+    }
+    // indicesのエントリから辿れるテーブル (PDPT, PD, PT) を解放してエントリを消す
+    // マップされていたページ自体は解放しない
+    /// # Safety
+    /// indicesのエントリが指すテーブルを他のPML4と共有しておらず (カーネルの部分を含めないこと)、
+    /// このPML4がどのCPUのCR3にも設定されていないこと
+    pub unsafe fn free_tables(&mut self, indices: Range<usize>) {
+        for e in &mut self.entry[indices] {
+            let Ok(pdpt) = e.table_mut() else {
+                continue;
+            };
+            for e in pdpt.entry.iter_mut() {
+                if e.is_large_page() {
+                    continue;
+                }
+                let Ok(pd) = e.table_mut() else {
+                    continue;
+                };
+                for e in pd.entry.iter_mut() {
+                    if e.is_large_page() {
+                        continue;
+                    }
+                    if let Ok(pt) = e.table_mut() {
+                        drop(Box::from_raw(pt as *mut PT));
+                    }
+                }
+                drop(Box::from_raw(pd as *mut PD));
+            }
+            drop(Box::from_raw(pdpt as *mut PDPT));
+            e.value = 0;
+        }
+    }
     // 仮想アドレスと物理アドレスのマッピングを新たに作成する
     pub fn create_mapping(
         &mut self,
@@ -229,20 +444,80 @@ impl PML4 {
         if virt_start >= virt_end {
             return Err("Invalid virt range");
         }
+        let user = attr as u64 & ATTR_USER != 0;
         for addr in (virt_start..virt_end).step_by(PAGE_SIZE) {
             // 4レベル分掘り下げていく
             let index = self.calc_index(addr);
-            let table = self.entry[index].ensure_populated()?.table_mut()?;
+            let table = self.entry[index]
+                .ensure_populated()?
+                .allow_user(user)
+                .table_mut()?;
             let index = table.calc_index(addr);
-            let table = table.entry[index].ensure_populated()?.table_mut()?;
+            let table = table.entry[index]
+                .ensure_populated()?
+                .allow_user(user)
+                .table_mut()?;
             let index = table.calc_index(addr);
-            let table = table.entry[index].ensure_populated()?.table_mut()?;
+            let table = table.entry[index]
+                .ensure_populated()?
+                .allow_user(user)
+                .table_mut()?;
             let index = table.calc_index(addr);
             let pte = &mut table.entry[index];
             pte.set_page(phys + addr - virt_start, attr)?;
         }
         Ok(())
     }
+    // ユーザモードからvirtのページにアクセスできるか (writeなら書き込みも) を調べる
+    pub fn is_user_accessible(&self, virt: u64, write: bool) -> bool {
+        let required = ATTR_PRESENT | ATTR_USER | if write { ATTR_WRITABLE } else { 0 };
+        let ok = |value: u64| value & required == required;
+        let pml4e = &self.entry[self.calc_index(virt)];
+        let Ok(pdpt) = pml4e.table() else {
+            return false;
+        };
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        let Ok(pd) = pdpte.table() else {
+            return false;
+        };
+        let pde = &pd.entry[pd.calc_index(virt)];
+        let Ok(pt) = pde.table() else {
+            return false;
+        };
+        let pte = &pt.entry[pt.calc_index(virt)];
+        ok(pml4e.read_value())
+            && ok(pdpte.read_value())
+            && !pdpte.is_large_page()
+            && ok(pde.read_value())
+            && !pde.is_large_page()
+            && ok(pte.read_value())
+    }
+    // 仮想アドレスがどの物理アドレスにマップされているかを調べる
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let pml4e = &self.entry[self.calc_index(virt)];
+        let pdpt = pml4e.table()?;
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        if pdpte.is_present() && pdpte.is_large_page() {
+            return Ok(TranslationResult::PageMapped1G {
+                phys: pdpte.page_addr() + (virt & ((1 << 30) - 1)),
+            });
+        }
+        let pd = pdpte.table()?;
+        let pde = &pd.entry[pd.calc_index(virt)];
+        if pde.is_present() && pde.is_large_page() {
+            return Ok(TranslationResult::PageMapped2M {
+                phys: pde.page_addr() + (virt & ((1 << 21) - 1)),
+            });
+        }
+        let pt = pde.table()?;
+        let pte = &pt.entry[pt.calc_index(virt)];
+        if !pte.is_present() {
+            return Err("Page Not Found");
+        }
+        Ok(TranslationResult::PageMapped4K {
+            phys: pte.page_addr() + (virt & ATTR_MASK),
+        })
+    }
 }
 
 // Code Segment
@@ -401,6 +676,133 @@ impl fmt::Debug for InterruptInfo {
     }
 }
 
+impl FPUContext {
+    // FNINIT直後と同じ状態 (FCW=0x037F, MXCSR=0x1F80)
+    // https://www.felixcloutier.com/x86/fxsave#tbl-3-43
+    fn new() -> Self {
+        let mut data = [0u8; 512];
+        data[0..2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        data[24..28].copy_from_slice(&0x1F80u32.to_le_bytes());
+        Self { data }
+    }
+}
+
+// switch_contextで切り替えるタスクの状態
+// System V ABIでcallee-savedなレジスタとrsp, rip, rflagsだけを持つ
+#[repr(C, align(16))]
+pub struct Context {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rflags: u64,
+    save_fpu: u64,
+    fpu_context: FPUContext,
+}
+const _: () = assert!(offset_of!(Context, rip) == 0x38);
+const _: () = assert!(offset_of!(Context, save_fpu) == 0x48);
+const _: () = assert!(offset_of!(Context, fpu_context) == 0x50);
+
+const RFLAGS_RESERVED: u64 = 1 << 1;
+const RFLAGS_IF: u64 = 1 << 9;
+
+impl Context {
+    // 現在のタスクの状態を保存する先として使う
+    pub fn empty() -> Self {
+        Self {
+            rsp: 0,
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip: 0,
+            rflags: RFLAGS_RESERVED,
+            save_fpu: 1,
+            fpu_context: FPUContext::new(),
+        }
+    }
+    // stack_topから積むスタックでentryを呼んだ直後の状態を作る。割り込みは許可した状態で始まる
+    pub fn new(entry: extern "sysv64" fn() -> !, stack_top: u64) -> Self {
+        let mut ctx = Self::empty();
+        // callの直後と同じく、rsp + 8が16バイト境界になるようにする
+        ctx.rsp = (stack_top & !0xF) - 8;
+        ctx.rip = entry as usize as u64;
+        ctx.rflags = RFLAGS_RESERVED | RFLAGS_IF;
+        ctx
+    }
+    // FPUやSSEを使わないタスクなら、保存と復元を省いて切り替えを軽くできる
+    pub fn set_save_fpu(&mut self, save_fpu: bool) {
+        self.save_fpu = save_fpu as u64;
+    }
+    pub fn rsp(&self) -> u64 {
+        self.rsp
+    }
+    pub fn rbp(&self) -> u64 {
+        self.rbp
+    }
+    pub fn rip(&self) -> u64 {
+        self.rip
+    }
+}
+
+// rdi: from (保存先), rsi: to (復元元)
+global_asm!(
+    r#"
+  .global switch_context_asm
+  switch_context_asm:
+    mov [rdi + 0x00], rsp
+    mov [rdi + 0x08], rbx
+    mov [rdi + 0x10], rbp
+    mov [rdi + 0x18], r12
+    mov [rdi + 0x20], r13
+    mov [rdi + 0x28], r14
+    mov [rdi + 0x30], r15
+    lea rax, [rip + 2f]
+    mov [rdi + 0x38], rax
+    pushfq
+    pop rax
+    mov [rdi + 0x40], rax
+    cmp qword ptr [rdi + 0x48], 0
+    je 1f
+    fxsave64 [rdi + 0x50]
+  1:
+    cmp qword ptr [rsi + 0x48], 0
+    je 1f
+    fxrstor64 [rsi + 0x50]
+  1:
+    mov rsp, [rsi + 0x00]
+    mov rbx, [rsi + 0x08]
+    mov rbp, [rsi + 0x10]
+    mov r12, [rsi + 0x18]
+    mov r13, [rsi + 0x20]
+    mov r14, [rsi + 0x28]
+    mov r15, [rsi + 0x30]
+    push qword ptr [rsi + 0x40]
+    popfq
+    jmp [rsi + 0x38]
+  2:
+    ret
+  "#
+);
+
+extern "sysv64" {
+    fn switch_context_asm(from: *mut Context, to: *const Context);
+}
+
+// 現在の状態をfromに保存してtoに切り替える
+// fromが再びswitch_contextで復元されると、この関数から戻ってくる
+/// # Safety
+/// toは有効なスタックとエントリポイントを持つContextであること
+pub unsafe fn switch_context(from: &mut Context, to: &Context) {
+    switch_context_asm(from, to)
+}
+
 // 割り込み時のエントリポイントを登録するアセンブリを生成するマクロ
 // 内部ではinthandler_commonにジャンプする
 macro_rules! interrupt_entrypoint {
@@ -440,23 +842,165 @@ macro_rules! interrupt_entrypoint_with_ecode {
     };
 }
 
+interrupt_entrypoint!(0);
+interrupt_entrypoint!(1);
+interrupt_entrypoint!(2);
 interrupt_entrypoint!(3);
+interrupt_entrypoint!(4);
+interrupt_entrypoint!(5);
 interrupt_entrypoint!(6);
+interrupt_entrypoint!(7);
 interrupt_entrypoint_with_ecode!(8);
+interrupt_entrypoint_with_ecode!(10);
+interrupt_entrypoint_with_ecode!(11);
+interrupt_entrypoint_with_ecode!(12);
 interrupt_entrypoint_with_ecode!(13);
 interrupt_entrypoint_with_ecode!(14);
+interrupt_entrypoint!(16);
+interrupt_entrypoint_with_ecode!(17);
+interrupt_entrypoint!(18);
+interrupt_entrypoint!(19);
 interrupt_entrypoint!(32);
+interrupt_entrypoint!(33);
+interrupt_entrypoint!(34);
+interrupt_entrypoint!(35);
+interrupt_entrypoint!(36);
+interrupt_entrypoint!(37);
+interrupt_entrypoint!(38);
+interrupt_entrypoint!(39);
+interrupt_entrypoint!(40);
+interrupt_entrypoint!(41);
+interrupt_entrypoint!(42);
+interrupt_entrypoint!(43);
+interrupt_entrypoint!(44);
+interrupt_entrypoint!(45);
+interrupt_entrypoint!(46);
+interrupt_entrypoint!(47);
+interrupt_entrypoint!(48);
+interrupt_entrypoint!(49);
+interrupt_entrypoint!(50);
+interrupt_entrypoint!(51);
+interrupt_entrypoint!(52);
+interrupt_entrypoint!(53);
+interrupt_entrypoint!(54);
+interrupt_entrypoint!(55);
+interrupt_entrypoint!(56);
+interrupt_entrypoint!(57);
+interrupt_entrypoint!(58);
+interrupt_entrypoint!(59);
+interrupt_entrypoint!(60);
+interrupt_entrypoint!(61);
+interrupt_entrypoint!(62);
+interrupt_entrypoint!(63);
 
 // 上のマクロで定義された割り込みハンドラ
 extern "sysv64" {
+    fn interrupt_entrypoint0();
+    fn interrupt_entrypoint1();
+    fn interrupt_entrypoint2();
     fn interrupt_entrypoint3();
+    fn interrupt_entrypoint4();
+    fn interrupt_entrypoint5();
     fn interrupt_entrypoint6();
+    fn interrupt_entrypoint7();
     fn interrupt_entrypoint8();
+    fn interrupt_entrypoint10();
+    fn interrupt_entrypoint11();
+    fn interrupt_entrypoint12();
     fn interrupt_entrypoint13();
     fn interrupt_entrypoint14();
+    fn interrupt_entrypoint16();
+    fn interrupt_entrypoint17();
+    fn interrupt_entrypoint18();
+    fn interrupt_entrypoint19();
     fn interrupt_entrypoint32();
+    fn interrupt_entrypoint33();
+    fn interrupt_entrypoint34();
+    fn interrupt_entrypoint35();
+    fn interrupt_entrypoint36();
+    fn interrupt_entrypoint37();
+    fn interrupt_entrypoint38();
+    fn interrupt_entrypoint39();
+    fn interrupt_entrypoint40();
+    fn interrupt_entrypoint41();
+    fn interrupt_entrypoint42();
+    fn interrupt_entrypoint43();
+    fn interrupt_entrypoint44();
+    fn interrupt_entrypoint45();
+    fn interrupt_entrypoint46();
+    fn interrupt_entrypoint47();
+    fn interrupt_entrypoint48();
+    fn interrupt_entrypoint49();
+    fn interrupt_entrypoint50();
+    fn interrupt_entrypoint51();
+    fn interrupt_entrypoint52();
+    fn interrupt_entrypoint53();
+    fn interrupt_entrypoint54();
+    fn interrupt_entrypoint55();
+    fn interrupt_entrypoint56();
+    fn interrupt_entrypoint57();
+    fn interrupt_entrypoint58();
+    fn interrupt_entrypoint59();
+    fn interrupt_entrypoint60();
+    fn interrupt_entrypoint61();
+    fn interrupt_entrypoint62();
+    fn interrupt_entrypoint63();
 }
 
+// Idt::newで個別に設定するもの以外の例外。ユーザモードからでも起こせる
+// (#DB: TFを立てる, #SS: 正規でないrspでpushする, #AC, #MF, #XM など)
+const OTHER_EXCEPTION_ENTRYPOINTS: [(usize, unsafe extern "sysv64" fn()); 11] = [
+    (1, interrupt_entrypoint1),
+    (4, interrupt_entrypoint4),
+    (5, interrupt_entrypoint5),
+    (7, interrupt_entrypoint7),
+    (10, interrupt_entrypoint10),
+    (11, interrupt_entrypoint11),
+    (12, interrupt_entrypoint12),
+    (16, interrupt_entrypoint16),
+    (17, interrupt_entrypoint17),
+    (18, interrupt_entrypoint18),
+    (19, interrupt_entrypoint19),
+];
+
+// デバイスからの割り込みに使うベクタ
+pub const FIRST_EXTERNAL_VECTOR: usize = 32;
+pub const NUM_OF_EXTERNAL_VECTORS: usize = 32;
+const EXTERNAL_INTERRUPT_ENTRYPOINTS: [unsafe extern "sysv64" fn(); NUM_OF_EXTERNAL_VECTORS] = [
+    interrupt_entrypoint32,
+    interrupt_entrypoint33,
+    interrupt_entrypoint34,
+    interrupt_entrypoint35,
+    interrupt_entrypoint36,
+    interrupt_entrypoint37,
+    interrupt_entrypoint38,
+    interrupt_entrypoint39,
+    interrupt_entrypoint40,
+    interrupt_entrypoint41,
+    interrupt_entrypoint42,
+    interrupt_entrypoint43,
+    interrupt_entrypoint44,
+    interrupt_entrypoint45,
+    interrupt_entrypoint46,
+    interrupt_entrypoint47,
+    interrupt_entrypoint48,
+    interrupt_entrypoint49,
+    interrupt_entrypoint50,
+    interrupt_entrypoint51,
+    interrupt_entrypoint52,
+    interrupt_entrypoint53,
+    interrupt_entrypoint54,
+    interrupt_entrypoint55,
+    interrupt_entrypoint56,
+    interrupt_entrypoint57,
+    interrupt_entrypoint58,
+    interrupt_entrypoint59,
+    interrupt_entrypoint60,
+    interrupt_entrypoint61,
+    interrupt_entrypoint62,
+    interrupt_entrypoint63,
+];
+
 // inthandler_common
 global_asm!(
     r#"
@@ -521,6 +1065,27 @@ global_asm!(
   "#
 );
 
+// 割り込みのフレームが無い所 (kassert!など) で、今のレジスタの様子を出す
+pub fn print_current_registers() {
+    let (rsp, rbp, rflags): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "mov {rsp}, rsp",
+            "mov {rbp}, rbp",
+            "pushfq",
+            "pop {rflags}",
+            rsp = out(reg) rsp,
+            rbp = out(reg) rbp,
+            rflags = out(reg) rflags,
+        );
+    }
+    error!(
+        "RSP={rsp:#018X} RBP={rbp:#018X} RFLAGS={rflags:#018X} CR2={:#018X} CR3={:#018X}",
+        read_cr2(),
+        read_cr3() as u64
+    );
+}
+
 // Page Fault
 pub fn read_cr2() -> u64 {
     let mut cr2: u64;
@@ -531,22 +1096,84 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+// 今処理している外部割り込みが、ユーザモードで動いているところに来たならtrue
+static INTERRUPTED_USER_MODE: AtomicBool = AtomicBool::new(false);
+
+pub fn interrupted_user_mode() -> bool {
+    INTERRUPTED_USER_MODE.load(Ordering::SeqCst)
+}
+
+// int3を受けた回数 (selftestで使う)
+static BREAKPOINT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub fn breakpoint_count() -> u64 {
+    BREAKPOINT_COUNT.load(Ordering::SeqCst)
+}
+
+const NUM_OF_VECTORS: usize = FIRST_EXTERNAL_VECTOR + NUM_OF_EXTERNAL_VECTORS;
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_COUNT: AtomicU64 = AtomicU64::new(0);
+// ベクタごとの割り込み・例外の回数
+static INTERRUPT_COUNTS: [AtomicU64; NUM_OF_VECTORS] = [ZERO_COUNT; NUM_OF_VECTORS];
+
+// (ベクタ, 回数) を、一度でも来たものだけ返す
+pub fn interrupt_counts() -> impl Iterator<Item = (usize, u64)> {
+    INTERRUPT_COUNTS
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed))
+        .enumerate()
+        .filter(|(_, count)| *count != 0)
+}
+
 // inthandler_commonから呼び出される関数
 #[no_mangle]
 extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
+    if let Some(count) = INTERRUPT_COUNTS.get(index) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+    // NMIとダブルフォルトは、ログのロックを持っている最中にも来るので、ロックを取らずに出力する
+    match index {
+        2 => {
+            emergency_println!("NMI received: {info:?}");
+            return;
+        }
+        8 => {
+            emergency_println!("Double Fault: {info:?}");
+            set_panic_exit_code(QemuExitCode::DoubleFault);
+            panic!("Double Fault")
+        }
+        _ => {}
+    }
+    if index >= FIRST_EXTERNAL_VECTOR {
+        INTERRUPTED_USER_MODE.store(info.ctx.cs & 3 == 3, Ordering::SeqCst);
+        handle_external_interrupt(index as u8);
+        return;
+    }
+    // ユーザモードで、まだ割り当てていないページに触った時は、割り当てて続ける
+    if index == 14
+        && info.error_code & 0b0101 == 0b0100
+        && handle_user_page_fault(read_cr2(), info.error_code & 0b0010 != 0)
+    {
+        return;
+    }
+    // ユーザモードで起きた例外では、カーネルは止めずにそのプロセスだけを終わらせる
+    if info.ctx.cs & 3 == 3 {
+        kill_user_process_on_exception(info, index);
+    }
     error!("Intterupt Info: {:?}", info);
     error!("Exception {index:#04X}: ");
     match index {
+        0 => {
+            error!("Divide Error");
+        }
         3 => {
             error!("Breakpoint");
+            BREAKPOINT_COUNT.fetch_add(1, Ordering::SeqCst);
             return;
         }
         6 => {
             error!("Invalid Opcode");
         }
-        8 => {
-            error!("Double Fault");
-        }
         13 => {
             error!("General Protection Fault");
             // instruction pointer=次に実行する・実行中の命令のアドレス
@@ -559,6 +1186,9 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
         14 => {
             error!("Page Fault");
             error!("CR2={:018X}", read_cr2());
+            if is_stack_guard_page_of_current_task(read_cr2()) {
+                error!("Kernel stack overflow in the current task");
+            }
             error!(
                 "Caused by: A {} mode {} on a {} page, page structures are {}",
                 // https://wiki.osdev.org/Exceptions#Error_code
@@ -591,9 +1221,113 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
             error!("Not handled");
         }
     };
+    if monitor_enabled() && enter_monitor(index, info) == MonitorAction::Continue {
+        return;
+    }
     panic!("Failal exception")
 }
 
+fn kill_user_process_on_exception(info: &InterruptInfo, index: usize) {
+    let rip = info.ctx.rip;
+    let (signal, description) = match index {
+        // ブレークポイントはカーネルと同じく、記録するだけで続ける
+        3 => return,
+        13 => (
+            Signal::Segv,
+            format!(
+                "General protection fault at rip = {rip:#X} (error code = {:#X})",
+                info.error_code
+            ),
+        ),
+        14 => (
+            Signal::Segv,
+            format!(
+                "Invalid {} of {:#X} at rip = {rip:#X}",
+                if info.error_code & 0b0001_0000 != 0 {
+                    "instruction fetch"
+                } else if info.error_code & 0b0010 != 0 {
+                    "write"
+                } else {
+                    "read"
+                },
+                read_cr2()
+            ),
+        ),
+        _ => {
+            let (signal, name) = match index {
+                0 => (Signal::Fpe, "Divide error"),
+                1 => (Signal::Trap, "Debug exception"),
+                4 => (Signal::Segv, "Overflow"),
+                5 => (Signal::Segv, "BOUND range exceeded"),
+                6 => (Signal::Ill, "Invalid opcode"),
+                7 => (Signal::Fpe, "Device not available"),
+                10 => (Signal::Segv, "Invalid TSS"),
+                11 => (Signal::Segv, "Segment not present"),
+                12 => (Signal::Segv, "Stack-segment fault"),
+                16 => (Signal::Fpe, "x87 floating-point error"),
+                17 => (Signal::Bus, "Alignment check"),
+                18 => (Signal::Bus, "Machine check"),
+                19 => (Signal::Fpe, "SIMD floating-point error"),
+                _ => (Signal::Segv, "Unexpected exception"),
+            };
+            (signal, format!("{name} (vector {index}) at rip = {rip:#X}"))
+        }
+    };
+    error!("Registers: {info:?}");
+    kill_current_process(signal, &description)
+}
+
+pub type InterruptHandler = fn(vector: u8);
+
+static INTERRUPT_HANDLERS: SpinLockIrqSave<[Option<InterruptHandler>; NUM_OF_EXTERNAL_VECTORS]> =
+    SpinLockIrqSave::new([None; NUM_OF_EXTERNAL_VECTORS]);
+
+fn handle_external_interrupt(vector: u8) {
+    let handler = INTERRUPT_HANDLERS.lock()[vector as usize - FIRST_EXTERNAL_VECTOR];
+    if let Some(handler) = handler {
+        handler(vector);
+    } else {
+        error!("Spurious interrupt: vector {vector:#04X}");
+    }
+    send_eoi();
+    // EOIを送った後でないと、切り替え先のタスクで割り込みが来なくなる
+    preempt_if_needed();
+}
+
+pub fn register_interrupt_handler(vector: u8, handler: InterruptHandler) -> Result<()> {
+    let index = (vector as usize)
+        .checked_sub(FIRST_EXTERNAL_VECTOR)
+        .filter(|i| *i < NUM_OF_EXTERNAL_VECTORS)
+        .ok_or("Vector is not for external interrupts")?;
+    let mut handlers = INTERRUPT_HANDLERS.lock();
+    if handlers[index].is_some() {
+        return Err("Vector is already in use");
+    }
+    handlers[index] = Some(handler);
+    Ok(())
+}
+
+// 空いているベクタを確保してハンドラを登録する
+// MSIやIOAPICから割り込みを受けるときに使う
+pub fn allocate_interrupt_vector(handler: InterruptHandler) -> Result<u8> {
+    let mut handlers = INTERRUPT_HANDLERS.lock();
+    let index = handlers
+        .iter()
+        .position(|h| h.is_none())
+        .ok_or("No free interrupt vector")?;
+    handlers[index] = Some(handler);
+    Ok((FIRST_EXTERNAL_VECTOR + index) as u8)
+}
+
+pub fn free_interrupt_vector(vector: u8) {
+    if let Some(h) = (vector as usize)
+        .checked_sub(FIRST_EXTERNAL_VECTOR)
+        .and_then(|i| INTERRUPT_HANDLERS.lock().get_mut(i).map(|h| h.take()))
+    {
+        assert!(h.is_some(), "Freeing an unused vector");
+    }
+}
+
 #[no_mangle]
 extern "sysv64" fn int_handler_unimplemented() {
     panic!("unexpected interrupt!");
@@ -682,6 +1416,20 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             int_handler_unimplemented,
         ); 0x100];
+        // Divide Error
+        entries[0] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint0,
+        );
+        // NMI: どこにでも割り込んでくるので、他の例外とは別のスタックを使う
+        entries[2] = IdtDescriptor::new(
+            segment_selector,
+            3,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint2,
+        );
         // Breakpoint Exception
         entries[3] = IdtDescriptor::new(
             segment_selector,
@@ -717,12 +1465,15 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint14,
         );
-        entries[32] = IdtDescriptor::new(
-            segment_selector,
-            1,
-            IdtAttr::IntGateDPL0,
-            interrupt_entrypoint32,
-        );
+        for (i, f) in OTHER_EXCEPTION_ENTRYPOINTS {
+            entries[i] = IdtDescriptor::new(segment_selector, 1, IdtAttr::IntGateDPL0, f);
+        }
+        // 外部割り込みはISTを使わず、割り込まれたタスクのスタックで処理する
+        // ハンドラの中でタスクを切り替えるので、タスク間で共有するISTの上に割り込みのフレームを残せない
+        for (i, f) in EXTERNAL_INTERRUPT_ENTRYPOINTS.iter().enumerate() {
+            entries[FIRST_EXTERNAL_VECTOR + i] =
+                IdtDescriptor::new(segment_selector, 0, IdtAttr::IntGateDPL0, *f);
+        }
         let limit = size_of_val(&entries) as u16;
         // アドレスを固定
         let entries = Box::pin(entries);
@@ -784,9 +1535,84 @@ impl TaskStateSegment64 {
             inner: Box::pin(tss64),
         };
         info!("TSS64 created @ {:#X}", this.phys_addr());
+        TSS64_ADDR.store(this.phys_addr(), Ordering::SeqCst);
         this
     }
 }
+// タスクを切り替えるたびにRSP0を書き換えるので、TSSの場所を覚えておく
+static TSS64_ADDR: AtomicU64 = AtomicU64::new(0);
+
+// syscall命令で入ってきた時に使うカーネルのスタック。syscallのエントリから名前で参照する
+#[no_mangle]
+static SYSCALL_KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+
+// ユーザモードから割り込まれた時やシステムコールで使うカーネルのスタック (RSP0) を設定する
+pub fn set_kernel_stack(rsp0: u64) {
+    SYSCALL_KERNEL_RSP.store(rsp0, Ordering::SeqCst);
+    let tss = TSS64_ADDR.load(Ordering::SeqCst);
+    if tss == 0 {
+        return;
+    }
+    let rsp0_addr = tss + offset_of!(TaskStateSegment64Inner, _rsp) as u64;
+    unsafe { (rsp0_addr as *mut u64).write_unaligned(rsp0) }
+}
+
+// 今のタスクのカーネルのスタック (RSP0) の一番上
+pub fn current_kernel_stack_top() -> u64 {
+    SYSCALL_KERNEL_RSP.load(Ordering::SeqCst)
+}
+
+// 今のスタックを捨てて、stack_topから始まるスタックでfを呼ぶ
+/// # Safety
+/// stack_topは使われていないスタックの一番上であること
+pub unsafe fn switch_stack_and_call(stack_top: u64, f: extern "sysv64" fn() -> !) -> ! {
+    asm!(
+        "mov rsp, {stack_top}",
+        "call {f}",
+        "ud2",
+        stack_top = in(reg) stack_top & !0xF,
+        f = in(reg) f,
+        options(noreturn)
+    )
+}
+
+// ユーザモードに移ってentryから実行する。戻ってこない
+// カーネルの値が見えないように、汎用レジスタは全部消してから移る
+/// # Safety
+/// 今のページテーブルで、entryとuser_rspがユーザモードから使えるアドレスになっていること
+/// 割り込みとシステムコールで戻ってくるカーネルのスタック (TSSのrsp0など) が設定済みであること
+pub unsafe fn enter_user_mode(entry: u64, user_rsp: u64) -> ! {
+    asm!(
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) USER_DS as u64,
+        rsp = in(reg) user_rsp,
+        rflags = in(reg) RFLAGS_RESERVED | RFLAGS_IF,
+        cs = in(reg) USER_CS as u64,
+        rip = in(reg) entry,
+        options(noreturn)
+    )
+}
+
 impl Drop for TaskStateSegment64 {
     fn drop(&mut self) {
         panic!("TSS64 being dropped!");
@@ -800,15 +1626,22 @@ pub const BIT_PRESENT: u64 = 1u64 << 47;
 pub const BIT_CS_LONG_MODE: u64 = 1u64 << 53;
 pub const BIT_CS_READABLE: u64 = 1u64 << 41;
 pub const BIT_DS_WRITABLE: u64 = 1u64 << 41;
+pub const BIT_DPL3: u64 = 3u64 << 45;
 
 pub const KERNEL_CS: u16 = 1 << 3;
 pub const KERNEL_DS: u16 = 2 << 3;
 pub const TSS64_SEL: u16 = 3 << 3;
+// TSSのディスクリプタは16バイトなので、その次は5番目になる
+// sysretで使えるように、ユーザのデータセグメントをコードセグメントの直前に置く
+pub const USER_DS: u16 = (5 << 3) | 3;
+pub const USER_CS: u16 = (6 << 3) | 3;
 
 #[repr(u64)]
 enum GdtAttr {
     KernelCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
     KernelData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE,
+    UserCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE | BIT_DPL3,
+    UserData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE | BIT_DPL3,
 }
 
 pub struct GdtSegmentDescriptor {
@@ -862,7 +1695,9 @@ const _: () = assert!(size_of::<TaskStateSegment64Descriptor>() == 16);
  * ├─ Segment Descriptor（例: 0番: NULLセグメント）
  * ├─ Segment Descriptor（例: 1番: カーネルコードセグメント）
  * ├─ Segment Descriptor（例: 2番: カーネルデータ
- * └─ TSS Descriptor（例: 3番: TSSセグメント） →割り込み時のスタック切り替え制御
+ * ├─ TSS Descriptor（例: 3番: TSSセグメント） →割り込み時のスタック切り替え制御
+ * ├─ Segment Descriptor（例: 5番: ユーザデータ）
+ * └─ Segment Descriptor（例: 6番: ユーザコード）
  */
 
 // https://wiki.osdev.org/GDT_Tutorial#Small_Kernel_Setup
@@ -873,8 +1708,10 @@ pub struct Gdt {
     kernel_code_segment: GdtSegmentDescriptor,
     kernel_data_segment: GdtSegmentDescriptor,
     task_state_segment: TaskStateSegment64Descriptor,
+    user_data_segment: GdtSegmentDescriptor,
+    user_code_segment: GdtSegmentDescriptor,
 }
-const _: () = assert!(size_of::<Gdt>() == 40);
+const _: () = assert!(size_of::<Gdt>() == 56);
 
 #[allow(dead_code)]
 #[repr(C, packed)]
@@ -915,6 +1752,8 @@ impl Default for GdtWrapper {
             kernel_code_segment: GdtSegmentDescriptor::new(GdtAttr::KernelCode),
             kernel_data_segment: GdtSegmentDescriptor::new(GdtAttr::KernelData),
             task_state_segment: TaskStateSegment64Descriptor::new(tss64.phys_addr()),
+            user_data_segment: GdtSegmentDescriptor::new(GdtAttr::UserData),
+            user_code_segment: GdtSegmentDescriptor::new(GdtAttr::UserCode),
         };
         let gdt = Box::pin(gdt);
         Self { inner: gdt, tss64 }
@@ -958,3 +1797,74 @@ pub fn flush_tlb() {
         write_cr3(read_cr3());
     }
 }
+
+// MMIO領域をキャッシュ無効でマップし直す
+// ストレートマッピングなので返るアドレスは物理アドレスと同じ
+pub fn map_mmio(phys: u64, size: u64) -> Result<*mut u8> {
+    if size == 0 {
+        return Err("Invalid MMIO size");
+    }
+    let start = phys & !ATTR_MASK;
+    let end = phys
+        .checked_add(size)
+        .and_then(|e| e.checked_add(ATTR_MASK))
+        .ok_or("MMIO range overflow")?
+        & !ATTR_MASK;
+    let table = unsafe { &mut *read_cr3() };
+    table.create_mapping(start, end, start, PageAttr::ReadWriteIo)?;
+    flush_tlb();
+    Ok(phys as *mut u8)
+}
+
+// ストレートマッピングされた範囲の属性を変える (スタックのガードページなど)
+pub fn set_page_attr(start: u64, end: u64, attr: PageAttr) -> Result<()> {
+    let table = unsafe { &mut *read_cr3() };
+    table.create_mapping(start, end, start, attr)?;
+    flush_tlb();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use core::ptr::null_mut;
+    use core::sync::atomic::AtomicPtr;
+    use core::sync::atomic::AtomicU64;
+    use core::sync::atomic::Ordering;
+
+    static MAIN_CONTEXT: AtomicPtr<Context> = AtomicPtr::new(null_mut());
+    static SUB_CONTEXT: AtomicPtr<Context> = AtomicPtr::new(null_mut());
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    extern "sysv64" fn sub_entry() -> ! {
+        let (main, sub) = (
+            MAIN_CONTEXT.load(Ordering::SeqCst),
+            SUB_CONTEXT.load(Ordering::SeqCst),
+        );
+        loop {
+            // FPUの状態がタスクごとに保存されていることも確かめる
+            let x = core::hint::black_box(1.5f64) * 2.0;
+            COUNTER.fetch_add(x as u64, Ordering::SeqCst);
+            unsafe { switch_context(&mut *sub, &*main) }
+        }
+    }
+
+    #[test_case]
+    fn switch_between_contexts() {
+        let stack = vec![0u8; 16 * 1024];
+        let stack_top = stack.as_ptr() as u64 + stack.len() as u64;
+        let main = Box::into_raw(Box::new(Context::empty()));
+        let sub = Box::into_raw(Box::new(Context::new(sub_entry, stack_top)));
+        MAIN_CONTEXT.store(main, Ordering::SeqCst);
+        SUB_CONTEXT.store(sub, Ordering::SeqCst);
+        for i in 1..=3 {
+            unsafe { switch_context(&mut *main, &*sub) };
+            assert_eq!(COUNTER.load(Ordering::SeqCst), i * 3);
+        }
+        unsafe {
+            drop(Box::from_raw(main));
+            drop(Box::from_raw(sub));
+        }
+    }
+}
@@ -0,0 +1,32 @@
+// ユーザモードで例外を起こす。カーネルは止まらずに、このプロセスだけが終わるはず
+// run /bin/fault db  (db: TFを立てて#DB, ss: 正規でないrspにpushして#SS)
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use wasabi_user::args;
+use wasabi_user::println;
+
+wasabi_user::entry!(main);
+
+fn main() -> i64 {
+    match args().nth(1).unwrap_or("db") {
+        "db" => unsafe {
+            asm!("pushfq", "or qword ptr [rsp], 0x100", "popfq", "nop");
+        },
+        "ss" => unsafe {
+            asm!(
+                "mov rsp, {}",
+                "push rax",
+                in(reg) 0x8000_0000_0000_0000u64,
+                options(noreturn)
+            );
+        },
+        kind => {
+            println!("usage: fault [db|ss] (got {kind})");
+            return 1;
+        }
+    }
+    println!("Still alive after the fault");
+    1
+}
@@ -0,0 +1,22 @@
+// cargo build -p wasabi_user --example hello --target x86_64-unknown-none \
+//   (RUSTFLAGSは src/lib.rs の先頭を参照)
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use wasabi_user::args;
+use wasabi_user::println;
+
+wasabi_user::entry!(main);
+
+fn main() -> i64 {
+    println!("Hello from user mode!");
+    for (i, arg) in args().enumerate() {
+        println!("argv[{i}] = {arg}");
+    }
+    let squares: Vec<u64> = (1..=5).map(|i| i * i).collect();
+    println!("squares = {squares:?}");
+    0
+}
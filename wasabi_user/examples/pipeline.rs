@@ -0,0 +1,59 @@
+// 2つのプログラムをパイプでつないで動かす (cmd1 | cmd2)
+// pipeline /bin/hello /bin/upper
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use wasabi_user::args;
+use wasabi_user::println;
+use wasabi_user::syscall::close;
+use wasabi_user::syscall::dup;
+use wasabi_user::syscall::dup2;
+use wasabi_user::syscall::pipe;
+use wasabi_user::syscall::spawn;
+use wasabi_user::syscall::waitpid;
+use wasabi_user::syscall::Result;
+use wasabi_user::syscall::STDIN;
+use wasabi_user::syscall::STDOUT;
+
+wasabi_user::entry!(main);
+
+// fdをtargetにつないだ状態でpathを動かす。子はfdを引き継ぐ
+fn spawn_with(path: &str, fd: usize, target: usize) -> Result<u64> {
+    let saved = dup(target)?;
+    dup2(fd, target)?;
+    let pid = spawn(path, &[]);
+    dup2(saved, target)?;
+    close(saved)?;
+    pid
+}
+
+fn run(producer: &str, consumer: &str) -> Result<(i64, i64)> {
+    let (reader, writer) = pipe()?;
+    let producer = spawn_with(producer, writer, STDOUT)?;
+    // 書く側を閉じておかないと、producerが終わってもconsumerにEOFが届かない
+    close(writer)?;
+    let consumer = spawn_with(consumer, reader, STDIN)?;
+    close(reader)?;
+    Ok((waitpid(producer)?, waitpid(consumer)?))
+}
+
+fn main() -> i64 {
+    let args: Vec<&str> = args().collect();
+    let [_, producer, consumer] = args[..] else {
+        println!("usage: pipeline <producer> <consumer>");
+        return 1;
+    };
+    match run(producer, consumer) {
+        Ok((p, c)) => {
+            println!("{producer} exited with {p}, {consumer} exited with {c}");
+            0
+        }
+        Err(e) => {
+            println!("pipeline failed: {e}");
+            1
+        }
+    }
+}
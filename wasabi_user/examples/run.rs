@@ -0,0 +1,39 @@
+// 引数で渡されたプログラムを子プロセスとして動かして、終了コードを表示する
+// run /bin/hello a b
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use wasabi_user::args;
+use wasabi_user::println;
+use wasabi_user::syscall::spawn;
+use wasabi_user::syscall::waitpid;
+
+wasabi_user::entry!(main);
+
+fn main() -> i64 {
+    let args: Vec<&str> = args().skip(1).collect();
+    let Some((path, rest)) = args.split_first() else {
+        println!("usage: run <path> [args...]");
+        return 1;
+    };
+    let pid = match spawn(path, rest) {
+        Ok(pid) => pid,
+        Err(e) => {
+            println!("Failed to spawn {path}: {e}");
+            return 1;
+        }
+    };
+    match waitpid(pid) {
+        Ok(code) => {
+            println!("{path} (pid {pid}) exited with code {code}");
+            0
+        }
+        Err(e) => {
+            println!("Failed to wait for {pid}: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// 標準入力をEOFまで読んで、大文字にして書き出す
+#![no_std]
+#![no_main]
+
+use wasabi_user::syscall::read;
+use wasabi_user::syscall::write;
+use wasabi_user::syscall::STDIN;
+use wasabi_user::syscall::STDOUT;
+
+wasabi_user::entry!(main);
+
+fn main() -> i64 {
+    let mut buf = [0u8; 256];
+    loop {
+        let n = match read(STDIN, &mut buf) {
+            Ok(0) => return 0,
+            Ok(n) => n,
+            Err(_) => return 1,
+        };
+        buf[..n].make_ascii_uppercase();
+        if write(STDOUT, &buf[..n]).is_err() {
+            return 1;
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+use crate::syscall::sbrk;
+
+// 解放されたブロック。ブロックの先頭にそのまま書き込む
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const BLOCK_UNIT: usize = 16;
+const _: () = assert!(size_of::<FreeBlock>() <= BLOCK_UNIT);
+
+// sbrkで伸ばしたヒープから切り出すアロケータ
+// 解放されたブロックはリストにつないでおき、先頭から探して最初に入るものを使い回す
+pub struct SbrkAllocator {
+    free_list: UnsafeCell<*mut FreeBlock>,
+}
+
+// ユーザプログラムはシングルスレッドで動く
+unsafe impl Sync for SbrkAllocator {}
+
+impl SbrkAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_list: UnsafeCell::new(null_mut()),
+        }
+    }
+    // deallocにも同じLayoutが渡されるので、大きさはブロックに持たせなくてよい
+    fn block_size(layout: Layout) -> usize {
+        layout.size().max(1).next_multiple_of(BLOCK_UNIT)
+    }
+    unsafe fn push_free(&self, addr: *mut u8, size: usize) {
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock {
+            size,
+            next: *self.free_list.get(),
+        });
+        *self.free_list.get() = block;
+    }
+    unsafe fn take_free(&self, size: usize, align: usize) -> *mut u8 {
+        let mut link = self.free_list.get();
+        while !(*link).is_null() {
+            let block = *link;
+            if (block as usize) & (align - 1) == 0 && (*block).size >= size {
+                let rest = (*block).size - size;
+                *link = (*block).next;
+                if rest > 0 {
+                    self.push_free((block as *mut u8).add(size), rest);
+                }
+                return block as *mut u8;
+            }
+            link = &mut (*block).next;
+        }
+        null_mut()
+    }
+}
+
+impl Default for SbrkAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for SbrkAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = Self::block_size(layout);
+        let align = layout.align().max(BLOCK_UNIT);
+        let p = self.take_free(size, align);
+        if !p.is_null() {
+            return p;
+        }
+        let Ok(brk) = sbrk(0) else {
+            return null_mut();
+        };
+        let pad = (brk as usize).next_multiple_of(align) - brk as usize;
+        let Ok(start) = sbrk(pad + size) else {
+            return null_mut();
+        };
+        if pad > 0 {
+            self.push_free(start, pad);
+        }
+        start.add(pad)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.push_free(ptr, Self::block_size(layout))
+    }
+}
+
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: SbrkAllocator = SbrkAllocator::new();
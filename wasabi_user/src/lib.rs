@@ -0,0 +1,83 @@
+// wasabiのユーザプログラム用のランタイム
+//
+// ユーザプログラムは x86_64-unknown-none 向けに、次のオプションでビルドする
+//   -C relocation-model=static -C code-model=large -C link-arg=--image-base=0x400000000000
+// ローダは静的リンクされたET_EXECしか読まず、ユーザ空間は0x4000_0000_0000から始まる
+// 2GiBより上に置くので、code-modelもlargeにする
+//
+// #![no_std]
+// #![no_main]
+// wasabi_user::entry!(main);
+// fn main() -> i64 { wasabi_user::println!("hello"); 0 }
+#![no_std]
+
+pub mod allocator;
+pub mod print;
+pub mod syscall;
+
+use core::ffi::c_char;
+use core::ffi::CStr;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+#[cfg(not(test))]
+core::arch::global_asm!(
+    r#"
+.global _start
+_start:
+    // カーネルはrspにargc、その上にargv[]を積んで始める
+    xor ebp, ebp
+    mov rdi, [rsp]
+    lea rsi, [rsp + 8]
+    and rsp, -16
+    call wasabi_user_start
+    ud2
+"#
+);
+
+extern "Rust" {
+    // entry!で定義される
+    fn wasabi_user_main() -> i64;
+}
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+#[no_mangle]
+extern "C" fn wasabi_user_start(argc: usize, argv: *mut *const c_char) -> ! {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    let code = unsafe { wasabi_user_main() };
+    syscall::exit(code)
+}
+
+// コマンドライン引数を返す。最初の要素は実行ファイルのパス
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argc = ARGC.load(Ordering::Relaxed);
+    let argv = ARGV.load(Ordering::Relaxed);
+    (0..argc).map(move |i| {
+        // カーネルが積んだ文字列はUTF-8のNUL終端で、プログラムが終わるまで残っている
+        let arg = unsafe { CStr::from_ptr(*argv.add(i)) };
+        arg.to_str().unwrap_or("")
+    })
+}
+
+// ユーザプログラムのmain関数を指定する
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        fn wasabi_user_main() -> i64 {
+            let main: fn() -> i64 = $main;
+            main()
+        }
+    };
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("PANIC: {info}");
+    syscall::exit(-1)
+}
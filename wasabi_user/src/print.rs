@@ -0,0 +1,40 @@
+use core::fmt;
+
+use crate::syscall::write;
+use crate::syscall::STDOUT;
+
+pub struct StdoutWriter;
+
+impl fmt::Write for StdoutWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = s.as_bytes();
+        while !buf.is_empty() {
+            match write(STDOUT, buf) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(n) => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn _print(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut StdoutWriter, args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::print::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::print!("{}\n", format_args!($($arg)*))
+    };
+}
@@ -0,0 +1,168 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::time::Duration;
+
+// 番号とABIはカーネルのsrc/syscall.rsと揃える
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_READ: u64 = 1;
+pub const SYS_WRITE: u64 = 2;
+pub const SYS_OPEN: u64 = 3;
+pub const SYS_CLOSE: u64 = 4;
+pub const SYS_SLEEP: u64 = 5;
+pub const SYS_SBRK: u64 = 6;
+pub const SYS_SPAWN: u64 = 7;
+pub const SYS_WAITPID: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 10;
+pub const SYS_PIPE: u64 = 11;
+pub const SYS_DUP: u64 = 12;
+pub const SYS_DUP2: u64 = 13;
+
+pub const OPEN_CREATE: u64 = 1 << 0;
+pub const OPEN_TRUNCATE: u64 = 1 << 1;
+pub const OPEN_APPEND: u64 = 1 << 2;
+
+pub const MMAP_WRITABLE: u64 = 1 << 0;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+pub type Result<T> = core::result::Result<T, i64>;
+
+/// # Safety
+/// 引数がポインタの場合、カーネルがそのメモリを読み書きする
+pub unsafe fn syscall3(nr: u64, a0: u64, a1: u64, a2: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") nr as i64 => ret,
+        in("rdi") a0,
+        in("rsi") a1,
+        in("rdx") a2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// # Safety
+/// syscall3と同じ
+pub unsafe fn syscall4(nr: u64, a0: u64, a1: u64, a2: u64, a3: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") nr as i64 => ret,
+        in("rdi") a0,
+        in("rsi") a1,
+        in("rdx") a2,
+        in("r10") a3,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+fn to_result(ret: i64) -> Result<u64> {
+    if ret < 0 {
+        Err(ret)
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+pub fn exit(code: i64) -> ! {
+    unsafe { syscall3(SYS_EXIT, code as u64, 0, 0) };
+    unreachable!("exit returned")
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
+    let ret = unsafe {
+        syscall3(
+            SYS_READ,
+            fd as u64,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+        )
+    };
+    to_result(ret).map(|n| n as usize)
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize> {
+    let ret = unsafe { syscall3(SYS_WRITE, fd as u64, buf.as_ptr() as u64, buf.len() as u64) };
+    to_result(ret).map(|n| n as usize)
+}
+
+pub fn open(path: &str, flags: u64) -> Result<usize> {
+    let ret = unsafe { syscall3(SYS_OPEN, path.as_ptr() as u64, path.len() as u64, flags) };
+    to_result(ret).map(|fd| fd as usize)
+}
+
+pub fn close(fd: usize) -> Result<()> {
+    to_result(unsafe { syscall3(SYS_CLOSE, fd as u64, 0, 0) }).map(|_| ())
+}
+
+pub fn sleep(duration: Duration) {
+    unsafe { syscall3(SYS_SLEEP, duration.as_millis() as u64, 0, 0) };
+}
+
+// ヒープをincrementバイト伸ばして、伸ばす前の終わりを返す
+pub fn sbrk(increment: usize) -> Result<*mut u8> {
+    to_result(unsafe { syscall3(SYS_SBRK, increment as u64, 0, 0) }).map(|p| p as *mut u8)
+}
+
+// 読む側と書く側のfdを返す
+pub fn pipe() -> Result<(usize, usize)> {
+    let mut fds = [0u64; 2];
+    to_result(unsafe { syscall3(SYS_PIPE, fds.as_mut_ptr() as u64, 0, 0) })?;
+    Ok((fds[0] as usize, fds[1] as usize))
+}
+
+pub fn dup(fd: usize) -> Result<usize> {
+    to_result(unsafe { syscall3(SYS_DUP, fd as u64, 0, 0) }).map(|fd| fd as usize)
+}
+
+pub fn dup2(oldfd: usize, newfd: usize) -> Result<usize> {
+    to_result(unsafe { syscall3(SYS_DUP2, oldfd as u64, newfd as u64, 0) }).map(|fd| fd as usize)
+}
+
+// ゼロ埋めされたメモリをsizeバイト用意する。ページは触った時に割り当てられる
+pub fn mmap(size: usize, flags: u64) -> Result<*mut u8> {
+    to_result(unsafe { syscall3(SYS_MMAP, size as u64, flags, 0) }).map(|p| p as *mut u8)
+}
+
+/// # Safety
+/// [addr, addr + size)をこの後使ってはいけない
+pub unsafe fn munmap(addr: *mut u8, size: usize) -> Result<()> {
+    to_result(syscall3(SYS_MUNMAP, addr as u64, size as u64, 0)).map(|_| ())
+}
+
+// pathの実行ファイルを子プロセスとして動かして、pidを返す
+pub fn spawn(path: &str, args: &[&str]) -> Result<u64> {
+    // 引数はそれぞれ'\0'で終わらせて、1つのバッファで渡す
+    let mut buf: Vec<u8> = Vec::new();
+    for arg in args {
+        buf.extend_from_slice(arg.as_bytes());
+        buf.push(0);
+    }
+    to_result(unsafe {
+        syscall4(
+            SYS_SPAWN,
+            path.as_ptr() as u64,
+            path.len() as u64,
+            buf.as_ptr() as u64,
+            buf.len() as u64,
+        )
+    })
+}
+
+// 子プロセスが終わるまで待って、終了コードを返す
+pub fn waitpid(pid: u64) -> Result<i64> {
+    let mut status: i64 = 0;
+    to_result(unsafe { syscall3(SYS_WAITPID, pid, &mut status as *mut i64 as u64, 0) })?;
+    Ok(status)
+}